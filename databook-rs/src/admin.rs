@@ -0,0 +1,592 @@
+use crate::audit::{self, Action, AuditEntry, AuditFilter, AuditRecord};
+use crate::databook::databook_admin_server::DatabookAdmin;
+use crate::databook::{
+    install_plugin_request, AuditRequest, AuditResponse, InstallPluginRequest, ListPluginsRequest,
+    ListPluginsResponse, PluginRef, PluginStatus, UninstallPluginResponse,
+};
+use crate::plugin_manager::{PluginError, PluginInfo, PluginManager, PluginSource};
+use crate::rest;
+use crate::timeline_api::from_millis;
+use crate::{run_blocking, AUDIT, CONFIG, PLUGINS};
+
+use chrono::{DateTime, Utc};
+use rocket::request::{self, FromRequest};
+use rocket::Outcome;
+use rocket_contrib::json::Json;
+use tonic::{Code, Request, Response, Status};
+use tracing::instrument;
+
+#[derive(Debug)]
+enum AdminError {
+    Plugin(PluginError),
+    InvalidRequest(String),
+    Audit(audit::AuditError),
+    Internal(String),
+}
+
+impl From<AdminError> for Status {
+    fn from(e: AdminError) -> Self {
+        tracing::error!("admin request failed {:?}", e);
+        match e {
+            AdminError::Plugin(PluginError::AlreadyInstalled(name)) => Status::new(
+                Code::AlreadyExists,
+                format!("plugin {} already installed", name),
+            ),
+            AdminError::Plugin(PluginError::PluginDoesNotExist(name)) => {
+                Status::new(Code::NotFound, format!("plugin {} does not exist", name))
+            }
+            AdminError::Plugin(PluginError::InvalidPlugin(message))
+            | AdminError::InvalidRequest(message) => Status::new(Code::InvalidArgument, message),
+            AdminError::Plugin(_) | AdminError::Audit(_) | AdminError::Internal(_) => {
+                Status::new(Code::Internal, "Internal Error")
+            }
+        }
+    }
+}
+
+// read_plugins runs f holding the read lock of the plugin manager, which
+// invocations share
+fn read_plugins<T>(
+    f: impl FnOnce(&PluginManager) -> Result<T, PluginError>,
+) -> Result<T, AdminError> {
+    match PLUGINS.get() {
+        Some(p) => {
+            let plugins = p.read().map_err(|e| {
+                AdminError::Internal(format!("Could not get lock for plugins object {:?}", e))
+            })?;
+            f(&plugins).map_err(AdminError::Plugin)
+        }
+        None => Err(AdminError::Internal("No plugins setup".into())),
+    }
+}
+
+// with_plugins runs f holding the write lock of the plugin manager, so
+// invocations never see a plugin half installed. It is only held for quick
+// changes, see AdminToken::install.
+fn with_plugins<T>(
+    f: impl FnOnce(&mut PluginManager) -> Result<T, PluginError>,
+) -> Result<T, AdminError> {
+    match PLUGINS.get() {
+        Some(p) => {
+            let mut plugins = p.write().map_err(|e| {
+                AdminError::Internal(format!("Could not get lock for plugins object {:?}", e))
+            })?;
+            f(&mut plugins).map_err(AdminError::Plugin)
+        }
+        None => Err(AdminError::Internal("No plugins setup".into())),
+    }
+}
+
+// admin_name is who an admin token stands for in the audit log, the tokens
+// are not tied to a user so it is their position in admin_tokens
+fn admin_name(token: &str) -> Option<String> {
+    CONFIG
+        .get()
+        .and_then(|c| c.admin_tokens.as_ref())
+        .and_then(|tokens| {
+            tokens
+                .iter()
+                .position(|t| constant_time_eq(t.as_bytes(), token.as_bytes()))
+        })
+        .map(|i| format!("admin-token-{}", i + 1))
+}
+
+fn is_admin_token(token: &str) -> bool {
+    admin_name(token).is_some()
+}
+
+// compares the whole token even after a mismatch, so the time taken does not
+// tell how much of a guessed token is right
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub fn bearer_token(header: Option<&str>) -> Option<&str> {
+    header.and_then(|h| h.strip_prefix("Bearer "))
+}
+
+// tonic interceptor for the admin service
+pub fn check_admin_token(request: Request<()>) -> Result<Request<()>, Status> {
+    let header = request
+        .metadata()
+        .get("authorization")
+        .and_then(|v| v.to_str().ok());
+    match bearer_token(header) {
+        Some(token) if is_admin_token(token) => Ok(request),
+        _ => Err(Status::new(Code::Unauthenticated, "invalid admin token")),
+    }
+}
+
+// AdminToken is who made an admin request, the rocket request guard for
+// the admin routes
+#[derive(Debug)]
+pub struct AdminToken {
+    name: Option<String>,
+    source: Option<String>,
+}
+
+impl AdminToken {
+    // grpc requests went through check_admin_token already
+    fn from_grpc<T>(request: &Request<T>) -> Self {
+        let header = request
+            .metadata()
+            .get("authorization")
+            .and_then(|v| v.to_str().ok());
+        Self {
+            name: bearer_token(header).and_then(admin_name),
+            source: request.remote_addr().map(|a| a.ip().to_string()),
+        }
+    }
+
+    // audit records the change, failed ones too, and its start before it
+    // is made (`result` is None then). Unless the audit log fails open, a
+    // change that cannot be recorded is not made, or is reported as failed
+    // when only its outcome could not be. The plugin of an install is only
+    // known once the package was read.
+    fn audit<T>(
+        &self,
+        action: Action,
+        plugin: Option<String>,
+        started_at: DateTime<Utc>,
+        result: Option<&Result<T, AdminError>>,
+    ) -> Result<(), AdminError> {
+        let log = match AUDIT.get() {
+            Some(log) => log,
+            None => return Ok(()),
+        };
+        let outcome = match result {
+            None => audit::Outcome::Started,
+            Some(Ok(_)) => audit::Outcome::Succeeded,
+            Some(Err(_)) => audit::Outcome::Failed,
+        };
+        let record = AuditRecord {
+            action,
+            user: self.name.clone(),
+            groups: vec![],
+            source: self.source.clone(),
+            plugin,
+            options_hash: None,
+            approvers: vec![],
+            outcome,
+            error: result
+                .and_then(|r| r.as_ref().err())
+                .map(|e| format!("{:?}", e)),
+            started_at,
+            duration_ms: (Utc::now() - started_at).num_milliseconds(),
+        };
+        match log.append(record) {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                tracing::error!("could not audit the admin {} request: {}", action, e);
+                if log.fails_open() {
+                    Ok(())
+                } else {
+                    Err(AdminError::Audit(e))
+                }
+            }
+        }
+    }
+
+    fn install(
+        &self,
+        source: Result<PluginSource, AdminError>,
+        upgrade: bool,
+    ) -> Result<PluginInfo, AdminError> {
+        let started_at = Utc::now();
+        let action = if upgrade {
+            Action::Upgrade
+        } else {
+            Action::Install
+        };
+        self.audit::<()>(action, None, started_at, None)?;
+        // the wasm is compiled and written out under the read lock, only
+        // moving it in place blocks invocations
+        let result = source
+            .and_then(|s| read_plugins(|p| p.stage(s, upgrade)))
+            .and_then(|staged| with_plugins(|p| p.commit(staged)));
+        self.audit(
+            action,
+            result.as_ref().ok().map(|i| i.name.clone()),
+            started_at,
+            Some(&result),
+        )?;
+        result
+    }
+
+    fn change<T>(
+        &self,
+        action: Action,
+        name: &str,
+        f: impl FnOnce(&mut PluginManager) -> Result<T, PluginError>,
+    ) -> Result<T, AdminError> {
+        let started_at = Utc::now();
+        self.audit::<()>(action, Some(name.to_string()), started_at, None)?;
+        let result = with_plugins(f);
+        self.audit(action, Some(name.to_string()), started_at, Some(&result))?;
+        result
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for AdminToken {
+    type Error = ();
+
+    fn from_request(request: &'a rocket::Request<'r>) -> request::Outcome<Self, Self::Error> {
+        match bearer_token(request.headers().get_one("Authorization")).and_then(admin_name) {
+            Some(name) => Outcome::Success(AdminToken {
+                name: Some(name),
+                source: request.client_ip().map(|ip| ip.to_string()),
+            }),
+            None => Outcome::Failure((rocket::http::Status::Unauthorized, ())),
+        }
+    }
+}
+
+fn to_grpc_status(info: PluginInfo) -> PluginStatus {
+    PluginStatus {
+        name: info.name,
+        version: info.version.unwrap_or_default(),
+        enabled: info.enabled,
+    }
+}
+
+fn to_rest_status(info: PluginInfo) -> rest::PluginStatus {
+    rest::PluginStatus {
+        name: info.name,
+        version: info.version,
+        enabled: info.enabled,
+    }
+}
+
+fn grpc_source(request: InstallPluginRequest) -> Result<PluginSource, AdminError> {
+    match request.source {
+        Some(install_plugin_request::Source::Package(package)) => {
+            Ok(PluginSource::Package(package))
+        }
+        Some(install_plugin_request::Source::Files(files)) => Ok(PluginSource::Files {
+            wasm: files.wasm,
+            config: files.config.into_bytes(),
+            signature: Some(files.signature)
+                .filter(|s| !s.is_empty())
+                .map(String::into_bytes),
+        }),
+        None => Err(AdminError::InvalidRequest(
+            "either package or files must be set".into(),
+        )),
+    }
+}
+
+fn rest_source(request: rest::InstallPluginRequest) -> Result<PluginSource, AdminError> {
+    let decode = |content: String| {
+        base64::decode(content).map_err(|e| AdminError::InvalidRequest(e.to_string()))
+    };
+    match request {
+        rest::InstallPluginRequest {
+            package: Some(package),
+            ..
+        } => Ok(PluginSource::Package(decode(package)?)),
+        rest::InstallPluginRequest {
+            wasm: Some(wasm),
+            config: Some(config),
+            signature,
+            ..
+        } => Ok(PluginSource::Files {
+            wasm: decode(wasm)?,
+            config: config.into_bytes(),
+            signature: signature.map(String::into_bytes),
+        }),
+        _ => Err(AdminError::InvalidRequest(
+            "either package or wasm and config must be set".into(),
+        )),
+    }
+}
+
+const DEFAULT_AUDIT_LIMIT: usize = 100;
+
+fn query_audit(mut filter: AuditFilter) -> Result<Vec<AuditEntry>, AdminError> {
+    let log = AUDIT
+        .get()
+        .ok_or_else(|| AdminError::InvalidRequest("no [audit] log is configured".into()))?;
+    filter.limit = filter.limit.or(Some(DEFAULT_AUDIT_LIMIT));
+    log.query(&filter).map_err(AdminError::Audit)
+}
+
+fn parse_action(action: &str) -> Result<Action, AdminError> {
+    Action::parse(action).ok_or_else(|| {
+        AdminError::InvalidRequest(format!(
+            "unknown action {:?}, expected invoke, install, upgrade, disable, enable or uninstall",
+            action
+        ))
+    })
+}
+
+fn parse_time(time: Option<String>) -> Result<Option<DateTime<Utc>>, AdminError> {
+    time.map(|t| {
+        t.parse::<DateTime<Utc>>().map_err(|e| {
+            AdminError::InvalidRequest(format!("invalid time {:?}, use RFC 3339 {}", t, e))
+        })
+    })
+    .transpose()
+}
+
+fn to_grpc_entry(entry: AuditEntry) -> crate::databook::AuditEntry {
+    let record = entry.record;
+    crate::databook::AuditEntry {
+        sequence: entry.sequence,
+        action: record.action.to_string(),
+        user: record.user.unwrap_or_default(),
+        groups: record.groups,
+        source: record.source.unwrap_or_default(),
+        plugin: record.plugin.unwrap_or_default(),
+        options_hash: record.options_hash.unwrap_or_default(),
+        outcome: record.outcome.to_string(),
+        error: record.error.unwrap_or_default(),
+        started_at: record.started_at.timestamp_millis(),
+        duration_ms: record.duration_ms,
+        previous_hash: entry.previous_hash,
+        hash: entry.hash,
+        approvers: record.approvers,
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct DatabookAdminGrpc {}
+
+#[tonic::async_trait]
+impl DatabookAdmin for DatabookAdminGrpc {
+    #[instrument]
+    async fn list(
+        &self,
+        _request: Request<ListPluginsRequest>,
+    ) -> Result<Response<ListPluginsResponse>, Status> {
+        run_blocking(|| {
+            read_plugins(|p| Ok(p.list())).map(|plugins| ListPluginsResponse {
+                plugins: plugins.into_iter().map(to_grpc_status).collect(),
+            })
+        })
+        .await
+    }
+
+    #[instrument(skip(request))]
+    async fn install(
+        &self,
+        request: Request<InstallPluginRequest>,
+    ) -> Result<Response<PluginStatus>, Status> {
+        tracing::info!("received install request");
+        let admin = AdminToken::from_grpc(&request);
+        run_blocking(move || {
+            admin
+                .install(grpc_source(request.into_inner()), false)
+                .map(to_grpc_status)
+        })
+        .await
+    }
+
+    #[instrument(skip(request))]
+    async fn upgrade(
+        &self,
+        request: Request<InstallPluginRequest>,
+    ) -> Result<Response<PluginStatus>, Status> {
+        tracing::info!("received upgrade request");
+        let admin = AdminToken::from_grpc(&request);
+        run_blocking(move || {
+            admin
+                .install(grpc_source(request.into_inner()), true)
+                .map(to_grpc_status)
+        })
+        .await
+    }
+
+    #[instrument]
+    async fn disable(&self, request: Request<PluginRef>) -> Result<Response<PluginStatus>, Status> {
+        tracing::info!("received disable request");
+        let admin = AdminToken::from_grpc(&request);
+        run_blocking(move || {
+            let name = request.into_inner().name;
+            admin
+                .change(Action::Disable, &name, |p| p.disable(&name))
+                .map(to_grpc_status)
+        })
+        .await
+    }
+
+    #[instrument]
+    async fn enable(&self, request: Request<PluginRef>) -> Result<Response<PluginStatus>, Status> {
+        tracing::info!("received enable request");
+        let admin = AdminToken::from_grpc(&request);
+        run_blocking(move || {
+            let name = request.into_inner().name;
+            admin
+                .change(Action::Enable, &name, |p| p.enable(&name))
+                .map(to_grpc_status)
+        })
+        .await
+    }
+
+    #[instrument]
+    async fn uninstall(
+        &self,
+        request: Request<PluginRef>,
+    ) -> Result<Response<UninstallPluginResponse>, Status> {
+        tracing::info!("received uninstall request");
+        let admin = AdminToken::from_grpc(&request);
+        run_blocking(move || {
+            let name = request.into_inner().name;
+            admin
+                .change(Action::Uninstall, &name, |p| p.uninstall(&name))
+                .map(|_| UninstallPluginResponse {})
+        })
+        .await
+    }
+
+    #[instrument]
+    async fn audit(
+        &self,
+        request: Request<AuditRequest>,
+    ) -> Result<Response<AuditResponse>, Status> {
+        run_blocking(|| {
+            let request = request.into_inner();
+            let action = match request.action.as_str() {
+                "" => None,
+                action => Some(parse_action(action)?),
+            };
+            let filter = AuditFilter {
+                user: Some(request.user).filter(|u| !u.is_empty()),
+                plugin: Some(request.plugin).filter(|p| !p.is_empty()),
+                action,
+                from: from_millis(request.from),
+                to: from_millis(request.to),
+                limit: Some(request.limit as usize).filter(|l| *l > 0),
+            };
+            query_audit(filter).map(|entries| AuditResponse {
+                entries: entries.into_iter().map(to_grpc_entry).collect(),
+            })
+        })
+        .await
+    }
+}
+
+fn rest_status_response(
+    response: Result<PluginInfo, AdminError>,
+) -> Json<rest::PluginStatusResponse> {
+    match response {
+        Ok(info) => Json(rest::PluginStatusResponse {
+            plugin: Some(to_rest_status(info)),
+            error: None,
+        }),
+        Err(e) => {
+            tracing::error!("admin request failed {:?}", e);
+            Json(rest::PluginStatusResponse {
+                plugin: None,
+                error: Some(format!("{:?}", e)),
+            })
+        }
+    }
+}
+
+#[get("/admin/plugins")]
+pub fn rest_list(_admin: AdminToken) -> Json<rest::ListPluginsResponse> {
+    match read_plugins(|p| Ok(p.list())) {
+        Ok(plugins) => Json(rest::ListPluginsResponse {
+            plugins: plugins.into_iter().map(to_rest_status).collect(),
+            error: None,
+        }),
+        Err(e) => Json(rest::ListPluginsResponse {
+            plugins: vec![],
+            error: Some(format!("{:?}", e)),
+        }),
+    }
+}
+
+#[post("/admin/plugins", data = "<request>")]
+pub fn rest_install(
+    admin: AdminToken,
+    request: Json<rest::InstallPluginRequest>,
+) -> Json<rest::PluginStatusResponse> {
+    tracing::info!("received install request");
+    rest_status_response(admin.install(rest_source(request.into_inner()), false))
+}
+
+#[put("/admin/plugins", data = "<request>")]
+pub fn rest_upgrade(
+    admin: AdminToken,
+    request: Json<rest::InstallPluginRequest>,
+) -> Json<rest::PluginStatusResponse> {
+    tracing::info!("received upgrade request");
+    rest_status_response(admin.install(rest_source(request.into_inner()), true))
+}
+
+#[post("/admin/plugins/<name>/disable")]
+pub fn rest_disable(admin: AdminToken, name: String) -> Json<rest::PluginStatusResponse> {
+    tracing::info!("received disable request");
+    rest_status_response(admin.change(Action::Disable, &name, |p| p.disable(&name)))
+}
+
+#[post("/admin/plugins/<name>/enable")]
+pub fn rest_enable(admin: AdminToken, name: String) -> Json<rest::PluginStatusResponse> {
+    tracing::info!("received enable request");
+    rest_status_response(admin.change(Action::Enable, &name, |p| p.enable(&name)))
+}
+
+#[delete("/admin/plugins/<name>")]
+pub fn rest_uninstall(admin: AdminToken, name: String) -> Json<rest::PluginStatusResponse> {
+    tracing::info!("received uninstall request");
+    match admin.change(Action::Uninstall, &name, |p| p.uninstall(&name)) {
+        Ok(()) => Json(rest::PluginStatusResponse {
+            plugin: None,
+            error: None,
+        }),
+        Err(e) => rest_status_response(Err(e)),
+    }
+}
+
+#[get("/admin/audit?<user>&<plugin>&<action>&<from>&<to>&<limit>")]
+pub fn rest_audit(
+    _admin: AdminToken,
+    user: Option<String>,
+    plugin: Option<String>,
+    action: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    limit: Option<usize>,
+) -> Json<rest::AuditResponse> {
+    let result = (|| {
+        let filter = AuditFilter {
+            user,
+            plugin,
+            action: action.as_deref().map(parse_action).transpose()?,
+            from: parse_time(from)?,
+            to: parse_time(to)?,
+            limit,
+        };
+        query_audit(filter)
+    })();
+    match result {
+        Ok(entries) => Json(rest::AuditResponse {
+            entries,
+            error: None,
+        }),
+        Err(e) => Json(rest::AuditResponse {
+            entries: vec![],
+            error: Some(format!("{:?}", e)),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"token", b"token"));
+        assert!(!constant_time_eq(b"token", b"tokem"));
+        assert!(!constant_time_eq(b"token", b"token2"));
+    }
+
+    #[test]
+    fn test_bearer_token() {
+        assert_eq!(Some("abc"), bearer_token(Some("Bearer abc")));
+        assert_eq!(None, bearer_token(Some("Basic abc")));
+        assert_eq!(None, bearer_token(None));
+    }
+}
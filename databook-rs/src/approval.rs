@@ -0,0 +1,345 @@
+use crate::notebook::new_id;
+use crate::notebook_store::StoreError;
+use crate::plugin_config::ApprovalConfig;
+use crate::policy::Principal;
+use crate::timeline::ExecutionContext;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+// Plugins that change production can require approval in their
+// config.toml:
+//
+//   [requires_approval]
+//   group = "sre-leads"
+//   min_approvers = 2
+//   window_minutes = 30
+//
+// Invoking one does not run it, it creates a pending ApprovalRequest and
+// followers of the notebook are told about it. Once min_approvers users
+// of the group, other than the one who invoked it, approved it within the
+// window it runs on behalf of that user, with the approvals recorded on
+// the execution. A single rejection closes the request.
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Approval {
+    pub user: String,
+    pub at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ApprovalStatus {
+    Pending,
+    // it ran, see event_id
+    Approved,
+    Rejected,
+    Expired,
+    // approved, but it could not run, see error
+    Failed,
+}
+
+impl ApprovalStatus {
+    pub fn parse(text: &str) -> Option<Self> {
+        serde_json::from_value(serde_json::Value::String(text.to_string())).ok()
+    }
+}
+
+impl fmt::Display for ApprovalStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ApprovalStatus::Pending => "pending",
+            ApprovalStatus::Approved => "approved",
+            ApprovalStatus::Rejected => "rejected",
+            ApprovalStatus::Expired => "expired",
+            ApprovalStatus::Failed => "failed",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApprovalRequest {
+    pub id: String,
+    pub plugin: String,
+    // the options it runs with, references already resolved
+    pub options: BTreeMap<String, String>,
+    pub notebook_id: Option<String>,
+    pub cell_id: Option<String>,
+    pub inputs: BTreeMap<String, String>,
+    // who invoked the plugin, it runs on their behalf
+    pub requested_by: Option<String>,
+    // the identity of who invoked it, the policy is checked for it again
+    // when the plugin runs
+    #[serde(default)]
+    pub principal: Principal,
+    pub source: Option<String>,
+    pub requested_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub group: String,
+    pub min_approvers: u32,
+    pub approvals: Vec<Approval>,
+    pub status: ApprovalStatus,
+    pub rejected_by: Option<String>,
+    // the execution, once approved
+    pub event_id: Option<String>,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ApprovalError {
+    NotPending(ApprovalStatus),
+    Expired,
+    OwnRequest,
+    AlreadyApproved(String),
+}
+
+impl fmt::Display for ApprovalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApprovalError::NotPending(status) => write!(f, "the request is {}", status),
+            ApprovalError::Expired => write!(f, "the request expired"),
+            ApprovalError::OwnRequest => {
+                write!(f, "an invocation cannot be approved by who made it")
+            }
+            ApprovalError::AlreadyApproved(user) => write!(f, "{} already approved it", user),
+        }
+    }
+}
+
+impl ApprovalRequest {
+    pub fn new(
+        context: ExecutionContext,
+        plugin: &str,
+        options: BTreeMap<String, String>,
+        config: &ApprovalConfig,
+        now: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            id: new_id(),
+            plugin: plugin.to_string(),
+            options,
+            notebook_id: context.notebook_id,
+            cell_id: context.cell_id,
+            inputs: context.inputs,
+            requested_by: context.user,
+            principal: context.principal,
+            source: context.source,
+            requested_at: now,
+            expires_at: now + config.window(),
+            group: config.group.clone(),
+            min_approvers: config.min_approvers(),
+            approvals: vec![],
+            status: ApprovalStatus::Pending,
+            rejected_by: None,
+            event_id: None,
+            error: None,
+        }
+    }
+
+    // context is where the approved execution comes from, the invocation
+    // it was made for
+    pub fn context(&self) -> ExecutionContext {
+        ExecutionContext {
+            notebook_id: self.notebook_id.clone(),
+            cell_id: self.cell_id.clone(),
+            user: self.requested_by.clone(),
+            principal: self.principal.clone(),
+            inputs: self.inputs.clone(),
+            source: self.source.clone(),
+            approvals: self.approvals.clone(),
+        }
+    }
+
+    // expire closes a pending request past its window, true if it did
+    pub fn expire(&mut self, now: DateTime<Utc>) -> bool {
+        if self.status == ApprovalStatus::Pending && now > self.expires_at {
+            self.status = ApprovalStatus::Expired;
+            return true;
+        }
+        false
+    }
+
+    fn check_pending(&mut self, now: DateTime<Utc>) -> Result<(), ApprovalError> {
+        if self.expire(now) {
+            return Err(ApprovalError::Expired);
+        }
+        match self.status {
+            ApprovalStatus::Pending => Ok(()),
+            status => Err(ApprovalError::NotPending(status)),
+        }
+    }
+
+    // approve adds the approval of the user, who must be in the group, and
+    // returns whether the request has enough of them to run
+    pub fn approve(&mut self, user: &str, now: DateTime<Utc>) -> Result<bool, ApprovalError> {
+        self.check_pending(now)?;
+        if self.requested_by.as_deref() == Some(user)
+            || self.principal.user.as_deref() == Some(user)
+        {
+            return Err(ApprovalError::OwnRequest);
+        }
+        if self.approvals.iter().any(|a| a.user == user) {
+            return Err(ApprovalError::AlreadyApproved(user.to_string()));
+        }
+        self.approvals.push(Approval {
+            user: user.to_string(),
+            at: now,
+        });
+        Ok(self.approvals.len() as u32 >= self.min_approvers)
+    }
+
+    // reject closes the request, the one who made it can withdraw it too
+    pub fn reject(&mut self, user: &str, now: DateTime<Utc>) -> Result<(), ApprovalError> {
+        self.check_pending(now)?;
+        self.status = ApprovalStatus::Rejected;
+        self.rejected_by = Some(user.to_string());
+        Ok(())
+    }
+}
+
+// check tells why the approvals are not enough to run a plugin invoked by
+// the user: approvers other than the user, within the window
+pub fn check(
+    config: &ApprovalConfig,
+    user: Option<&str>,
+    approvals: &[Approval],
+    now: DateTime<Utc>,
+) -> Result<(), String> {
+    let approvers: HashSet<&str> = approvals
+        .iter()
+        .filter(|a| Some(a.user.as_str()) != user && now - a.at <= config.window())
+        .map(|a| a.user.as_str())
+        .collect();
+    if approvers.len() as u32 >= config.min_approvers() {
+        return Ok(());
+    }
+    Err(format!(
+        "needs {} approval(s) from {}, has {}",
+        config.min_approvers(),
+        config.group,
+        approvers.len()
+    ))
+}
+
+pub trait ApprovalStore: Send + Sync {
+    // save_approval adds or replaces the request
+    fn save_approval(&self, request: &ApprovalRequest) -> Result<(), StoreError>;
+    fn approval(&self, id: &str) -> Result<ApprovalRequest, StoreError>;
+    // approvals lists the requests made on a notebook, or all of them,
+    // oldest first
+    fn approvals(&self, notebook_id: Option<&str>) -> Result<Vec<ApprovalRequest>, StoreError>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn config() -> ApprovalConfig {
+        ApprovalConfig {
+            group: "sre-leads".into(),
+            min_approvers: Some(2),
+            window_minutes: Some(30),
+        }
+    }
+
+    fn request(now: DateTime<Utc>) -> ApprovalRequest {
+        let context = ExecutionContext {
+            notebook_id: Some("n1".into()),
+            cell_id: Some("restart".into()),
+            user: Some("alice".into()),
+            ..Default::default()
+        };
+        ApprovalRequest::new(context, "restart-service", BTreeMap::new(), &config(), now)
+    }
+
+    #[test]
+    fn test_two_approvals() {
+        let now = Utc::now();
+        let mut request = request(now);
+        assert_eq!(
+            Err(ApprovalError::OwnRequest),
+            request.approve("alice", now)
+        );
+        assert_eq!(Ok(false), request.approve("bob", now));
+        assert_eq!(
+            Err(ApprovalError::AlreadyApproved("bob".into())),
+            request.approve("bob", now)
+        );
+        assert_eq!(
+            Ok(true),
+            request.approve("carol", now + Duration::minutes(5))
+        );
+
+        let context = request.context();
+        assert_eq!(Some("alice".to_string()), context.user);
+        assert!(check(
+            &config(),
+            Some("alice"),
+            &context.approvals,
+            now + Duration::minutes(6)
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_expired_and_rejected() {
+        let now = Utc::now();
+        let mut request = request(now);
+        request.approve("bob", now).unwrap();
+        assert_eq!(
+            Err(ApprovalError::Expired),
+            request.approve("carol", now + Duration::minutes(31))
+        );
+        assert_eq!(ApprovalStatus::Expired, request.status);
+        assert_eq!(
+            Some(ApprovalStatus::Expired),
+            ApprovalStatus::parse("expired")
+        );
+
+        let mut request = self::request(now);
+        request.reject("bob", now).unwrap();
+        assert_eq!(
+            Err(ApprovalError::NotPending(ApprovalStatus::Rejected)),
+            request.approve("carol", now)
+        );
+    }
+
+    #[test]
+    fn test_check() {
+        let now = Utc::now();
+        let approval = |user: &str, minutes_ago: i64| Approval {
+            user: user.into(),
+            at: now - Duration::minutes(minutes_ago),
+        };
+        assert_eq!(
+            Err("needs 2 approval(s) from sre-leads, has 0".to_string()),
+            check(&config(), Some("alice"), &[], now)
+        );
+        // the requester and stale approvals do not count
+        assert_eq!(
+            Err("needs 2 approval(s) from sre-leads, has 1".to_string()),
+            check(
+                &config(),
+                Some("alice"),
+                &[
+                    approval("alice", 1),
+                    approval("bob", 1),
+                    approval("carol", 45)
+                ],
+                now
+            )
+        );
+        assert!(check(
+            &config(),
+            Some("alice"),
+            &[approval("bob", 1), approval("carol", 2)],
+            now
+        )
+        .is_ok());
+    }
+}
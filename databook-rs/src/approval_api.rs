@@ -0,0 +1,347 @@
+use crate::approval::{ApprovalError, ApprovalRequest, ApprovalStatus, ApprovalStore};
+use crate::auth;
+use crate::databook;
+use crate::databook::approvals_server::Approvals;
+use crate::live::LiveEvent;
+use crate::live_api;
+use crate::notebook_api::{self, NotebookApiError};
+use crate::plugin_config::ApprovalConfig;
+use crate::policy::{Permission, Principal};
+use crate::timeline::ExecutionContext;
+use crate::timeline_api::{self, empty_as_none, not_found};
+use crate::{rest, run_blocking, APPROVALS, POLICY};
+
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use rocket_contrib::json::Json;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use tonic::{Request, Response, Status};
+use tracing::instrument;
+
+// approvals and rejections of a request are made one at a time, so two
+// approvers cannot both run it. Every request has its own lock, the map is
+// only locked to find it, so a slow plugin only holds up its own request.
+static DECIDING: Lazy<Mutex<HashMap<String, Arc<Mutex<()>>>>> = Lazy::new(Default::default);
+
+pub fn store() -> Result<&'static dyn ApprovalStore, NotebookApiError> {
+    APPROVALS
+        .get()
+        .map(|s| s.as_ref())
+        .ok_or_else(|| NotebookApiError::Internal("No approval store setup".into()))
+}
+
+impl From<ApprovalError> for NotebookApiError {
+    fn from(e: ApprovalError) -> Self {
+        NotebookApiError::InvalidRequest(e.to_string())
+    }
+}
+
+// deciding returns the lock of the request, and drops the ones nobody holds
+fn deciding(id: &str) -> Result<Arc<Mutex<()>>, NotebookApiError> {
+    let mut locks = DECIDING
+        .lock()
+        .map_err(|e| NotebookApiError::Internal(format!("could not lock approvals {:?}", e)))?;
+    locks.retain(|other, lock| other == id || Arc::strong_count(lock) > 1);
+    Ok(locks.entry(id.to_string()).or_default().clone())
+}
+
+fn publish(request: &ApprovalRequest) {
+    if let Some(notebook_id) = &request.notebook_id {
+        live_api::publish(
+            notebook_id,
+            LiveEvent::Approval {
+                approval: Box::new(request.clone()),
+            },
+        );
+    }
+}
+
+// is_member is true when the principal is in the group, through their
+// identity or the [groups] of the policy
+fn is_member(principal: &Principal, group: &str) -> bool {
+    match POLICY.get() {
+        Some(policy) => policy.current().is_member(principal, group),
+        None => principal.user.is_some() && principal.groups.iter().any(|g| g == group),
+    }
+}
+
+// request_approval saves a pending request for an invocation of a plugin
+// requiring approval and tells the followers of the notebook about it
+pub fn request_approval(
+    context: ExecutionContext,
+    plugin: &str,
+    options: BTreeMap<String, String>,
+    config: &ApprovalConfig,
+) -> Result<ApprovalRequest, NotebookApiError> {
+    let request = ApprovalRequest::new(context, plugin, options, config, Utc::now());
+    store()?.save_approval(&request)?;
+    tracing::info!(
+        "invocation of {} by {:?} waits for approval {}",
+        plugin,
+        request.requested_by,
+        request.id
+    );
+    publish(&request);
+    Ok(request)
+}
+
+// list_approvals returns the requests of the notebook, or of the notebooks
+// the principal may view, most recent first
+pub fn list_approvals(
+    notebook_id: Option<&str>,
+    status: Option<ApprovalStatus>,
+    principal: &Principal,
+) -> Result<Vec<ApprovalRequest>, NotebookApiError> {
+    let store = store()?;
+    if let Some(notebook_id) = notebook_id {
+        notebook_api::get_authorized(notebook_id, principal, Permission::View)?;
+    }
+    let visible = match notebook_id {
+        Some(_) => None,
+        None => notebook_api::visible_notebooks(principal)?,
+    };
+    let now = Utc::now();
+    let mut approvals = vec![];
+    for mut request in store.approvals(notebook_id)? {
+        if request.expire(now) {
+            store.save_approval(&request)?;
+        }
+        let shown = visible.as_ref().is_none_or(|visible| {
+            request
+                .notebook_id
+                .as_ref()
+                .is_none_or(|id| visible.contains(id))
+        });
+        if shown && status.is_none_or(|s| s == request.status) {
+            approvals.push(request);
+        }
+    }
+    approvals.reverse();
+    Ok(approvals)
+}
+
+// decide loads the request for the principal to approve or reject it, a
+// user an anonymous caller claims to be cannot decide anything. Changes are
+// saved and published even when the decision failed, e.g. the request
+// expired or the approved plugin could not run.
+fn decide(
+    id: &str,
+    principal: &Principal,
+    f: impl FnOnce(&mut ApprovalRequest, &str) -> Result<(), NotebookApiError>,
+) -> Result<ApprovalRequest, NotebookApiError> {
+    let user = principal.user.as_deref().ok_or_else(|| {
+        NotebookApiError::Forbidden("approval requests are decided by authenticated users".into())
+    })?;
+    let lock = deciding(id)?;
+    let _deciding = lock.lock().map_err(|e| {
+        NotebookApiError::Internal(format!("could not lock approval request {} {:?}", id, e))
+    })?;
+    let store = store()?;
+    let mut request = store
+        .approval(id)
+        .map_err(|e| not_found("approval request", id, e))?;
+    if let Some(notebook_id) = &request.notebook_id {
+        notebook_api::get_authorized(notebook_id, principal, Permission::View)?;
+    }
+    let before = request.clone();
+    let result = f(&mut request, user);
+    if request != before {
+        store.save_approval(&request)?;
+        publish(&request);
+    }
+    result?;
+    Ok(request)
+}
+
+// approve adds the approval of a member of the group, the plugin runs on
+// behalf of who invoked it once enough of them approved. It runs while the
+// request is locked so it cannot run twice, and the request is only
+// approved once it ran; when it could not run the request failed instead.
+pub fn approve(id: &str, principal: &Principal) -> Result<ApprovalRequest, NotebookApiError> {
+    let request = decide(id, principal, |request, user| {
+        if !is_member(principal, &request.group) {
+            return Err(NotebookApiError::Forbidden(format!(
+                "{} is not in {} and may not approve {}",
+                user, request.group, request.plugin
+            )));
+        }
+        if !request.approve(user, Utc::now())? {
+            return Ok(());
+        }
+        let options = request.options.clone();
+        match timeline_api::execute(request.context(), &request.plugin, options) {
+            Ok(event) => {
+                request.status = ApprovalStatus::Approved;
+                request.event_id = Some(event.id);
+                Ok(())
+            }
+            Err(e) => {
+                tracing::error!(
+                    "approved invocation of {} could not run {:?}",
+                    request.plugin,
+                    e
+                );
+                request.status = ApprovalStatus::Failed;
+                request.error = Some(format!("{:?}", e));
+                Err(e)
+            }
+        }
+    })?;
+    tracing::info!("approval request {} approved by {:?}", id, principal.user);
+    Ok(request)
+}
+
+// reject closes the request, for members of the group or who invoked it
+pub fn reject(id: &str, principal: &Principal) -> Result<ApprovalRequest, NotebookApiError> {
+    let request = decide(id, principal, |request, user| {
+        if request.principal.user.as_deref() != Some(user) && !is_member(principal, &request.group)
+        {
+            return Err(NotebookApiError::Forbidden(format!(
+                "{} is not in {} and may not reject {}",
+                user, request.group, request.plugin
+            )));
+        }
+        Ok(request.reject(user, Utc::now())?)
+    })?;
+    tracing::info!("approval request {} rejected by {:?}", id, principal.user);
+    Ok(request)
+}
+
+fn parse_status(status: Option<&str>) -> Result<Option<ApprovalStatus>, NotebookApiError> {
+    status
+        .map(|s| {
+            ApprovalStatus::parse(s).ok_or_else(|| {
+                NotebookApiError::InvalidRequest(format!(
+                    "unknown status {}, expected pending, approved, rejected, expired or failed",
+                    s
+                ))
+            })
+        })
+        .transpose()
+}
+
+pub fn to_grpc_request(request: ApprovalRequest) -> databook::ApprovalRequest {
+    databook::ApprovalRequest {
+        id: request.id,
+        plugin: request.plugin,
+        options: request.options.into_iter().collect(),
+        notebook_id: request.notebook_id.unwrap_or_default(),
+        cell_id: request.cell_id.unwrap_or_default(),
+        requested_by: request.requested_by.unwrap_or_default(),
+        requested_at: request.requested_at.timestamp_millis(),
+        expires_at: request.expires_at.timestamp_millis(),
+        group: request.group,
+        min_approvers: request.min_approvers,
+        approvals: request
+            .approvals
+            .into_iter()
+            .map(timeline_api::to_grpc_approval)
+            .collect(),
+        status: request.status.to_string(),
+        rejected_by: request.rejected_by.unwrap_or_default(),
+        event_id: request.event_id.unwrap_or_default(),
+        error: request.error.unwrap_or_default(),
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ApprovalsGrpc {}
+
+#[tonic::async_trait]
+impl Approvals for ApprovalsGrpc {
+    #[instrument]
+    async fn list_approvals(
+        &self,
+        request: Request<databook::ListApprovalsRequest>,
+    ) -> Result<Response<databook::ListApprovalsResponse>, Status> {
+        let principal = auth::actor(&request, "").principal;
+        run_blocking(move || {
+            let request = request.into_inner();
+            let status = parse_status(empty_as_none(request.status).as_deref())?;
+            let approvals = list_approvals(
+                empty_as_none(request.notebook_id).as_deref(),
+                status,
+                &principal,
+            )?;
+            Ok::<_, NotebookApiError>(databook::ListApprovalsResponse {
+                approvals: approvals.into_iter().map(to_grpc_request).collect(),
+            })
+        })
+        .await
+    }
+
+    #[instrument]
+    async fn approve(
+        &self,
+        request: Request<databook::DecideApprovalRequest>,
+    ) -> Result<Response<databook::ApprovalRequest>, Status> {
+        tracing::info!("received approve request");
+        let principal = auth::actor(&request, "").principal;
+        run_blocking(move || approve(&request.into_inner().id, &principal).map(to_grpc_request))
+            .await
+    }
+
+    #[instrument]
+    async fn reject(
+        &self,
+        request: Request<databook::DecideApprovalRequest>,
+    ) -> Result<Response<databook::ApprovalRequest>, Status> {
+        tracing::info!("received reject request");
+        let principal = auth::actor(&request, "").principal;
+        run_blocking(move || reject(&request.into_inner().id, &principal).map(to_grpc_request))
+            .await
+    }
+}
+
+fn rest_approval_response(
+    result: Result<ApprovalRequest, NotebookApiError>,
+) -> Json<rest::ApprovalResponse> {
+    match result {
+        Ok(approval) => Json(rest::ApprovalResponse {
+            approval: Some(approval),
+            error: None,
+        }),
+        Err(e) => Json(rest::ApprovalResponse {
+            approval: None,
+            error: Some(format!("{:?}", e)),
+        }),
+    }
+}
+
+#[get("/approvals?<notebook_id>&<status>")]
+pub fn rest_list(
+    caller: auth::Caller,
+    notebook_id: Option<String>,
+    status: Option<String>,
+) -> Json<rest::ListApprovalsResponse> {
+    let approvals = parse_status(status.as_deref()).and_then(|status| {
+        list_approvals(
+            notebook_id.as_deref(),
+            status,
+            &caller.actor(None).principal,
+        )
+    });
+    match approvals {
+        Ok(approvals) => Json(rest::ListApprovalsResponse {
+            approvals,
+            error: None,
+        }),
+        Err(e) => Json(rest::ListApprovalsResponse {
+            approvals: vec![],
+            error: Some(format!("{:?}", e)),
+        }),
+    }
+}
+
+#[post("/approvals/<id>/approve")]
+pub fn rest_approve(caller: auth::Caller, id: String) -> Json<rest::ApprovalResponse> {
+    tracing::info!("received approve request");
+    rest_approval_response(approve(&id, &caller.actor(None).principal))
+}
+
+#[post("/approvals/<id>/reject")]
+pub fn rest_reject(caller: auth::Caller, id: String) -> Json<rest::ApprovalResponse> {
+    tracing::info!("received reject request");
+    rest_approval_response(reject(&id, &caller.actor(None).principal))
+}
@@ -0,0 +1,257 @@
+use crate::references::{self, Segment};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+
+// Assertions check the output of a plugin cell when a notebook runs
+// headless. They are kept on the cell under the `expect` option, one per
+// line or separated by `;`, and the option is not passed to the plugin:
+//
+//   @expect=status == 200; body.items contains "api"
+//
+// The subject is `output` (the raw output) or a json path into the output.
+// Arrays and objects compare by their length against numbers, so
+// `rows < 10` checks how many rows came back.
+
+pub const EXPECT_OPTION: &str = "expect";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operator {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    Contains,
+}
+
+impl Operator {
+    fn parse(text: &str) -> Option<Self> {
+        Some(match text {
+            "==" => Operator::Equal,
+            "!=" => Operator::NotEqual,
+            "<" => Operator::Less,
+            "<=" => Operator::LessOrEqual,
+            ">" => Operator::Greater,
+            ">=" => Operator::GreaterOrEqual,
+            "contains" => Operator::Contains,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Subject {
+    Output,
+    Path(Vec<Segment>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Assertion {
+    // as written on the cell
+    pub text: String,
+    subject: Subject,
+    operator: Operator,
+    expected: Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssertionError(pub String);
+
+impl fmt::Display for AssertionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AssertionResult {
+    pub assertion: String,
+    pub passed: bool,
+    // the value found on the output, as json
+    pub actual: Option<String>,
+    // why the assertion could not be checked (e.g. the output is not json)
+    pub error: Option<String>,
+}
+
+fn parse_expected(text: &str) -> Value {
+    // numbers, quoted strings, booleans and null read as json, anything
+    // else is a bare string
+    serde_json::from_str(text).unwrap_or_else(|_| Value::String(text.to_string()))
+}
+
+fn parse_assertion(text: &str) -> Result<Assertion, AssertionError> {
+    let mut parts = text.splitn(3, char::is_whitespace);
+    let (subject, operator, expected) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(subject), Some(operator), Some(expected)) if !expected.trim().is_empty() => {
+            (subject, operator, expected.trim())
+        }
+        _ => {
+            return Err(AssertionError(format!(
+                "expected `<subject> <operator> <value>`, got {:?}",
+                text
+            )))
+        }
+    };
+    let operator = Operator::parse(operator).ok_or_else(|| {
+        AssertionError(format!(
+            "unknown operator {:?} in {:?}, expected ==, !=, <, <=, >, >= or contains",
+            operator, text
+        ))
+    })?;
+    let subject = if subject == "output" {
+        Subject::Output
+    } else {
+        let path = if subject.starts_with('[') {
+            subject.to_string()
+        } else {
+            format!(".{}", subject)
+        };
+        Subject::Path(
+            references::parse_path(&path, subject).map_err(|e| AssertionError(e.to_string()))?,
+        )
+    };
+    Ok(Assertion {
+        text: text.to_string(),
+        subject,
+        operator,
+        expected: parse_expected(expected),
+    })
+}
+
+// parse_assertions reads the `expect` option of a cell
+pub fn parse_assertions(option: &str) -> Result<Vec<Assertion>, AssertionError> {
+    option
+        .split(['\n', ';'])
+        .map(str::trim)
+        .filter(|a| !a.is_empty())
+        .map(parse_assertion)
+        .collect()
+}
+
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.trim().parse().ok(),
+        Value::Array(a) => Some(a.len() as f64),
+        Value::Object(o) => Some(o.len() as f64),
+        _ => None,
+    }
+}
+
+fn compare(actual: &Value, operator: Operator, expected: &Value) -> Result<bool, String> {
+    if operator == Operator::Contains {
+        return match (actual, expected) {
+            (Value::String(actual), Value::String(expected)) => {
+                Ok(actual.contains(expected.as_str()))
+            }
+            (Value::String(actual), expected) => Ok(actual.contains(&expected.to_string())),
+            (Value::Array(items), expected) => Ok(items.contains(expected)),
+            (Value::Object(fields), Value::String(key)) => Ok(fields.contains_key(key)),
+            _ => Err(format!("cannot look for {} in {}", expected, actual)),
+        };
+    }
+
+    let numbers = match expected {
+        Value::Number(_) => number(actual).zip(number(expected)),
+        _ => None,
+    };
+    match (operator, numbers) {
+        (Operator::Equal, Some((a, e))) => Ok(a == e),
+        (Operator::NotEqual, Some((a, e))) => Ok(a != e),
+        (Operator::Equal, None) => Ok(actual == expected),
+        (Operator::NotEqual, None) => Ok(actual != expected),
+        (Operator::Less, Some((a, e))) => Ok(a < e),
+        (Operator::LessOrEqual, Some((a, e))) => Ok(a <= e),
+        (Operator::Greater, Some((a, e))) => Ok(a > e),
+        (Operator::GreaterOrEqual, Some((a, e))) => Ok(a >= e),
+        _ => Err(format!("cannot compare {} with {}", actual, expected)),
+    }
+}
+
+impl Assertion {
+    // check evaluates the assertion against the output of the cell
+    pub fn check(&self, output: &str) -> AssertionResult {
+        let actual = match &self.subject {
+            Subject::Output => Ok(Value::String(output.to_string())),
+            Subject::Path(path) => serde_json::from_str::<Value>(output)
+                .map_err(|_| "the output is not json".to_string())
+                .and_then(|json| {
+                    references::select(&json, path)
+                        .cloned()
+                        .map_err(|segment| format!("no {} in the output", segment))
+                }),
+        };
+        let (passed, error) = match &actual {
+            Ok(actual) => match compare(actual, self.operator, &self.expected) {
+                Ok(passed) => (passed, None),
+                Err(e) => (false, Some(e)),
+            },
+            Err(e) => (false, Some(e.clone())),
+        };
+        AssertionResult {
+            assertion: self.text.clone(),
+            passed,
+            actual: actual.ok().map(|a| a.to_string()),
+            error,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(assertion: &str, output: &str) -> bool {
+        parse_assertions(assertion).unwrap()[0].check(output).passed
+    }
+
+    #[test]
+    fn test_parse_assertions() {
+        let assertions = parse_assertions("status == 200;\n rows < 10\n\n").unwrap();
+        assert_eq!(
+            vec!["status == 200", "rows < 10"],
+            assertions
+                .iter()
+                .map(|a| a.text.as_str())
+                .collect::<Vec<_>>()
+        );
+
+        assert!(parse_assertions("status 200").is_err());
+        assert!(parse_assertions("status =~ 200").is_err());
+        assert!(parse_assertions("items[x] == 1").is_err());
+    }
+
+    #[test]
+    fn test_check() {
+        let output = r#"{"status": 200, "rows": [1, 2, 3], "body": {"name": "api-7f9c"}, "latency": "0.25"}"#;
+        assert!(check("status == 200", output));
+        assert!(!check("status != 200", output));
+        assert!(check("rows < 10", output));
+        assert!(check("rows[0] >= 1", output));
+        assert!(check("rows contains 2", output));
+        assert!(check(r#"body.name == "api-7f9c""#, output));
+        assert!(check("body.name == api-7f9c", output));
+        assert!(check("body contains name", output));
+        assert!(check("latency <= 0.5", output));
+        assert!(check("output contains api", output));
+        assert!(check("output contains api", "plain text from the api"));
+    }
+
+    #[test]
+    fn test_check_failures() {
+        let result = parse_assertions("status == 200").unwrap()[0].check("OK");
+        assert!(!result.passed);
+        assert_eq!(Some("the output is not json".to_string()), result.error);
+
+        let result = parse_assertions("status == 200").unwrap()[0].check(r#"{"code": 500}"#);
+        assert_eq!(Some("no .status in the output".to_string()), result.error);
+
+        let result = parse_assertions("status > high").unwrap()[0].check(r#"{"status": 500}"#);
+        assert!(!result.passed);
+        assert_eq!(Some("500".to_string()), result.actual);
+        assert!(result.error.is_some());
+    }
+}
@@ -0,0 +1,911 @@
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// The audit log is an append only record of every plugin invocation and
+// every change made through the admin API. Entries are json lines in
+// audit-<n>.log files of a directory, a new file is started once the
+// current one reaches max_file_bytes. Each entry carries the hash of the
+// one before it and its own hash over both, so a removed, reordered or
+// edited entry breaks the chain, across files too. Old files can be moved
+// away, the chain is then checked from the oldest file left.
+//
+// With a key_file the hashes are HMAC-SHA256 keyed with its content, so
+// whoever can write the files cannot compute a new chain. The head_file
+// keeps the sequence and hash of the last entry out of the directory (e.g.
+// on another volume), so removing entries at the end shows too.
+
+// previous_hash of the very first entry
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+const DEFAULT_MAX_FILE_BYTES: u64 = 10 * 1024 * 1024;
+const FILE_PREFIX: &str = "audit-";
+const FILE_EXTENSION: &str = "log";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuditConfig {
+    // where the audit-<n>.log files are written
+    pub directory: String,
+    // size after which a new file is started, 10MiB by default
+    pub max_file_bytes: Option<u64>,
+    // file holding the secret the hashes are keyed with, the chain of a
+    // directory is keyed or not from its first entry
+    pub key_file: Option<String>,
+    // where the last entry is anchored, best outside the directory
+    pub head_file: Option<String>,
+    // go on when entries cannot be written, the error is only logged. By
+    // default plugins do not run and admin changes are not made then.
+    pub fail_open: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Invoke,
+    Install,
+    Upgrade,
+    Disable,
+    Enable,
+    Uninstall,
+}
+
+impl Action {
+    pub fn parse(text: &str) -> Option<Self> {
+        serde_json::from_value(serde_json::Value::String(text.to_string())).ok()
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Action::Invoke => "invoke",
+            Action::Install => "install",
+            Action::Upgrade => "upgrade",
+            Action::Disable => "disable",
+            Action::Enable => "enable",
+            Action::Uninstall => "uninstall",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    // written before a plugin runs or an admin change is made, so nothing
+    // happens unaudited, the outcome follows in another entry
+    Started,
+    Succeeded,
+    Failed,
+    // the policy did not let the caller do it
+    Refused,
+    // the plugin waits for approval, see approval.rs
+    Pending,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Outcome::Started => "started",
+            Outcome::Succeeded => "succeeded",
+            Outcome::Failed => "failed",
+            Outcome::Refused => "refused",
+            Outcome::Pending => "pending",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// AuditRecord is what happened, the log numbers and chains it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub action: Action,
+    pub user: Option<String>,
+    pub groups: Vec<String>,
+    // address of the caller, none for scheduled runs
+    pub source: Option<String>,
+    pub plugin: Option<String>,
+    // sha256 of the options of an invocation, they can hold secrets
+    pub options_hash: Option<String>,
+    // who approved the invocation, left out when nobody had to
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub approvers: Vec<String>,
+    pub outcome: Outcome,
+    pub error: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub duration_ms: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub sequence: u64,
+    #[serde(flatten)]
+    pub record: AuditRecord,
+    pub previous_hash: String,
+    pub hash: String,
+}
+
+impl AuditEntry {
+    fn new(
+        key: Option<&[u8]>,
+        sequence: u64,
+        record: AuditRecord,
+        previous_hash: String,
+    ) -> Result<Self, AuditError> {
+        let hash = entry_hash(key, sequence, &record, &previous_hash)?;
+        Ok(Self {
+            sequence,
+            record,
+            previous_hash,
+            hash,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuditError {
+    Io(String),
+    // the chain does not hold at that line of that file
+    Tampered {
+        file: String,
+        line: usize,
+        reason: String,
+    },
+}
+
+impl fmt::Display for AuditError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuditError::Io(e) => write!(f, "could not access the audit log: {}", e),
+            AuditError::Tampered { file, line, reason } => {
+                write!(f, "audit log altered at {}:{}: {}", file, line, reason)
+            }
+        }
+    }
+}
+
+impl From<std::io::Error> for AuditError {
+    fn from(e: std::io::Error) -> Self {
+        AuditError::Io(e.to_string())
+    }
+}
+
+// options_hash hashes the options of an invocation, in key order
+pub fn options_hash(options: &HashMap<String, String>) -> String {
+    let sorted: BTreeMap<&String, &String> = options.iter().collect();
+    let json = serde_json::to_string(&sorted).unwrap_or_default();
+    hex::encode(Sha256::digest(json.as_bytes()))
+}
+
+// hmac_sha256 is HMAC (RFC 2104) over SHA-256
+fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(message);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn entry_hash(
+    key: Option<&[u8]>,
+    sequence: u64,
+    record: &AuditRecord,
+    previous_hash: &str,
+) -> Result<String, AuditError> {
+    let record = serde_json::to_string(record).map_err(|e| AuditError::Io(e.to_string()))?;
+    let message = format!("{}\n{}\n{}", sequence, previous_hash, record);
+    Ok(hex::encode(match key {
+        Some(key) => hmac_sha256(key, message.as_bytes()),
+        None => Sha256::digest(message.as_bytes()).to_vec(),
+    }))
+}
+
+// read_key reads the secret of a key_file, surrounding whitespace is not
+// part of it
+pub fn read_key(path: &Path) -> Result<Vec<u8>, AuditError> {
+    let key = fs::read(path)?;
+    let key = key.trim_ascii().to_vec();
+    if key.is_empty() {
+        return Err(AuditError::Io(format!("{} is empty", path.display())));
+    }
+    Ok(key)
+}
+
+// Head is the last entry of the log, as kept in the head_file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Head {
+    sequence: u64,
+    hash: String,
+}
+
+fn read_head(path: &Path) -> Result<Option<Head>, AuditError> {
+    match fs::read_to_string(path) {
+        Ok(text) => serde_json::from_str(&text)
+            .map(Some)
+            .map_err(|e| AuditError::Tampered {
+                file: path.display().to_string(),
+                line: 1,
+                reason: format!("not an audit head: {}", e),
+            }),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// write_head replaces the head_file at once, a crash leaves the previous
+// head, which the log is then ahead of
+fn write_head(path: &Path, head: &Head) -> Result<(), AuditError> {
+    let temporary = path.with_extension("tmp");
+    let mut file = fs::File::create(&temporary)?;
+    file.write_all(&serde_json::to_vec(head).map_err(|e| AuditError::Io(e.to_string()))?)?;
+    file.sync_data()?;
+    fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+// check_head fails when the log ends before the head, entries were
+// removed at the end, or has another entry where the head is. The log may
+// be ahead of the head after a crash.
+fn check_head(
+    path: &Path,
+    head: &Head,
+    last: Option<u64>,
+    hash_at_head: Option<&str>,
+) -> Result<(), AuditError> {
+    let tampered = |reason: String| AuditError::Tampered {
+        file: path.display().to_string(),
+        line: 1,
+        reason,
+    };
+    match last {
+        Some(last) if last >= head.sequence => match hash_at_head {
+            Some(hash) if hash != head.hash => Err(tampered(format!(
+                "entry {} is not the one of the head",
+                head.sequence
+            ))),
+            _ => Ok(()),
+        },
+        Some(last) => Err(tampered(format!(
+            "the log ends at entry {}, its head is entry {}",
+            last, head.sequence
+        ))),
+        None => Err(tampered(format!(
+            "the log is empty, its head is entry {}",
+            head.sequence
+        ))),
+    }
+}
+
+fn file_number(path: &Path) -> Option<u64> {
+    if path.extension()? != FILE_EXTENSION {
+        return None;
+    }
+    path.file_stem()?
+        .to_str()?
+        .strip_prefix(FILE_PREFIX)?
+        .parse()
+        .ok()
+}
+
+fn file_path(directory: &Path, number: u64) -> PathBuf {
+    directory.join(format!("{}{:06}.{}", FILE_PREFIX, number, FILE_EXTENSION))
+}
+
+// log_files lists the audit files of the directory, oldest first
+fn log_files(directory: &Path) -> Result<Vec<(u64, PathBuf)>, AuditError> {
+    let mut files = vec![];
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if let Some(number) = file_number(&path) {
+            files.push((number, path));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+// scan_lines parses the entries of a file one line at a time, with their
+// line number. Blank lines are skipped: they hide nothing, the chain runs
+// over the entries. With `partial_end` a last line that cannot be read is
+// skipped too, it is still being written.
+fn scan_lines(
+    path: &Path,
+    partial_end: bool,
+    mut f: impl FnMut(usize, AuditEntry),
+) -> Result<(), AuditError> {
+    let unreadable = |line: usize, reason: String| AuditError::Tampered {
+        file: path.display().to_string(),
+        line,
+        reason: format!("not an audit entry: {}", reason),
+    };
+    let mut pending = None;
+    for (i, line) in BufReader::new(fs::File::open(path)?).lines().enumerate() {
+        if let Some(e) = pending.take() {
+            return Err(e);
+        }
+        let line = match line {
+            Ok(line) => line,
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                pending = Some(unreadable(i + 1, e.to_string()));
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(entry) => f(i + 1, entry),
+            Err(e) => pending = Some(unreadable(i + 1, e.to_string())),
+        }
+    }
+    match pending {
+        Some(e) if !partial_end => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn read_lines(path: &Path) -> Result<Vec<(usize, AuditEntry)>, AuditError> {
+    let mut entries = vec![];
+    scan_lines(path, false, |line, entry| entries.push((line, entry)))?;
+    Ok(entries)
+}
+
+// Verification is what `verify` went through
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verification {
+    pub files: usize,
+    pub entries: u64,
+    // above 0 when older files were moved away
+    pub first_sequence: u64,
+}
+
+// verify checks the chain of every file in the directory, in order, with
+// the key the log was written with and against its head when given
+pub fn verify(
+    directory: &Path,
+    key: Option<&[u8]>,
+    head_file: Option<&Path>,
+) -> Result<Verification, AuditError> {
+    let head = match head_file {
+        Some(path) => read_head(path)?,
+        None => None,
+    };
+    let mut hash_at_head = None;
+    let files = log_files(directory)?;
+    let mut verification = Verification {
+        files: files.len(),
+        entries: 0,
+        first_sequence: 0,
+    };
+    let mut last: Option<(u64, String)> = None;
+    for (_, path) in &files {
+        let file = path.display().to_string();
+        for (line, entry) in read_lines(path)? {
+            let tampered = |reason: String| AuditError::Tampered {
+                file: file.clone(),
+                line,
+                reason,
+            };
+            match &last {
+                Some((sequence, hash)) => {
+                    if entry.sequence != sequence + 1 {
+                        return Err(tampered(format!(
+                            "expected entry {}, found {}",
+                            sequence + 1,
+                            entry.sequence
+                        )));
+                    }
+                    if &entry.previous_hash != hash {
+                        return Err(tampered("does not follow the entry before it".into()));
+                    }
+                }
+                None => {
+                    if entry.sequence == 0 && entry.previous_hash != GENESIS {
+                        return Err(tampered("first entry does not start the chain".into()));
+                    }
+                    verification.first_sequence = entry.sequence;
+                }
+            }
+            if entry_hash(key, entry.sequence, &entry.record, &entry.previous_hash)? != entry.hash {
+                return Err(tampered(format!("entry {} was modified", entry.sequence)));
+            }
+            verification.entries += 1;
+            if head
+                .as_ref()
+                .is_some_and(|head| head.sequence == entry.sequence)
+            {
+                hash_at_head = Some(entry.hash.clone());
+            }
+            last = Some((entry.sequence, entry.hash));
+        }
+    }
+    if let (Some(path), Some(head)) = (head_file, &head) {
+        check_head(
+            path,
+            head,
+            last.map(|(sequence, _)| sequence),
+            hash_at_head.as_deref(),
+        )?;
+    }
+    Ok(verification)
+}
+
+// AuditFilter narrows a query, every field that is set must match
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuditFilter {
+    pub user: Option<String>,
+    pub plugin: Option<String>,
+    pub action: Option<Action>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub limit: Option<usize>,
+}
+
+impl AuditFilter {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        let record = &entry.record;
+        self.user
+            .as_ref()
+            .is_none_or(|u| record.user.as_ref() == Some(u))
+            && self
+                .plugin
+                .as_ref()
+                .is_none_or(|p| record.plugin.as_ref() == Some(p))
+            && self.action.is_none_or(|a| record.action == a)
+            && self.from.is_none_or(|from| record.started_at >= from)
+            && self.to.is_none_or(|to| record.started_at < to)
+    }
+}
+
+// Tail is where the next entry goes
+#[derive(Debug)]
+struct Tail {
+    file: u64,
+    size: u64,
+    sequence: u64,
+    hash: String,
+}
+
+#[derive(Debug)]
+pub struct AuditLog {
+    directory: PathBuf,
+    max_file_bytes: u64,
+    key: Option<Vec<u8>>,
+    head_file: Option<PathBuf>,
+    fail_open: bool,
+    tail: Mutex<Tail>,
+}
+
+impl AuditLog {
+    // open continues the chain of the newest file of the directory. A last
+    // entry that cannot be read, was not written with the key or is not
+    // the head stops the server rather than starting a new chain.
+    pub fn open(config: &AuditConfig) -> Result<Self, AuditError> {
+        let directory = PathBuf::from(&config.directory);
+        fs::create_dir_all(&directory)?;
+        let key = config
+            .key_file
+            .as_deref()
+            .map(|path| read_key(Path::new(path)))
+            .transpose()?;
+        let head_file = config.head_file.as_ref().map(PathBuf::from);
+        let mut tail = Tail {
+            file: 1,
+            size: 0,
+            sequence: 0,
+            hash: GENESIS.to_string(),
+        };
+        let mut last = None;
+        if let Some((number, path)) = log_files(&directory)?.pop() {
+            tail.file = number;
+            tail.size = fs::metadata(&path)?.len();
+            last = read_lines(&path)?
+                .pop()
+                .map(|(line, entry)| (path, line, entry));
+            if last.is_none() && number > 1 {
+                // an empty newest file, the chain goes on from the one before
+                let path = file_path(&directory, number - 1);
+                last = read_lines(&path)?
+                    .pop()
+                    .map(|(line, entry)| (path, line, entry));
+            }
+        }
+        if let Some((path, line, entry)) = &last {
+            if entry_hash(
+                key.as_deref(),
+                entry.sequence,
+                &entry.record,
+                &entry.previous_hash,
+            )? != entry.hash
+            {
+                return Err(AuditError::Tampered {
+                    file: path.display().to_string(),
+                    line: *line,
+                    reason: format!(
+                        "entry {} was modified or written with another key",
+                        entry.sequence
+                    ),
+                });
+            }
+            tail.sequence = entry.sequence + 1;
+            tail.hash = entry.hash.clone();
+        }
+        if let Some(path) = &head_file {
+            if let Some(head) = read_head(path)? {
+                let last = last.as_ref().map(|(_, _, entry)| entry);
+                let hash_at_head = last
+                    .filter(|e| e.sequence == head.sequence)
+                    .map(|e| e.hash.as_str());
+                check_head(path, &head, last.map(|e| e.sequence), hash_at_head)?;
+            }
+        }
+        if key.is_none() {
+            tracing::warn!(
+                "the audit log has no key_file, whoever can write it can rewrite its chain"
+            );
+        }
+        Ok(Self {
+            directory,
+            max_file_bytes: config.max_file_bytes.unwrap_or(DEFAULT_MAX_FILE_BYTES),
+            key,
+            head_file,
+            fail_open: config.fail_open.unwrap_or(false),
+            tail: Mutex::new(tail),
+        })
+    }
+
+    pub fn fails_open(&self) -> bool {
+        self.fail_open
+    }
+
+    pub fn append(&self, record: AuditRecord) -> Result<AuditEntry, AuditError> {
+        let mut tail = self
+            .tail
+            .lock()
+            .map_err(|e| AuditError::Io(format!("could not lock the audit log {:?}", e)))?;
+        let entry = AuditEntry::new(
+            self.key.as_deref(),
+            tail.sequence,
+            record,
+            tail.hash.clone(),
+        )?;
+        let mut line = serde_json::to_string(&entry).map_err(|e| AuditError::Io(e.to_string()))?;
+        line.push('\n');
+        if tail.size > 0 && tail.size + line.len() as u64 > self.max_file_bytes {
+            tail.file += 1;
+            tail.size = 0;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(file_path(&self.directory, tail.file))?;
+        file.write_all(line.as_bytes())?;
+        file.sync_data()?;
+        tail.size += line.len() as u64;
+        tail.sequence += 1;
+        tail.hash = entry.hash.clone();
+        // the entry is recorded by now, a head left behind only hides that
+        // entries at the end were removed, until the next append moves it
+        if let Some(path) = &self.head_file {
+            let head = Head {
+                sequence: entry.sequence,
+                hash: entry.hash.clone(),
+            };
+            if let Err(e) = write_head(path, &head) {
+                tracing::error!(
+                    "could not move the audit head to entry {}: {}",
+                    entry.sequence,
+                    e
+                );
+            }
+        }
+        Ok(entry)
+    }
+
+    // query returns the matching entries, the most recent first. Appends do
+    // not wait for it: only the newest file can be half written, and its
+    // last line is skipped until it can be read. Older files are not read
+    // once there are `limit` entries.
+    pub fn query(&self, filter: &AuditFilter) -> Result<Vec<AuditEntry>, AuditError> {
+        let limit = filter.limit.unwrap_or(usize::MAX);
+        let mut entries = vec![];
+        let files = log_files(&self.directory)?;
+        for (i, (_, path)) in files.iter().enumerate().rev() {
+            let wanted = limit - entries.len();
+            if wanted == 0 {
+                break;
+            }
+            // the last matches of the file, oldest first
+            let mut matches = VecDeque::new();
+            scan_lines(path, i + 1 == files.len(), |_, entry| {
+                if filter.matches(&entry) {
+                    if matches.len() == wanted {
+                        matches.pop_front();
+                    }
+                    matches.push_back(entry);
+                }
+            })?;
+            entries.extend(matches.into_iter().rev());
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(user: &str, plugin: &str) -> AuditRecord {
+        AuditRecord {
+            action: Action::Invoke,
+            user: Some(user.to_string()),
+            groups: vec!["oncall".to_string()],
+            source: Some("10.0.0.7".to_string()),
+            plugin: Some(plugin.to_string()),
+            options_hash: Some(options_hash(&HashMap::from([(
+                "cluster".to_string(),
+                "prod".to_string(),
+            )]))),
+            approvers: vec![],
+            outcome: Outcome::Succeeded,
+            error: None,
+            started_at: Utc::now(),
+            duration_ms: 12,
+        }
+    }
+
+    fn open(directory: &Path, max_file_bytes: u64) -> AuditLog {
+        AuditLog::open(&AuditConfig {
+            directory: directory.display().to_string(),
+            max_file_bytes: Some(max_file_bytes),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    fn protected(
+        directory: &Path,
+        key_file: &Path,
+        head_file: &Path,
+    ) -> Result<AuditLog, AuditError> {
+        AuditLog::open(&AuditConfig {
+            directory: directory.display().to_string(),
+            key_file: Some(key_file.display().to_string()),
+            head_file: Some(head_file.display().to_string()),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_chain_across_files_and_restarts() {
+        let directory = tempfile::tempdir().unwrap();
+        let log = open(directory.path(), 600);
+        for i in 0..4 {
+            log.append(record("alice", &format!("plugin{}", i)))
+                .unwrap();
+        }
+        // a restarted server goes on with the same chain
+        let log = open(directory.path(), 600);
+        let entry = log.append(record("bob", "kubectl")).unwrap();
+        assert_eq!(4, entry.sequence);
+
+        assert!(log_files(directory.path()).unwrap().len() > 1);
+        let verification = verify(directory.path(), None, None).unwrap();
+        assert_eq!(5, verification.entries);
+        assert_eq!(0, verification.first_sequence);
+
+        // moving the oldest file away keeps the rest verifiable
+        fs::remove_file(file_path(directory.path(), 1)).unwrap();
+        let verification = verify(directory.path(), None, None).unwrap();
+        assert!(verification.first_sequence > 0);
+    }
+
+    #[test]
+    fn test_verify_detects_edits_and_gaps() {
+        let directory = tempfile::tempdir().unwrap();
+        let log = open(directory.path(), DEFAULT_MAX_FILE_BYTES);
+        for user in ["alice", "bob", "carol"] {
+            log.append(record(user, "kubectl")).unwrap();
+        }
+        let path = file_path(directory.path(), 1);
+        let original = fs::read_to_string(&path).unwrap();
+
+        fs::write(&path, original.replace("\"bob\"", "\"dave\"")).unwrap();
+        match verify(directory.path(), None, None) {
+            Err(AuditError::Tampered { line, reason, .. }) => {
+                assert_eq!(2, line);
+                assert_eq!("entry 1 was modified", reason);
+            }
+            other => panic!("expected a tampered log, got {:?}", other),
+        }
+
+        let without_bob: Vec<&str> = original
+            .lines()
+            .filter(|l| !l.contains("\"bob\""))
+            .collect();
+        fs::write(&path, without_bob.join("\n")).unwrap();
+        match verify(directory.path(), None, None) {
+            Err(AuditError::Tampered { line, reason, .. }) => {
+                assert_eq!(2, line);
+                assert_eq!("expected entry 1, found 2", reason);
+            }
+            other => panic!("expected a tampered log, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_blank_lines_are_skipped_by_verify_and_query() {
+        let directory = tempfile::tempdir().unwrap();
+        let log = open(directory.path(), DEFAULT_MAX_FILE_BYTES);
+        log.append(record("alice", "kubectl")).unwrap();
+        log.append(record("bob", "kubectl")).unwrap();
+        let path = file_path(directory.path(), 1);
+        let original = fs::read_to_string(&path).unwrap();
+        fs::write(&path, original.replacen('\n', "\n\n", 1)).unwrap();
+
+        assert_eq!(2, verify(directory.path(), None, None).unwrap().entries);
+        assert_eq!(2, log.query(&AuditFilter::default()).unwrap().len());
+        // line numbers still point at the file
+        fs::write(&path, original.replacen('\n', "\n\nnot json\n", 1)).unwrap();
+        match verify(directory.path(), None, None) {
+            Err(AuditError::Tampered { line, .. }) => assert_eq!(3, line),
+            other => panic!("expected a tampered log, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_hmac_sha256() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            hex::encode(hmac_sha256(b"Jefe", b"what do ya want for nothing?"))
+        );
+    }
+
+    #[test]
+    fn test_keyed_chain_and_head() {
+        let directory = tempfile::tempdir().unwrap();
+        let secrets = tempfile::tempdir().unwrap();
+        let key_file = secrets.path().join("audit.key");
+        let head_file = secrets.path().join("audit.head");
+        fs::write(&key_file, "s3cret\n").unwrap();
+        let log = protected(directory.path(), &key_file, &head_file).unwrap();
+        for user in ["alice", "bob", "carol"] {
+            log.append(record(user, "kubectl")).unwrap();
+        }
+        let key = read_key(&key_file).unwrap();
+        assert_eq!(b"s3cret".to_vec(), key);
+        assert_eq!(
+            3,
+            verify(directory.path(), Some(&key), Some(&head_file))
+                .unwrap()
+                .entries
+        );
+
+        // without the key the chain cannot be checked, nor rewritten
+        assert!(verify(directory.path(), None, None).is_err());
+        assert!(verify(directory.path(), Some(b"guess"), None).is_err());
+
+        // removing the last entry keeps the chain but not the head
+        let path = file_path(directory.path(), 1);
+        let original = fs::read_to_string(&path).unwrap();
+        let truncated: Vec<&str> = original.lines().take(2).collect();
+        fs::write(&path, truncated.join("\n")).unwrap();
+        assert_eq!(
+            2,
+            verify(directory.path(), Some(&key), None).unwrap().entries
+        );
+        match verify(directory.path(), Some(&key), Some(&head_file)) {
+            Err(AuditError::Tampered { reason, .. }) => {
+                assert_eq!("the log ends at entry 1, its head is entry 2", reason)
+            }
+            other => panic!("expected a tampered log, got {:?}", other),
+        }
+        assert!(protected(directory.path(), &key_file, &head_file).is_err());
+
+        // and the server does not go on with another key
+        fs::write(&path, &original).unwrap();
+        assert!(protected(directory.path(), &key_file, &head_file).is_ok());
+        fs::write(&key_file, "other").unwrap();
+        assert!(protected(directory.path(), &key_file, &head_file).is_err());
+    }
+
+    #[test]
+    fn test_head_failures_do_not_fail_appends() {
+        let directory = tempfile::tempdir().unwrap();
+        let secrets = tempfile::tempdir().unwrap();
+        let key_file = secrets.path().join("audit.key");
+        let head_file = secrets.path().join("audit.head");
+        fs::write(&key_file, "s3cret").unwrap();
+        let log = protected(directory.path(), &key_file, &head_file).unwrap();
+        log.append(record("alice", "kubectl")).unwrap();
+        // the head is replaced through audit.tmp, which cannot be created
+        fs::create_dir(head_file.with_extension("tmp")).unwrap();
+        assert_eq!(1, log.append(record("bob", "kubectl")).unwrap().sequence);
+        assert_eq!(2, log.append(record("carol", "kubectl")).unwrap().sequence);
+
+        // the head is behind the log, as after a crash
+        let key = read_key(&key_file).unwrap();
+        assert_eq!(
+            3,
+            verify(directory.path(), Some(&key), Some(&head_file))
+                .unwrap()
+                .entries
+        );
+    }
+
+    #[test]
+    fn test_query() {
+        let directory = tempfile::tempdir().unwrap();
+        let log = open(directory.path(), 600);
+        log.append(record("alice", "kubectl")).unwrap();
+        log.append(record("bob", "prometheus")).unwrap();
+        log.append(record("alice", "prometheus")).unwrap();
+
+        let alice = log
+            .query(&AuditFilter {
+                user: Some("alice".into()),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(
+            vec![2, 0],
+            alice.iter().map(|e| e.sequence).collect::<Vec<u64>>()
+        );
+
+        let latest = log
+            .query(&AuditFilter {
+                plugin: Some("prometheus".into()),
+                limit: Some(1),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(
+            vec![2],
+            latest.iter().map(|e| e.sequence).collect::<Vec<u64>>()
+        );
+    }
+
+    #[test]
+    fn test_query_does_not_wait_for_appends() {
+        let directory = tempfile::tempdir().unwrap();
+        let log = open(directory.path(), DEFAULT_MAX_FILE_BYTES);
+        log.append(record("alice", "kubectl")).unwrap();
+        log.append(record("bob", "kubectl")).unwrap();
+        let _appending = log.tail.lock().unwrap();
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(file_path(directory.path(), 1))
+            .unwrap();
+        file.write_all(b"{\"sequence\":2,").unwrap();
+
+        let entries = log.query(&AuditFilter::default()).unwrap();
+        assert_eq!(
+            vec![1, 0],
+            entries.iter().map(|e| e.sequence).collect::<Vec<u64>>()
+        );
+        // the half written line is only skipped at the end of the file
+        file.write_all(b"\nnot json\n").unwrap();
+        assert!(log.query(&AuditFilter::default()).is_err());
+    }
+
+    #[test]
+    fn test_options_hash_ignores_order() {
+        let a = HashMap::from([
+            ("a".to_string(), "1".to_string()),
+            ("b".to_string(), "2".to_string()),
+        ]);
+        let b = HashMap::from([
+            ("b".to_string(), "2".to_string()),
+            ("a".to_string(), "1".to_string()),
+        ]);
+        assert_eq!(options_hash(&a), options_hash(&b));
+        assert_ne!(options_hash(&a), options_hash(&HashMap::new()));
+    }
+}
@@ -0,0 +1,706 @@
+use crate::admin::{bearer_token, constant_time_eq};
+use crate::policy::{Actor, Principal};
+use crate::AUTH;
+
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use once_cell::sync::Lazy;
+use rocket::request::{self, FromRequest};
+use rocket::Outcome;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tonic::{Code, Request, Status};
+
+// Authentication of the gRPC and REST APIs (the admin API keeps its own
+// tokens). Callers present one of
+//
+//   - a static token from the config, `Authorization: Bearer <token>`
+//   - a JWT signed by a key of the configured JWKS, as a bearer token too
+//   - a client certificate, when the gRPC server runs with mTLS
+//
+// The identity found replaces the user a request claims, so the timeline
+// records who actually ran a cell. Without an [auth] section every request
+// is accepted as before. Requests without an identity are anonymous for the
+// policy, the user they claim is only recorded.
+
+const DEFAULT_USER_CLAIM: &str = "sub";
+const DEFAULT_GROUPS_CLAIM: &str = "groups";
+const DEFAULT_JWKS_REFRESH_SECONDS: u64 = 3600;
+// a token signed by an unknown key loads the JWKS again, at most that
+// often so made up kids cannot hammer the identity provider
+const UNKNOWN_KEY_REFRESH: Duration = Duration::from_secs(60);
+// how long a websocket ticket can be redeemed
+const TICKET_LIFETIME: Duration = Duration::from_secs(30);
+
+// Browsers cannot set the Authorization header of a websocket handshake,
+// so viewers exchange their credentials for a ticket through the REST API
+// and pass it on the handshake url instead. A ticket is redeemed once.
+// ticket -> actor and expiry
+type Tickets = HashMap<String, (Actor, Instant)>;
+static TICKETS: Lazy<Mutex<Tickets>> = Lazy::new(Default::default);
+
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone, Default)]
+pub struct AuthConfig {
+    // accept requests without credentials, with the user they claim
+    pub allow_anonymous: Option<bool>,
+    pub tokens: Option<Vec<TokenConfig>>,
+    pub jwt: Option<JwtConfig>,
+    pub mtls: Option<MtlsConfig>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone, Default)]
+pub struct TokenConfig {
+    pub token: String,
+    pub user: String,
+    pub groups: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone, Default)]
+pub struct JwtConfig {
+    // url (http:// or https://) or path of the JSON Web Key Set
+    pub jwks: String,
+    // the iss and aud the tokens must carry, not checked when missing
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+    // claim holding the user, defaults to sub
+    pub user_claim: Option<String>,
+    // claim holding the groups (a list or a single string), defaults to groups
+    pub groups_claim: Option<String>,
+    // how often a JWKS url is fetched again, defaults to an hour
+    pub jwks_refresh_seconds: Option<u64>,
+    // algorithms accepted for keys of the JWKS that do not name theirs
+    // (alg), e.g. ["RS256"]. Keys naming one only accept that one, which
+    // must be in the list when it is set.
+    pub algorithms: Option<Vec<Algorithm>>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone, Default)]
+pub struct MtlsConfig {
+    // pem files of the server certificate and key
+    pub certificate: String,
+    pub key: String,
+    // pem file of the CA the client certificates must be signed by
+    pub client_ca: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Token,
+    Jwt,
+    Certificate,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    pub user: String,
+    pub groups: Vec<String>,
+    pub method: Method,
+}
+
+impl Identity {
+    pub fn principal(&self) -> Principal {
+        Principal {
+            user: Some(self.user.clone()),
+            groups: self.groups.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthError {
+    MissingCredentials,
+    InvalidToken(String),
+    InvalidCertificate(String),
+    Jwks(String),
+    // unknown, expired or already used websocket ticket
+    InvalidTicket,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::MissingCredentials => write!(f, "missing credentials"),
+            AuthError::InvalidToken(e) => write!(f, "invalid token: {}", e),
+            AuthError::InvalidCertificate(e) => write!(f, "invalid client certificate: {}", e),
+            AuthError::Jwks(e) => write!(f, "could not load the JWKS: {}", e),
+            AuthError::InvalidTicket => write!(f, "invalid ticket"),
+        }
+    }
+}
+
+fn is_url(location: &str) -> bool {
+    location.starts_with("http://") || location.starts_with("https://")
+}
+
+fn load_jwks(location: &str) -> Result<JwkSet, AuthError> {
+    let text = if is_url(location) {
+        reqwest::blocking::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .and_then(|client| client.get(location).send())
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.text())
+            .map_err(|e| AuthError::Jwks(format!("{}: {}", location, e)))?
+    } else {
+        fs::read_to_string(location).map_err(|e| AuthError::Jwks(format!("{}: {}", location, e)))?
+    };
+    serde_json::from_str(&text).map_err(|e| AuthError::Jwks(format!("{}: {}", location, e)))
+}
+
+// groups accepts both a list of strings and a single string
+fn groups(claim: Option<&Value>) -> Vec<String> {
+    match claim {
+        Some(Value::Array(groups)) => groups
+            .iter()
+            .filter_map(|g| g.as_str().map(str::to_string))
+            .collect(),
+        Some(Value::String(group)) => vec![group.clone()],
+        _ => vec![],
+    }
+}
+
+// common_name reads the CN of the subject of a DER certificate
+fn common_name(der: &[u8]) -> Result<String, AuthError> {
+    let (_, certificate) = x509_parser::parse_x509_certificate(der)
+        .map_err(|e| AuthError::InvalidCertificate(e.to_string()))?;
+    let name = certificate
+        .subject()
+        .iter_common_name()
+        .next()
+        .and_then(|cn| cn.as_str().ok())
+        .map(str::to_string);
+    name.ok_or_else(|| AuthError::InvalidCertificate("no common name in the subject".into()))
+}
+
+pub struct Authenticator {
+    config: AuthConfig,
+    // the keys are loaded at startup and refreshed by `start`, validating
+    // a token only waits on the network when its key is unknown
+    keys: RwLock<JwkSet>,
+    // when an unknown key last loaded the keys
+    unknown_key_refresh: Mutex<Option<Instant>>,
+}
+
+impl Authenticator {
+    pub fn new(config: AuthConfig) -> Result<Self, AuthError> {
+        let keys = match &config.jwt {
+            Some(jwt) => load_jwks(&jwt.jwks)?,
+            None => JwkSet { keys: vec![] },
+        };
+        Ok(Self {
+            config,
+            keys: RwLock::new(keys),
+            unknown_key_refresh: Mutex::new(None),
+        })
+    }
+
+    pub fn refresh_keys(&self) -> Result<(), AuthError> {
+        if let Some(jwt) = &self.config.jwt {
+            let keys = load_jwks(&jwt.jwks)?;
+            *self
+                .keys
+                .write()
+                .map_err(|e| AuthError::Jwks(format!("could not lock the keys {:?}", e)))? = keys;
+        }
+        Ok(())
+    }
+
+    fn static_token(&self, token: &str) -> Option<Identity> {
+        self.config.tokens.as_ref()?.iter().find_map(|t| {
+            constant_time_eq(t.token.as_bytes(), token.as_bytes()).then(|| Identity {
+                user: t.user.clone(),
+                groups: t.groups.clone().unwrap_or_default(),
+                method: Method::Token,
+            })
+        })
+    }
+
+    // key returns the key of the JWKS with the kid and the algorithms it
+    // verifies, the ones of the token header are never trusted
+    fn key(
+        &self,
+        jwt: &JwtConfig,
+        kid: &str,
+    ) -> Result<Option<(DecodingKey, Vec<Algorithm>)>, AuthError> {
+        let keys = self
+            .keys
+            .read()
+            .map_err(|e| AuthError::Jwks(format!("could not lock the keys {:?}", e)))?;
+        let jwk = match keys.find(kid) {
+            Some(jwk) => jwk,
+            None => return Ok(None),
+        };
+        let algorithms = match (jwk.common.algorithm, &jwt.algorithms) {
+            (Some(algorithm), Some(allowed)) if !allowed.contains(&algorithm) => {
+                return Err(AuthError::InvalidToken(format!(
+                    "key {} uses {:?}, which is not in jwt.algorithms",
+                    kid, algorithm
+                )))
+            }
+            (Some(algorithm), _) => vec![algorithm],
+            (None, Some(allowed)) => allowed.clone(),
+            (None, None) => {
+                return Err(AuthError::InvalidToken(format!(
+                    "key {} names no algorithm and jwt.algorithms is not set",
+                    kid
+                )))
+            }
+        };
+        let key = DecodingKey::from_jwk(jwk).map_err(|e| AuthError::InvalidToken(e.to_string()))?;
+        Ok(Some((key, algorithms)))
+    }
+
+    // refresh_for_unknown_key loads the keys again unless an unknown key
+    // already did it recently, it returns whether it did
+    fn refresh_for_unknown_key(&self) -> Result<bool, AuthError> {
+        {
+            let mut last = self
+                .unknown_key_refresh
+                .lock()
+                .map_err(|e| AuthError::Jwks(format!("could not lock the keys {:?}", e)))?;
+            if last.is_some_and(|last| last.elapsed() < UNKNOWN_KEY_REFRESH) {
+                return Ok(false);
+            }
+            *last = Some(Instant::now());
+        }
+        self.refresh_keys()?;
+        Ok(true)
+    }
+
+    fn jwt(&self, jwt: &JwtConfig, token: &str) -> Result<Identity, AuthError> {
+        let invalid = |e: jsonwebtoken::errors::Error| AuthError::InvalidToken(e.to_string());
+        let header = decode_header(token).map_err(invalid)?;
+        let kid = header
+            .kid
+            .ok_or_else(|| AuthError::InvalidToken("the token has no kid".into()))?;
+        let mut key = self.key(jwt, &kid)?;
+        if key.is_none() {
+            // the identity provider may have rotated its keys
+            match self.refresh_for_unknown_key() {
+                Ok(true) => key = self.key(jwt, &kid)?,
+                Ok(false) => {}
+                Err(e) => tracing::warn!("could not refresh the JWKS {}", e),
+            }
+        }
+        let (key, algorithms) =
+            key.ok_or_else(|| AuthError::InvalidToken(format!("unknown key {}", kid)))?;
+
+        // tokens signed with another algorithm than the ones of the key are
+        // refused by decode
+        let mut validation = Validation::default();
+        validation.algorithms = algorithms;
+        if let Some(issuer) = &jwt.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        if let Some(audience) = &jwt.audience {
+            validation.set_audience(&[audience]);
+        }
+        let claims = decode::<serde_json::Map<String, Value>>(token, &key, &validation)
+            .map_err(invalid)?
+            .claims;
+
+        let user_claim = jwt.user_claim.as_deref().unwrap_or(DEFAULT_USER_CLAIM);
+        let user = claims
+            .get(user_claim)
+            .and_then(Value::as_str)
+            .filter(|u| !u.is_empty())
+            .ok_or_else(|| AuthError::InvalidToken(format!("no {} claim", user_claim)))?;
+        let groups_claim = jwt.groups_claim.as_deref().unwrap_or(DEFAULT_GROUPS_CLAIM);
+        Ok(Identity {
+            user: user.to_string(),
+            groups: groups(claims.get(groups_claim)),
+            method: Method::Jwt,
+        })
+    }
+
+    // authenticate finds who sent a request out of its Authorization header
+    // and the DER client certificate of the connection. None means an
+    // anonymous request that is allowed through.
+    pub fn authenticate(
+        &self,
+        authorization: Option<&str>,
+        certificate: Option<&[u8]>,
+    ) -> Result<Option<Identity>, AuthError> {
+        if let Some(token) = bearer_token(authorization) {
+            if let Some(identity) = self.static_token(token) {
+                return Ok(Some(identity));
+            }
+            return match &self.config.jwt {
+                Some(jwt) => self.jwt(jwt, token).map(Some),
+                None => Err(AuthError::InvalidToken("unknown token".into())),
+            };
+        }
+        if let Some(der) = certificate {
+            return Ok(Some(Identity {
+                user: common_name(der)?,
+                groups: vec![],
+                method: Method::Certificate,
+            }));
+        }
+        if self.config.allow_anonymous.unwrap_or(false) {
+            Ok(None)
+        } else {
+            Err(AuthError::MissingCredentials)
+        }
+    }
+}
+
+// authenticate checks a request against the server authenticator, every
+// request is anonymous when none is configured
+pub fn authenticate(
+    authorization: Option<&str>,
+    certificate: Option<&[u8]>,
+) -> Result<Option<Identity>, AuthError> {
+    match AUTH.get() {
+        Some(auth) => auth.authenticate(authorization, certificate),
+        None => Ok(None),
+    }
+}
+
+// start refreshes the keys of a JWKS url in the background, so keys rotated
+// by the identity provider are picked up
+pub fn start() {
+    let refresh = match AUTH.get().and_then(|a| a.config.jwt.as_ref()) {
+        Some(jwt) if is_url(&jwt.jwks) => jwt
+            .jwks_refresh_seconds
+            .unwrap_or(DEFAULT_JWKS_REFRESH_SECONDS),
+        _ => return,
+    };
+    std::thread::spawn(move || loop {
+        std::thread::sleep(Duration::from_secs(refresh));
+        if let Some(auth) = AUTH.get() {
+            if let Err(e) = auth.refresh_keys() {
+                tracing::warn!("could not refresh the JWKS {}", e);
+            }
+        }
+    });
+}
+
+// actor_for runs a request as its identity, or as an anonymous principal
+// named after the user it claims
+pub fn actor_for(identity: Option<&Identity>, claimed: Option<String>) -> Actor {
+    match identity {
+        Some(identity) => Actor::for_principal(identity.principal()),
+        None => Actor {
+            principal: Principal::default(),
+            name: claimed.filter(|u| !u.is_empty()),
+        },
+    }
+}
+
+fn tickets() -> Result<std::sync::MutexGuard<'static, Tickets>, AuthError> {
+    TICKETS.lock().map_err(|_| AuthError::InvalidTicket)
+}
+
+// issue_ticket returns a ticket standing for the actor of an authenticated
+// request, for TICKET_LIFETIME
+pub fn issue_ticket(actor: Actor) -> Result<String, AuthError> {
+    let ticket = hex::encode(rand::random::<[u8; 32]>());
+    let mut tickets = tickets()?;
+    tickets.retain(|_, (_, expires)| *expires > Instant::now());
+    tickets.insert(ticket.clone(), (actor, Instant::now() + TICKET_LIFETIME));
+    Ok(ticket)
+}
+
+// redeem_ticket returns the actor the ticket was issued to, once
+pub fn redeem_ticket(ticket: &str) -> Result<Actor, AuthError> {
+    match tickets()?.remove(ticket) {
+        Some((actor, expires)) if expires > Instant::now() => Ok(actor),
+        _ => Err(AuthError::InvalidTicket),
+    }
+}
+
+// tonic interceptor for every service but the admin one, the identity is
+// kept in the extensions of the request
+pub fn check(mut request: Request<()>) -> Result<Request<()>, Status> {
+    let authorization = request
+        .metadata()
+        .get("authorization")
+        .and_then(|v| v.to_str().ok());
+    let certificates = request.peer_certs();
+    let certificate = certificates
+        .as_ref()
+        .and_then(|c| c.first())
+        .map(|c| c.get_ref());
+    match authenticate(authorization, certificate) {
+        Ok(identity) => {
+            if let Some(identity) = identity {
+                request.extensions_mut().insert(identity);
+            }
+            Ok(request)
+        }
+        Err(e) => {
+            tracing::warn!("rejected grpc request {}", e);
+            Err(Status::new(Code::Unauthenticated, e.to_string()))
+        }
+    }
+}
+
+pub fn identity<T>(request: &Request<T>) -> Option<&Identity> {
+    request.extensions().get::<Identity>()
+}
+
+// source is the address a grpc request comes from, for the audit log
+pub fn source<T>(request: &Request<T>) -> Option<String> {
+    request.remote_addr().map(|a| a.ip().to_string())
+}
+
+// actor is who a grpc request runs as, the user it claims only names it
+// when it was let through anonymously
+pub fn actor<T>(request: &Request<T>, claimed: &str) -> Actor {
+    actor_for(identity(request), Some(claimed.to_string()))
+}
+
+// Caller is the rocket request guard of the REST routes. Rocket does not
+// see client certificates, so REST callers use bearer tokens.
+#[derive(Debug)]
+pub struct Caller {
+    pub identity: Option<Identity>,
+    // address of the client, for the audit log
+    pub source: Option<String>,
+}
+
+impl Caller {
+    pub fn actor(&self, claimed: Option<String>) -> Actor {
+        actor_for(self.identity.as_ref(), claimed)
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Caller {
+    type Error = AuthError;
+
+    fn from_request(request: &'a rocket::Request<'r>) -> request::Outcome<Self, Self::Error> {
+        match authenticate(request.headers().get_one("Authorization"), None) {
+            Ok(identity) => Outcome::Success(Caller {
+                identity,
+                source: request.client_ip().map(|ip| ip.to_string()),
+            }),
+            Err(e) => {
+                tracing::warn!("rejected rest request {}", e);
+                Outcome::Failure((rocket::http::Status::Unauthorized, e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+    use serde_json::json;
+    use std::io::Write;
+
+    const SECRET: &[u8] = b"databook-test-secret";
+    // base64 of SECRET, as the k of an oct JWK
+    const SECRET_BASE64: &str = "ZGF0YWJvb2stdGVzdC1zZWNyZXQ=";
+
+    fn authenticator(jwks: &tempfile::NamedTempFile, allow_anonymous: bool) -> Authenticator {
+        Authenticator::new(AuthConfig {
+            allow_anonymous: Some(allow_anonymous),
+            tokens: Some(vec![TokenConfig {
+                token: "s3cret".into(),
+                user: "ci".into(),
+                groups: Some(vec!["automation".into()]),
+            }]),
+            jwt: Some(JwtConfig {
+                jwks: jwks.path().to_string_lossy().to_string(),
+                issuer: Some("https://sso.example.com".into()),
+                audience: Some("databook".into()),
+                ..Default::default()
+            }),
+            mtls: None,
+        })
+        .unwrap()
+    }
+
+    fn jwks() -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        let keys =
+            json!({"keys": [{"kty": "oct", "kid": "test", "alg": "HS256", "k": SECRET_BASE64}]});
+        file.write_all(keys.to_string().as_bytes()).unwrap();
+        file
+    }
+
+    fn token(kid: &str, claims: Value) -> String {
+        signed(Algorithm::HS256, kid, claims)
+    }
+
+    fn signed(algorithm: Algorithm, kid: &str, claims: Value) -> String {
+        let mut header = Header::new(algorithm);
+        header.kid = Some(kid.to_string());
+        let token = encode(&header, &claims, &EncodingKey::from_secret(SECRET)).unwrap();
+        format!("Bearer {}", token)
+    }
+
+    fn claims(issuer: &str) -> Value {
+        json!({
+            "sub": "alice",
+            "groups": ["sre", "oncall"],
+            "iss": issuer,
+            "aud": "databook",
+            "exp": chrono::Utc::now().timestamp() + 600,
+        })
+    }
+
+    #[test]
+    fn test_static_tokens() {
+        let jwks = jwks();
+        let auth = authenticator(&jwks, false);
+        assert_eq!(
+            Some(Identity {
+                user: "ci".into(),
+                groups: vec!["automation".into()],
+                method: Method::Token,
+            }),
+            auth.authenticate(Some("Bearer s3cret"), None).unwrap()
+        );
+        assert!(matches!(
+            auth.authenticate(Some("Bearer s3cre"), None),
+            Err(AuthError::InvalidToken(_))
+        ));
+    }
+
+    #[test]
+    fn test_jwt() {
+        let jwks = jwks();
+        let auth = authenticator(&jwks, false);
+        let identity = auth
+            .authenticate(
+                Some(&token("test", claims("https://sso.example.com"))),
+                None,
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!("alice", identity.user);
+        assert_eq!(vec!["sre", "oncall"], identity.groups);
+        assert_eq!(Method::Jwt, identity.method);
+
+        // wrong issuer, unknown key and expired tokens are rejected
+        assert!(auth
+            .authenticate(
+                Some(&token("test", claims("https://evil.example.com"))),
+                None
+            )
+            .is_err());
+        assert!(auth
+            .authenticate(
+                Some(&token("other", claims("https://sso.example.com"))),
+                None
+            )
+            .is_err());
+        let mut expired = claims("https://sso.example.com");
+        expired["exp"] = json!(chrono::Utc::now().timestamp() - 3600);
+        assert!(auth
+            .authenticate(Some(&token("test", expired)), None)
+            .is_err());
+    }
+
+    #[test]
+    fn test_jwt_algorithm_comes_from_the_key() {
+        let jwks = jwks();
+        let auth = authenticator(&jwks, false);
+        // the key is HS256, a token claiming another algorithm is refused
+        assert!(auth
+            .authenticate(
+                Some(&signed(
+                    Algorithm::HS512,
+                    "test",
+                    claims("https://sso.example.com")
+                )),
+                None
+            )
+            .is_err());
+
+        // keys without alg need the configured list
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        let keys = json!({"keys": [{"kty": "oct", "kid": "test", "k": SECRET_BASE64}]});
+        file.write_all(keys.to_string().as_bytes()).unwrap();
+        let auth = authenticator(&file, false);
+        assert!(auth
+            .authenticate(
+                Some(&token("test", claims("https://sso.example.com"))),
+                None
+            )
+            .is_err());
+        let mut config = auth.config.clone();
+        config.jwt.as_mut().unwrap().algorithms = Some(vec![Algorithm::HS256]);
+        let auth = Authenticator::new(config).unwrap();
+        assert!(auth
+            .authenticate(
+                Some(&token("test", claims("https://sso.example.com"))),
+                None
+            )
+            .is_ok());
+    }
+
+    #[test]
+    fn test_unknown_key_refreshes_the_jwks() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(br#"{"keys": []}"#).unwrap();
+        let auth = authenticator(&file, false);
+
+        // the identity provider rotated its keys since the start
+        let keys =
+            json!({"keys": [{"kty": "oct", "kid": "test", "alg": "HS256", "k": SECRET_BASE64}]});
+        fs::write(file.path(), keys.to_string()).unwrap();
+        assert!(auth
+            .authenticate(
+                Some(&token("test", claims("https://sso.example.com"))),
+                None
+            )
+            .is_ok());
+        // another unknown key does not load them again right away
+        assert!(!auth.refresh_for_unknown_key().unwrap());
+    }
+
+    #[test]
+    fn test_tickets() {
+        let actor = actor_for(None, Some("bob".into()));
+        let ticket = issue_ticket(actor.clone()).unwrap();
+        assert_eq!(64, ticket.len());
+        assert_eq!(Ok(actor), redeem_ticket(&ticket));
+        // a ticket is used once
+        assert_eq!(Err(AuthError::InvalidTicket), redeem_ticket(&ticket));
+        assert_eq!(Err(AuthError::InvalidTicket), redeem_ticket("made-up"));
+    }
+
+    #[test]
+    fn test_anonymous() {
+        let jwks = jwks();
+        assert_eq!(
+            Err(AuthError::MissingCredentials),
+            authenticator(&jwks, false).authenticate(None, None)
+        );
+        assert_eq!(
+            Ok(None),
+            authenticator(&jwks, true).authenticate(None, None)
+        );
+        // credentials are still checked when anonymous requests are allowed
+        assert!(authenticator(&jwks, true)
+            .authenticate(Some("Bearer nope"), None)
+            .is_err());
+    }
+
+    #[test]
+    fn test_identity_wins_over_claimed_user() {
+        let identity = Identity {
+            user: "alice".into(),
+            groups: vec!["oncall".into()],
+            method: Method::Jwt,
+        };
+        let actor = actor_for(Some(&identity), Some("bob".into()));
+        assert_eq!(Some("alice".to_string()), actor.name);
+        assert_eq!(vec!["oncall".to_string()], actor.principal.groups);
+
+        // the claimed user is a name, the policy sees an anonymous caller
+        let actor = actor_for(None, Some("bob".into()));
+        assert_eq!(Some("bob".to_string()), actor.name);
+        assert_eq!(Principal::default(), actor.principal);
+        assert_eq!(None, actor_for(None, Some("".into())).name);
+    }
+}
@@ -0,0 +1,347 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+// Grammar of a plugin cell, as typed on the web UI, the CLI or a chat:
+//
+//   @plugin=prometheus        directive, required once
+//   # p99 of the api          comment
+//   @step=30s                 option, `@key=value`
+//   histogram_quantile(...)   body, everything after the header
+//
+// The header is made of directive, option, comment and blank lines, the
+// body starts at the first other line and reaches the plugin unchanged
+// (including `#` or `@` lines) under the plugin's body option. A body
+// whose first line starts with `@` or `#` is escaped with a `\`.
+
+pub const PLUGIN_DIRECTIVE: &str = "plugin";
+// option receiving the body of plugins not declaring a body_option
+pub const DEFAULT_BODY_OPTION: &str = "query";
+
+// Position is 1-based, columns count characters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    MissingPlugin,
+    DuplicatePlugin,
+    InvalidPluginName(String),
+    MissingEquals,
+    InvalidKey(String),
+    DuplicateOption(String),
+    // the body would overwrite an option set in the header
+    BodyConflictsWithOption(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub position: Position,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match &self.kind {
+            ParseErrorKind::MissingPlugin => "missing @plugin=<name> directive".to_string(),
+            ParseErrorKind::DuplicatePlugin => "@plugin is set more than once".to_string(),
+            ParseErrorKind::InvalidPluginName(name) => format!("invalid plugin name {:?}", name),
+            ParseErrorKind::MissingEquals => "expected @key=value".to_string(),
+            ParseErrorKind::InvalidKey(key) => format!("invalid option name {:?}", key),
+            ParseErrorKind::DuplicateOption(key) => format!("option {} is set more than once", key),
+            ParseErrorKind::BodyConflictsWithOption(key) => {
+                format!("the body sets option {} which is already set", key)
+            }
+        };
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.position.line, self.position.column, message
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Body {
+    pub text: String,
+    pub position: Position,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParsedCell {
+    pub plugin: String,
+    pub options: BTreeMap<String, String>,
+    pub body: Option<Body>,
+}
+
+impl ParsedCell {
+    // into_options returns the options the plugin is invoked with, the body
+    // is added under `body_option`
+    pub fn into_options(self, body_option: &str) -> Result<BTreeMap<String, String>, ParseError> {
+        let mut options = self.options;
+        if let Some(body) = self.body {
+            if options.contains_key(body_option) {
+                return Err(ParseError {
+                    kind: ParseErrorKind::BodyConflictsWithOption(body_option.to_string()),
+                    position: body.position,
+                });
+            }
+            options.insert(body_option.to_string(), body.text);
+        }
+        Ok(options)
+    }
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+}
+
+// plugin names become file and folder names on the plugin folder, without
+// dots they cannot be hidden paths, `..` or look like a package. The plugin
+// manager installs plugins under the same rule.
+pub fn is_valid_plugin_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+fn error(kind: ParseErrorKind, line: usize, column: usize) -> ParseError {
+    ParseError {
+        kind,
+        position: Position { line, column },
+    }
+}
+
+// column of the first character of `part`, a subslice of `line`
+fn column_of(line: &str, part: &str) -> usize {
+    let offset = part.as_ptr() as usize - line.as_ptr() as usize;
+    line[..offset].chars().count() + 1
+}
+
+pub fn parse_cell(text: &str) -> Result<ParsedCell, ParseError> {
+    let lines: Vec<&str> = text
+        .split('\n')
+        .map(|l| l.strip_suffix('\r').unwrap_or(l))
+        .collect();
+    let mut plugin: Option<String> = None;
+    let mut options = BTreeMap::new();
+    let mut body_start = lines.len();
+
+    for (index, line) in lines.iter().enumerate() {
+        let number = index + 1;
+        let trimmed = line.trim_start();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let directive = match trimmed.strip_prefix('@') {
+            Some(directive) => directive,
+            None => {
+                body_start = index;
+                break;
+            }
+        };
+
+        let (key, value) = match directive.split_once('=') {
+            Some(pair) => pair,
+            None => {
+                return Err(error(
+                    ParseErrorKind::MissingEquals,
+                    number,
+                    column_of(line, directive) + directive.chars().count(),
+                ))
+            }
+        };
+        let key_column = column_of(line, key);
+        let (key, value) = (key.trim(), value.trim());
+        if !is_valid_name(key) {
+            return Err(error(
+                ParseErrorKind::InvalidKey(key.to_string()),
+                number,
+                key_column,
+            ));
+        }
+
+        if key == PLUGIN_DIRECTIVE {
+            if plugin.is_some() {
+                return Err(error(ParseErrorKind::DuplicatePlugin, number, key_column));
+            }
+            if !is_valid_plugin_name(value) {
+                return Err(error(
+                    ParseErrorKind::InvalidPluginName(value.to_string()),
+                    number,
+                    key_column,
+                ));
+            }
+            plugin = Some(value.to_string());
+        } else if options.insert(key.to_string(), value.to_string()).is_some() {
+            return Err(error(
+                ParseErrorKind::DuplicateOption(key.to_string()),
+                number,
+                key_column,
+            ));
+        }
+    }
+
+    let plugin = plugin.ok_or_else(|| error(ParseErrorKind::MissingPlugin, 1, 1))?;
+
+    let mut body_lines = lines[body_start..].to_vec();
+    while matches!(body_lines.last(), Some(l) if l.trim().is_empty()) {
+        body_lines.pop();
+    }
+    let body = if body_lines.is_empty() {
+        None
+    } else {
+        let first = body_lines[0];
+        let position = Position {
+            line: body_start + 1,
+            column: column_of(first, first.trim_start()),
+        };
+        // `\@` and `\#` keep a first body line from being read as header
+        let unescaped = first.trim_start();
+        if let Some(rest) = unescaped.strip_prefix('\\') {
+            if rest.starts_with('@') || rest.starts_with('#') {
+                body_lines[0] = rest;
+            }
+        }
+        Some(Body {
+            text: body_lines.join("\n"),
+            position,
+        })
+    };
+
+    Ok(ParsedCell {
+        plugin,
+        options,
+        body,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(text: &str) -> (String, BTreeMap<String, String>) {
+        let cell = parse_cell(text).unwrap();
+        let plugin = cell.plugin.clone();
+        (plugin, cell.into_options(DEFAULT_BODY_OPTION).unwrap())
+    }
+
+    fn options(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn error_at(text: &str) -> (ParseErrorKind, usize, usize) {
+        let e = parse_cell(text).unwrap_err();
+        (e.kind, e.position.line, e.position.column)
+    }
+
+    #[test]
+    fn test_readme_example() {
+        assert_eq!(
+            (
+                "prometheus".to_string(),
+                options(&[("query", "my_metric[5m]")])
+            ),
+            parsed("@plugin=prometheus\nmy_metric[5m]")
+        );
+    }
+
+    #[test]
+    fn test_options_comments_and_multiline_body() {
+        let text = "# p99 of the api\r\n@plugin=prometheus\n  @step = 30s\n\n@range=a=b\nsum(rate(x[5m]))\n# by pod\nby (pod)\n\n";
+        assert_eq!(
+            (
+                "prometheus".to_string(),
+                options(&[
+                    ("query", "sum(rate(x[5m]))\n# by pod\nby (pod)"),
+                    ("range", "a=b"),
+                    ("step", "30s"),
+                ])
+            ),
+            parsed(text)
+        );
+    }
+
+    #[test]
+    fn test_no_body() {
+        assert_eq!(
+            ("http".to_string(), options(&[("url", "https://status")])),
+            parsed("@plugin=http\n@url=https://status\n")
+        );
+    }
+
+    #[test]
+    fn test_escaped_body() {
+        assert_eq!(
+            (
+                "elastic".to_string(),
+                options(&[("query", "@timestamp:[now-1h TO now]")])
+            ),
+            parsed("@plugin=elastic\n\\@timestamp:[now-1h TO now]")
+        );
+    }
+
+    #[test]
+    fn test_custom_body_option() {
+        let cell = parse_cell("@plugin=postgres\nselect 1").unwrap();
+        assert_eq!(
+            options(&[("sql", "select 1")]),
+            cell.into_options("sql").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_errors_have_positions() {
+        assert_eq!(
+            (ParseErrorKind::MissingPlugin, 1, 1),
+            error_at("my_metric[5m]")
+        );
+        assert_eq!(
+            (ParseErrorKind::DuplicatePlugin, 2, 2),
+            error_at("@plugin=a\n@plugin=b")
+        );
+        assert_eq!(
+            (ParseErrorKind::MissingEquals, 2, 8),
+            error_at("@plugin=a\n  @step")
+        );
+        assert_eq!(
+            (ParseErrorKind::InvalidKey("st ep".into()), 2, 2),
+            error_at("@plugin=a\n@st ep=1")
+        );
+        assert_eq!(
+            (ParseErrorKind::DuplicateOption("step".into()), 3, 2),
+            error_at("@plugin=a\n@step=1\n@step=2")
+        );
+        assert_eq!(
+            (ParseErrorKind::InvalidPluginName("../etc".into()), 1, 2),
+            error_at("@plugin=../etc")
+        );
+        assert_eq!(
+            (ParseErrorKind::InvalidPluginName("..".into()), 1, 2),
+            error_at("@plugin=..")
+        );
+        assert_eq!(
+            (ParseErrorKind::InvalidPluginName("hello.v2".into()), 1, 2),
+            error_at("@plugin=hello.v2")
+        );
+
+        let e = parse_cell("@plugin=prometheus\n@query=up\n\n  rate(x)")
+            .unwrap()
+            .into_options(DEFAULT_BODY_OPTION)
+            .unwrap_err();
+        assert_eq!(
+            "line 4, column 3: the body sets option query which is already set",
+            e.to_string()
+        );
+    }
+}
@@ -0,0 +1,724 @@
+use chrono::{DateTime, TimeZone, Utc};
+use clap::{Parser, Subcommand};
+use databook::databook_admin_client::DatabookAdminClient;
+use databook::databook_client::DatabookClient;
+use databook::exports_client::ExportsClient;
+use databook::imports_client::ImportsClient;
+use databook::runs_client::RunsClient;
+use databook::schedules_client::SchedulesClient;
+use databook::search_client::SearchClient;
+use databook::{
+    AuditRequest, CreateScheduleRequest, ExportRequest, ImportNotebookRequest,
+    InstantiateRunbookRequest, InvokeCellRequest, NotebookRun, RunNotebookRequest, SearchRequest,
+};
+use std::fs;
+use std::io::Read;
+use std::path::PathBuf;
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Request, Status};
+
+// modules shared with the server binary, only part of them is used here
+#[allow(dead_code)]
+mod audit;
+#[allow(dead_code)]
+mod cell_parser;
+#[allow(dead_code)]
+mod plugin_package;
+#[allow(dead_code)]
+mod plugin_signature;
+
+use plugin_package::PluginPackage;
+
+#[allow(non_camel_case_types, clippy::large_enum_variant)]
+pub mod databook {
+    tonic::include_proto!("databook");
+}
+
+// databook is the command line tool to manage plugins and work with cells
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct Args {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    // Manages plugins
+    #[clap(subcommand)]
+    Plugin(PluginCommand),
+    // Works with `@plugin=` cells, parsed the same way as on the server
+    #[clap(subcommand)]
+    Cell(CellCommand),
+    // Works with notebooks stored on the server
+    #[clap(subcommand)]
+    Notebook(NotebookCommand),
+    // Works with runbook templates stored on the server
+    #[clap(subcommand)]
+    Runbook(RunbookCommand),
+    // Checks and queries the audit log of plugin invocations and admin
+    // changes
+    #[clap(subcommand)]
+    Audit(AuditCommand),
+    // Searches notebooks, their cells and the outputs of every execution
+    Search {
+        // words must all match, "quoted words" as a phrase, word* as a prefix
+        #[clap(value_parser)]
+        text: Vec<String>,
+        #[clap(short, long, value_parser)]
+        plugin: Option<String>,
+        #[clap(short, long, value_parser)]
+        tag: Option<String>,
+        // sev1 to sev4, the severity of the incident of the notebook
+        #[clap(long, value_parser)]
+        severity: Option<String>,
+        #[clap(short, long, value_parser)]
+        notebook_id: Option<String>,
+        // RFC 3339 times, e.g. 2026-03-04T10:00:00Z
+        #[clap(long, value_parser)]
+        from: Option<DateTime<Utc>>,
+        #[clap(long, value_parser)]
+        to: Option<DateTime<Utc>>,
+        #[clap(short, long, value_parser, default_value_t = 20)]
+        limit: u32,
+        #[clap(short, long, value_parser, default_value_t = String::from("http://[::1]:50051"))]
+        server: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum NotebookCommand {
+    // Runs the plugin cells of a notebook in order and checks their
+    // assertions, exits with 1 if any step failed
+    Run {
+        #[clap(value_parser)]
+        notebook_id: String,
+        #[clap(short, long, value_parser, default_value_t = String::from("http://[::1]:50051"))]
+        server: String,
+        // recorded on the timeline as who ran the cells
+        #[clap(short, long, value_parser)]
+        user: Option<String>,
+        // stop at the first failed step
+        #[clap(long, value_parser)]
+        fail_fast: bool,
+    },
+    // Exports a notebook and its timeline as a postmortem document
+    Export {
+        #[clap(value_parser)]
+        notebook_id: String,
+        // markdown or html
+        #[clap(short, long, value_parser, default_value_t = String::from("markdown"))]
+        format: String,
+        // a local template file, see templates/ for the default ones
+        #[clap(short, long, value_parser)]
+        template: Option<PathBuf>,
+        // the name of a template in the export_templates directory of the server
+        #[clap(long, value_parser)]
+        template_name: Option<String>,
+        // where to write the document, stdout when missing
+        #[clap(short, long, value_parser)]
+        output: Option<PathBuf>,
+        #[clap(short, long, value_parser, default_value_t = String::from("http://[::1]:50051"))]
+        server: String,
+    },
+    // Imports a Jupyter notebook (.ipynb) or a Markdown document as a new
+    // notebook and prints its id
+    Import {
+        #[clap(value_parser)]
+        file: PathBuf,
+        // jupyter or markdown, guessed from the file extension when missing
+        #[clap(short, long, value_parser)]
+        format: Option<String>,
+        // owner of the new notebook
+        #[clap(short, long, value_parser)]
+        owner: String,
+        // defaults to the title found in the document
+        #[clap(long, value_parser)]
+        title: Option<String>,
+        #[clap(long = "tag", value_parser)]
+        tags: Vec<String>,
+        // code blocks in this language become cells of this plugin, as
+        // language=plugin, can be repeated
+        #[clap(short, long = "language", value_parser = parse_parameter)]
+        languages: Vec<(String, String)>,
+        #[clap(short, long, value_parser, default_value_t = String::from("http://[::1]:50051"))]
+        server: String,
+    },
+    // Schedules a cell, or the whole notebook, to run on the server every
+    // interval or on a cron expression, until the incident is resolved
+    Watch {
+        #[clap(value_parser)]
+        notebook_id: String,
+        // the whole notebook runs when missing
+        #[clap(short, long, value_parser)]
+        cell: Option<String>,
+        // e.g. 1m or 1h30m
+        #[clap(short, long, value_parser)]
+        every: Option<String>,
+        // minute hour day-of-month month day-of-week, in UTC
+        #[clap(long, value_parser)]
+        cron: Option<String>,
+        // assertions on the output of the cell, e.g. "error_rate < 0.01",
+        // followers are notified when they start to hold
+        #[clap(long, value_parser)]
+        condition: Option<String>,
+        // url the notification is also posted to
+        #[clap(short, long, value_parser)]
+        webhook: Option<String>,
+        #[clap(short, long, value_parser)]
+        user: Option<String>,
+        #[clap(short, long, value_parser, default_value_t = String::from("http://[::1]:50051"))]
+        server: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum RunbookCommand {
+    // Instantiates a runbook into a new notebook and runs it, exits with 1
+    // if any step failed
+    Run {
+        #[clap(value_parser)]
+        runbook_id: String,
+        // the latest version when missing
+        #[clap(long, value_parser)]
+        version: Option<u32>,
+        // runbook parameters as name=value, can be repeated
+        #[clap(short, long = "param", value_parser = parse_parameter)]
+        parameters: Vec<(String, String)>,
+        // owner of the new notebook
+        #[clap(short, long, value_parser)]
+        owner: String,
+        #[clap(short, long, value_parser, default_value_t = String::from("http://[::1]:50051"))]
+        server: String,
+        #[clap(short, long, value_parser)]
+        user: Option<String>,
+        #[clap(long, value_parser)]
+        fail_fast: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum AuditCommand {
+    // Checks the hash chain of the audit-<n>.log files of a directory,
+    // exits with 1 if an entry was altered, removed or reordered
+    Verify {
+        #[clap(value_parser)]
+        directory: PathBuf,
+        // the key_file of the server, when it has one
+        #[clap(long, value_parser)]
+        key_file: Option<PathBuf>,
+        // the head_file of the server, to find entries removed at the end
+        #[clap(long, value_parser)]
+        head_file: Option<PathBuf>,
+    },
+    // Lists the audit entries of the server, most recent first,
+    // $DATABOOK_TOKEN must be one of its admin tokens
+    Query {
+        #[clap(short, long, value_parser)]
+        user: Option<String>,
+        #[clap(short, long, value_parser)]
+        plugin: Option<String>,
+        // invoke, install, upgrade, disable, enable or uninstall
+        #[clap(short, long, value_parser)]
+        action: Option<String>,
+        // RFC 3339 times, e.g. 2026-03-04T10:00:00Z
+        #[clap(long, value_parser)]
+        from: Option<DateTime<Utc>>,
+        #[clap(long, value_parser)]
+        to: Option<DateTime<Utc>>,
+        #[clap(short, long, value_parser, default_value_t = 100)]
+        limit: u32,
+        #[clap(short, long, value_parser, default_value_t = String::from("http://[::1]:50051"))]
+        server: String,
+    },
+}
+
+fn parse_parameter(text: &str) -> Result<(String, String), String> {
+    text.split_once('=')
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected name=value, got {:?}", text))
+}
+
+// with_token sends $DATABOOK_TOKEN as a bearer token, for servers that
+// require authentication
+fn with_token(mut request: Request<()>) -> Result<Request<()>, Status> {
+    if let Ok(token) = std::env::var("DATABOOK_TOKEN") {
+        let value = format!("Bearer {}", token)
+            .parse()
+            .map_err(|_| Status::invalid_argument("DATABOOK_TOKEN is not a valid header value"))?;
+        request.metadata_mut().insert("authorization", value);
+    }
+    Ok(request)
+}
+
+async fn connect(server: String) -> Result<Channel, tonic::transport::Error> {
+    Endpoint::from_shared(server)?.connect().await
+}
+
+fn run_notebook(
+    server: String,
+    request: RunNotebookRequest,
+) -> Result<NotebookRun, Box<dyn std::error::Error>> {
+    tokio::runtime::Runtime::new()?.block_on(async {
+        let mut client = RunsClient::with_interceptor(connect(server).await?, with_token);
+        Ok(client.run_notebook(request).await?.into_inner())
+    })
+}
+
+// print_report shows every step of a run and exits with 1 if it failed
+fn print_report(run: NotebookRun) {
+    for step in &run.steps {
+        let status = if step.passed { "PASS" } else { "FAIL" };
+        println!("{} {} ({})", status, step.cell_id, step.plugin);
+        if !step.error.is_empty() {
+            println!("    error: {}", step.error);
+        }
+        for assertion in &step.assertions {
+            let status = if assertion.passed { "ok" } else { "failed" };
+            print!("    expect {}: {}", assertion.assertion, status);
+            if !assertion.passed && !assertion.actual.is_empty() {
+                print!(", got {}", assertion.actual);
+            }
+            if !assertion.error.is_empty() {
+                print!(" ({})", assertion.error);
+            }
+            println!();
+        }
+    }
+    let passed = run.steps.iter().filter(|s| s.passed).count();
+    println!(
+        "{}: {}/{} steps passed, notebook {}, run {}",
+        if run.passed { "passed" } else { "failed" },
+        passed,
+        run.steps.len(),
+        run.notebook_id,
+        run.id
+    );
+    if !run.passed {
+        std::process::exit(1);
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum CellCommand {
+    // Parses a cell and prints the plugin and options it would invoke
+    Parse {
+        // file with the cell, stdin when missing
+        #[clap(value_parser)]
+        file: Option<String>,
+        // option receiving the body, the server uses the plugin body_option
+        #[clap(short, long, value_parser, default_value_t = String::from(cell_parser::DEFAULT_BODY_OPTION))]
+        body_option: String,
+    },
+    // Sends a cell to the server and prints the output
+    Invoke {
+        // file with the cell, stdin when missing
+        #[clap(value_parser)]
+        file: Option<String>,
+        #[clap(short, long, value_parser, default_value_t = String::from("http://[::1]:50051"))]
+        server: String,
+        // recorded on the timeline as who invoked the cell
+        #[clap(short, long, value_parser)]
+        user: Option<String>,
+    },
+}
+
+fn read_input(file: Option<String>) -> Result<String, std::io::Error> {
+    match file {
+        Some(file) => fs::read_to_string(file),
+        None => {
+            let mut text = String::new();
+            std::io::stdin().read_to_string(&mut text)?;
+            Ok(text)
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum PluginCommand {
+    // Generates a new ed25519 keypair to sign plugins
+    Keygen {
+        // where the (secret) keypair will be written
+        #[clap(short, long, value_parser)]
+        output: String,
+    },
+    // Signs the config.toml and plugin.wasm of a plugin folder,
+    // writing plugin.sig next to them
+    Sign {
+        // file with the keypair generated by `databook plugin keygen`
+        #[clap(short, long, value_parser)]
+        key: String,
+        // the plugin folder
+        #[clap(value_parser)]
+        folder: String,
+    },
+    // Builds a .dbplugin package out of a plugin folder
+    // (manifest.toml, config.toml, plugin.wasm and optionally plugin.sig,
+    // README.md, icon.png, examples/ and widgets/)
+    Pack {
+        // the plugin folder
+        #[clap(value_parser)]
+        folder: String,
+        // defaults to <name>-<version>.dbplugin
+        #[clap(short, long, value_parser)]
+        output: Option<String>,
+    },
+    // Extracts a .dbplugin package into a plugin folder
+    Unpack {
+        #[clap(value_parser)]
+        package: String,
+        #[clap(value_parser)]
+        folder: String,
+    },
+    // Shows the manifest, config and files of a .dbplugin package
+    Inspect {
+        #[clap(value_parser)]
+        package: String,
+    },
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+
+    match args.command {
+        Command::Plugin(PluginCommand::Keygen { output }) => {
+            let keypair = plugin_signature::generate_keypair();
+            fs::write(&output, plugin_signature::keypair_to_hex(&keypair))?;
+            println!(
+                "keypair written to {}, add the public key to trusted_keys:\n{}",
+                output,
+                hex::encode(keypair.public.as_bytes())
+            );
+        }
+        Command::Plugin(PluginCommand::Sign { key, folder }) => {
+            let keypair = plugin_signature::keypair_from_hex(&fs::read_to_string(key)?)
+                .map_err(|e| format!("invalid key {:?}", e))?;
+            let folder = PathBuf::from(folder);
+            let wasm = fs::read(folder.join("plugin.wasm"))?;
+            let config = fs::read(folder.join("config.toml"))?;
+            let signature = plugin_signature::sign(&keypair, &wasm, &config);
+            fs::write(
+                folder.join(plugin_signature::SIGNATURE_FILE),
+                signature.to_toml(),
+            )?;
+            println!("signed {}", folder.display());
+        }
+        Command::Plugin(PluginCommand::Pack { folder, output }) => {
+            let package = PluginPackage::new_from_folder(&PathBuf::from(folder))
+                .map_err(|e| format!("could not read plugin folder {:?}", e))?;
+            let output = output.unwrap_or_else(|| package.file_name());
+            let bytes = package
+                .to_bytes()
+                .map_err(|e| format!("could not build package {:?}", e))?;
+            fs::write(&output, bytes)?;
+            println!("package written to {}", output);
+        }
+        Command::Plugin(PluginCommand::Unpack { package, folder }) => {
+            PluginPackage::new_from_file(&PathBuf::from(package))
+                .and_then(|p| p.unpack(&PathBuf::from(&folder)))
+                .map_err(|e| format!("could not unpack package {:?}", e))?;
+            println!("package extracted to {}", folder);
+        }
+        Command::Plugin(PluginCommand::Inspect { package }) => {
+            let package = PluginPackage::new_from_file(&PathBuf::from(package))
+                .map_err(|e| format!("invalid package {:?}", e))?;
+            println!("name: {}", package.manifest.name);
+            println!("version: {}", package.manifest.version);
+            if let Some(ref description) = package.manifest.description {
+                println!("description: {}", description);
+            }
+            if let Some(ref authors) = package.manifest.authors {
+                println!("authors: {}", authors.join(", "));
+            }
+            println!("signed: {}", package.signature.is_some());
+            println!("wasm: {} bytes", package.wasm.len());
+            println!("config:\n{}", String::from_utf8_lossy(&package.config));
+            println!("files:");
+            for (path, content) in &package.files {
+                println!("  {} ({} bytes)", path, content.len());
+            }
+        }
+        Command::Cell(CellCommand::Parse { file, body_option }) => {
+            let text = read_input(file)?;
+            let cell = cell_parser::parse_cell(&text).map_err(|e| e.to_string())?;
+            println!("plugin: {}", cell.plugin);
+            let options = cell.into_options(&body_option).map_err(|e| e.to_string())?;
+            for (key, value) in options {
+                println!("{}: {}", key, value);
+            }
+        }
+        Command::Cell(CellCommand::Invoke { file, server, user }) => {
+            let text = read_input(file)?;
+            // parse locally first, so errors show up without a round trip
+            cell_parser::parse_cell(&text).map_err(|e| e.to_string())?;
+            let response = tokio::runtime::Runtime::new()?.block_on(async {
+                let mut client =
+                    DatabookClient::with_interceptor(connect(server).await?, with_token);
+                let request = InvokeCellRequest {
+                    text,
+                    user: user.unwrap_or_default(),
+                };
+                Ok::<_, Box<dyn std::error::Error>>(client.invoke_cell(request).await?.into_inner())
+            })?;
+            println!("{}", response.output);
+        }
+        Command::Notebook(NotebookCommand::Run {
+            notebook_id,
+            server,
+            user,
+            fail_fast,
+        }) => {
+            let request = RunNotebookRequest {
+                notebook_id,
+                user: user.unwrap_or_default(),
+                fail_fast,
+                runbook: None,
+            };
+            print_report(run_notebook(server, request)?);
+        }
+        Command::Notebook(NotebookCommand::Watch {
+            notebook_id,
+            cell,
+            every,
+            cron,
+            condition,
+            webhook,
+            user,
+            server,
+        }) => {
+            let request = CreateScheduleRequest {
+                notebook_id,
+                cell_id: cell.unwrap_or_default(),
+                every: every.unwrap_or_default(),
+                cron: cron.unwrap_or_default(),
+                condition: condition.unwrap_or_default(),
+                webhook: webhook.unwrap_or_default(),
+                user: user.unwrap_or_default(),
+            };
+            let schedule = tokio::runtime::Runtime::new()?.block_on(async {
+                let mut client =
+                    SchedulesClient::with_interceptor(connect(server).await?, with_token);
+                Ok::<_, Box<dyn std::error::Error>>(
+                    client.create_schedule(request).await?.into_inner(),
+                )
+            })?;
+            let next = Utc
+                .timestamp_millis_opt(schedule.next_run_at)
+                .single()
+                .unwrap_or_else(Utc::now);
+            println!(
+                "{} first run at {}",
+                schedule.id,
+                next.format("%Y-%m-%d %H:%M:%S")
+            );
+        }
+        Command::Notebook(NotebookCommand::Export {
+            notebook_id,
+            format,
+            template,
+            template_name,
+            output,
+            server,
+        }) => {
+            let request = ExportRequest {
+                notebook_id,
+                format,
+                template: match template {
+                    Some(path) => fs::read_to_string(path)?,
+                    None => String::new(),
+                },
+                template_name: template_name.unwrap_or_default(),
+            };
+            let response = tokio::runtime::Runtime::new()?.block_on(async {
+                let mut client =
+                    ExportsClient::with_interceptor(connect(server).await?, with_token);
+                Ok::<_, Box<dyn std::error::Error>>(client.export(request).await?.into_inner())
+            })?;
+            match output {
+                Some(path) => fs::write(path, response.content)?,
+                None => print!("{}", response.content),
+            }
+        }
+        Command::Notebook(NotebookCommand::Import {
+            file,
+            format,
+            owner,
+            title,
+            tags,
+            languages,
+            server,
+        }) => {
+            let format = format.or_else(|| match file.extension().and_then(|e| e.to_str()) {
+                Some("ipynb") => Some("jupyter".to_string()),
+                Some("md") | Some("markdown") => Some("markdown".to_string()),
+                _ => None,
+            });
+            let request = ImportNotebookRequest {
+                format: format.unwrap_or_default(),
+                content: fs::read_to_string(&file)?,
+                owner,
+                title: title.unwrap_or_default(),
+                tags,
+                languages: languages.into_iter().collect(),
+            };
+            let notebook = tokio::runtime::Runtime::new()?.block_on(async {
+                let mut client =
+                    ImportsClient::with_interceptor(connect(server).await?, with_token);
+                Ok::<_, Box<dyn std::error::Error>>(
+                    client.import_notebook(request).await?.into_inner(),
+                )
+            })?;
+            println!("{} ({} cells)", notebook.id, notebook.cells.len());
+        }
+        Command::Runbook(RunbookCommand::Run {
+            runbook_id,
+            version,
+            parameters,
+            owner,
+            server,
+            user,
+            fail_fast,
+        }) => {
+            let request = RunNotebookRequest {
+                notebook_id: String::new(),
+                user: user.unwrap_or_default(),
+                fail_fast,
+                runbook: Some(InstantiateRunbookRequest {
+                    id: runbook_id,
+                    version: version.unwrap_or(0),
+                    parameters: parameters.into_iter().collect(),
+                    owner,
+                    title: String::new(),
+                }),
+            };
+            print_report(run_notebook(server, request)?);
+        }
+        Command::Search {
+            text,
+            plugin,
+            tag,
+            severity,
+            notebook_id,
+            from,
+            to,
+            limit,
+            server,
+        } => {
+            let request = SearchRequest {
+                text: text.join(" "),
+                plugin: plugin.unwrap_or_default(),
+                tag: tag.unwrap_or_default(),
+                severity: severity.unwrap_or_default(),
+                notebook_id: notebook_id.unwrap_or_default(),
+                from: from.map(|t| t.timestamp_millis()).unwrap_or(0),
+                to: to.map(|t| t.timestamp_millis()).unwrap_or(0),
+                limit,
+            };
+            let response = tokio::runtime::Runtime::new()?.block_on(async {
+                let mut client = SearchClient::with_interceptor(connect(server).await?, with_token);
+                Ok::<_, Box<dyn std::error::Error>>(client.search(request).await?.into_inner())
+            })?;
+            for hit in response.hits {
+                let at = Utc
+                    .timestamp_millis_opt(hit.at)
+                    .single()
+                    .unwrap_or_else(Utc::now);
+                let notebook = if hit.notebook_id.is_empty() {
+                    "(no notebook)".to_string()
+                } else {
+                    format!("{} ({})", hit.notebook_title, hit.notebook_id)
+                };
+                let cell = if hit.cell_id.is_empty() {
+                    String::new()
+                } else {
+                    format!(" cell {}", hit.cell_id)
+                };
+                println!(
+                    "{} {} {}{}",
+                    at.format("%Y-%m-%d %H:%M"),
+                    notebook,
+                    hit.source,
+                    cell
+                );
+                println!("    {}", hit.snippet.replace('\n', " "));
+            }
+        }
+        Command::Audit(AuditCommand::Verify {
+            directory,
+            key_file,
+            head_file,
+        }) => match key_file
+            .map(|path| audit::read_key(&path))
+            .transpose()
+            .and_then(|key| audit::verify(&directory, key.as_deref(), head_file.as_deref()))
+        {
+            Ok(verification) => println!(
+                "ok: {} entries in {} files, starting at entry {}",
+                verification.entries, verification.files, verification.first_sequence
+            ),
+            Err(e) => {
+                println!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        Command::Audit(AuditCommand::Query {
+            user,
+            plugin,
+            action,
+            from,
+            to,
+            limit,
+            server,
+        }) => {
+            let request = AuditRequest {
+                user: user.unwrap_or_default(),
+                plugin: plugin.unwrap_or_default(),
+                action: action.unwrap_or_default(),
+                from: from.map(|t| t.timestamp_millis()).unwrap_or(0),
+                to: to.map(|t| t.timestamp_millis()).unwrap_or(0),
+                limit,
+            };
+            let response = tokio::runtime::Runtime::new()?.block_on(async {
+                let mut client =
+                    DatabookAdminClient::with_interceptor(connect(server).await?, with_token);
+                Ok::<_, Box<dyn std::error::Error>>(client.audit(request).await?.into_inner())
+            })?;
+            for entry in response.entries {
+                let at = Utc
+                    .timestamp_millis_opt(entry.started_at)
+                    .single()
+                    .unwrap_or_else(Utc::now);
+                let user = if entry.user.is_empty() {
+                    "anonymous"
+                } else {
+                    &entry.user
+                };
+                let source = if entry.source.is_empty() {
+                    "-"
+                } else {
+                    &entry.source
+                };
+                print!(
+                    "{} #{} {} from {} {} {} {} ({}ms)",
+                    at.format("%Y-%m-%d %H:%M:%S"),
+                    entry.sequence,
+                    user,
+                    source,
+                    entry.action,
+                    entry.plugin,
+                    entry.outcome,
+                    entry.duration_ms
+                );
+                if !entry.error.is_empty() {
+                    print!(": {}", entry.error);
+                }
+                if !entry.approvers.is_empty() {
+                    print!(", approved by {}", entry.approvers.join(", "));
+                }
+                println!();
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,794 @@
+use crate::notebook::{Cell, CellContent};
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+// Notebook content as a CRDT, so responders editing the same notebook (or
+// coming back online with local edits) never lose each other's changes.
+// The cell list and the text of every cell are RGA sequences, plugin
+// options and the kind of a cell are last-writer-wins registers.
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CrdtError {
+    // the operation refers to something not applied yet
+    MissingDependency(String),
+    InvalidOperation(String),
+    // the counters of the operation go past MAX_COUNTER
+    CounterOverflow(String),
+}
+
+// counters stay below 2^53 so browsers read them exactly, operations with
+// greater ones are refused before anything is applied
+pub const MAX_COUNTER: u64 = (1 << 53) - 1;
+
+// OpId is a lamport timestamp made unique by the site (e.g. a browser tab)
+// that created it. Ids are ordered by counter, then by site.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct OpId {
+    pub counter: u64,
+    pub site: String,
+}
+
+impl OpId {
+    pub fn new(counter: u64, site: &str) -> Self {
+        Self {
+            counter,
+            site: site.to_string(),
+        }
+    }
+
+    // the id of the nth character of a text insertion
+    fn offset(&self, n: usize) -> Result<Self, CrdtError> {
+        let counter = self
+            .counter
+            .checked_add(n as u64)
+            .filter(|counter| *counter <= MAX_COUNTER)
+            .ok_or_else(|| {
+                CrdtError::CounterOverflow(format!("{}+{}@{}", self.counter, n, self.site))
+            })?;
+        Ok(Self {
+            counter,
+            site: self.site.clone(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum CellKind {
+    Markdown,
+    Plugin { plugin: String },
+}
+
+impl CellKind {
+    fn of(content: &CellContent) -> Self {
+        match content {
+            CellContent::Markdown { .. } => CellKind::Markdown,
+            CellContent::Plugin { plugin, .. } => CellKind::Plugin {
+                plugin: plugin.clone(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Operation {
+    // places the cell after `after` (None is the top), if the cell exists
+    // this is a move and the old position must be deleted
+    InsertCell {
+        id: OpId,
+        after: Option<OpId>,
+        cell_id: String,
+        kind: CellKind,
+    },
+    // removes a position of the cell list, the id of an InsertCell
+    DeleteCell {
+        target: OpId,
+    },
+    // the nth character gets the id `id.counter + n`
+    InsertText {
+        cell_id: String,
+        id: OpId,
+        after: Option<OpId>,
+        text: String,
+    },
+    DeleteText {
+        cell_id: String,
+        targets: Vec<OpId>,
+    },
+    // a None value removes the option
+    SetOption {
+        cell_id: String,
+        id: OpId,
+        key: String,
+        value: Option<String>,
+    },
+}
+
+// SequencedOperation is an operation as stored with the notebook, seq is
+// its position on the notebook history
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SequencedOperation {
+    pub seq: u64,
+    pub operation: Operation,
+}
+
+#[derive(Debug, Clone)]
+struct Element<T> {
+    id: OpId,
+    value: T,
+    deleted: bool,
+}
+
+// Rga is a replicated sequence, deleted elements stay as tombstones so
+// concurrent inserts next to them still find their place
+#[derive(Debug, Clone)]
+struct Rga<T> {
+    elements: Vec<Element<T>>,
+}
+
+impl<T> Default for Rga<T> {
+    fn default() -> Self {
+        Self { elements: vec![] }
+    }
+}
+
+impl<T> Rga<T> {
+    fn position(&self, id: &OpId) -> Option<usize> {
+        self.elements.iter().position(|e| &e.id == id)
+    }
+
+    fn check(&self, id: &OpId) -> Result<usize, CrdtError> {
+        self.position(id)
+            .ok_or_else(|| CrdtError::MissingDependency(format!("{}@{}", id.counter, id.site)))
+    }
+
+    // insert places the element right after `after`, concurrent inserts at
+    // the same place end up ordered by id, the greatest first
+    fn insert(&mut self, id: OpId, after: Option<&OpId>, value: T) -> Result<bool, CrdtError> {
+        if self.position(&id).is_some() {
+            return Ok(false);
+        }
+        let mut index = match after {
+            Some(after) => self.check(after)? + 1,
+            None => 0,
+        };
+        while index < self.elements.len() && self.elements[index].id > id {
+            index += 1;
+        }
+        self.elements.insert(
+            index,
+            Element {
+                id,
+                value,
+                deleted: false,
+            },
+        );
+        Ok(true)
+    }
+
+    fn delete(&mut self, id: &OpId) -> Result<bool, CrdtError> {
+        let index = self.check(id)?;
+        let element = &mut self.elements[index];
+        let changed = !element.deleted;
+        element.deleted = true;
+        Ok(changed)
+    }
+
+    fn visible(&self) -> impl Iterator<Item = &Element<T>> {
+        self.elements.iter().filter(|e| !e.deleted)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct CellState {
+    kind: CellKind,
+    // the insert that set the kind, the latest one wins
+    kind_id: OpId,
+    text: Rga<char>,
+    options: BTreeMap<String, (OpId, Option<String>)>,
+}
+
+impl CellState {
+    fn content(&self) -> CellContent {
+        match &self.kind {
+            CellKind::Markdown => CellContent::Markdown {
+                source: self.text.visible().map(|e| e.value).collect(),
+            },
+            CellKind::Plugin { plugin } => CellContent::Plugin {
+                plugin: plugin.clone(),
+                options: self
+                    .options
+                    .iter()
+                    .filter_map(|(key, (_, value))| Some((key.clone(), value.clone()?)))
+                    .collect(),
+            },
+        }
+    }
+}
+
+// NotebookDoc is the replicated content of a notebook, the result of
+// applying its operations in any causal order
+#[derive(Debug, Clone, Default)]
+pub struct NotebookDoc {
+    // the greatest counter seen, new operations must use a greater one
+    clock: u64,
+    order: Rga<String>,
+    cells: HashMap<String, CellState>,
+}
+
+impl NotebookDoc {
+    fn observe(&mut self, last: &OpId) {
+        self.clock = self.clock.max(last.counter);
+    }
+
+    fn cell_mut(&mut self, cell_id: &str) -> Result<&mut CellState, CrdtError> {
+        self.cells
+            .get_mut(cell_id)
+            .ok_or_else(|| CrdtError::MissingDependency(format!("cell {}", cell_id)))
+    }
+
+    // apply integrates the operation, it returns false if the operation was
+    // already applied (e.g. resent by a reconnecting client)
+    pub fn apply(&mut self, operation: &Operation) -> Result<bool, CrdtError> {
+        match operation {
+            Operation::InsertCell {
+                id,
+                after,
+                cell_id,
+                kind,
+            } => {
+                let last = id.offset(0)?;
+                if !self
+                    .order
+                    .insert(id.clone(), after.as_ref(), cell_id.clone())?
+                {
+                    return Ok(false);
+                }
+                self.observe(&last);
+                match self.cells.get_mut(cell_id) {
+                    Some(state) => {
+                        if *id > state.kind_id {
+                            state.kind = kind.clone();
+                            state.kind_id = id.clone();
+                        }
+                    }
+                    None => {
+                        self.cells.insert(
+                            cell_id.clone(),
+                            CellState {
+                                kind: kind.clone(),
+                                kind_id: id.clone(),
+                                text: Rga::default(),
+                                options: BTreeMap::new(),
+                            },
+                        );
+                    }
+                }
+                Ok(true)
+            }
+            Operation::DeleteCell { target } => self.order.delete(target),
+            Operation::InsertText {
+                cell_id,
+                id,
+                after,
+                text,
+            } => {
+                let length = text.chars().count();
+                if length == 0 {
+                    return Err(CrdtError::InvalidOperation("empty text insertion".into()));
+                }
+                let last = id.offset(length - 1)?;
+                let cell = self.cell_mut(cell_id)?;
+                if let Some(after) = after {
+                    cell.text.check(after)?;
+                }
+                let mut previous = after.clone();
+                let mut inserted = false;
+                for (n, character) in text.chars().enumerate() {
+                    let character_id = id.offset(n)?;
+                    inserted |=
+                        cell.text
+                            .insert(character_id.clone(), previous.as_ref(), character)?;
+                    previous = Some(character_id);
+                }
+                self.observe(&last);
+                Ok(inserted)
+            }
+            Operation::DeleteText { cell_id, targets } => {
+                let cell = self.cell_mut(cell_id)?;
+                for target in targets {
+                    cell.text.check(target)?;
+                }
+                let mut deleted = false;
+                for target in targets {
+                    deleted |= cell.text.delete(target)?;
+                }
+                Ok(deleted)
+            }
+            Operation::SetOption {
+                cell_id,
+                id,
+                key,
+                value,
+            } => {
+                let last = id.offset(0)?;
+                let cell = self.cell_mut(cell_id)?;
+                let newer = match cell.options.get(key) {
+                    Some((current, _)) => id > current,
+                    None => true,
+                };
+                if newer {
+                    cell.options
+                        .insert(key.clone(), (id.clone(), value.clone()));
+                }
+                self.observe(&last);
+                Ok(newer)
+            }
+        }
+    }
+
+    // positions lists the visible cells with the id of their position. A
+    // cell moved concurrently by two people stays at the latest move.
+    fn positions(&self) -> Vec<(OpId, String)> {
+        let mut latest: HashMap<&String, &OpId> = HashMap::new();
+        for element in self.order.visible() {
+            let id = latest.entry(&element.value).or_insert(&element.id);
+            if element.id > **id {
+                *id = &element.id;
+            }
+        }
+        self.order
+            .visible()
+            .filter(|e| latest.get(&e.value) == Some(&&e.id))
+            .map(|e| (e.id.clone(), e.value.clone()))
+            .collect()
+    }
+
+    pub fn cells(&self) -> Vec<Cell> {
+        self.positions()
+            .into_iter()
+            .filter_map(|(_, cell_id)| {
+                let content = self.cells.get(&cell_id)?.content();
+                Some(Cell {
+                    id: cell_id,
+                    content,
+                })
+            })
+            .collect()
+    }
+
+    // diff returns the operations, stamped by `site`, turning the document
+    // into `cells`. It is used for clients saving the whole notebook, so
+    // their changes still merge with concurrent edits. Cells must have ids.
+    // It fails once the counters of the document are used up.
+    pub fn diff(&self, cells: &[Cell], site: &str) -> Result<Vec<Operation>, CrdtError> {
+        let mut doc = self.clone();
+        let mut operations = vec![];
+
+        let wanted: HashSet<&str> = cells.iter().map(|c| c.id.as_str()).collect();
+        let removed: Vec<OpId> = doc
+            .order
+            .visible()
+            .filter(|e| !wanted.contains(e.value.as_str()))
+            .map(|e| e.id.clone())
+            .collect();
+        for target in removed {
+            doc.push(&mut operations, Operation::DeleteCell { target })?;
+        }
+
+        // cells keeping their kind and relative order are left in place
+        let positions = doc.positions();
+        let index_of: HashMap<&str, usize> = positions
+            .iter()
+            .enumerate()
+            .map(|(i, (_, cell_id))| (cell_id.as_str(), i))
+            .collect();
+        let current: Vec<Option<usize>> = cells
+            .iter()
+            .map(|cell| {
+                let index = *index_of.get(cell.id.as_str())?;
+                let same_kind = doc.cells[&cell.id].kind == CellKind::of(&cell.content);
+                same_kind.then_some(index)
+            })
+            .collect();
+        let in_place = longest_increasing(&current);
+
+        let mut previous: Option<OpId> = None;
+        for (i, cell) in cells.iter().enumerate() {
+            if in_place.contains(&i) {
+                previous = current[i].map(|index| positions[index].0.clone());
+            } else {
+                let id = doc.next_id(site)?;
+                doc.push(
+                    &mut operations,
+                    Operation::InsertCell {
+                        id: id.clone(),
+                        after: previous.clone(),
+                        cell_id: cell.id.clone(),
+                        kind: CellKind::of(&cell.content),
+                    },
+                )?;
+                if let Some(&index) = index_of.get(cell.id.as_str()) {
+                    doc.push(
+                        &mut operations,
+                        Operation::DeleteCell {
+                            target: positions[index].0.clone(),
+                        },
+                    )?;
+                }
+                previous = Some(id);
+            }
+            match &cell.content {
+                CellContent::Markdown { source } => {
+                    doc.diff_text(&mut operations, &cell.id, source, site)?
+                }
+                CellContent::Plugin { options, .. } => {
+                    doc.diff_options(&mut operations, &cell.id, options, site)?
+                }
+            }
+        }
+        Ok(operations)
+    }
+
+    fn next_id(&self, site: &str) -> Result<OpId, CrdtError> {
+        OpId::new(self.clock, site).offset(1)
+    }
+
+    // push applies an operation built from the document, only counter
+    // overflows can make it fail
+    fn push(
+        &mut self,
+        operations: &mut Vec<Operation>,
+        operation: Operation,
+    ) -> Result<(), CrdtError> {
+        self.apply(&operation)?;
+        operations.push(operation);
+        Ok(())
+    }
+
+    // diff_text keeps the common prefix and suffix, the middle is replaced
+    fn diff_text(
+        &mut self,
+        operations: &mut Vec<Operation>,
+        cell_id: &str,
+        source: &str,
+        site: &str,
+    ) -> Result<(), CrdtError> {
+        let current: Vec<(OpId, char)> = self.cells[cell_id]
+            .text
+            .visible()
+            .map(|e| (e.id.clone(), e.value))
+            .collect();
+        let wanted: Vec<char> = source.chars().collect();
+        let prefix = current
+            .iter()
+            .zip(&wanted)
+            .take_while(|((_, a), b)| a == *b)
+            .count();
+        let suffix = current[prefix..]
+            .iter()
+            .rev()
+            .zip(wanted[prefix..].iter().rev())
+            .take_while(|((_, a), b)| a == *b)
+            .count();
+
+        let targets: Vec<OpId> = current[prefix..current.len() - suffix]
+            .iter()
+            .map(|(id, _)| id.clone())
+            .collect();
+        if !targets.is_empty() {
+            self.push(
+                operations,
+                Operation::DeleteText {
+                    cell_id: cell_id.to_string(),
+                    targets,
+                },
+            )?;
+        }
+        let text: String = wanted[prefix..wanted.len() - suffix].iter().collect();
+        if !text.is_empty() {
+            let id = self.next_id(site)?;
+            self.push(
+                operations,
+                Operation::InsertText {
+                    cell_id: cell_id.to_string(),
+                    id,
+                    after: prefix.checked_sub(1).map(|i| current[i].0.clone()),
+                    text,
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    fn diff_options(
+        &mut self,
+        operations: &mut Vec<Operation>,
+        cell_id: &str,
+        options: &BTreeMap<String, String>,
+        site: &str,
+    ) -> Result<(), CrdtError> {
+        let current: BTreeMap<String, String> = self.cells[cell_id]
+            .options
+            .iter()
+            .filter_map(|(key, (_, value))| Some((key.clone(), value.clone()?)))
+            .collect();
+        let keys: BTreeSet<&String> = current.keys().chain(options.keys()).collect();
+        for key in keys {
+            if current.get(key) != options.get(key) {
+                let id = self.next_id(site)?;
+                self.push(
+                    operations,
+                    Operation::SetOption {
+                        cell_id: cell_id.to_string(),
+                        id,
+                        key: key.clone(),
+                        value: options.get(key).cloned(),
+                    },
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// longest_increasing returns the indexes of the longest strictly increasing
+// subsequence of the present values
+fn longest_increasing(values: &[Option<usize>]) -> HashSet<usize> {
+    let mut length = vec![0usize; values.len()];
+    let mut previous: Vec<Option<usize>> = vec![None; values.len()];
+    let mut best: Option<usize> = None;
+    for i in 0..values.len() {
+        let value = match values[i] {
+            Some(value) => value,
+            None => continue,
+        };
+        length[i] = 1;
+        for j in 0..i {
+            if matches!(values[j], Some(v) if v < value) && length[j] + 1 > length[i] {
+                length[i] = length[j] + 1;
+                previous[i] = Some(j);
+            }
+        }
+        match best {
+            Some(b) if length[b] >= length[i] => {}
+            _ => best = Some(i),
+        }
+    }
+    let mut indexes = HashSet::new();
+    while let Some(i) = best {
+        indexes.insert(i);
+        best = previous[i];
+    }
+    indexes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn markdown(id: &str, source: &str) -> Cell {
+        Cell {
+            id: id.to_string(),
+            content: CellContent::Markdown {
+                source: source.to_string(),
+            },
+        }
+    }
+
+    fn doc_with(cells: &[Cell]) -> (NotebookDoc, Vec<Operation>) {
+        let mut doc = NotebookDoc::default();
+        let operations = doc.diff(cells, "server").unwrap();
+        for operation in &operations {
+            doc.apply(operation).unwrap();
+        }
+        (doc, operations)
+    }
+
+    fn apply_all(doc: &mut NotebookDoc, operations: &[Operation]) {
+        for operation in operations {
+            doc.apply(operation).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_diff_rebuilds_cells() {
+        let cells = vec![
+            markdown("a", "# Incident"),
+            Cell {
+                id: "b".into(),
+                content: CellContent::Plugin {
+                    plugin: "prometheus".into(),
+                    options: BTreeMap::from([("query".to_string(), "up".to_string())]),
+                },
+            },
+        ];
+        let (doc, _) = doc_with(&cells);
+        assert_eq!(cells, doc.cells());
+        assert!(doc.diff(&cells, "server").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_concurrent_edits_of_the_same_notebook_merge() {
+        let (base, _) = doc_with(&[markdown("a", "errors"), markdown("b", "notes")]);
+
+        let alice = base
+            .diff(
+                &[markdown("a", "5xx errors"), markdown("b", "notes")],
+                "alice",
+            )
+            .unwrap();
+        let bob = base
+            .diff(
+                &[
+                    markdown("a", "errors"),
+                    markdown("b", "notes: restarted db"),
+                ],
+                "bob",
+            )
+            .unwrap();
+
+        let mut first = base.clone();
+        apply_all(&mut first, &alice);
+        apply_all(&mut first, &bob);
+        let mut second = base.clone();
+        apply_all(&mut second, &bob);
+        apply_all(&mut second, &alice);
+
+        let expected = vec![
+            markdown("a", "5xx errors"),
+            markdown("b", "notes: restarted db"),
+        ];
+        assert_eq!(expected, first.cells());
+        assert_eq!(expected, second.cells());
+    }
+
+    #[test]
+    fn test_concurrent_inserts_at_the_same_place_converge() {
+        let (base, _) = doc_with(&[markdown("a", "db down")]);
+        let alice = base
+            .diff(&[markdown("a", "db down (eu)")], "alice")
+            .unwrap();
+        let bob = base.diff(&[markdown("a", "db down (us)")], "bob").unwrap();
+
+        let mut first = base.clone();
+        apply_all(&mut first, &alice);
+        apply_all(&mut first, &bob);
+        let mut second = base.clone();
+        apply_all(&mut second, &bob);
+        apply_all(&mut second, &alice);
+
+        assert_eq!(first.cells(), second.cells());
+        assert_eq!(vec![markdown("a", "db down (us) (eu)")], first.cells());
+    }
+
+    #[test]
+    fn test_offline_edits_merge_on_reconnect() {
+        let (base, _) = doc_with(&[markdown("a", "timeline")]);
+
+        // the offline client keeps editing its own copy
+        let mut offline = base.clone();
+        let mut pending = vec![];
+        for cells in [
+            vec![markdown("a", "timeline"), markdown("b", "10:00 paged")],
+            vec![
+                markdown("a", "timeline"),
+                markdown("b", "10:00 paged\n10:05 ack"),
+            ],
+        ] {
+            let operations = offline.diff(&cells, "laptop").unwrap();
+            apply_all(&mut offline, &operations);
+            pending.extend(operations);
+        }
+
+        // meanwhile the server got edits and a removal from someone else
+        let mut server = base.clone();
+        let online = server
+            .diff(&[markdown("a", "timeline of the outage")], "alice")
+            .unwrap();
+        apply_all(&mut server, &online);
+
+        apply_all(&mut server, &pending);
+        // resending after a flaky reconnect changes nothing
+        for operation in &pending {
+            assert!(!server.apply(operation).unwrap());
+        }
+        apply_all(&mut offline, &online);
+
+        let expected = vec![
+            markdown("a", "timeline of the outage"),
+            markdown("b", "10:00 paged\n10:05 ack"),
+        ];
+        assert_eq!(expected, server.cells());
+        assert_eq!(expected, offline.cells());
+    }
+
+    #[test]
+    fn test_move_keeps_concurrent_text_edits() {
+        let (base, _) = doc_with(&[markdown("a", "first"), markdown("b", "second")]);
+        let moved = base
+            .diff(&[markdown("b", "second"), markdown("a", "first")], "alice")
+            .unwrap();
+        let edited = base
+            .diff(&[markdown("a", "first!"), markdown("b", "second")], "bob")
+            .unwrap();
+
+        let mut doc = base.clone();
+        apply_all(&mut doc, &edited);
+        apply_all(&mut doc, &moved);
+        assert_eq!(
+            vec![markdown("b", "second"), markdown("a", "first!")],
+            doc.cells()
+        );
+    }
+
+    #[test]
+    fn test_missing_dependency() {
+        let mut doc = NotebookDoc::default();
+        let operation = Operation::InsertText {
+            cell_id: "a".into(),
+            id: OpId::new(1, "alice"),
+            after: None,
+            text: "hi".into(),
+        };
+        assert!(matches!(
+            doc.apply(&operation),
+            Err(CrdtError::MissingDependency(_))
+        ));
+    }
+
+    #[test]
+    fn test_counter_overflow() {
+        let (mut doc, _) = doc_with(&[markdown("a", "abc")]);
+        for operation in [
+            Operation::InsertText {
+                cell_id: "a".into(),
+                id: OpId::new(u64::MAX, "mallory"),
+                after: None,
+                text: "xy".into(),
+            },
+            Operation::InsertText {
+                cell_id: "a".into(),
+                id: OpId::new(MAX_COUNTER, "mallory"),
+                after: None,
+                text: "xy".into(),
+            },
+            Operation::SetOption {
+                cell_id: "a".into(),
+                id: OpId::new(MAX_COUNTER + 1, "mallory"),
+                key: "query".into(),
+                value: None,
+            },
+        ] {
+            assert!(matches!(
+                doc.apply(&operation),
+                Err(CrdtError::CounterOverflow(_))
+            ));
+        }
+        assert_eq!(vec![markdown("a", "abc")], doc.cells());
+        assert_eq!(4, doc.clock);
+
+        // the last counter can still be used, nothing comes after it
+        let last = Operation::InsertText {
+            cell_id: "a".into(),
+            id: OpId::new(MAX_COUNTER, "mallory"),
+            after: None,
+            text: "x".into(),
+        };
+        assert!(doc.apply(&last).unwrap());
+        assert!(matches!(
+            doc.diff(&[markdown("a", "abcd")], "server"),
+            Err(CrdtError::CounterOverflow(_))
+        ));
+    }
+
+    #[test]
+    fn test_clock_follows_operations() {
+        let (doc, operations) = doc_with(&[markdown("a", "abc")]);
+        // one insert for the cell, three counters for the characters
+        assert_eq!(2, operations.len());
+        assert_eq!(4, doc.clock);
+    }
+}
@@ -0,0 +1,742 @@
+use crate::notebook::{CellContent, Notebook};
+use crate::template::{escape_html, Escape, Template, TemplateError};
+use crate::timeline::{EventKind, ExecutionEvent};
+
+use chrono::{DateTime, Duration, Utc};
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, BTreeSet};
+
+// Exports render a notebook and its timeline as a postmortem document,
+// in Markdown or as a single html file with no external resources. The
+// layout comes from a template (see template.rs) rendered with:
+//
+//   notebook  id, title, owner, tags, variables, created_at, updated_at
+//   summary   started_at, finished_at, duration, executions, failures,
+//             responders, the runbook the notebook comes from and the
+//             incident: severity, status, commander, time_to_identify,
+//             time_to_mitigate, time_to_resolve
+//   cells     every cell with its runs: user, times, inputs, error and
+//             the output, already rendered for the format (use {{{output}}})
+//   timeline  every event in order: time, user, cell_id, plugin, status,
+//             incident changes have the change as status
+//
+// Outputs that are a json list of objects render as a table, prometheus
+// style series (`values` as [timestamp, value] pairs) as a chart.
+
+const MAX_ROWS: usize = 100;
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S UTC";
+
+// the default templates, a starting point for custom ones
+pub const MARKDOWN_TEMPLATE: &str = include_str!("../templates/export.md");
+pub const HTML_TEMPLATE: &str = include_str!("../templates/export.html");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Markdown,
+    Html,
+}
+
+impl Format {
+    pub fn parse(format: &str) -> Option<Self> {
+        match format.to_lowercase().as_str() {
+            "markdown" | "md" => Some(Format::Markdown),
+            "html" => Some(Format::Html),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Markdown => "md",
+            Format::Html => "html",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Markdown => "text/markdown; charset=utf-8",
+            Format::Html => "text/html; charset=utf-8",
+        }
+    }
+
+    fn default_template(&self) -> &'static str {
+        match self {
+            Format::Markdown => MARKDOWN_TEMPLATE,
+            Format::Html => HTML_TEMPLATE,
+        }
+    }
+}
+
+// export renders the notebook with the events of its timeline, `template`
+// replaces the default template of the format
+pub fn export(
+    notebook: &Notebook,
+    events: &[ExecutionEvent],
+    format: Format,
+    template: Option<&str>,
+) -> Result<String, TemplateError> {
+    let template = Template::parse(template.unwrap_or_else(|| format.default_template()))?;
+    let escape = match format {
+        Format::Markdown => Escape::None,
+        Format::Html => Escape::Html,
+    };
+    Ok(template.render(&context(notebook, events, format, Utc::now()), escape))
+}
+
+fn time(time: &DateTime<Utc>) -> String {
+    time.format(TIME_FORMAT).to_string()
+}
+
+fn duration(duration: Duration) -> String {
+    let seconds = duration.num_seconds();
+    if seconds < 1 {
+        format!("{}ms", duration.num_milliseconds().max(0))
+    } else if seconds < 60 {
+        format!("{}s", seconds)
+    } else if seconds < 3600 {
+        format!("{}m {}s", seconds / 60, seconds % 60)
+    } else {
+        format!("{}h {}m", seconds / 3600, seconds % 3600 / 60)
+    }
+}
+
+fn pairs(map: &BTreeMap<String, String>) -> Value {
+    map.iter()
+        .map(|(name, value)| json!({"name": name, "value": value}))
+        .collect()
+}
+
+fn context(
+    notebook: &Notebook,
+    events: &[ExecutionEvent],
+    format: Format,
+    now: DateTime<Utc>,
+) -> Value {
+    let first = events.iter().map(|e| e.started_at).min();
+    let last = events.iter().map(|e| e.finished_at).max();
+    let responders: BTreeSet<&str> = events.iter().filter_map(|e| e.user.as_deref()).collect();
+    let executions: Vec<&ExecutionEvent> = events
+        .iter()
+        .filter(|e| e.kind == EventKind::Execution)
+        .collect();
+
+    let cells: Vec<Value> = notebook
+        .cells
+        .iter()
+        .map(|cell| match &cell.content {
+            CellContent::Markdown { source } => json!({
+                "id": cell.id,
+                "markdown": true,
+                "source": match format {
+                    Format::Markdown => source.clone(),
+                    Format::Html => markdown_to_html(source),
+                },
+            }),
+            CellContent::Plugin { plugin, options } => {
+                let runs: Vec<Value> = events
+                    .iter()
+                    .filter(|e| e.cell_id.as_deref() == Some(cell.id.as_str()))
+                    .map(|e| run(e, format))
+                    .collect();
+                json!({
+                    "id": cell.id,
+                    "plugin": plugin,
+                    "options": pairs(options),
+                    "runs": runs,
+                })
+            }
+        })
+        .collect();
+
+    let timeline: Vec<Value> = events
+        .iter()
+        .map(|e| {
+            json!({
+                "time": time(&e.started_at),
+                "user": e.user.as_deref().unwrap_or("unknown"),
+                "cell_id": e.cell_id.as_deref().unwrap_or(""),
+                "plugin": e.plugin,
+                "status": match e.kind {
+                    EventKind::Incident => e.output.as_deref().unwrap_or("incident"),
+                    EventKind::Execution if e.error.is_some() => "failed",
+                    EventKind::Execution => "ok",
+                },
+            })
+        })
+        .collect();
+
+    json!({
+        "notebook": {
+            "id": notebook.id,
+            "title": notebook.metadata.title,
+            "owner": notebook.metadata.owner,
+            "tags": notebook.metadata.tags,
+            "variables": pairs(&notebook.metadata.variables),
+            "created_at": time(&notebook.metadata.created_at),
+            "updated_at": time(&notebook.metadata.updated_at),
+        },
+        "summary": {
+            "started_at": first.map(|t| time(&t)).unwrap_or_else(|| time(&notebook.metadata.created_at)),
+            "finished_at": last.map(|t| time(&t)).unwrap_or_else(|| time(&notebook.metadata.updated_at)),
+            "duration": match (first, last) {
+                (Some(first), Some(last)) => duration(last - first),
+                _ => "-".to_string(),
+            },
+            "executions": executions.len(),
+            "failures": executions.iter().filter(|e| e.error.is_some()).count(),
+            "responders": responders,
+            "runbook": notebook.metadata.runbook.as_ref().map(|r| json!({
+                "id": r.id,
+                "version": r.version,
+                "parameters": pairs(&r.parameters),
+            })),
+            "incident": notebook.metadata.incident.as_ref().map(|incident| {
+                let metrics = incident.metrics();
+                let since = |seconds: Option<i64>| {
+                    seconds.map(|s| duration(Duration::seconds(s))).unwrap_or_else(|| "-".to_string())
+                };
+                json!({
+                    "severity": incident.severity.to_string(),
+                    "status": incident.status.to_string(),
+                    "commander": incident.commander,
+                    "declared_at": time(&incident.declared_at),
+                    "time_to_identify": since(metrics.time_to_identify),
+                    "time_to_mitigate": since(metrics.time_to_mitigate),
+                    "time_to_resolve": since(metrics.time_to_resolve),
+                })
+            }),
+        },
+        "cells": cells,
+        "timeline": timeline,
+        "generated_at": time(&now),
+    })
+}
+
+fn run(event: &ExecutionEvent, format: Format) -> Value {
+    json!({
+        "event_id": event.id,
+        "user": event.user.as_deref().unwrap_or("unknown"),
+        "started_at": time(&event.started_at),
+        "finished_at": time(&event.finished_at),
+        "duration": duration(event.finished_at - event.started_at),
+        "plugin_version": event.plugin_version,
+        "options": pairs(&event.options),
+        "inputs": pairs(&event.inputs),
+        "error": event.error,
+        "output": event.output.as_deref().map(|o| render_output(o, format)).unwrap_or_default(),
+    })
+}
+
+// render_output turns the output of a run into a table, a chart or a code
+// block depending on what it looks like
+fn render_output(output: &str, format: Format) -> String {
+    let json = serde_json::from_str::<Value>(output).ok();
+    if let Some(json) = &json {
+        if let Some(series) = time_series(json) {
+            return match format {
+                Format::Markdown => series_markdown(&series),
+                Format::Html => series_html(&series),
+            };
+        }
+        if let Some(rows) = table_rows(json) {
+            return match format {
+                Format::Markdown => table_markdown(&rows),
+                Format::Html => table_html(&rows),
+            };
+        }
+    }
+    let text = match &json {
+        Some(json @ (Value::Object(_) | Value::Array(_))) => {
+            serde_json::to_string_pretty(json).unwrap_or_else(|_| output.to_string())
+        }
+        _ => output.to_string(),
+    };
+    match format {
+        Format::Markdown => code_block(&text),
+        Format::Html => format!("<pre>{}</pre>", escape_html(&text)),
+    }
+}
+
+fn code_block(text: &str) -> String {
+    // the fence has to be longer than any run of backticks in the text
+    let mut longest = 0;
+    let mut current = 0;
+    for c in text.chars() {
+        current = if c == '`' { current + 1 } else { 0 };
+        longest = longest.max(current);
+    }
+    let fence = "`".repeat(longest.max(2) + 1);
+    format!("{}\n{}\n{}", fence, text.trim_end_matches('\n'), fence)
+}
+
+fn cell_text(value: Option<&Value>) -> String {
+    match value {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(s)) => s.clone(),
+        Some(value) => value.to_string(),
+    }
+}
+
+struct Rows<'a> {
+    columns: Vec<&'a str>,
+    rows: Vec<&'a Map<String, Value>>,
+    total: usize,
+}
+
+// table_rows reads a json list of objects, the columns are the keys in the
+// order they first appear
+fn table_rows(json: &Value) -> Option<Rows<'_>> {
+    let items = json.as_array().filter(|items| !items.is_empty())?;
+    let rows: Vec<&Map<String, Value>> =
+        items.iter().map(Value::as_object).collect::<Option<_>>()?;
+    let mut columns: Vec<&str> = vec![];
+    for row in &rows {
+        for key in row.keys() {
+            if !columns.contains(&key.as_str()) {
+                columns.push(key);
+            }
+        }
+    }
+    Some(Rows {
+        columns,
+        total: rows.len(),
+        rows: rows.into_iter().take(MAX_ROWS).collect(),
+    })
+}
+
+fn table_markdown(rows: &Rows) -> String {
+    let escape = |text: String| text.replace('|', "\\|").replace('\n', " ");
+    let mut table = format!(
+        "| {} |\n|{}\n",
+        rows.columns.join(" | "),
+        "---|".repeat(rows.columns.len())
+    );
+    for row in &rows.rows {
+        let cells: Vec<String> = rows
+            .columns
+            .iter()
+            .map(|c| escape(cell_text(row.get(*c))))
+            .collect();
+        table.push_str(&format!("| {} |\n", cells.join(" | ")));
+    }
+    if rows.total > rows.rows.len() {
+        table.push_str(&format!("\n_{} more rows_\n", rows.total - rows.rows.len()));
+    }
+    table
+}
+
+fn table_html(rows: &Rows) -> String {
+    let mut table = String::from("<table>\n<tr>");
+    for column in &rows.columns {
+        table.push_str(&format!("<th>{}</th>", escape_html(column)));
+    }
+    table.push_str("</tr>\n");
+    for row in &rows.rows {
+        table.push_str("<tr>");
+        for column in &rows.columns {
+            table.push_str(&format!(
+                "<td>{}</td>",
+                escape_html(&cell_text(row.get(*column)))
+            ));
+        }
+        table.push_str("</tr>\n");
+    }
+    table.push_str("</table>");
+    if rows.total > rows.rows.len() {
+        table.push_str(&format!(
+            "\n<p class=\"meta\">{} more rows</p>",
+            rows.total - rows.rows.len()
+        ));
+    }
+    table
+}
+
+struct Series {
+    label: String,
+    // timestamp in seconds and value
+    points: Vec<(f64, f64)>,
+}
+
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+// time_series reads `{"data": {"result": [...]}}` as returned by prometheus
+// range queries, or directly a list of `{"metric": {...}, "values": [...]}`
+fn time_series(json: &Value) -> Option<Vec<Series>> {
+    let result = json
+        .pointer("/data/result")
+        .unwrap_or(json)
+        .as_array()
+        .filter(|r| !r.is_empty())?;
+    result
+        .iter()
+        .map(|item| {
+            let points = item
+                .get("values")?
+                .as_array()?
+                .iter()
+                .map(|point| match point.as_array()?.as_slice() {
+                    [t, v] => Some((number(t)?, number(v)?)),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>()?;
+            let label = match item.get("metric").and_then(Value::as_object) {
+                Some(metric) if !metric.is_empty() => {
+                    let labels: Vec<String> = metric
+                        .iter()
+                        .map(|(k, v)| format!("{}={}", k, cell_text(Some(v))))
+                        .collect();
+                    format!("{{{}}}", labels.join(", "))
+                }
+                _ => "series".to_string(),
+            };
+            Some(Series { label, points })
+        })
+        .collect()
+}
+
+fn bounds(values: impl Iterator<Item = f64>) -> Option<(f64, f64)> {
+    values.fold(None, |bounds, v| match bounds {
+        None => Some((v, v)),
+        Some((min, max)) => Some((min.min(v), max.max(v))),
+    })
+}
+
+fn series_markdown(series: &[Series]) -> String {
+    const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+    let mut text = String::new();
+    for s in series {
+        let (min, max) = match bounds(s.points.iter().map(|p| p.1)) {
+            Some(bounds) => bounds,
+            None => continue,
+        };
+        let sparkline: String = s
+            .points
+            .iter()
+            .map(|(_, v)| {
+                let level = if max > min {
+                    (v - min) / (max - min) * 7.0
+                } else {
+                    0.0
+                };
+                BARS[level.round() as usize]
+            })
+            .collect();
+        let last = s.points.last().map(|p| p.1).unwrap_or_default();
+        text.push_str(&format!(
+            "- `{}` {} min {} max {} last {}\n",
+            s.label, sparkline, min, max, last
+        ));
+    }
+    text
+}
+
+fn series_html(series: &[Series]) -> String {
+    const WIDTH: f64 = 600.0;
+    const HEIGHT: f64 = 120.0;
+    let mut html = String::new();
+    for s in series {
+        let ((t0, t1), (min, max)) = match (
+            bounds(s.points.iter().map(|p| p.0)),
+            bounds(s.points.iter().map(|p| p.1)),
+        ) {
+            (Some(t), Some(v)) => (t, v),
+            _ => continue,
+        };
+        let points: Vec<String> = s
+            .points
+            .iter()
+            .map(|(t, v)| {
+                let x = if t1 > t0 {
+                    (t - t0) / (t1 - t0) * WIDTH
+                } else {
+                    0.0
+                };
+                let y = if max > min {
+                    HEIGHT - (v - min) / (max - min) * HEIGHT
+                } else {
+                    HEIGHT / 2.0
+                };
+                format!("{:.1},{:.1}", x, y)
+            })
+            .collect();
+        let last = s.points.last().map(|p| p.1).unwrap_or_default();
+        html.push_str(&format!(
+            "<div class=\"chart\"><div class=\"meta\"><code>{}</code> min {} max {} last {}</div>\
+             <svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" viewBox=\"-2 -2 {} {}\">\
+             <polyline fill=\"none\" stroke=\"#0969da\" stroke-width=\"1.5\" points=\"{}\"/></svg></div>\n",
+            escape_html(&s.label),
+            min,
+            max,
+            last,
+            WIDTH,
+            HEIGHT,
+            WIDTH + 4.0,
+            HEIGHT + 4.0,
+            points.join(" ")
+        ));
+    }
+    html
+}
+
+// markdown_to_html covers what notes in a notebook use: headings,
+// paragraphs, lists and code blocks. Everything is escaped, inline markup
+// is left as written.
+fn markdown_to_html(source: &str) -> String {
+    let mut html = String::new();
+    let mut paragraph: Vec<&str> = vec![];
+    let mut in_list = false;
+    let mut code: Option<Vec<&str>> = None;
+
+    fn flush(html: &mut String, paragraph: &mut Vec<&str>, in_list: &mut bool) {
+        if !paragraph.is_empty() {
+            html.push_str(&format!("<p>{}</p>\n", escape_html(&paragraph.join("\n"))));
+            paragraph.clear();
+        }
+        if *in_list {
+            html.push_str("</ul>\n");
+            *in_list = false;
+        }
+    }
+
+    for line in source.lines() {
+        if let Some(lines) = &mut code {
+            if line.trim_start().starts_with("```") {
+                html.push_str(&format!("<pre>{}</pre>\n", escape_html(&lines.join("\n"))));
+                code = None;
+            } else {
+                lines.push(line);
+            }
+            continue;
+        }
+        let trimmed = line.trim();
+        if trimmed.starts_with("```") {
+            flush(&mut html, &mut paragraph, &mut in_list);
+            code = Some(vec![]);
+        } else if trimmed.is_empty() {
+            flush(&mut html, &mut paragraph, &mut in_list);
+        } else if let Some(item) = trimmed
+            .strip_prefix("- ")
+            .or_else(|| trimmed.strip_prefix("* "))
+        {
+            if !paragraph.is_empty() {
+                flush(&mut html, &mut paragraph, &mut in_list);
+            }
+            if !in_list {
+                html.push_str("<ul>\n");
+                in_list = true;
+            }
+            html.push_str(&format!("<li>{}</li>\n", escape_html(item)));
+        } else if trimmed.starts_with('#') {
+            flush(&mut html, &mut paragraph, &mut in_list);
+            let level = trimmed.chars().take_while(|c| *c == '#').count().min(6);
+            html.push_str(&format!(
+                "<h{level}>{}</h{level}>\n",
+                escape_html(trimmed[level..].trim()),
+                level = level
+            ));
+        } else {
+            if in_list {
+                flush(&mut html, &mut paragraph, &mut in_list);
+            }
+            paragraph.push(trimmed);
+        }
+    }
+    if let Some(lines) = code {
+        html.push_str(&format!("<pre>{}</pre>\n", escape_html(&lines.join("\n"))));
+    }
+    flush(&mut html, &mut paragraph, &mut in_list);
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::incident::{Incident, Severity, Status};
+    use crate::notebook::Cell;
+    use crate::plugin_manager::{Execution, InvocationError};
+    use crate::plugin_runtime::InvocationTrace;
+    use crate::timeline::ExecutionContext;
+
+    fn notebook() -> Notebook {
+        let mut notebook = Notebook::new(
+            "api latency <p99>".into(),
+            "elias".into(),
+            vec!["sev2".into()],
+            vec![
+                Cell {
+                    id: "notes".into(),
+                    content: CellContent::Markdown {
+                        source: "## Impact\nslow *checkout*\n\n- eu\n- us".into(),
+                    },
+                },
+                Cell {
+                    id: "pods".into(),
+                    content: CellContent::Plugin {
+                        plugin: "k8s".into(),
+                        options: BTreeMap::new(),
+                    },
+                },
+                Cell {
+                    id: "latency".into(),
+                    content: CellContent::Plugin {
+                        plugin: "prometheus".into(),
+                        options: BTreeMap::new(),
+                    },
+                },
+            ],
+        );
+        notebook
+            .metadata
+            .variables
+            .insert("cluster".into(), "prod-eu".into());
+        notebook
+    }
+
+    fn event(
+        cell_id: &str,
+        user: &str,
+        minute: i64,
+        output: Result<String, InvocationError>,
+    ) -> ExecutionEvent {
+        let at =
+            "2026-03-04T10:00:00Z".parse::<DateTime<Utc>>().unwrap() + Duration::minutes(minute);
+        ExecutionEvent::new(
+            ExecutionContext {
+                notebook_id: Some("n1".into()),
+                cell_id: Some(cell_id.into()),
+                user: Some(user.into()),
+                inputs: BTreeMap::from([("vars.cluster".to_string(), "prod-eu".to_string())]),
+                ..Default::default()
+            },
+            "plugin",
+            BTreeMap::new(),
+            Execution {
+                plugin_version: None,
+                output,
+                trace: InvocationTrace::default(),
+            },
+            at,
+            at + Duration::seconds(2),
+        )
+    }
+
+    fn events() -> Vec<ExecutionEvent> {
+        vec![
+            event("pods", "ana", 0, Err(InvocationError::GenericError)),
+            event(
+                "pods",
+                "ana",
+                5,
+                Ok(r#"[{"name": "api-1", "status": "Running"}, {"name": "api-2", "restarts": 4}]"#.into()),
+            ),
+            event(
+                "latency",
+                "bo",
+                30,
+                Ok(r#"{"data": {"result": [{"metric": {"job": "api"}, "values": [[1, "0.2"], [2, "0.9"], [3, "0.4"]]}]}}"#.into()),
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_export_markdown() {
+        let markdown = export(&notebook(), &events(), Format::Markdown, None).unwrap();
+        assert!(markdown.starts_with("# api latency <p99>\n"));
+        assert!(markdown.contains("| Started | 2026-03-04 10:00:00 UTC |"));
+        assert!(markdown.contains("| Duration | 30m 2s |"));
+        assert!(markdown.contains("| Executions | 3 (1 failed) |"));
+        assert!(markdown.contains("| Responders | ana bo |"));
+        assert!(markdown.contains("## Impact\nslow *checkout*"));
+        assert!(
+            markdown.contains("**2026-03-04 10:00:00 UTC** by ana in 2s, failed: `GenericError`")
+        );
+        assert!(markdown.contains("- `vars.cluster` = `prod-eu`"));
+        assert!(markdown.contains(
+            "| name | status | restarts |\n|---|---|---|\n| api-1 | Running |  |\n| api-2 |  | 4 |"
+        ));
+        assert!(markdown.contains("- `{job=api}` ▁█▃ min 0.2 max 0.9 last 0.4"));
+        assert!(markdown.contains("| 2026-03-04 10:30:00 UTC | bo | latency | plugin | ok |"));
+    }
+
+    #[test]
+    fn test_export_html() {
+        let html = export(&notebook(), &events(), Format::Html, None).unwrap();
+        assert!(html.contains("<title>api latency &lt;p99&gt;</title>"));
+        assert!(html.contains(
+            "<h2>Impact</h2>\n<p>slow *checkout*</p>\n<ul>\n<li>eu</li>\n<li>us</li>\n</ul>"
+        ));
+        assert!(html.contains("<tr><th>name</th><th>status</th><th>restarts</th></tr>"));
+        assert!(html.contains("<polyline"));
+        assert!(!html.contains("<script"));
+        assert!(!html.contains("{{"));
+    }
+
+    #[test]
+    fn test_export_incident() {
+        let at = |minute| {
+            "2026-03-04T10:00:00Z".parse::<DateTime<Utc>>().unwrap() + Duration::minutes(minute)
+        };
+        let mut notebook = notebook();
+        let mut incident = Incident::declare(
+            Severity::Sev2,
+            Some("ana".into()),
+            vec![],
+            Some("ana".into()),
+            at(0),
+        );
+        incident
+            .transition(Status::Resolved, Some("bo".into()), None, at(45))
+            .unwrap();
+        notebook.metadata.incident = Some(incident);
+        let mut events = events();
+        events.push(ExecutionEvent::incident(
+            "n1",
+            Some("bo".into()),
+            "incident resolved".into(),
+            BTreeMap::new(),
+            at(45),
+        ));
+
+        let markdown = export(&notebook, &events, Format::Markdown, None).unwrap();
+        assert!(markdown.contains("| Incident | sev2, resolved, commander ana |"));
+        assert!(markdown.contains("| Time to resolve | 45m 0s |"));
+        assert!(markdown.contains("| Executions | 3 (1 failed) |"));
+        assert!(
+            markdown.contains("| 2026-03-04 10:45:00 UTC | bo |  | incident | incident resolved |")
+        );
+    }
+
+    #[test]
+    fn test_custom_template() {
+        let template = "{{notebook.title}}: {{#timeline}}{{user}} {{status}}, {{/timeline}}";
+        assert_eq!(
+            "api latency <p99>: ana failed, ana ok, bo ok, ",
+            export(&notebook(), &events(), Format::Markdown, Some(template)).unwrap()
+        );
+        assert!(export(&notebook(), &[], Format::Html, Some("{{#cells}}")).is_err());
+    }
+
+    #[test]
+    fn test_render_output() {
+        assert_eq!("```\nplain\n```", render_output("plain", Format::Markdown));
+        assert_eq!(
+            "````\na ``` b\n````",
+            render_output("a ``` b", Format::Markdown)
+        );
+        assert_eq!("<pre>&lt;b&gt;</pre>", render_output("<b>", Format::Html));
+        assert_eq!(
+            "```\n{\n  \"a\": 1\n}\n```",
+            render_output(r#"{"a": 1}"#, Format::Markdown)
+        );
+        assert_eq!(
+            "| x |\n|---|\n| a\\|b |\n",
+            render_output(r#"[{"x": "a|b"}]"#, Format::Markdown)
+        );
+    }
+}
@@ -0,0 +1,138 @@
+use crate::auth;
+use crate::databook;
+use crate::databook::exports_server::Exports;
+use crate::export::{self, Format};
+use crate::notebook_api::{self, NotebookApiError};
+use crate::policy::{Permission, Principal};
+use crate::references;
+use crate::timeline_api::{self, empty_as_none};
+use crate::{rest, run_blocking, CONFIG};
+
+use rocket_contrib::json::Json;
+use std::fs;
+use std::path::Path;
+use tonic::{Request, Response, Status};
+use tracing::instrument;
+
+pub struct Export {
+    pub content: String,
+    pub format: Format,
+}
+
+// named_template reads <name>.<extension> from the export_templates
+// directory of the server config
+fn named_template(name: &str, format: Format) -> Result<String, NotebookApiError> {
+    if !references::is_name(name) {
+        return Err(NotebookApiError::InvalidRequest(format!(
+            "invalid template name {:?}",
+            name
+        )));
+    }
+    let directory = CONFIG
+        .get()
+        .and_then(|c| c.export_templates.as_ref())
+        .ok_or_else(|| {
+            NotebookApiError::InvalidRequest("no export_templates directory is configured".into())
+        })?;
+    let path = Path::new(directory).join(format!("{}.{}", name, format.extension()));
+    fs::read_to_string(&path).map_err(|e| {
+        tracing::warn!("unable to read export template {:?} {:?}", path, e);
+        NotebookApiError::NotFound(format!(
+            "template {} does not exist for {}",
+            name,
+            format.extension()
+        ))
+    })
+}
+
+// export_notebook renders the notebook and its timeline, with the given
+// template text, a named one or the default one of the format
+pub fn export_notebook(
+    notebook_id: &str,
+    format: Option<&str>,
+    template: Option<String>,
+    template_name: Option<&str>,
+    principal: &Principal,
+) -> Result<Export, NotebookApiError> {
+    let format = match format {
+        None => Format::Markdown,
+        Some(format) => Format::parse(format).ok_or_else(|| {
+            NotebookApiError::InvalidRequest(format!(
+                "unknown format {:?}, expected markdown or html",
+                format
+            ))
+        })?,
+    };
+    let template = match (template, template_name) {
+        (Some(template), _) => Some(template),
+        (None, Some(name)) => Some(named_template(name, format)?),
+        (None, None) => None,
+    };
+    let notebook = notebook_api::get_authorized(notebook_id, principal, Permission::View)?;
+    let events = timeline_api::notebook_events(notebook_id)?;
+    let content = export::export(&notebook, &events, format, template.as_deref())
+        .map_err(|e| NotebookApiError::InvalidRequest(format!("invalid template: {}", e)))?;
+    Ok(Export { content, format })
+}
+
+#[derive(Debug, Default)]
+pub struct ExportsGrpc {}
+
+#[tonic::async_trait]
+impl Exports for ExportsGrpc {
+    #[instrument]
+    async fn export(
+        &self,
+        request: Request<databook::ExportRequest>,
+    ) -> Result<Response<databook::ExportResponse>, Status> {
+        tracing::info!("received export request");
+        let principal = auth::actor(&request, "").principal;
+        run_blocking(move || {
+            let request = request.into_inner();
+            let export = export_notebook(
+                &request.notebook_id,
+                empty_as_none(request.format).as_deref(),
+                empty_as_none(request.template),
+                empty_as_none(request.template_name).as_deref(),
+                &principal,
+            )?;
+            Ok::<_, NotebookApiError>(databook::ExportResponse {
+                content: export.content,
+                content_type: export.format.content_type().to_string(),
+                extension: export.format.extension().to_string(),
+            })
+        })
+        .await
+    }
+}
+
+#[post("/notebooks/<id>/export", data = "<request>")]
+pub fn rest_export(
+    caller: auth::Caller,
+    id: String,
+    request: Json<rest::ExportRequest>,
+) -> Json<rest::ExportResponse> {
+    tracing::info!("received export request");
+    let request = request.into_inner();
+    match export_notebook(
+        &id,
+        request.format.as_deref(),
+        request.template,
+        request.template_name.as_deref(),
+        &caller.actor(None).principal,
+    ) {
+        Ok(export) => Json(rest::ExportResponse {
+            content: Some(export.content),
+            content_type: Some(export.format.content_type().to_string()),
+            error: None,
+        }),
+        Err(e) => {
+            tracing::error!("export request failed {:?}", e);
+            Json(rest::ExportResponse {
+                content: None,
+                content_type: None,
+                error: Some(format!("{:?}", e)),
+            })
+        }
+    }
+}
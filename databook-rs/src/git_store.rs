@@ -0,0 +1,677 @@
+use crate::crdt::{Operation, SequencedOperation};
+use crate::notebook::Notebook;
+use crate::notebook_file;
+use crate::notebook_store::{NotebookStore, StoreError};
+
+use chrono::{DateTime, Utc};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::{Mutex, MutexGuard};
+
+// GitStore keeps notebooks as files (see notebook_file.rs) in a bare git
+// repository, `notebooks/<id>.md` on the main branch. Every save is a
+// commit authored by whoever made it, so notebooks can be reviewed, merged
+// and reverted like code. With branch_per_incident, a notebook with an
+// incident is saved on `incident/<id>` instead, branched from main, and
+// read from there until that branch is merged and removed.
+//
+// Commits are made with git plumbing, there is no working tree to keep in
+// sync. The edit history of the notebooks (their CRDT operations) is not
+// versioned, it is kept next to the repository in databook/operations.
+pub struct GitStore {
+    repository: PathBuf,
+    branch: String,
+    branch_per_incident: bool,
+    // commits go through a single index file
+    lock: Mutex<()>,
+}
+
+const NOTEBOOKS_DIR: &str = "notebooks";
+const INCIDENT_BRANCHES: &str = "incident/";
+const NO_COMMIT: &str = "0000000000000000000000000000000000000000";
+// author of commits made without a user, e.g. deletes
+const SERVER_AUTHOR: &str = "databook";
+
+// identity turns a user into a git author, users that look like an email
+// are used as is
+fn identity(user: &str) -> (String, String) {
+    match user.split_once('@') {
+        Some((name, _)) if !name.is_empty() => (name.to_string(), user.to_string()),
+        _ => (user.to_string(), format!("{}@databook", user)),
+    }
+}
+
+// ids end up in paths and refs, only the ones the server makes are valid
+fn check_id(id: &str) -> Result<(), StoreError> {
+    if !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        Ok(())
+    } else {
+        Err(StoreError::NotFound(id.to_string()))
+    }
+}
+
+fn path(id: &str) -> String {
+    format!("{}/{}.md", NOTEBOOKS_DIR, id)
+}
+
+fn file_error(id: &str, e: notebook_file::FileError) -> StoreError {
+    StoreError::Backend(format!("notebook {} {}", id, e))
+}
+
+impl GitStore {
+    // open uses the repository at `repository`, creating a bare one when
+    // the directory does not exist yet
+    pub fn open(
+        repository: &str,
+        branch: &str,
+        branch_per_incident: bool,
+    ) -> Result<Self, StoreError> {
+        let store = Self {
+            repository: PathBuf::from(repository),
+            branch: branch.to_string(),
+            branch_per_incident,
+            lock: Mutex::new(()),
+        };
+        if !store.repository.exists() {
+            run(
+                Command::new("git")
+                    .args(["init", "--bare", "-q"])
+                    .arg(&store.repository),
+                None,
+            )?;
+            store.git(&["symbolic-ref", "HEAD", &format!("refs/heads/{}", branch)])?;
+        }
+        store.git(&["rev-parse", "--git-dir"])?;
+        fs::create_dir_all(store.operations_dir())
+            .map_err(|e| StoreError::Backend(e.to_string()))?;
+        Ok(store)
+    }
+
+    fn operations_dir(&self) -> PathBuf {
+        self.repository.join("databook").join("operations")
+    }
+
+    fn command(&self) -> Command {
+        let mut command = Command::new("git");
+        command
+            .arg("--git-dir")
+            .arg(&self.repository)
+            // the index needs a work tree, none of the commands touch it
+            .arg("--work-tree")
+            .arg(self.repository.join("databook"))
+            .env(
+                "GIT_INDEX_FILE",
+                self.repository.join("databook").join("index"),
+            )
+            .env("GIT_COMMITTER_NAME", SERVER_AUTHOR)
+            .env("GIT_COMMITTER_EMAIL", format!("{}@databook", SERVER_AUTHOR));
+        command
+    }
+
+    fn git(&self, args: &[&str]) -> Result<String, StoreError> {
+        Ok(run(self.command().args(args), None)?.trim_end().to_string())
+    }
+
+    // rev resolves a commit, None when it does not exist
+    fn rev(&self, name: &str) -> Result<Option<String>, StoreError> {
+        let output = self
+            .command()
+            .args([
+                "rev-parse",
+                "-q",
+                "--verify",
+                &format!("{}^{{commit}}", name),
+            ])
+            .output()
+            .map_err(|e| StoreError::Backend(format!("could not run git {}", e)))?;
+        if output.status.success() {
+            Ok(Some(
+                String::from_utf8_lossy(&output.stdout).trim().to_string(),
+            ))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn has_file(&self, branch: &str, path: &str) -> Result<bool, StoreError> {
+        let status = self
+            .command()
+            .args(["cat-file", "-e", &format!("refs/heads/{}:{}", branch, path)])
+            .stderr(Stdio::null())
+            .status()
+            .map_err(|e| StoreError::Backend(format!("could not run git {}", e)))?;
+        Ok(status.success())
+    }
+
+    fn incident_branch(id: &str) -> String {
+        format!("{}{}", INCIDENT_BRANCHES, id)
+    }
+
+    // branch_of is where the notebook is read from
+    fn branch_of(&self, id: &str) -> Result<Option<String>, StoreError> {
+        let incident = Self::incident_branch(id);
+        for branch in [incident, self.branch.clone()] {
+            if self.has_file(&branch, &path(id))? {
+                return Ok(Some(branch));
+            }
+        }
+        Ok(None)
+    }
+
+    // target is the branch a save of the notebook goes to
+    fn target(&self, notebook: &Notebook) -> Result<String, StoreError> {
+        let incident = Self::incident_branch(&notebook.id);
+        let on_incident_branch = self.rev(&format!("refs/heads/{}", incident))?.is_some();
+        if on_incident_branch || (self.branch_per_incident && notebook.metadata.incident.is_some())
+        {
+            Ok(incident)
+        } else {
+            Ok(self.branch.clone())
+        }
+    }
+
+    fn read(&self, branch: &str, id: &str) -> Result<Notebook, StoreError> {
+        let file = run(
+            self.command().args([
+                "cat-file",
+                "blob",
+                &format!("refs/heads/{}:{}", branch, path(id)),
+            ]),
+            None,
+        )?;
+        notebook_file::read(&file).map_err(|e| file_error(id, e))
+    }
+
+    // commit writes (or removes, when `content` is None) the file on the
+    // branch, which starts from main when it does not exist yet. Saves that
+    // change nothing make no commit.
+    fn commit(
+        &self,
+        _lock: &MutexGuard<()>,
+        branch: &str,
+        file: &str,
+        content: Option<&str>,
+        message: &str,
+        author: &str,
+    ) -> Result<(), StoreError> {
+        let reference = format!("refs/heads/{}", branch);
+        let head = self.rev(&reference)?;
+        let parent = match &head {
+            Some(head) => Some(head.clone()),
+            None => self.rev(&format!("refs/heads/{}", self.branch))?,
+        };
+        match &parent {
+            Some(parent) => self.git(&["read-tree", parent])?,
+            None => self.git(&["read-tree", "--empty"])?,
+        };
+        match content {
+            Some(content) => {
+                let blob = run(
+                    self.command().args(["hash-object", "-w", "--stdin"]),
+                    Some(content.as_bytes()),
+                )?;
+                let blob = blob.trim_end();
+                self.git(&[
+                    "update-index",
+                    "--add",
+                    "--cacheinfo",
+                    &format!("100644,{},{}", blob, file),
+                ])?
+            }
+            None => self.git(&["update-index", "--force-remove", file])?,
+        };
+        let tree = self.git(&["write-tree"])?;
+        if let Some(parent) = &parent {
+            if head.is_some() && self.git(&["rev-parse", &format!("{}^{{tree}}", parent)])? == tree
+            {
+                return Ok(());
+            }
+        }
+
+        let (name, email) = identity(author);
+        let mut command = self.command();
+        command
+            .args(["commit-tree", &tree, "-m", message])
+            .env("GIT_AUTHOR_NAME", name)
+            .env("GIT_AUTHOR_EMAIL", email);
+        if let Some(parent) = &parent {
+            command.args(["-p", parent]);
+        }
+        let commit = run(&mut command, None)?;
+        let commit = commit.trim_end();
+        self.git(&[
+            "update-ref",
+            &reference,
+            commit,
+            head.as_deref().unwrap_or(NO_COMMIT),
+        ])?;
+        Ok(())
+    }
+
+    fn locked(&self) -> Result<MutexGuard<'_, ()>, StoreError> {
+        self.lock
+            .lock()
+            .map_err(|e| StoreError::Backend(format!("could not lock the repository {:?}", e)))
+    }
+
+    fn operations_file(&self, id: &str) -> PathBuf {
+        self.operations_dir().join(format!("{}.jsonl", id))
+    }
+
+    fn read_operations(&self, id: &str) -> Result<Vec<SequencedOperation>, StoreError> {
+        match fs::read_to_string(self.operations_file(id)) {
+            Ok(lines) => lines
+                .lines()
+                .map(|line| Ok(serde_json::from_str(line)?))
+                .collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+            Err(e) => Err(StoreError::Backend(e.to_string())),
+        }
+    }
+
+    // write_operations appends to the operations file, the lock is the one
+    // of the repository
+    fn write_operations(
+        &self,
+        _lock: &MutexGuard<()>,
+        id: &str,
+        operations: &[Operation],
+    ) -> Result<u64, StoreError> {
+        let mut seq = self.read_operations(id)?.last().map(|o| o.seq).unwrap_or(0);
+        let mut lines = String::new();
+        for operation in operations {
+            seq += 1;
+            lines.push_str(&serde_json::to_string(&SequencedOperation {
+                seq,
+                operation: operation.clone(),
+            })?);
+            lines.push('\n');
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.operations_file(id))
+            .and_then(|mut file| file.write_all(lines.as_bytes()))
+            .map_err(|e| StoreError::Backend(e.to_string()))?;
+        Ok(seq)
+    }
+}
+
+// run returns the stdout of the command, its stderr when it fails
+fn run(command: &mut Command, input: Option<&[u8]>) -> Result<String, StoreError> {
+    let mut child = command
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| StoreError::Backend(format!("could not run git {}", e)))?;
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        stdin
+            .write_all(input)
+            .map_err(|e| StoreError::Backend(e.to_string()))?;
+    }
+    let output = child
+        .wait_with_output()
+        .map_err(|e| StoreError::Backend(e.to_string()))?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    } else {
+        Err(StoreError::Backend(format!(
+            "git failed {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )))
+    }
+}
+
+impl NotebookStore for GitStore {
+    fn create(&self, notebook: &Notebook) -> Result<(), StoreError> {
+        check_id(&notebook.id)?;
+        let lock = self.locked()?;
+        if self.branch_of(&notebook.id)?.is_some() {
+            return Err(StoreError::AlreadyExists(notebook.id.clone()));
+        }
+        let file = notebook_file::write(notebook).map_err(|e| file_error(&notebook.id, e))?;
+        self.commit(
+            &lock,
+            &self.target(notebook)?,
+            &path(&notebook.id),
+            Some(&file),
+            &format!("Create notebook {}", notebook.metadata.title),
+            &notebook.metadata.owner,
+        )
+    }
+
+    fn get(&self, id: &str) -> Result<Notebook, StoreError> {
+        check_id(id)?;
+        match self.branch_of(id)? {
+            Some(branch) => self.read(&branch, id),
+            None => Err(StoreError::NotFound(id.to_string())),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<Notebook>, StoreError> {
+        let mut ids: Vec<String> = vec![];
+        if self.rev(&format!("refs/heads/{}", self.branch))?.is_some() {
+            let files = self.git(&[
+                "ls-tree",
+                "--name-only",
+                &format!("refs/heads/{}", self.branch),
+                &format!("{}/", NOTEBOOKS_DIR),
+            ])?;
+            ids.extend(files.lines().filter_map(|f| {
+                f.strip_prefix(&format!("{}/", NOTEBOOKS_DIR))
+                    .and_then(|f| f.strip_suffix(".md"))
+                    .map(str::to_string)
+            }));
+        }
+        let branches = self.git(&[
+            "for-each-ref",
+            "--format=%(refname)",
+            &format!("refs/heads/{}", INCIDENT_BRANCHES),
+        ])?;
+        for branch in branches.lines() {
+            if let Some(id) = branch.strip_prefix(&format!("refs/heads/{}", INCIDENT_BRANCHES)) {
+                if !ids.iter().any(|i| i == id) {
+                    ids.push(id.to_string());
+                }
+            }
+        }
+
+        let mut notebooks = vec![];
+        for id in ids {
+            if let Some(branch) = self.branch_of(&id)? {
+                notebooks.push(self.read(&branch, &id)?);
+            }
+        }
+        notebooks.sort_by_key(|n| std::cmp::Reverse(n.metadata.updated_at));
+        Ok(notebooks)
+    }
+
+    // update appends the operations first and truncates them back when the
+    // commit fails, the history never gets ahead of the notebook
+    fn update(
+        &self,
+        notebook: &Notebook,
+        previous: DateTime<Utc>,
+        operations: &[Operation],
+        author: Option<&str>,
+    ) -> Result<u64, StoreError> {
+        check_id(&notebook.id)?;
+        let lock = self.locked()?;
+        let branch = self
+            .branch_of(&notebook.id)?
+            .ok_or_else(|| StoreError::NotFound(notebook.id.clone()))?;
+        if self.read(&branch, &notebook.id)?.metadata.updated_at != previous {
+            return Err(StoreError::Conflict(notebook.id.clone()));
+        }
+        let file = notebook_file::write(notebook).map_err(|e| file_error(&notebook.id, e))?;
+        let length = match fs::metadata(self.operations_file(&notebook.id)) {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(StoreError::Backend(e.to_string())),
+        };
+        let seq = self.write_operations(&lock, &notebook.id, operations)?;
+        let committed = self.commit(
+            &lock,
+            &self.target(notebook)?,
+            &path(&notebook.id),
+            Some(&file),
+            &format!("Update notebook {}", notebook.metadata.title),
+            author.unwrap_or(SERVER_AUTHOR),
+        );
+        if let Err(e) = committed {
+            OpenOptions::new()
+                .write(true)
+                .open(self.operations_file(&notebook.id))
+                .and_then(|file| file.set_len(length))
+                .map_err(|e| StoreError::Backend(format!("could not undo the operations {}", e)))?;
+            return Err(e);
+        }
+        Ok(seq)
+    }
+
+    fn delete(&self, id: &str) -> Result<(), StoreError> {
+        check_id(id)?;
+        let lock = self.locked()?;
+        let notebook = self.get(id)?;
+        for branch in [Self::incident_branch(id), self.branch.clone()] {
+            if self.has_file(&branch, &path(id))? {
+                self.commit(
+                    &lock,
+                    &branch,
+                    &path(id),
+                    None,
+                    &format!("Delete notebook {}", notebook.metadata.title),
+                    SERVER_AUTHOR,
+                )?;
+            }
+        }
+        match fs::remove_file(self.operations_file(id)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(StoreError::Backend(e.to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn append_operations(&self, id: &str, operations: &[Operation]) -> Result<u64, StoreError> {
+        check_id(id)?;
+        let lock = self.locked()?;
+        self.write_operations(&lock, id, operations)
+    }
+
+    fn operations(&self, id: &str, since: u64) -> Result<Vec<SequencedOperation>, StoreError> {
+        check_id(id)?;
+        let mut operations = self.read_operations(id)?;
+        operations.retain(|o| o.seq > since);
+        Ok(operations)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crdt::NotebookDoc;
+    use crate::incident::{Incident, Severity};
+    use crate::notebook::Cell;
+    use chrono::Utc;
+    use std::collections::BTreeMap;
+
+    fn notebook() -> Notebook {
+        Notebook::new(
+            "api latency".into(),
+            "elias".into(),
+            vec!["sev2".into()],
+            vec![
+                Cell::markdown("p99 is up since 10:00"),
+                Cell::plugin(
+                    "prometheus",
+                    BTreeMap::from([("query".to_string(), "latency[5m]".to_string())]),
+                ),
+            ],
+        )
+    }
+
+    fn open(dir: &tempfile::TempDir, branch_per_incident: bool) -> GitStore {
+        let repository = dir.path().join("notebooks.git");
+        GitStore::open(repository.to_str().unwrap(), "main", branch_per_incident).unwrap()
+    }
+
+    // log lists "<author> <subject>" of the commits on the branch
+    fn log(store: &GitStore, branch: &str) -> Vec<String> {
+        store
+            .git(&["log", "--format=%an <%ae> %s", branch])
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn test_commits_every_save() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = open(&dir, false);
+        let mut notebook = notebook();
+        store.create(&notebook).unwrap();
+        assert_eq!(notebook, store.get(&notebook.id).unwrap());
+        assert_eq!(
+            Err(StoreError::AlreadyExists(notebook.id.clone())),
+            store.create(&notebook)
+        );
+
+        notebook.metadata.title = "api down".into();
+        store
+            .update(
+                &notebook,
+                notebook.metadata.updated_at,
+                &[],
+                Some("ana@example.com"),
+            )
+            .unwrap();
+        // nothing changed, nothing to commit
+        store
+            .update(&notebook, notebook.metadata.updated_at, &[], Some("bo"))
+            .unwrap();
+        // saved over a version that is not the stored one
+        let stale = notebook.metadata.updated_at - chrono::Duration::seconds(1);
+        assert_eq!(
+            Err(StoreError::Conflict(notebook.id.clone())),
+            store.update(&notebook, stale, &[], Some("bo"))
+        );
+        assert_eq!(notebook, store.get(&notebook.id).unwrap());
+        assert_eq!(
+            vec![
+                "ana <ana@example.com> Update notebook api down",
+                "elias <elias@databook> Create notebook api latency",
+            ],
+            log(&store, "main")
+        );
+        // the file is in the tree, as notebook_file writes it
+        assert_eq!(
+            notebook_file::write(&notebook).unwrap(),
+            run(
+                store
+                    .command()
+                    .args(["show", &format!("main:{}", path(&notebook.id))]),
+                None
+            )
+            .unwrap()
+        );
+
+        let other = Notebook::new("db failover".into(), "ana".into(), vec![], vec![]);
+        store.create(&other).unwrap();
+        assert_eq!(vec![other.clone(), notebook.clone()], store.list().unwrap());
+
+        store.delete(&notebook.id).unwrap();
+        assert_eq!(
+            Err(StoreError::NotFound(notebook.id.clone())),
+            store.get(&notebook.id)
+        );
+        assert_eq!(vec![other], store.list().unwrap());
+        assert_eq!(
+            Err(StoreError::NotFound(notebook.id.clone())),
+            store.update(&notebook, notebook.metadata.updated_at, &[], None)
+        );
+        assert_eq!(
+            Err(StoreError::NotFound("../main".into())),
+            store.get("../main")
+        );
+
+        // the repository is opened again as it is
+        let store = open(&dir, false);
+        assert_eq!(1, store.list().unwrap().len());
+    }
+
+    #[test]
+    fn test_branch_per_incident() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = open(&dir, true);
+        let mut notebook = notebook();
+        store.create(&notebook).unwrap();
+        let before = notebook.clone();
+
+        notebook.metadata.incident = Some(Incident::declare(
+            Severity::Sev1,
+            None,
+            vec![],
+            None,
+            Utc::now(),
+        ));
+        store
+            .update(&notebook, notebook.metadata.updated_at, &[], Some("ana"))
+            .unwrap();
+        notebook.cells.push(Cell::markdown("rolled back"));
+        store
+            .update(&notebook, notebook.metadata.updated_at, &[], Some("bo"))
+            .unwrap();
+
+        assert_eq!(notebook, store.get(&notebook.id).unwrap());
+        assert_eq!(vec![notebook.clone()], store.list().unwrap());
+        assert_eq!(before, store.read("main", &notebook.id).unwrap());
+        let incident = format!("incident/{}", notebook.id);
+        assert_eq!(3, log(&store, &incident).len());
+        assert_eq!(1, log(&store, "main").len());
+
+        // once merged and the branch removed, main has the notebook
+        store
+            .git(&["update-ref", "refs/heads/main", &incident])
+            .unwrap();
+        store
+            .git(&["update-ref", "-d", &format!("refs/heads/{}", incident)])
+            .unwrap();
+        assert_eq!(notebook, store.get(&notebook.id).unwrap());
+
+        store.delete(&notebook.id).unwrap();
+        assert!(store.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_operations() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = open(&dir, false);
+        let notebook = notebook();
+        store.create(&notebook).unwrap();
+        let operations = NotebookDoc::default()
+            .diff(&notebook.cells, "server")
+            .unwrap();
+
+        assert_eq!(
+            operations.len() as u64,
+            store.append_operations(&notebook.id, &operations).unwrap()
+        );
+        let stored = store.operations(&notebook.id, 1).unwrap();
+        assert_eq!(operations.len() - 1, stored.len());
+        assert_eq!(2, stored[0].seq);
+        assert_eq!(operations[1], stored[0].operation);
+
+        // the operations of a refused update are not kept
+        let stale = notebook.metadata.updated_at - chrono::Duration::seconds(1);
+        assert_eq!(
+            Err(StoreError::Conflict(notebook.id.clone())),
+            store.update(&notebook, stale, &operations, None)
+        );
+        assert_eq!(
+            operations.len(),
+            store.operations(&notebook.id, 0).unwrap().len()
+        );
+        assert_eq!(
+            2 * operations.len() as u64,
+            store
+                .update(&notebook, notebook.metadata.updated_at, &operations, None)
+                .unwrap()
+        );
+
+        store.delete(&notebook.id).unwrap();
+        assert!(store.operations(&notebook.id, 0).unwrap().is_empty());
+    }
+}
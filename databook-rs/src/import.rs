@@ -0,0 +1,438 @@
+use crate::cell_parser::{parse_cell, ParseError, PLUGIN_DIRECTIVE};
+use crate::notebook::Cell;
+
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+
+// Imports turn existing runbooks into notebooks:
+//
+//   - Jupyter notebooks (nbformat 4): markdown and raw cells stay markdown,
+//     code cells become plugin cells if they start with a `@plugin=`
+//     header, are tagged `@plugin=<name>` (cell metadata, other `@key=value`
+//     tags become options) or their language maps to a plugin. The
+//     language is the kernel one, or the `%%magic` on the first line.
+//   - Markdown documents: fenced code blocks become plugin cells the same
+//     way, with the language of the fence, the text around them markdown
+//     cells. The first `# heading` is the title.
+//
+// Code that does not map to a plugin is kept as a code block in markdown.
+
+// languages mapped when the server config has no import_languages
+pub const DEFAULT_LANGUAGES: [(&str, &str); 2] = [("promql", "prometheus"), ("logql", "loki")];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportFormat {
+    Jupyter,
+    Markdown,
+}
+
+impl ImportFormat {
+    pub fn parse(format: &str) -> Option<Self> {
+        match format.to_lowercase().as_str() {
+            "jupyter" | "ipynb" => Some(ImportFormat::Jupyter),
+            "markdown" | "md" => Some(ImportFormat::Markdown),
+            _ => None,
+        }
+    }
+
+    // detect tells a Jupyter notebook (json) from a Markdown document
+    pub fn detect(content: &str) -> Self {
+        if content.trim_start().starts_with('{') {
+            ImportFormat::Jupyter
+        } else {
+            ImportFormat::Markdown
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ImportError {
+    InvalidJson(String),
+    UnsupportedVersion(i64),
+    // a cell claims to be a plugin cell but does not parse, the index
+    // counts from 1
+    InvalidCell(usize, ParseError),
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImportError::InvalidJson(e) => write!(f, "not a Jupyter notebook: {}", e),
+            ImportError::UnsupportedVersion(version) => {
+                write!(f, "nbformat {} is not supported, only 4 is", version)
+            }
+            ImportError::InvalidCell(index, e) => write!(f, "cell {}: {}", index, e),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Imported {
+    // None if the document has no title, the caller picks one
+    pub title: Option<String>,
+    pub cells: Vec<Cell>,
+}
+
+pub struct Importer<'a> {
+    // language -> plugin, languages are lower case
+    pub languages: &'a BTreeMap<String, String>,
+    // option receiving the body of a plugin, see cell_parser
+    pub body_option: &'a dyn Fn(&str) -> String,
+}
+
+// is_invocation tells if the header of the text has a `@plugin=` directive,
+// other code (e.g. python decorators) is left alone
+fn is_invocation(text: &str) -> bool {
+    for line in text.lines() {
+        let trimmed = line.trim_start();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        match trimmed.strip_prefix('@').and_then(|d| d.split_once('=')) {
+            Some((key, _)) if key.trim() == PLUGIN_DIRECTIVE => return true,
+            Some(_) => continue,
+            None => return false,
+        }
+    }
+    false
+}
+
+// cell_text builds a `@plugin=` cell from header lines and a body
+fn cell_text(header: &[String], body: &str) -> String {
+    let body = body.trim_start_matches(['\n', '\r']);
+    let escape = if body.starts_with(['@', '#']) {
+        "\\"
+    } else {
+        ""
+    };
+    format!("{}\n{}{}", header.join("\n"), escape, body)
+}
+
+fn code_block(language: &str, code: &str) -> String {
+    let fence = if code.contains("```") { "~~~~" } else { "```" };
+    format!(
+        "{}{}\n{}\n{}",
+        fence,
+        language,
+        code.trim_end_matches('\n'),
+        fence
+    )
+}
+
+fn first_heading(text: &str) -> Option<String> {
+    text.lines()
+        .find_map(|l| l.strip_prefix("# "))
+        .map(|t| t.trim().to_string())
+        .filter(|t| !t.is_empty())
+}
+
+impl<'a> Importer<'a> {
+    pub fn import(&self, content: &str, format: ImportFormat) -> Result<Imported, ImportError> {
+        match format {
+            ImportFormat::Jupyter => self.jupyter(content),
+            ImportFormat::Markdown => self.markdown(content),
+        }
+    }
+
+    fn plugin_cell(&self, index: usize, text: &str) -> Result<Cell, ImportError> {
+        let parsed = parse_cell(text).map_err(|e| ImportError::InvalidCell(index, e))?;
+        let plugin = parsed.plugin.clone();
+        let options = parsed
+            .into_options(&(self.body_option)(&plugin))
+            .map_err(|e| ImportError::InvalidCell(index, e))?;
+        Ok(Cell::plugin(&plugin, options))
+    }
+
+    // code turns a block of code into a plugin cell, or None when it does
+    // not map to a plugin
+    fn code(
+        &self,
+        index: usize,
+        language: &str,
+        code: &str,
+        tags: &[String],
+    ) -> Result<Option<Cell>, ImportError> {
+        if is_invocation(code) {
+            return self.plugin_cell(index, code).map(Some);
+        }
+        let directive = format!("@{}=", PLUGIN_DIRECTIVE);
+        if tags.iter().any(|t| t.starts_with(&directive)) {
+            let header: Vec<String> = tags
+                .iter()
+                .filter(|t| t.starts_with('@'))
+                .cloned()
+                .collect();
+            return self.plugin_cell(index, &cell_text(&header, code)).map(Some);
+        }
+        match self.languages.get(&language.to_lowercase()) {
+            Some(plugin) => self
+                .plugin_cell(
+                    index,
+                    &cell_text(&[format!("{}{}", directive, plugin)], code),
+                )
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn jupyter(&self, content: &str) -> Result<Imported, ImportError> {
+        let notebook: Value =
+            serde_json::from_str(content).map_err(|e| ImportError::InvalidJson(e.to_string()))?;
+        match notebook.get("nbformat").and_then(Value::as_i64) {
+            Some(4) => {}
+            Some(version) => return Err(ImportError::UnsupportedVersion(version)),
+            None => return Err(ImportError::InvalidJson("nbformat is missing".into())),
+        }
+        let metadata = notebook.get("metadata");
+        let kernel_language = metadata
+            .and_then(|m| {
+                m.pointer("/kernelspec/language")
+                    .or_else(|| m.pointer("/language_info/name"))
+            })
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+
+        let mut cells = vec![];
+        let mut title = metadata
+            .and_then(|m| m.get("title"))
+            .and_then(Value::as_str)
+            .map(str::to_string);
+        let empty = vec![];
+        let items = notebook
+            .get("cells")
+            .and_then(Value::as_array)
+            .unwrap_or(&empty);
+        for (index, cell) in items.iter().enumerate() {
+            // the source is a string or a list of lines keeping their \n
+            let source = match cell.get("source") {
+                Some(Value::String(source)) => source.clone(),
+                Some(Value::Array(lines)) => lines.iter().filter_map(Value::as_str).collect(),
+                _ => String::new(),
+            };
+            if source.trim().is_empty() {
+                continue;
+            }
+            let cell_type = cell
+                .get("cell_type")
+                .and_then(Value::as_str)
+                .unwrap_or("raw");
+            if cell_type != "code" {
+                if title.is_none() && cell_type == "markdown" {
+                    title = first_heading(&source);
+                }
+                cells.push(Cell::markdown(source.trim_end()));
+                continue;
+            }
+
+            let tags: Vec<String> = cell
+                .pointer("/metadata/tags")
+                .and_then(Value::as_array)
+                .map(|tags| {
+                    tags.iter()
+                        .filter_map(Value::as_str)
+                        .map(str::to_string)
+                        .collect()
+                })
+                .unwrap_or_default();
+            // a %%magic on the first line sets the language of the cell
+            let (language, code) = match source.strip_prefix("%%") {
+                Some(magic) => {
+                    let (first, rest) = magic.split_once('\n').unwrap_or((magic, ""));
+                    (first.split_whitespace().next().unwrap_or_default(), rest)
+                }
+                None => (kernel_language, source.as_str()),
+            };
+            match self.code(index + 1, language, code, &tags)? {
+                Some(cell) => cells.push(cell),
+                None => cells.push(Cell::markdown(&code_block(language, &source))),
+            }
+        }
+        Ok(Imported { title, cells })
+    }
+
+    fn markdown(&self, content: &str) -> Result<Imported, ImportError> {
+        let mut cells = vec![];
+        let mut text: Vec<&str> = vec![];
+        let mut title = None;
+        let mut lines = content.lines().enumerate();
+
+        fn flush(cells: &mut Vec<Cell>, text: &mut Vec<&str>) {
+            let source = text.join("\n");
+            if !source.trim().is_empty() {
+                cells.push(Cell::markdown(source.trim_matches('\n')));
+            }
+            text.clear();
+        }
+
+        while let Some((index, line)) = lines.next() {
+            if title.is_none() && text.iter().all(|l| l.trim().is_empty()) && cells.is_empty() {
+                if let Some(heading) = first_heading(line) {
+                    title = Some(heading);
+                    continue;
+                }
+            }
+            let trimmed = line.trim_start();
+            let fence_char = match trimmed.chars().next() {
+                Some(c @ ('`' | '~')) => c,
+                _ => {
+                    text.push(line);
+                    continue;
+                }
+            };
+            let fence_len = trimmed.chars().take_while(|c| *c == fence_char).count();
+            if fence_len < 3 {
+                text.push(line);
+                continue;
+            }
+            let language = trimmed[fence_len..]
+                .split_whitespace()
+                .next()
+                .unwrap_or_default();
+
+            let mut block = vec![line];
+            let mut code = vec![];
+            for (_, line) in lines.by_ref() {
+                block.push(line);
+                let closing = line.trim();
+                if closing.len() >= fence_len && closing.chars().all(|c| c == fence_char) {
+                    break;
+                }
+                code.push(line);
+            }
+            // cells are numbered by the line their block starts on
+            match self.code(index + 1, language, &code.join("\n"), &[])? {
+                Some(cell) => {
+                    flush(&mut cells, &mut text);
+                    cells.push(cell);
+                }
+                None => text.extend(block),
+            }
+        }
+        flush(&mut cells, &mut text);
+        Ok(Imported { title, cells })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notebook::CellContent;
+
+    fn import(content: &str, format: ImportFormat) -> Result<Imported, ImportError> {
+        let languages = BTreeMap::from([
+            ("promql".to_string(), "prometheus".to_string()),
+            ("sql".to_string(), "postgres".to_string()),
+        ]);
+        let body_option = |plugin: &str| {
+            if plugin == "postgres" {
+                "statement".to_string()
+            } else {
+                "query".to_string()
+            }
+        };
+        Importer {
+            languages: &languages,
+            body_option: &body_option,
+        }
+        .import(content, format)
+    }
+
+    fn contents(imported: &Imported) -> Vec<CellContent> {
+        imported.cells.iter().map(|c| c.content.clone()).collect()
+    }
+
+    fn plugin(plugin: &str, options: &[(&str, &str)]) -> CellContent {
+        CellContent::Plugin {
+            plugin: plugin.into(),
+            options: options
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        }
+    }
+
+    fn markdown(source: &str) -> CellContent {
+        CellContent::Markdown {
+            source: source.into(),
+        }
+    }
+
+    #[test]
+    fn test_import_jupyter() {
+        let notebook = r##"{
+            "nbformat": 4,
+            "nbformat_minor": 5,
+            "metadata": {"kernelspec": {"language": "python", "name": "python3"}},
+            "cells": [
+                {"cell_type": "markdown", "metadata": {}, "source": ["# API down\n", "check the pods"]},
+                {"cell_type": "code", "metadata": {}, "outputs": [], "source": "@plugin=k8s\n@namespace=api\npods"},
+                {"cell_type": "code", "metadata": {"tags": ["@plugin=prometheus", "@step=30s", "slow"]}, "outputs": [], "source": "# p99\nup"},
+                {"cell_type": "code", "metadata": {}, "outputs": [], "source": ["%%sql\n", "select 1"]},
+                {"cell_type": "code", "metadata": {}, "outputs": [], "source": "@retry\ndef f(): pass"},
+                {"cell_type": "code", "metadata": {}, "outputs": [], "source": ""}
+            ]
+        }"##;
+        let imported = import(notebook, ImportFormat::Jupyter).unwrap();
+        assert_eq!(Some("API down".to_string()), imported.title);
+        assert_eq!(
+            vec![
+                markdown("# API down\ncheck the pods"),
+                plugin("k8s", &[("namespace", "api"), ("query", "pods")]),
+                plugin("prometheus", &[("step", "30s"), ("query", "# p99\nup")]),
+                plugin("postgres", &[("statement", "select 1")]),
+                markdown("```python\n@retry\ndef f(): pass\n```"),
+            ],
+            contents(&imported)
+        );
+    }
+
+    #[test]
+    fn test_import_jupyter_errors() {
+        assert_eq!(
+            ImportError::UnsupportedVersion(3),
+            import(
+                r#"{"nbformat": 3, "worksheets": []}"#,
+                ImportFormat::Jupyter
+            )
+            .unwrap_err()
+        );
+        assert!(matches!(
+            import("not json", ImportFormat::Jupyter).unwrap_err(),
+            ImportError::InvalidJson(_)
+        ));
+        let broken = r#"{"nbformat": 4, "cells": [{"cell_type": "code", "source": "@plugin=k8s\n@namespace"}]}"#;
+        assert!(matches!(
+            import(broken, ImportFormat::Jupyter).unwrap_err(),
+            ImportError::InvalidCell(1, _)
+        ));
+    }
+
+    #[test]
+    fn test_import_markdown() {
+        let document = "# Disk full\n\nFirst check the usage:\n\n```promql\nnode_filesystem_avail_bytes\n```\n\nThen the logs:\n\n~~~\n@plugin=loki\n@limit=100\n{app=\"api\"}\n~~~\n\nClean up with:\n\n```bash\nrm -rf /tmp/cache\n```\n";
+        let imported = import(document, ImportFormat::Markdown).unwrap();
+        assert_eq!(Some("Disk full".to_string()), imported.title);
+        assert_eq!(
+            vec![
+                markdown("First check the usage:"),
+                plugin("prometheus", &[("query", "node_filesystem_avail_bytes")]),
+                markdown("Then the logs:"),
+                plugin("loki", &[("limit", "100"), ("query", "{app=\"api\"}")]),
+                markdown("Clean up with:\n\n```bash\nrm -rf /tmp/cache\n```"),
+            ],
+            contents(&imported)
+        );
+    }
+
+    #[test]
+    fn test_detect_format() {
+        assert_eq!(
+            ImportFormat::Jupyter,
+            ImportFormat::detect("\n {\"cells\": []}")
+        );
+        assert_eq!(ImportFormat::Markdown, ImportFormat::detect("# runbook"));
+        assert_eq!(Some(ImportFormat::Jupyter), ImportFormat::parse("ipynb"));
+        assert_eq!(None, ImportFormat::parse("docx"));
+    }
+}
@@ -0,0 +1,126 @@
+use crate::auth;
+use crate::databook;
+use crate::databook::imports_server::Imports;
+use crate::import::{ImportFormat, Importer, DEFAULT_LANGUAGES};
+use crate::notebook::Notebook;
+use crate::notebook_api::{self, NotebookApiError};
+use crate::policy::Principal;
+use crate::timeline_api::empty_as_none;
+use crate::{rest, run_blocking, CONFIG, PLUGINS};
+
+use rocket_contrib::json::Json;
+use std::collections::BTreeMap;
+use tonic::{Request, Response, Status};
+use tracing::instrument;
+
+const DEFAULT_TITLE: &str = "Imported notebook";
+
+// languages is the table of the server config (or the default one) with
+// the languages of the request on top
+fn languages(extra: BTreeMap<String, String>) -> BTreeMap<String, String> {
+    let mut languages: BTreeMap<String, String> =
+        match CONFIG.get().and_then(|c| c.import_languages.as_ref()) {
+            Some(languages) => languages.clone(),
+            None => DEFAULT_LANGUAGES
+                .iter()
+                .map(|(language, plugin)| (language.to_string(), plugin.to_string()))
+                .collect(),
+        };
+    languages.extend(extra);
+    languages
+        .into_iter()
+        .map(|(language, plugin)| (language.to_lowercase(), plugin))
+        .collect()
+}
+
+// import_notebook converts a Jupyter notebook or a Markdown document and
+// stores it as a new notebook
+pub fn import_notebook(
+    content: &str,
+    format: Option<&str>,
+    owner: String,
+    title: Option<String>,
+    tags: Vec<String>,
+    extra_languages: BTreeMap<String, String>,
+    principal: &Principal,
+) -> Result<Notebook, NotebookApiError> {
+    let format = match format {
+        None => ImportFormat::detect(content),
+        Some(format) => ImportFormat::parse(format).ok_or_else(|| {
+            NotebookApiError::InvalidRequest(format!(
+                "unknown format {:?}, expected jupyter or markdown",
+                format
+            ))
+        })?,
+    };
+    let plugins = PLUGINS
+        .get()
+        .ok_or_else(|| NotebookApiError::Internal("No plugins setup".into()))?
+        .read()
+        .map_err(|e| NotebookApiError::Internal(format!("could not lock plugins {:?}", e)))?;
+    let body_option = |plugin: &str| plugins.body_option(plugin);
+    let languages = languages(extra_languages);
+    let imported = Importer {
+        languages: &languages,
+        body_option: &body_option,
+    }
+    .import(content, format)
+    .map_err(|e| NotebookApiError::InvalidRequest(e.to_string()))?;
+    drop(plugins);
+
+    let title = title
+        .or(imported.title)
+        .unwrap_or_else(|| DEFAULT_TITLE.to_string());
+    notebook_api::insert_notebook(Notebook::new(title, owner, tags, imported.cells), principal)
+}
+
+#[derive(Debug, Default)]
+pub struct ImportsGrpc {}
+
+#[tonic::async_trait]
+impl Imports for ImportsGrpc {
+    #[instrument(skip(request))]
+    async fn import_notebook(
+        &self,
+        request: Request<databook::ImportNotebookRequest>,
+    ) -> Result<Response<databook::Notebook>, Status> {
+        tracing::info!("received import notebook request");
+        let actor = auth::actor(&request, "");
+        run_blocking(move || {
+            let request = request.into_inner();
+            import_notebook(
+                &request.content,
+                empty_as_none(request.format).as_deref(),
+                notebook_api::owner(&actor, request.owner)?,
+                empty_as_none(request.title),
+                request.tags,
+                request.languages.into_iter().collect(),
+                &actor.principal,
+            )
+            .map(notebook_api::to_grpc_notebook)
+        })
+        .await
+    }
+}
+
+#[post("/notebooks/import", data = "<request>")]
+pub fn rest_import(
+    caller: auth::Caller,
+    request: Json<rest::ImportNotebookRequest>,
+) -> Json<rest::NotebookResponse> {
+    tracing::info!("received import notebook request");
+    let request = request.into_inner();
+    let actor = caller.actor(None);
+    let notebook = notebook_api::owner(&actor, request.owner).and_then(|owner| {
+        import_notebook(
+            &request.content,
+            request.format.as_deref(),
+            owner,
+            request.title,
+            request.tags,
+            request.languages,
+            &actor.principal,
+        )
+    });
+    notebook_api::rest_notebook_response(notebook)
+}
@@ -0,0 +1,301 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// An incident is declared on the notebook used to work on it. Status
+// changes are kept with who made them and when, so the time to mitigate
+// and to resolve are computed rather than typed in, and every change is
+// also written to the timeline of the notebook.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Sev1,
+    Sev2,
+    Sev3,
+    Sev4,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Investigating,
+    Identified,
+    Mitigated,
+    Resolved,
+}
+
+impl Severity {
+    pub fn parse(severity: &str) -> Option<Self> {
+        match severity.to_lowercase().as_str() {
+            "sev1" | "1" => Some(Severity::Sev1),
+            "sev2" | "2" => Some(Severity::Sev2),
+            "sev3" | "3" => Some(Severity::Sev3),
+            "sev4" | "4" => Some(Severity::Sev4),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self {
+            Severity::Sev1 => "sev1",
+            Severity::Sev2 => "sev2",
+            Severity::Sev3 => "sev3",
+            Severity::Sev4 => "sev4",
+        };
+        write!(f, "{}", severity)
+    }
+}
+
+impl Status {
+    pub fn parse(status: &str) -> Option<Self> {
+        match status.to_lowercase().as_str() {
+            "investigating" => Some(Status::Investigating),
+            "identified" => Some(Status::Identified),
+            "mitigated" => Some(Status::Mitigated),
+            "resolved" => Some(Status::Resolved),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = match self {
+            Status::Investigating => "investigating",
+            Status::Identified => "identified",
+            Status::Mitigated => "mitigated",
+            Status::Resolved => "resolved",
+        };
+        write!(f, "{}", status)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Transition {
+    // None for the declaration
+    pub from: Option<Status>,
+    pub to: Status,
+    pub user: Option<String>,
+    pub at: DateTime<Utc>,
+    pub note: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Incident {
+    pub severity: Severity,
+    pub status: Status,
+    pub commander: Option<String>,
+    pub responders: Vec<String>,
+    pub declared_at: DateTime<Utc>,
+    // every status change, oldest first, starting with the declaration
+    pub transitions: Vec<Transition>,
+}
+
+// Metrics are durations in seconds from the declaration, None until the
+// incident gets there
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Metrics {
+    pub time_to_identify: Option<i64>,
+    pub time_to_mitigate: Option<i64>,
+    pub time_to_resolve: Option<i64>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum IncidentError {
+    AlreadyDeclared,
+    NotDeclared,
+    SameStatus(Status),
+}
+
+impl fmt::Display for IncidentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IncidentError::AlreadyDeclared => {
+                write!(f, "an incident is already declared on the notebook")
+            }
+            IncidentError::NotDeclared => write!(f, "no incident is declared on the notebook"),
+            IncidentError::SameStatus(status) => write!(f, "the incident is already {}", status),
+        }
+    }
+}
+
+impl Incident {
+    pub fn declare(
+        severity: Severity,
+        commander: Option<String>,
+        responders: Vec<String>,
+        user: Option<String>,
+        at: DateTime<Utc>,
+    ) -> Self {
+        let mut incident = Self {
+            severity,
+            status: Status::Investigating,
+            commander: None,
+            responders: vec![],
+            declared_at: at,
+            transitions: vec![Transition {
+                from: None,
+                to: Status::Investigating,
+                user: user.clone(),
+                at,
+                note: None,
+            }],
+        };
+        incident.set_commander(commander);
+        for responder in responders.into_iter().chain(user) {
+            incident.add_responder(&responder);
+        }
+        incident
+    }
+
+    pub fn add_responder(&mut self, responder: &str) {
+        if !responder.is_empty() && !self.responders.iter().any(|r| r == responder) {
+            self.responders.push(responder.to_string());
+        }
+    }
+
+    // the commander is a responder too
+    pub fn set_commander(&mut self, commander: Option<String>) {
+        if let Some(commander) = &commander {
+            self.add_responder(commander);
+        }
+        self.commander = commander;
+    }
+
+    // transition moves the incident to another status, going back (e.g.
+    // reopening a resolved incident) is allowed and recorded the same way
+    pub fn transition(
+        &mut self,
+        to: Status,
+        user: Option<String>,
+        note: Option<String>,
+        at: DateTime<Utc>,
+    ) -> Result<&Transition, IncidentError> {
+        if to == self.status {
+            return Err(IncidentError::SameStatus(to));
+        }
+        if let Some(user) = &user {
+            self.add_responder(user);
+        }
+        self.transitions.push(Transition {
+            from: Some(self.status),
+            to,
+            user,
+            at,
+            note,
+        });
+        self.status = to;
+        Ok(self.transitions.last().expect("just pushed"))
+    }
+
+    // reached is when the incident first got to `status` or a later one,
+    // e.g. resolving without saying it was mitigated also mitigates it
+    fn reached(&self, status: Status) -> Option<DateTime<Utc>> {
+        self.transitions
+            .iter()
+            .find(|t| t.to >= status)
+            .map(|t| t.at)
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        let since = |at: Option<DateTime<Utc>>| at.map(|at| (at - self.declared_at).num_seconds());
+        Metrics {
+            time_to_identify: since(self.reached(Status::Identified)),
+            time_to_mitigate: since(self.reached(Status::Mitigated)),
+            // a reopened incident is resolved again later
+            time_to_resolve: if self.status == Status::Resolved {
+                since(self.transitions.last().map(|t| t.at))
+            } else {
+                None
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn at(minutes: i64) -> DateTime<Utc> {
+        "2026-03-04T10:00:00Z".parse::<DateTime<Utc>>().unwrap() + Duration::minutes(minutes)
+    }
+
+    fn declared() -> Incident {
+        Incident::declare(
+            Severity::Sev2,
+            Some("ana".into()),
+            vec!["bo".into(), "ana".into()],
+            Some("elias".into()),
+            at(0),
+        )
+    }
+
+    #[test]
+    fn test_declare() {
+        let incident = declared();
+        assert_eq!(Status::Investigating, incident.status);
+        assert_eq!(vec!["ana", "bo", "elias"], incident.responders);
+        assert_eq!(1, incident.transitions.len());
+        assert_eq!(Metrics::default(), incident.metrics());
+    }
+
+    #[test]
+    fn test_transitions_and_metrics() {
+        let mut incident = declared();
+        incident
+            .transition(
+                Status::Identified,
+                Some("bo".into()),
+                Some("bad deploy".into()),
+                at(12),
+            )
+            .unwrap();
+        assert_eq!(
+            Err(IncidentError::SameStatus(Status::Identified)),
+            incident.transition(Status::Identified, None, None, at(13))
+        );
+        incident
+            .transition(Status::Resolved, Some("dan".into()), None, at(40))
+            .unwrap();
+        assert_eq!(
+            Metrics {
+                time_to_identify: Some(12 * 60),
+                time_to_mitigate: Some(40 * 60),
+                time_to_resolve: Some(40 * 60),
+            },
+            incident.metrics()
+        );
+        assert!(incident.responders.contains(&"dan".to_string()));
+
+        // reopened, then resolved again
+        incident
+            .transition(Status::Investigating, None, None, at(50))
+            .unwrap();
+        assert_eq!(None, incident.metrics().time_to_resolve);
+        incident
+            .transition(Status::Mitigated, None, None, at(55))
+            .unwrap();
+        incident
+            .transition(Status::Resolved, None, None, at(70))
+            .unwrap();
+        let metrics = incident.metrics();
+        assert_eq!(
+            (Some(40 * 60), Some(70 * 60)),
+            (metrics.time_to_mitigate, metrics.time_to_resolve)
+        );
+        assert_eq!(Some(Status::Mitigated), incident.transitions[5].from);
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Some(Severity::Sev1), Severity::parse("SEV1"));
+        assert_eq!(Some(Severity::Sev3), Severity::parse("3"));
+        assert_eq!(None, Severity::parse("critical"));
+        assert_eq!(Some(Status::Mitigated), Status::parse("mitigated"));
+        assert_eq!("identified", Status::Identified.to_string());
+    }
+}
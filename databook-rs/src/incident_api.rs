@@ -0,0 +1,385 @@
+use crate::auth;
+use crate::databook;
+use crate::databook::incidents_server::Incidents;
+use crate::incident::{
+    Incident, IncidentError, Metrics, Severity, Status as IncidentStatus, Transition,
+};
+use crate::notebook_api::{self, NotebookApiError};
+use crate::policy::{Actor, Permission, Principal};
+use crate::schedule_api;
+use crate::timeline::ExecutionEvent;
+use crate::timeline_api::{self, empty_as_none};
+use crate::{rest, run_blocking};
+
+use chrono::{DateTime, Utc};
+use rocket_contrib::json::Json;
+use std::collections::BTreeMap;
+use tonic::{Request, Response, Status};
+use tracing::instrument;
+
+impl From<IncidentError> for NotebookApiError {
+    fn from(e: IncidentError) -> Self {
+        NotebookApiError::InvalidRequest(e.to_string())
+    }
+}
+
+fn parse_severity(severity: &str) -> Result<Severity, NotebookApiError> {
+    Severity::parse(severity).ok_or_else(|| {
+        NotebookApiError::InvalidRequest(format!(
+            "invalid severity {:?}, use sev1 to sev4",
+            severity
+        ))
+    })
+}
+
+fn parse_status(status: &str) -> Result<IncidentStatus, NotebookApiError> {
+    IncidentStatus::parse(status).ok_or_else(|| {
+        NotebookApiError::InvalidRequest(format!(
+            "invalid status {:?}, use investigating, identified, mitigated or resolved",
+            status
+        ))
+    })
+}
+
+// change runs `f` on the incident of the notebook and writes the change it
+// describes to the timeline, once the notebook is saved
+fn change(
+    notebook_id: &str,
+    actor: Actor,
+    f: impl FnOnce(
+        &mut Option<Incident>,
+        DateTime<Utc>,
+    ) -> Result<(String, BTreeMap<String, String>), NotebookApiError>,
+) -> Result<Incident, NotebookApiError> {
+    // fails before the notebook is changed when there is no timeline
+    timeline_api::timeline()?;
+    let at = Utc::now();
+    let (notebook, (summary, details)) =
+        notebook_api::change_metadata(notebook_id, &actor, |notebook| {
+            f(&mut notebook.metadata.incident, at)
+        })?;
+    let incident = notebook
+        .metadata
+        .incident
+        .ok_or(IncidentError::NotDeclared)?;
+    let mut details = details;
+    details.insert("severity".into(), incident.severity.to_string());
+    details.insert("status".into(), incident.status.to_string());
+    timeline_api::append(&ExecutionEvent::incident(
+        notebook_id,
+        actor.name,
+        summary,
+        details,
+        at,
+    ))?;
+    Ok(incident)
+}
+
+fn declared(incident: &mut Option<Incident>) -> Result<&mut Incident, NotebookApiError> {
+    incident
+        .as_mut()
+        .ok_or_else(|| IncidentError::NotDeclared.into())
+}
+
+pub fn declare_incident(
+    notebook_id: &str,
+    severity: &str,
+    commander: Option<String>,
+    responders: Vec<String>,
+    actor: Actor,
+) -> Result<Incident, NotebookApiError> {
+    let severity = parse_severity(severity)?;
+    let user = actor.name.clone();
+    change(notebook_id, actor, |incident, at| {
+        if incident.is_some() {
+            return Err(IncidentError::AlreadyDeclared.into());
+        }
+        let declared = Incident::declare(severity, commander, responders, user, at);
+        let mut details = BTreeMap::new();
+        if let Some(commander) = &declared.commander {
+            details.insert("commander".into(), commander.clone());
+        }
+        details.insert("responders".into(), declared.responders.join(","));
+        *incident = Some(declared);
+        Ok((format!("declared a {} incident", severity), details))
+    })
+}
+
+pub fn transition_incident(
+    notebook_id: &str,
+    status: &str,
+    actor: Actor,
+    note: Option<String>,
+) -> Result<Incident, NotebookApiError> {
+    let status = parse_status(status)?;
+    let user = actor.name.clone();
+    let incident = change(notebook_id, actor, |incident, at| {
+        let transition = declared(incident)?.transition(status, user, note, at)?;
+        let mut details = BTreeMap::new();
+        if let Some(from) = transition.from {
+            details.insert("from".into(), from.to_string());
+        }
+        if let Some(note) = &transition.note {
+            details.insert("note".into(), note.clone());
+        }
+        Ok((format!("incident {}", status), details))
+    })?;
+    // the scheduler would stop them before their next run anyway
+    if status == IncidentStatus::Resolved {
+        if let Err(e) = schedule_api::stop_schedules(notebook_id, schedule_api::INCIDENT_RESOLVED) {
+            tracing::error!(
+                "could not stop the schedules of notebook {} {:?}",
+                notebook_id,
+                e
+            );
+        }
+    }
+    Ok(incident)
+}
+
+// update_incident changes the severity or the commander, or adds
+// responders. Fields left to None are not changed.
+pub fn update_incident(
+    notebook_id: &str,
+    severity: Option<&str>,
+    commander: Option<String>,
+    add_responders: Vec<String>,
+    actor: Actor,
+) -> Result<Incident, NotebookApiError> {
+    let severity = severity.map(parse_severity).transpose()?;
+    change(notebook_id, actor, |incident, _| {
+        let incident = declared(incident)?;
+        let mut changes = vec![];
+        let mut details = BTreeMap::new();
+        if let Some(severity) = severity.filter(|s| *s != incident.severity) {
+            details.insert("previous_severity".into(), incident.severity.to_string());
+            incident.severity = severity;
+            changes.push(format!("severity {}", severity));
+        }
+        if let Some(commander) = commander.filter(|c| incident.commander.as_ref() != Some(c)) {
+            details.insert("commander".into(), commander.clone());
+            changes.push(format!("commander {}", commander));
+            incident.set_commander(Some(commander));
+        }
+        let added: Vec<String> = add_responders
+            .into_iter()
+            .filter(|r| !r.is_empty() && !incident.responders.contains(r))
+            .collect();
+        if !added.is_empty() {
+            for responder in &added {
+                incident.add_responder(responder);
+            }
+            details.insert("added_responders".into(), added.join(","));
+            changes.push(format!("responders +{}", added.join(" +")));
+        }
+        if changes.is_empty() {
+            return Err(NotebookApiError::InvalidRequest(
+                "nothing to change on the incident".into(),
+            ));
+        }
+        Ok((format!("incident {}", changes.join(", ")), details))
+    })
+}
+
+pub fn get_incident(
+    notebook_id: &str,
+    principal: &Principal,
+) -> Result<Incident, NotebookApiError> {
+    notebook_api::get_authorized(notebook_id, principal, Permission::View)?
+        .metadata
+        .incident
+        .ok_or_else(|| {
+            NotebookApiError::NotFound(format!(
+                "no incident is declared on notebook {}",
+                notebook_id
+            ))
+        })
+}
+
+fn to_grpc_transition(transition: Transition) -> databook::IncidentTransition {
+    databook::IncidentTransition {
+        from: transition.from.map(|s| s.to_string()).unwrap_or_default(),
+        to: transition.to.to_string(),
+        user: transition.user.unwrap_or_default(),
+        at: transition.at.timestamp_millis(),
+        note: transition.note.unwrap_or_default(),
+    }
+}
+
+fn to_grpc_metrics(metrics: Metrics) -> databook::IncidentMetrics {
+    let seconds = |s: Option<i64>| s.unwrap_or(-1);
+    databook::IncidentMetrics {
+        time_to_identify: seconds(metrics.time_to_identify),
+        time_to_mitigate: seconds(metrics.time_to_mitigate),
+        time_to_resolve: seconds(metrics.time_to_resolve),
+    }
+}
+
+pub fn to_grpc_incident(incident: Incident) -> databook::Incident {
+    let metrics = incident.metrics();
+    databook::Incident {
+        severity: incident.severity.to_string(),
+        status: incident.status.to_string(),
+        commander: incident.commander.unwrap_or_default(),
+        responders: incident.responders,
+        declared_at: incident.declared_at.timestamp_millis(),
+        transitions: incident
+            .transitions
+            .into_iter()
+            .map(to_grpc_transition)
+            .collect(),
+        metrics: Some(to_grpc_metrics(metrics)),
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct IncidentsGrpc {}
+
+#[tonic::async_trait]
+impl Incidents for IncidentsGrpc {
+    #[instrument]
+    async fn declare_incident(
+        &self,
+        request: Request<databook::DeclareIncidentRequest>,
+    ) -> Result<Response<databook::Incident>, Status> {
+        tracing::info!("received declare incident request");
+        let actor = auth::actor(&request, &request.get_ref().user);
+        run_blocking(|| {
+            let request = request.into_inner();
+            declare_incident(
+                &request.notebook_id,
+                &request.severity,
+                empty_as_none(request.commander),
+                request.responders,
+                actor,
+            )
+            .map(to_grpc_incident)
+        })
+        .await
+    }
+
+    #[instrument]
+    async fn transition_incident(
+        &self,
+        request: Request<databook::TransitionIncidentRequest>,
+    ) -> Result<Response<databook::Incident>, Status> {
+        tracing::info!("received transition incident request");
+        let actor = auth::actor(&request, &request.get_ref().user);
+        run_blocking(|| {
+            let request = request.into_inner();
+            transition_incident(
+                &request.notebook_id,
+                &request.status,
+                actor,
+                empty_as_none(request.note),
+            )
+            .map(to_grpc_incident)
+        })
+        .await
+    }
+
+    #[instrument]
+    async fn update_incident(
+        &self,
+        request: Request<databook::UpdateIncidentRequest>,
+    ) -> Result<Response<databook::Incident>, Status> {
+        tracing::info!("received update incident request");
+        let actor = auth::actor(&request, &request.get_ref().user);
+        run_blocking(|| {
+            let request = request.into_inner();
+            update_incident(
+                &request.notebook_id,
+                empty_as_none(request.severity).as_deref(),
+                empty_as_none(request.commander),
+                request.add_responders,
+                actor,
+            )
+            .map(to_grpc_incident)
+        })
+        .await
+    }
+
+    #[instrument]
+    async fn get_incident(
+        &self,
+        request: Request<databook::GetIncidentRequest>,
+    ) -> Result<Response<databook::Incident>, Status> {
+        let principal = auth::actor(&request, "").principal;
+        run_blocking(move || {
+            get_incident(&request.into_inner().notebook_id, &principal).map(to_grpc_incident)
+        })
+        .await
+    }
+}
+
+fn rest_incident_response(
+    result: Result<Incident, NotebookApiError>,
+) -> Json<rest::IncidentResponse> {
+    match result {
+        Ok(incident) => Json(rest::IncidentResponse {
+            metrics: Some(incident.metrics()),
+            incident: Some(incident),
+            error: None,
+        }),
+        Err(e) => Json(rest::IncidentResponse {
+            incident: None,
+            metrics: None,
+            error: Some(format!("{:?}", e)),
+        }),
+    }
+}
+
+#[post("/notebooks/<id>/incident", data = "<request>")]
+pub fn rest_declare(
+    caller: auth::Caller,
+    id: String,
+    request: Json<rest::DeclareIncidentRequest>,
+) -> Json<rest::IncidentResponse> {
+    tracing::info!("received declare incident request");
+    let request = request.into_inner();
+    rest_incident_response(declare_incident(
+        &id,
+        &request.severity,
+        request.commander,
+        request.responders,
+        caller.actor(request.user),
+    ))
+}
+
+#[post("/notebooks/<id>/incident/status", data = "<request>")]
+pub fn rest_transition(
+    caller: auth::Caller,
+    id: String,
+    request: Json<rest::TransitionIncidentRequest>,
+) -> Json<rest::IncidentResponse> {
+    tracing::info!("received transition incident request");
+    let request = request.into_inner();
+    rest_incident_response(transition_incident(
+        &id,
+        &request.status,
+        caller.actor(request.user),
+        request.note,
+    ))
+}
+
+#[put("/notebooks/<id>/incident", data = "<request>")]
+pub fn rest_update(
+    caller: auth::Caller,
+    id: String,
+    request: Json<rest::UpdateIncidentRequest>,
+) -> Json<rest::IncidentResponse> {
+    tracing::info!("received update incident request");
+    let request = request.into_inner();
+    rest_incident_response(update_incident(
+        &id,
+        request.severity.as_deref(),
+        request.commander,
+        request.add_responders,
+        caller.actor(request.user),
+    ))
+}
+
+#[get("/notebooks/<id>/incident")]
+pub fn rest_get(caller: auth::Caller, id: String) -> Json<rest::IncidentResponse> {
+    rest_incident_response(get_incident(&id, &caller.actor(None).principal))
+}
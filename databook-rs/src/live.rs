@@ -0,0 +1,341 @@
+use crate::approval::ApprovalRequest;
+use crate::crdt::SequencedOperation;
+use crate::notebook::Cell;
+use crate::timeline::ExecutionEvent;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use tokio::sync::broadcast;
+
+// how many past messages a viewer gets when joining late
+pub const BACKFILL_SIZE: usize = 100;
+// slow viewers lagging more than this skip messages
+const CHANNEL_CAPACITY: usize = 256;
+
+// LiveEvent is something that happened on a notebook while people follow it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LiveEvent {
+    // a cell was added or its content changed
+    CellEdited {
+        cell: Cell,
+    },
+    CellRemoved {
+        cell_id: String,
+    },
+    // the order of the cells changed, it lists all of them
+    CellsReordered {
+        cell_ids: Vec<String>,
+    },
+    ExecutionStarted {
+        cell_id: String,
+        user: Option<String>,
+        plugin: String,
+    },
+    // the execution carries the output, logs and error of the run
+    ExecutionFinished {
+        execution: Box<ExecutionEvent>,
+    },
+    // cells whose last result no longer matches their inputs, sent after
+    // a run when cells referring to it (directly or not) are out of date
+    CellsStale {
+        cell_ids: Vec<String>,
+    },
+    // an invocation waiting for approval was made, approved or rejected,
+    // see approval.rs
+    Approval {
+        approval: Box<ApprovalRequest>,
+    },
+    // the condition of a schedule started to hold, see schedule.rs
+    ConditionMet {
+        schedule_id: String,
+        cell_id: Option<String>,
+        condition: String,
+        event_id: Option<String>,
+    },
+    // CRDT operations merged into the notebook, the cell events above
+    // describe their result for viewers that do not replay them
+    Operations {
+        user: Option<String>,
+        operations: Vec<SequencedOperation>,
+    },
+    // someone started or stopped following, viewers lists who is left
+    Presence {
+        user: String,
+        joined: bool,
+        viewers: Vec<String>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LiveMessage {
+    // increases by one per message of the same notebook
+    pub seq: u64,
+    pub notebook_id: String,
+    pub at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub event: LiveEvent,
+}
+
+// Subscription is what a new viewer gets, the backfill followed by
+// everything received on the receiver, without gaps or duplicates
+pub struct Subscription {
+    pub backfill: Vec<LiveMessage>,
+    pub receiver: broadcast::Receiver<LiveMessage>,
+}
+
+struct Channel {
+    sender: broadcast::Sender<LiveMessage>,
+    backlog: VecDeque<LiveMessage>,
+    next_seq: u64,
+    // users following the notebook and how many connections each has open
+    viewers: BTreeMap<String, usize>,
+}
+
+impl Channel {
+    fn new() -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Self {
+            sender,
+            backlog: VecDeque::new(),
+            next_seq: 1,
+            viewers: BTreeMap::new(),
+        }
+    }
+
+    fn push(&mut self, notebook_id: &str, event: LiveEvent) -> LiveMessage {
+        let message = LiveMessage {
+            seq: self.next_seq,
+            notebook_id: notebook_id.to_string(),
+            at: Utc::now(),
+            event,
+        };
+        self.next_seq += 1;
+        self.backlog.push_back(message.clone());
+        if self.backlog.len() > BACKFILL_SIZE {
+            self.backlog.pop_front();
+        }
+        // sending only fails when nobody is following, which is fine
+        let _ = self.sender.send(message.clone());
+        message
+    }
+
+    fn presence(&mut self, notebook_id: &str, user: &str, joined: bool) {
+        let viewers = self.viewers.keys().cloned().collect();
+        self.push(
+            notebook_id,
+            LiveEvent::Presence {
+                user: user.to_string(),
+                joined,
+                viewers,
+            },
+        );
+    }
+}
+
+// LiveHub fans out the activity of each notebook to everyone following it.
+// Messages are only kept in memory, the timeline is the durable record.
+#[derive(Default)]
+pub struct LiveHub {
+    channels: Mutex<HashMap<String, Channel>>,
+}
+
+impl LiveHub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn channels(&self) -> MutexGuard<'_, HashMap<String, Channel>> {
+        // a panic while publishing leaves the channels usable
+        self.channels.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn publish(&self, notebook_id: &str, event: LiveEvent) -> LiveMessage {
+        self.channels()
+            .entry(notebook_id.to_string())
+            .or_insert_with(Channel::new)
+            .push(notebook_id, event)
+    }
+
+    // subscribe registers the user as a viewer, leave must be called once
+    // the viewer is gone
+    pub fn subscribe(&self, notebook_id: &str, user: &str) -> Subscription {
+        let mut channels = self.channels();
+        let channel = channels
+            .entry(notebook_id.to_string())
+            .or_insert_with(Channel::new);
+        let backfill = channel.backlog.iter().cloned().collect();
+        let receiver = channel.sender.subscribe();
+        *channel.viewers.entry(user.to_string()).or_insert(0) += 1;
+        channel.presence(notebook_id, user, true);
+        Subscription { backfill, receiver }
+    }
+
+    pub fn leave(&self, notebook_id: &str, user: &str) {
+        let mut channels = self.channels();
+        let channel = match channels.get_mut(notebook_id) {
+            Some(channel) => channel,
+            None => return,
+        };
+        match channel.viewers.get_mut(user) {
+            Some(connections) if *connections > 1 => *connections -= 1,
+            Some(_) => {
+                channel.viewers.remove(user);
+            }
+            None => return,
+        }
+        channel.presence(notebook_id, user, false);
+    }
+}
+
+// cell_changes lists the events turning the old cells into the new ones:
+// edits, then removals, then the new order if it changed
+pub fn cell_changes(old: &[Cell], new: &[Cell]) -> Vec<LiveEvent> {
+    let mut events: Vec<LiveEvent> = new
+        .iter()
+        .filter(|cell| !old.contains(cell))
+        .map(|cell| LiveEvent::CellEdited { cell: cell.clone() })
+        .collect();
+    events.extend(
+        old.iter()
+            .filter(|cell| !new.iter().any(|c| c.id == cell.id))
+            .map(|cell| LiveEvent::CellRemoved {
+                cell_id: cell.id.clone(),
+            }),
+    );
+
+    let old_order: Vec<&String> = old
+        .iter()
+        .map(|c| &c.id)
+        .filter(|id| new.iter().any(|c| &&c.id == id))
+        .collect();
+    let new_order: Vec<&String> = new.iter().map(|c| &c.id).collect();
+    let appended_only = new_order.starts_with(&old_order);
+    if !appended_only {
+        events.push(LiveEvent::CellsReordered {
+            cell_ids: new_order.into_iter().cloned().collect(),
+        });
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn cell(id: &str, source: &str) -> Cell {
+        let mut cell = Cell::markdown(source);
+        cell.id = id.to_string();
+        cell
+    }
+
+    fn viewers(hub: &LiveHub, notebook_id: &str) -> Vec<String> {
+        hub.channels()[notebook_id]
+            .viewers
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    fn removed(id: &str) -> LiveEvent {
+        LiveEvent::CellRemoved {
+            cell_id: id.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_late_viewer_gets_backfill() {
+        let hub = LiveHub::new();
+        hub.publish("n1", removed("a"));
+        hub.publish("n2", removed("other notebook"));
+        hub.publish("n1", removed("b"));
+
+        let mut subscription = hub.subscribe("n1", "elias");
+        let backfill: Vec<(u64, LiveEvent)> = subscription
+            .backfill
+            .into_iter()
+            .map(|m| (m.seq, m.event))
+            .collect();
+        assert_eq!(vec![(1, removed("a")), (2, removed("b"))], backfill);
+
+        // the join itself is the first live message
+        let joined = subscription.receiver.try_recv().unwrap();
+        assert_eq!(3, joined.seq);
+        assert_eq!(
+            LiveEvent::Presence {
+                user: "elias".into(),
+                joined: true,
+                viewers: vec!["elias".into()],
+            },
+            joined.event
+        );
+
+        hub.publish("n1", removed("c"));
+        assert_eq!(
+            removed("c"),
+            subscription.receiver.try_recv().unwrap().event
+        );
+    }
+
+    #[test]
+    fn test_backfill_is_bounded() {
+        let hub = LiveHub::new();
+        for i in 0..BACKFILL_SIZE + 10 {
+            hub.publish("n1", removed(&i.to_string()));
+        }
+        let subscription = hub.subscribe("n1", "elias");
+        assert_eq!(BACKFILL_SIZE, subscription.backfill.len());
+        assert_eq!(11, subscription.backfill[0].seq);
+    }
+
+    #[test]
+    fn test_presence_counts_connections() {
+        let hub = LiveHub::new();
+        let _first = hub.subscribe("n1", "elias");
+        let _second = hub.subscribe("n1", "elias");
+        let _other = hub.subscribe("n1", "maria");
+        assert_eq!(vec!["elias", "maria"], viewers(&hub, "n1"));
+
+        hub.leave("n1", "elias");
+        assert_eq!(vec!["elias", "maria"], viewers(&hub, "n1"));
+        hub.leave("n1", "elias");
+        assert_eq!(vec!["maria"], viewers(&hub, "n1"));
+    }
+
+    #[test]
+    fn test_cell_changes() {
+        let old = vec![cell("a", "# a"), cell("b", "# b"), cell("c", "# c")];
+        let new = vec![cell("c", "# c"), cell("a", "# a!"), cell("d", "# d")];
+        assert_eq!(
+            vec![
+                LiveEvent::CellEdited {
+                    cell: cell("a", "# a!")
+                },
+                LiveEvent::CellEdited {
+                    cell: cell("d", "# d")
+                },
+                removed("b"),
+                LiveEvent::CellsReordered {
+                    cell_ids: vec!["c".into(), "a".into(), "d".into()]
+                },
+            ],
+            cell_changes(&old, &new)
+        );
+    }
+
+    #[test]
+    fn test_appending_cells_keeps_order() {
+        let old = vec![cell("a", "# a")];
+        let new = vec![
+            cell("a", "# a"),
+            Cell {
+                id: "b".into(),
+                ..Cell::plugin("prometheus", BTreeMap::new())
+            },
+        ];
+        assert_eq!(1, cell_changes(&old, &new).len());
+    }
+}
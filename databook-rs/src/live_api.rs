@@ -0,0 +1,402 @@
+use crate::auth;
+use crate::crdt::Operation;
+use crate::databook::live_server::Live;
+use crate::databook::{self, live_message};
+use crate::live::{LiveEvent, LiveHub, LiveMessage, Subscription};
+use crate::notebook_api::{self, NotebookApiError};
+use crate::policy::{Actor, Permission, Principal};
+use crate::{rest, run_blocking, LIVE};
+
+use futures_util::{SinkExt, StreamExt};
+use rocket_contrib::json::Json;
+use serde::{Deserialize, Serialize};
+use std::pin::Pin;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::tungstenite::Message;
+use tonic::{Code, Status};
+use tracing::instrument;
+
+// user shown in the presence of viewers that did not say who they are
+const ANONYMOUS: &str = "anonymous";
+// messages waiting to be written to a single viewer
+const VIEWER_BUFFER: usize = 64;
+
+#[derive(Debug)]
+pub enum LiveError {
+    WebSocket(tokio_tungstenite::tungstenite::Error),
+    Json(serde_json::Error),
+    Notebook(NotebookApiError),
+}
+
+impl From<tokio_tungstenite::tungstenite::Error> for LiveError {
+    fn from(e: tokio_tungstenite::tungstenite::Error) -> Self {
+        LiveError::WebSocket(e)
+    }
+}
+
+impl From<serde_json::Error> for LiveError {
+    fn from(e: serde_json::Error) -> Self {
+        LiveError::Json(e)
+    }
+}
+
+// ClientMessage is what viewers send on the websocket, their edits
+#[derive(Debug, Deserialize)]
+struct ClientMessage {
+    operations: Vec<Operation>,
+}
+
+// ErrorMessage is sent back to a viewer whose message was refused
+#[derive(Debug, Serialize)]
+struct ErrorMessage {
+    error: String,
+}
+
+fn hub() -> Result<&'static LiveHub, NotebookApiError> {
+    LIVE.get()
+        .ok_or_else(|| NotebookApiError::Internal("No live hub setup".into()))
+}
+
+// publish sends the event to everyone following the notebook
+pub fn publish(notebook_id: &str, event: LiveEvent) {
+    if let Ok(hub) = hub() {
+        hub.publish(notebook_id, event);
+    }
+}
+
+fn user_or_anonymous(user: Option<String>) -> String {
+    user.filter(|u| !u.is_empty())
+        .unwrap_or_else(|| ANONYMOUS.to_string())
+}
+
+// follow subscribes to the notebook and forwards the backfill and the live
+// messages to the returned receiver. Dropping the receiver leaves the notebook.
+fn follow(hub: &'static LiveHub, notebook_id: String, user: String) -> mpsc::Receiver<LiveMessage> {
+    let (sender, receiver) = mpsc::channel(VIEWER_BUFFER);
+    let Subscription {
+        backfill,
+        receiver: mut live,
+    } = hub.subscribe(&notebook_id, &user);
+    tokio::spawn(async move {
+        for message in backfill {
+            if sender.send(message).await.is_err() {
+                hub.leave(&notebook_id, &user);
+                return;
+            }
+        }
+        loop {
+            tokio::select! {
+                _ = sender.closed() => break,
+                received = live.recv() => match received {
+                    Ok(message) => {
+                        if sender.send(message).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::warn!("viewer {} of {} skipped {} messages", user, notebook_id, skipped);
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+            }
+        }
+        hub.leave(&notebook_id, &user);
+    });
+    receiver
+}
+
+// check_can_view fails when the notebook does not exist or the policy does
+// not let the principal view it
+async fn check_can_view(id: String, principal: Principal) -> Result<(), Status> {
+    run_blocking(move || {
+        notebook_api::get_authorized(&id, &principal, Permission::View).map(|_| ())
+    })
+    .await
+    .map(|_| ())
+}
+
+fn to_grpc_message(message: LiveMessage) -> databook::LiveMessage {
+    let event = match message.event {
+        LiveEvent::CellEdited { cell } => {
+            live_message::Event::CellEdited(notebook_api::to_grpc_cell(cell))
+        }
+        LiveEvent::CellRemoved { cell_id } => live_message::Event::CellRemoved(cell_id),
+        LiveEvent::CellsReordered { cell_ids } => {
+            live_message::Event::CellsReordered(databook::CellsReordered { cell_ids })
+        }
+        LiveEvent::CellsStale { cell_ids } => {
+            live_message::Event::CellsStale(databook::CellsStale { cell_ids })
+        }
+        LiveEvent::ExecutionStarted {
+            cell_id,
+            user,
+            plugin,
+        } => live_message::Event::ExecutionStarted(databook::ExecutionStarted {
+            cell_id,
+            user: user.unwrap_or_default(),
+            plugin,
+        }),
+        LiveEvent::Approval { approval } => {
+            live_message::Event::Approval(crate::approval_api::to_grpc_request(*approval))
+        }
+        LiveEvent::ConditionMet {
+            schedule_id,
+            cell_id,
+            condition,
+            event_id,
+        } => live_message::Event::ConditionMet(databook::ConditionMet {
+            schedule_id,
+            cell_id: cell_id.unwrap_or_default(),
+            condition,
+            event_id: event_id.unwrap_or_default(),
+        }),
+        LiveEvent::ExecutionFinished { execution } => {
+            live_message::Event::ExecutionFinished(crate::timeline_api::to_grpc_event(*execution))
+        }
+        LiveEvent::Operations { user, operations } => {
+            live_message::Event::Operations(databook::Operations {
+                user: user.unwrap_or_default(),
+                operations: operations
+                    .into_iter()
+                    .map(notebook_api::to_grpc_sequenced)
+                    .collect(),
+            })
+        }
+        LiveEvent::Presence {
+            user,
+            joined,
+            viewers,
+        } => live_message::Event::Presence(databook::Presence {
+            user,
+            joined,
+            viewers,
+        }),
+    };
+    databook::LiveMessage {
+        seq: message.seq,
+        notebook_id: message.notebook_id,
+        at: message.at.timestamp_millis(),
+        event: Some(event),
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct LiveGrpc {}
+
+#[tonic::async_trait]
+impl Live for LiveGrpc {
+    type followStream = Pin<Box<dyn Stream<Item = Result<databook::LiveMessage, Status>> + Send>>;
+
+    #[instrument]
+    async fn follow(
+        &self,
+        request: tonic::Request<databook::FollowRequest>,
+    ) -> Result<tonic::Response<Self::followStream>, Status> {
+        let actor = auth::actor(&request, &request.get_ref().user);
+        let request = request.into_inner();
+        tracing::info!("received follow request for {}", request.notebook_id);
+        let hub = hub().map_err(|_| Status::new(Code::Internal, "Internal Error"))?;
+        check_can_view(request.notebook_id.clone(), actor.principal).await?;
+
+        let messages = follow(hub, request.notebook_id, user_or_anonymous(actor.name));
+        let stream = ReceiverStream::new(messages).map(|m| Ok(to_grpc_message(m)));
+        Ok(tonic::Response::new(Box::pin(stream)))
+    }
+}
+
+#[post("/live/tickets", data = "<request>")]
+pub fn rest_ticket(
+    caller: auth::Caller,
+    request: Json<rest::LiveTicketRequest>,
+) -> Json<rest::LiveTicketResponse> {
+    match auth::issue_ticket(caller.actor(request.into_inner().user)) {
+        Ok(ticket) => Json(rest::LiveTicketResponse {
+            ticket: Some(ticket),
+            error: None,
+        }),
+        Err(e) => Json(rest::LiveTicketResponse {
+            ticket: None,
+            error: Some(e.to_string()),
+        }),
+    }
+}
+
+// FollowPath is what the handshake url of a viewer says
+#[derive(Debug, PartialEq, Eq)]
+struct FollowPath {
+    notebook_id: String,
+    ticket: Option<String>,
+}
+
+// parse_follow_path reads /notebooks/<id>/live?ticket=<ticket>
+fn parse_follow_path(uri: &str) -> Option<FollowPath> {
+    let url = url::Url::parse("ws://localhost").ok()?.join(uri).ok()?;
+    let segments: Vec<&str> = url.path_segments()?.collect();
+    let notebook_id = match segments.as_slice() {
+        ["notebooks", id, "live"] if !id.is_empty() => id.to_string(),
+        _ => return None,
+    };
+    let ticket = url
+        .query_pairs()
+        .find(|(key, _)| key == "ticket")
+        .map(|(_, value)| value.into_owned());
+    Some(FollowPath {
+        notebook_id,
+        ticket,
+    })
+}
+
+// serve_websocket accepts viewers following a notebook on
+// ws://<address>/notebooks/<id>/live, every message is a json LiveMessage.
+// Viewers send the same credentials as the REST API in the Authorization
+// header of the handshake, or a ticket from POST /live/tickets when they
+// cannot (browsers). The url does not name the viewer, anonymous ones name
+// themselves when asking for the ticket.
+pub async fn serve_websocket(address: String) {
+    let listener = match TcpListener::bind(&address).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            tracing::error!("could not listen for websockets on {} {:?}", address, e);
+            return;
+        }
+    };
+    tracing::info!("live websocket listening on {}", address);
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                tokio::spawn(async move {
+                    if let Err(e) = handle_websocket(stream).await {
+                        tracing::warn!("websocket from {} closed {:?}", peer, e);
+                    }
+                });
+            }
+            Err(e) => tracing::error!("could not accept websocket connection {:?}", e),
+        }
+    }
+}
+
+async fn handle_websocket(stream: TcpStream) -> Result<(), LiveError> {
+    let mut target = None;
+    let websocket = tokio_tungstenite::accept_hdr_async(
+        stream,
+        |request: &Request, response: Response| -> Result<Response, ErrorResponse> {
+            let path = match parse_follow_path(&request.uri().to_string()) {
+                Some(path) => path,
+                None => {
+                    let mut error =
+                        ErrorResponse::new(Some("expected /notebooks/<id>/live".into()));
+                    *error.status_mut() = StatusCode::NOT_FOUND;
+                    return Err(error);
+                }
+            };
+            let actor = match &path.ticket {
+                Some(ticket) => auth::redeem_ticket(ticket),
+                None => {
+                    let authorization = request
+                        .headers()
+                        .get("Authorization")
+                        .and_then(|v| v.to_str().ok());
+                    auth::authenticate(authorization, None)
+                        .map(|identity| auth::actor_for(identity.as_ref(), None))
+                }
+            };
+            match actor {
+                Ok(actor) => {
+                    target = Some((path.notebook_id, actor));
+                    Ok(response)
+                }
+                Err(e) => {
+                    let mut error = ErrorResponse::new(Some(e.to_string()));
+                    *error.status_mut() = StatusCode::UNAUTHORIZED;
+                    Err(error)
+                }
+            }
+        },
+    )
+    .await?;
+    let (notebook_id, actor) = match target {
+        Some(target) => target,
+        None => return Ok(()),
+    };
+
+    let (mut sink, mut incoming) = websocket.split();
+    let hub = hub().map_err(LiveError::Notebook)?;
+    if let Err(status) = check_can_view(notebook_id.clone(), actor.principal.clone()).await {
+        sink.send(Message::Text(status.message().to_string()))
+            .await?;
+        sink.close().await?;
+        return Ok(());
+    }
+
+    let mut messages = follow(
+        hub,
+        notebook_id.clone(),
+        user_or_anonymous(actor.name.clone()),
+    );
+    loop {
+        tokio::select! {
+            message = messages.recv() => match message {
+                Some(message) => sink.send(Message::Text(serde_json::to_string(&message)?)).await?,
+                None => break,
+            },
+            frame = incoming.next() => match frame {
+                Some(Ok(Message::Text(text))) => {
+                    // the edit comes back to the sender as a live message
+                    if let Err(error) = edit(&notebook_id, &actor, &text).await {
+                        sink.send(Message::Text(serde_json::to_string(&ErrorMessage { error })?)).await?;
+                    }
+                }
+                Some(Ok(Message::Close(_))) | None => break,
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.into()),
+            },
+        }
+    }
+    Ok(())
+}
+
+async fn edit(notebook_id: &str, actor: &Actor, text: &str) -> Result<(), String> {
+    let message: ClientMessage =
+        serde_json::from_str(text).map_err(|e| format!("invalid message {}", e))?;
+    let notebook_id = notebook_id.to_string();
+    let actor = actor.clone();
+    tokio::task::spawn_blocking(move || {
+        notebook_api::edit_notebook(&notebook_id, message.operations, &actor)
+    })
+    .await
+    .map_err(|e| format!("could not apply the operations {:?}", e))?
+    .map(|_| ())
+    .map_err(|e| format!("{:?}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_follow_path() {
+        assert_eq!(
+            Some(FollowPath {
+                notebook_id: "abc".into(),
+                ticket: None,
+            }),
+            parse_follow_path("/notebooks/abc/live")
+        );
+        // a claimed user is not read
+        assert_eq!(
+            Some(FollowPath {
+                notebook_id: "abc".into(),
+                ticket: Some("3f2a".into()),
+            }),
+            parse_follow_path("/notebooks/abc/live?user=elias&ticket=3f2a")
+        );
+        assert_eq!(None, parse_follow_path("/notebooks/abc"));
+        assert_eq!(None, parse_follow_path("/notebooks//live"));
+    }
+}
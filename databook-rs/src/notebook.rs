@@ -0,0 +1,154 @@
+use crate::incident::Incident;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+// Notebook is the record of an investigation: an ordered list of cells,
+// markdown notes and plugin invocations, plus metadata about it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Notebook {
+    pub id: String,
+    pub metadata: NotebookMetadata,
+    pub cells: Vec<Cell>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NotebookMetadata {
+    pub title: String,
+    pub owner: String,
+    pub tags: Vec<String>,
+    // variables are referenced by cells as `{{vars.<name>}}`
+    #[serde(default)]
+    pub variables: BTreeMap<String, String>,
+    // set on notebooks created from a runbook
+    #[serde(default)]
+    pub runbook: Option<RunbookSource>,
+    // set once an incident is declared on the notebook
+    #[serde(default)]
+    pub incident: Option<Incident>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+// RunbookSource is the runbook version a notebook was instantiated from
+// and the parameters used
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunbookSource {
+    pub id: String,
+    pub version: u32,
+    pub parameters: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cell {
+    // cells sent without an id get one when the notebook is saved
+    #[serde(default)]
+    pub id: String,
+    #[serde(flatten)]
+    pub content: CellContent,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CellContent {
+    Markdown {
+        source: String,
+    },
+    // invokes `plugin` with `options`, the same as a GetRequest
+    Plugin {
+        plugin: String,
+        options: BTreeMap<String, String>,
+    },
+}
+
+impl Notebook {
+    pub fn new(title: String, owner: String, tags: Vec<String>, cells: Vec<Cell>) -> Self {
+        let now = Utc::now();
+        let mut notebook = Self {
+            id: new_id(),
+            metadata: NotebookMetadata {
+                title,
+                owner,
+                tags,
+                variables: BTreeMap::new(),
+                runbook: None,
+                incident: None,
+                created_at: now,
+                updated_at: now,
+            },
+            cells,
+        };
+        notebook.assign_cell_ids();
+        notebook
+    }
+
+    pub fn assign_cell_ids(&mut self) {
+        for cell in self.cells.iter_mut().filter(|c| c.id.is_empty()) {
+            cell.id = new_id();
+        }
+    }
+
+    pub fn cell(&self, id: &str) -> Option<&Cell> {
+        self.cells.iter().find(|c| c.id == id)
+    }
+}
+
+impl Cell {
+    pub fn markdown(source: &str) -> Self {
+        Self {
+            id: new_id(),
+            content: CellContent::Markdown {
+                source: source.to_string(),
+            },
+        }
+    }
+
+    pub fn plugin(plugin: &str, options: BTreeMap<String, String>) -> Self {
+        Self {
+            id: new_id(),
+            content: CellContent::Plugin {
+                plugin: plugin.to_string(),
+                options,
+            },
+        }
+    }
+}
+
+pub fn new_id() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_notebook_assigns_cell_ids() {
+        let cell = Cell {
+            id: String::new(),
+            content: CellContent::Markdown {
+                source: "# incident".into(),
+            },
+        };
+        let notebook = Notebook::new("incident".into(), "elias".into(), vec![], vec![cell]);
+        assert!(!notebook.cells[0].id.is_empty());
+        assert_eq!(notebook.metadata.created_at, notebook.metadata.updated_at);
+    }
+
+    #[test]
+    fn test_cell_json() {
+        let cell: Cell = serde_json::from_str(
+            r#"{"type": "plugin", "plugin": "prometheus", "options": {"query": "up"}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            CellContent::Plugin {
+                plugin: "prometheus".into(),
+                options: BTreeMap::from([("query".to_string(), "up".to_string())]),
+            },
+            cell.content
+        );
+        assert!(cell.id.is_empty());
+    }
+}
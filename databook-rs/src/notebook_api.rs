@@ -0,0 +1,1052 @@
+use crate::auth;
+use crate::cell_parser::ParseError;
+use crate::crdt::{CellKind, CrdtError, NotebookDoc, OpId, Operation, SequencedOperation};
+use crate::databook::notebooks_server::Notebooks;
+use crate::databook::{self, cell, operation};
+use crate::incident_api;
+use crate::live::{cell_changes, LiveEvent};
+use crate::live_api;
+use crate::notebook::{Cell, CellContent, Notebook};
+use crate::notebook_store::{NotebookStore, StoreError};
+use crate::policy::{Actor, Permission, Principal};
+use crate::references::{self, ReferenceError};
+use crate::revision_api;
+use crate::search_api;
+use crate::timeline_api;
+use crate::{rest, run_blocking, NOTEBOOKS, POLICY};
+
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use rocket_contrib::json::Json;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tonic::{Code, Request, Response, Status};
+use tracing::instrument;
+
+#[derive(Debug)]
+pub enum NotebookApiError {
+    Store(StoreError),
+    InvalidRequest(String),
+    InvalidCell(ParseError),
+    InvalidReference(ReferenceError),
+    // for things other than notebooks, the message names them
+    NotFound(String),
+    AlreadyExists(String),
+    // the policy does not grant the caller what was asked
+    Forbidden(String),
+    // the plugin did not run, an approval request was made for it
+    ApprovalPending(String),
+    Internal(String),
+}
+
+impl From<StoreError> for NotebookApiError {
+    fn from(e: StoreError) -> Self {
+        NotebookApiError::Store(e)
+    }
+}
+
+// diffs only fail once the counters of the notebook are used up
+impl From<CrdtError> for NotebookApiError {
+    fn from(e: CrdtError) -> Self {
+        NotebookApiError::InvalidRequest(format!("the notebook cannot take more changes {:?}", e))
+    }
+}
+
+impl From<NotebookApiError> for Status {
+    fn from(e: NotebookApiError) -> Self {
+        match e {
+            NotebookApiError::Store(StoreError::NotFound(id)) => {
+                Status::new(Code::NotFound, format!("notebook {} does not exist", id))
+            }
+            NotebookApiError::Store(StoreError::AlreadyExists(id)) => Status::new(
+                Code::AlreadyExists,
+                format!("notebook {} already exists", id),
+            ),
+            NotebookApiError::Store(StoreError::Conflict(id)) => Status::new(
+                Code::Aborted,
+                format!("notebook {} was changed in the meantime, load it again", id),
+            ),
+            NotebookApiError::NotFound(message) => Status::new(Code::NotFound, message),
+            NotebookApiError::AlreadyExists(message) => Status::new(Code::AlreadyExists, message),
+            NotebookApiError::Forbidden(message) => Status::new(Code::PermissionDenied, message),
+            NotebookApiError::ApprovalPending(message) => {
+                Status::new(Code::FailedPrecondition, message)
+            }
+            NotebookApiError::InvalidRequest(message) => {
+                Status::new(Code::InvalidArgument, message)
+            }
+            NotebookApiError::InvalidCell(e) => Status::new(Code::InvalidArgument, e.to_string()),
+            NotebookApiError::InvalidReference(e) => {
+                Status::new(Code::InvalidArgument, e.to_string())
+            }
+            e => {
+                tracing::error!("notebook request failed {:?}", e);
+                Status::new(Code::Internal, "Internal Error")
+            }
+        }
+    }
+}
+
+pub fn store() -> Result<&'static dyn NotebookStore, NotebookApiError> {
+    NOTEBOOKS
+        .get()
+        .map(|s| s.as_ref())
+        .ok_or_else(|| NotebookApiError::Internal("No notebook store setup".into()))
+}
+
+// authorize checks the policy grants the principal the permission on the
+// notebook, everything is allowed without a policy
+pub fn authorize(
+    notebook: &Notebook,
+    principal: &Principal,
+    permission: Permission,
+) -> Result<(), NotebookApiError> {
+    let policy = match POLICY.get() {
+        Some(policy) => policy.current(),
+        None => return Ok(()),
+    };
+    if policy.allows(principal, notebook, permission) {
+        Ok(())
+    } else {
+        Err(NotebookApiError::Forbidden(format!(
+            "{} has no {} permission on notebook {}",
+            principal, permission, notebook.id
+        )))
+    }
+}
+
+// authorize_create checks the policy lets the principal create the new
+// notebook, everything is allowed without a policy
+fn authorize_create(notebook: &Notebook, principal: &Principal) -> Result<(), NotebookApiError> {
+    match POLICY.get() {
+        Some(policy) if !policy.current().may_create(principal, notebook) => {
+            Err(NotebookApiError::Forbidden(format!(
+                "{} may not create notebooks tagged {:?}",
+                principal, notebook.metadata.tags
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+// get_authorized loads the notebook once the principal may do that on it
+pub fn get_authorized(
+    id: &str,
+    principal: &Principal,
+    permission: Permission,
+) -> Result<Notebook, NotebookApiError> {
+    let notebook = store()?.get(id)?;
+    authorize(&notebook, principal, permission)?;
+    Ok(notebook)
+}
+
+// list_notebooks returns the notebooks the principal may view
+pub fn list_notebooks(principal: &Principal) -> Result<Vec<Notebook>, NotebookApiError> {
+    let notebooks = store()?.list()?;
+    Ok(notebooks
+        .into_iter()
+        .filter(|n| authorize(n, principal, Permission::View).is_ok())
+        .collect())
+}
+
+// visible_notebooks lists the ids of the notebooks the principal may view,
+// None when there is no policy and every notebook is visible
+pub fn visible_notebooks(
+    principal: &Principal,
+) -> Result<Option<HashSet<String>>, NotebookApiError> {
+    if POLICY.get().is_none() {
+        return Ok(None);
+    }
+    Ok(Some(
+        list_notebooks(principal)?
+            .into_iter()
+            .map(|n| n.id)
+            .collect(),
+    ))
+}
+
+// operations the server derives from whole notebook saves are stamped
+// with this site
+const SERVER_SITE: &str = "server";
+
+// the replicated content of the notebooks, rebuilt from the stored
+// operations the first time a notebook is edited. Every notebook has its
+// own lock, the map is only locked to find it.
+static DOCUMENTS: Lazy<Mutex<HashMap<String, SharedDocument>>> = Lazy::new(Default::default);
+
+// documents nobody edited for that long are dropped, they are loaded again
+// from the store on the next edit
+const DOCUMENT_IDLE: Duration = Duration::from_secs(10 * 60);
+
+type SharedDocument = Arc<Mutex<OpenDocument>>;
+
+#[derive(Default)]
+struct OpenDocument {
+    // None until loaded, or after a failed edit left the store ahead of it
+    document: Option<NotebookDoc>,
+    used: Option<Instant>,
+}
+
+pub fn create_notebook(
+    title: String,
+    owner: String,
+    tags: Vec<String>,
+    variables: BTreeMap<String, String>,
+    cells: Vec<Cell>,
+    principal: &Principal,
+) -> Result<Notebook, NotebookApiError> {
+    let mut notebook = Notebook::new(title, owner, tags, cells);
+    notebook.metadata.variables = variables;
+    insert_notebook(notebook, principal)
+}
+
+// owner is who a new notebook belongs to: the authenticated caller, whatever
+// owner the request names. Anonymous callers create notebooks nobody owns,
+// they cannot name an owner the policy would then trust.
+pub fn owner(actor: &Actor, requested: String) -> Result<String, NotebookApiError> {
+    match &actor.principal.user {
+        Some(user) => Ok(user.clone()),
+        None if requested.is_empty() => Ok(requested),
+        None => Err(NotebookApiError::Forbidden(format!(
+            "anonymous callers cannot create notebooks owned by {}",
+            requested
+        ))),
+    }
+}
+
+// insert_notebook stores a notebook the principal creates, built by the
+// server, e.g. from a runbook
+pub fn insert_notebook(
+    notebook: Notebook,
+    principal: &Principal,
+) -> Result<Notebook, NotebookApiError> {
+    authorize_create(&notebook, principal)?;
+    check_variables(&notebook.metadata.variables)?;
+    check_unique_cell_ids(&notebook.cells)?;
+    let store = store()?;
+    store.create(&notebook)?;
+    store.append_operations(
+        &notebook.id,
+        &NotebookDoc::default().diff(&notebook.cells, SERVER_SITE)?,
+    )?;
+    revision_api::record(&notebook, Some(&notebook.metadata.owner));
+    search_api::index_notebook(&notebook);
+    Ok(notebook)
+}
+
+// update_notebook replaces the content of the notebook, owner and
+// created_at never change. `base` is the updated_at of the notebook the
+// client changed: a save made on an older version is refused with a
+// Conflict, as replacing the content would revert what others changed
+// since. Without a base the content is replaced whatever it is, e.g. when
+// restoring a revision. The new cells are turned into operations, which
+// live viewers and offline clients merge like their own edits.
+pub fn update_notebook(
+    id: &str,
+    title: String,
+    tags: Vec<String>,
+    variables: BTreeMap<String, String>,
+    cells: Vec<Cell>,
+    base: Option<DateTime<Utc>>,
+    actor: &Actor,
+) -> Result<Notebook, NotebookApiError> {
+    check_variables(&variables)?;
+    with_document(id, |store, document| {
+        let mut notebook = store.get(id)?;
+        authorize(&notebook, &actor.principal, Permission::Edit)?;
+        // grpc clients send milliseconds
+        if base.is_some_and(|base| {
+            base.timestamp_millis() != notebook.metadata.updated_at.timestamp_millis()
+        }) {
+            return Err(StoreError::Conflict(id.to_string()).into());
+        }
+        notebook.metadata.title = title;
+        notebook.metadata.tags = tags;
+        notebook.metadata.variables = variables;
+        notebook.cells = cells;
+        notebook.assign_cell_ids();
+        check_unique_cell_ids(&notebook.cells)?;
+        let operations = document.diff(&notebook.cells, SERVER_SITE)?;
+        merge(
+            store,
+            &mut notebook,
+            document,
+            operations,
+            actor.name.clone(),
+        )?;
+        Ok(notebook)
+    })
+}
+
+// required_base is the version a save through the API was made on, clients
+// cannot replace the content without saying what they changed
+fn required_base(base: Option<DateTime<Utc>>) -> Result<DateTime<Utc>, NotebookApiError> {
+    base.ok_or_else(|| {
+        NotebookApiError::InvalidRequest(
+            "updated_at must be the one of the notebook the change was made on".into(),
+        )
+    })
+}
+
+// edit_notebook merges operations made by a client, possibly while it was
+// offline, and returns the ones that were new
+pub fn edit_notebook(
+    id: &str,
+    operations: Vec<Operation>,
+    actor: &Actor,
+) -> Result<(Notebook, Vec<SequencedOperation>), NotebookApiError> {
+    with_document(id, |store, document| {
+        let mut notebook = store.get(id)?;
+        authorize(&notebook, &actor.principal, Permission::Edit)?;
+        let applied = merge(
+            store,
+            &mut notebook,
+            document,
+            operations,
+            actor.name.clone(),
+        )?;
+        Ok((notebook, applied))
+    })
+}
+
+// change_metadata applies `f` to the stored notebook and saves it, the
+// cells are left as they are. Edits of the cells made at the same time
+// wait for it, so neither is lost.
+pub fn change_metadata<T>(
+    id: &str,
+    actor: &Actor,
+    f: impl FnOnce(&mut Notebook) -> Result<T, NotebookApiError>,
+) -> Result<(Notebook, T), NotebookApiError> {
+    with_document(id, |store, _| {
+        let mut notebook = store.get(id)?;
+        authorize(&notebook, &actor.principal, Permission::Edit)?;
+        let previous = notebook.metadata.updated_at;
+        let result = f(&mut notebook)?;
+        notebook.metadata.updated_at = Utc::now();
+        store.update(&notebook, previous, &[], actor.name.as_deref())?;
+        revision_api::record(&notebook, actor.name.as_deref());
+        search_api::index_notebook(&notebook);
+        Ok((notebook, result))
+    })
+}
+
+pub fn list_operations(id: &str, since: u64) -> Result<Vec<SequencedOperation>, NotebookApiError> {
+    // loading the document stores the operations of older notebooks
+    with_document(id, |store, _| Ok(store.operations(id, since)?))
+}
+
+pub fn delete_notebook(id: &str, principal: &Principal) -> Result<(), NotebookApiError> {
+    get_authorized(id, principal, Permission::Admin)?;
+    let open = open_document(id)?;
+    let mut open = lock_document(id, &open)?;
+    store()?.delete(id)?;
+    open.document = None;
+    documents()?.remove(id);
+    if let Err(e) = search_api::index().and_then(|index| Ok(index.remove_notebook(id)?)) {
+        tracing::error!(
+            "could not remove notebook {} from the search index {:?}",
+            id,
+            e
+        );
+    }
+    Ok(())
+}
+
+fn documents() -> Result<MutexGuard<'static, HashMap<String, SharedDocument>>, NotebookApiError> {
+    DOCUMENTS.lock().map_err(|e| {
+        NotebookApiError::Internal(format!("could not lock notebook documents {:?}", e))
+    })
+}
+
+// open_document returns the entry of the notebook, and drops the idle
+// ones nobody else holds
+fn open_document(id: &str) -> Result<SharedDocument, NotebookApiError> {
+    let mut documents = documents()?;
+    documents.retain(|other, open| {
+        other == id
+            || Arc::strong_count(open) > 1
+            || match open.try_lock() {
+                Ok(open) => open.used.is_some_and(|used| used.elapsed() < DOCUMENT_IDLE),
+                // being edited
+                Err(_) => true,
+            }
+    });
+    Ok(documents.entry(id.to_string()).or_default().clone())
+}
+
+fn lock_document<'a>(
+    id: &str,
+    open: &'a Mutex<OpenDocument>,
+) -> Result<MutexGuard<'a, OpenDocument>, NotebookApiError> {
+    open.lock().map_err(|e| {
+        NotebookApiError::Internal(format!(
+            "could not lock the document of notebook {} {:?}",
+            id, e
+        ))
+    })
+}
+
+// with_document runs `f` holding the document of the notebook, edits of a
+// notebook are applied one at a time
+fn with_document<T>(
+    id: &str,
+    f: impl FnOnce(&dyn NotebookStore, &mut NotebookDoc) -> Result<T, NotebookApiError>,
+) -> Result<T, NotebookApiError> {
+    let store = store()?;
+    let open = open_document(id)?;
+    let mut open = lock_document(id, &open)?;
+    open.used = Some(Instant::now());
+    let document = match &mut open.document {
+        Some(document) => document,
+        document => document.insert(load_document(store, id)?),
+    };
+    let result = f(store, document);
+    if result.is_err() {
+        // the store may be ahead of the document, reload it next time
+        open.document = None;
+    }
+    result
+}
+
+fn load_document(store: &dyn NotebookStore, id: &str) -> Result<NotebookDoc, NotebookApiError> {
+    let notebook = store.get(id)?;
+    let mut document = NotebookDoc::default();
+    let mut operations = store.operations(id, 0)?;
+    if operations.is_empty() && !notebook.cells.is_empty() {
+        // notebooks saved before their operations were kept
+        let initial = document.diff(&notebook.cells, SERVER_SITE)?;
+        store.append_operations(id, &initial)?;
+        operations = store.operations(id, 0)?;
+    }
+    for stored in operations {
+        document.apply(&stored.operation).map_err(|e| {
+            NotebookApiError::Internal(format!(
+                "operation {} of notebook {} does not apply {:?}",
+                stored.seq, id, e
+            ))
+        })?;
+    }
+    Ok(document)
+}
+
+// merge applies the operations to a copy of the document, which replaces
+// it once the operations and the resulting cells are stored
+fn merge(
+    store: &dyn NotebookStore,
+    notebook: &mut Notebook,
+    document: &mut NotebookDoc,
+    operations: Vec<Operation>,
+    user: Option<String>,
+) -> Result<Vec<SequencedOperation>, NotebookApiError> {
+    let mut next = document.clone();
+    let mut applied = vec![];
+    for operation in operations {
+        match next.apply(&operation) {
+            Ok(true) => applied.push(operation),
+            // already applied, e.g. resent after a reconnection
+            Ok(false) => {}
+            Err(e) => {
+                return Err(NotebookApiError::InvalidRequest(format!(
+                    "operation {:?} does not apply {:?}",
+                    operation, e
+                )))
+            }
+        }
+    }
+
+    let previous_cells = document.cells();
+    let previous = notebook.metadata.updated_at;
+    notebook.cells = next.cells();
+    notebook.metadata.updated_at = Utc::now();
+    // the operations and the cells they lead to are stored together
+    let last = store.update(notebook, previous, &applied, user.as_deref())?;
+    *document = next;
+    let first = last + 1 - applied.len() as u64;
+    let applied: Vec<SequencedOperation> = applied
+        .into_iter()
+        .zip(first..)
+        .map(|(operation, seq)| SequencedOperation { seq, operation })
+        .collect();
+    revision_api::record(notebook, user.as_deref());
+    search_api::index_notebook(notebook);
+
+    if !applied.is_empty() {
+        live_api::publish(
+            &notebook.id,
+            LiveEvent::Operations {
+                user,
+                operations: applied.clone(),
+            },
+        );
+    }
+    for event in cell_changes(&previous_cells, &notebook.cells) {
+        live_api::publish(&notebook.id, event);
+    }
+    Ok(applied)
+}
+
+fn check_unique_cell_ids(cells: &[Cell]) -> Result<(), NotebookApiError> {
+    let mut ids = HashSet::new();
+    match cells.iter().find(|c| !ids.insert(c.id.as_str())) {
+        Some(cell) => Err(NotebookApiError::InvalidRequest(format!(
+            "cell id {} is used more than once",
+            cell.id
+        ))),
+        None => Ok(()),
+    }
+}
+
+fn check_variables(variables: &BTreeMap<String, String>) -> Result<(), NotebookApiError> {
+    match variables.keys().find(|name| !references::is_name(name)) {
+        Some(name) => Err(NotebookApiError::InvalidRequest(format!(
+            "invalid variable name {:?}",
+            name
+        ))),
+        None => Ok(()),
+    }
+}
+
+pub fn to_grpc_notebook(notebook: Notebook) -> databook::Notebook {
+    databook::Notebook {
+        id: notebook.id,
+        title: notebook.metadata.title,
+        owner: notebook.metadata.owner,
+        tags: notebook.metadata.tags,
+        variables: notebook.metadata.variables.into_iter().collect(),
+        runbook: notebook.metadata.runbook.map(|r| databook::RunbookSource {
+            id: r.id,
+            version: r.version,
+            parameters: r.parameters.into_iter().collect(),
+        }),
+        incident: notebook
+            .metadata
+            .incident
+            .map(incident_api::to_grpc_incident),
+        cells: notebook.cells.into_iter().map(to_grpc_cell).collect(),
+        created_at: notebook.metadata.created_at.timestamp_millis(),
+        updated_at: notebook.metadata.updated_at.timestamp_millis(),
+    }
+}
+
+pub fn to_grpc_cell(cell: Cell) -> databook::Cell {
+    let content = match cell.content {
+        CellContent::Markdown { source } => {
+            cell::Content::Markdown(databook::MarkdownCell { source })
+        }
+        CellContent::Plugin { plugin, options } => cell::Content::Plugin(databook::PluginCell {
+            plugin,
+            options: options.into_iter().collect(),
+        }),
+    };
+    databook::Cell {
+        id: cell.id,
+        content: Some(content),
+    }
+}
+
+fn from_grpc_cell(cell: databook::Cell) -> Result<Cell, NotebookApiError> {
+    let content = match cell.content {
+        Some(cell::Content::Markdown(markdown)) => CellContent::Markdown {
+            source: markdown.source,
+        },
+        Some(cell::Content::Plugin(plugin)) => CellContent::Plugin {
+            plugin: plugin.plugin,
+            options: plugin.options.into_iter().collect(),
+        },
+        None => {
+            return Err(NotebookApiError::InvalidRequest(format!(
+                "cell {:?} has no content",
+                cell.id
+            )))
+        }
+    };
+    Ok(Cell {
+        id: cell.id,
+        content,
+    })
+}
+
+pub fn from_grpc_cells(cells: Vec<databook::Cell>) -> Result<Vec<Cell>, NotebookApiError> {
+    cells.into_iter().map(from_grpc_cell).collect()
+}
+
+fn to_grpc_op_id(id: OpId) -> databook::OpId {
+    databook::OpId {
+        counter: id.counter,
+        site: id.site,
+    }
+}
+
+fn from_grpc_op_id(id: Option<databook::OpId>) -> Result<OpId, NotebookApiError> {
+    id.map(|id| OpId {
+        counter: id.counter,
+        site: id.site,
+    })
+    .ok_or_else(|| NotebookApiError::InvalidRequest("operation without id".into()))
+}
+
+fn to_grpc_operation(operation: Operation) -> databook::Operation {
+    let op = match operation {
+        Operation::InsertCell {
+            id,
+            after,
+            cell_id,
+            kind,
+        } => operation::Op::InsertCell(databook::InsertCell {
+            id: Some(to_grpc_op_id(id)),
+            after: after.map(to_grpc_op_id),
+            cell_id,
+            plugin: match kind {
+                CellKind::Markdown => String::new(),
+                CellKind::Plugin { plugin } => plugin,
+            },
+        }),
+        Operation::DeleteCell { target } => operation::Op::DeleteCell(databook::DeleteCell {
+            target: Some(to_grpc_op_id(target)),
+        }),
+        Operation::InsertText {
+            cell_id,
+            id,
+            after,
+            text,
+        } => operation::Op::InsertText(databook::InsertText {
+            cell_id,
+            id: Some(to_grpc_op_id(id)),
+            after: after.map(to_grpc_op_id),
+            text,
+        }),
+        Operation::DeleteText { cell_id, targets } => {
+            operation::Op::DeleteText(databook::DeleteText {
+                cell_id,
+                targets: targets.into_iter().map(to_grpc_op_id).collect(),
+            })
+        }
+        Operation::SetOption {
+            cell_id,
+            id,
+            key,
+            value,
+        } => operation::Op::SetOption(databook::SetOption {
+            cell_id,
+            id: Some(to_grpc_op_id(id)),
+            key,
+            removed: value.is_none(),
+            value: value.unwrap_or_default(),
+        }),
+    };
+    databook::Operation { op: Some(op) }
+}
+
+fn from_grpc_operation(operation: databook::Operation) -> Result<Operation, NotebookApiError> {
+    Ok(match operation.op {
+        Some(operation::Op::InsertCell(insert)) => Operation::InsertCell {
+            id: from_grpc_op_id(insert.id)?,
+            after: insert.after.map(|a| from_grpc_op_id(Some(a))).transpose()?,
+            cell_id: insert.cell_id,
+            kind: if insert.plugin.is_empty() {
+                CellKind::Markdown
+            } else {
+                CellKind::Plugin {
+                    plugin: insert.plugin,
+                }
+            },
+        },
+        Some(operation::Op::DeleteCell(delete)) => Operation::DeleteCell {
+            target: from_grpc_op_id(delete.target)?,
+        },
+        Some(operation::Op::InsertText(insert)) => Operation::InsertText {
+            cell_id: insert.cell_id,
+            id: from_grpc_op_id(insert.id)?,
+            after: insert.after.map(|a| from_grpc_op_id(Some(a))).transpose()?,
+            text: insert.text,
+        },
+        Some(operation::Op::DeleteText(delete)) => Operation::DeleteText {
+            cell_id: delete.cell_id,
+            targets: delete
+                .targets
+                .into_iter()
+                .map(|t| from_grpc_op_id(Some(t)))
+                .collect::<Result<_, _>>()?,
+        },
+        Some(operation::Op::SetOption(set)) => Operation::SetOption {
+            cell_id: set.cell_id,
+            id: from_grpc_op_id(set.id)?,
+            key: set.key,
+            value: if set.removed { None } else { Some(set.value) },
+        },
+        None => return Err(NotebookApiError::InvalidRequest("empty operation".into())),
+    })
+}
+
+pub fn to_grpc_sequenced(operation: SequencedOperation) -> databook::SequencedOperation {
+    databook::SequencedOperation {
+        seq: operation.seq,
+        operation: Some(to_grpc_operation(operation.operation)),
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct NotebooksGrpc {}
+
+#[tonic::async_trait]
+impl Notebooks for NotebooksGrpc {
+    #[instrument]
+    async fn create(
+        &self,
+        request: Request<databook::CreateNotebookRequest>,
+    ) -> Result<Response<databook::Notebook>, Status> {
+        tracing::info!("received create notebook request");
+        let actor = auth::actor(&request, "");
+        run_blocking(move || {
+            let request = request.into_inner();
+            create_notebook(
+                request.title,
+                owner(&actor, request.owner)?,
+                request.tags,
+                request.variables.into_iter().collect(),
+                from_grpc_cells(request.cells)?,
+                &actor.principal,
+            )
+            .map(to_grpc_notebook)
+        })
+        .await
+    }
+
+    #[instrument]
+    async fn get(
+        &self,
+        request: Request<databook::GetNotebookRequest>,
+    ) -> Result<Response<databook::Notebook>, Status> {
+        let actor = auth::actor(&request, "");
+        run_blocking(move || {
+            let notebook =
+                get_authorized(&request.into_inner().id, &actor.principal, Permission::View)?;
+            Ok::<_, NotebookApiError>(to_grpc_notebook(notebook))
+        })
+        .await
+    }
+
+    #[instrument]
+    async fn list(
+        &self,
+        request: Request<databook::ListNotebooksRequest>,
+    ) -> Result<Response<databook::ListNotebooksResponse>, Status> {
+        let actor = auth::actor(&request, "");
+        run_blocking(move || {
+            let notebooks = list_notebooks(&actor.principal)?;
+            Ok::<_, NotebookApiError>(databook::ListNotebooksResponse {
+                notebooks: notebooks.into_iter().map(to_grpc_notebook).collect(),
+            })
+        })
+        .await
+    }
+
+    #[instrument]
+    async fn update(
+        &self,
+        request: Request<databook::Notebook>,
+    ) -> Result<Response<databook::Notebook>, Status> {
+        tracing::info!("received update notebook request");
+        let actor = auth::actor(&request, "");
+        run_blocking(move || {
+            let request = request.into_inner();
+            update_notebook(
+                &request.id,
+                request.title,
+                request.tags,
+                request.variables.into_iter().collect(),
+                from_grpc_cells(request.cells)?,
+                Some(required_base(timeline_api::from_millis(
+                    request.updated_at,
+                ))?),
+                &actor,
+            )
+            .map(to_grpc_notebook)
+        })
+        .await
+    }
+
+    #[instrument]
+    async fn delete(
+        &self,
+        request: Request<databook::DeleteNotebookRequest>,
+    ) -> Result<Response<databook::DeleteNotebookResponse>, Status> {
+        tracing::info!("received delete notebook request");
+        let actor = auth::actor(&request, "");
+        run_blocking(move || {
+            delete_notebook(&request.into_inner().id, &actor.principal)?;
+            Ok::<_, NotebookApiError>(databook::DeleteNotebookResponse {})
+        })
+        .await
+    }
+
+    #[instrument]
+    async fn edit(
+        &self,
+        request: Request<databook::EditNotebookRequest>,
+    ) -> Result<Response<databook::EditNotebookResponse>, Status> {
+        let actor = auth::actor(&request, &request.get_ref().user);
+        run_blocking(move || {
+            let request = request.into_inner();
+            let operations = request
+                .operations
+                .into_iter()
+                .map(from_grpc_operation)
+                .collect::<Result<_, _>>()?;
+            let (notebook, applied) = edit_notebook(&request.notebook_id, operations, &actor)?;
+            Ok::<_, NotebookApiError>(databook::EditNotebookResponse {
+                notebook: Some(to_grpc_notebook(notebook)),
+                operations: applied.into_iter().map(to_grpc_sequenced).collect(),
+            })
+        })
+        .await
+    }
+
+    #[instrument]
+    async fn list_operations(
+        &self,
+        request: Request<databook::ListOperationsRequest>,
+    ) -> Result<Response<databook::ListOperationsResponse>, Status> {
+        let actor = auth::actor(&request, "");
+        run_blocking(move || {
+            let request = request.into_inner();
+            get_authorized(&request.notebook_id, &actor.principal, Permission::View)?;
+            let operations = list_operations(&request.notebook_id, request.since)?;
+            Ok::<_, NotebookApiError>(databook::ListOperationsResponse {
+                operations: operations.into_iter().map(to_grpc_sequenced).collect(),
+            })
+        })
+        .await
+    }
+}
+
+pub fn rest_notebook_response(
+    response: Result<Notebook, NotebookApiError>,
+) -> Json<rest::NotebookResponse> {
+    match response {
+        Ok(notebook) => Json(rest::NotebookResponse {
+            notebook: Some(notebook),
+            error: None,
+        }),
+        Err(e) => {
+            tracing::error!("notebook request failed {:?}", e);
+            Json(rest::NotebookResponse {
+                notebook: None,
+                error: Some(format!("{:?}", e)),
+            })
+        }
+    }
+}
+
+#[get("/notebooks")]
+pub fn rest_list(caller: auth::Caller) -> Json<rest::ListNotebooksResponse> {
+    match list_notebooks(&caller.actor(None).principal) {
+        Ok(notebooks) => Json(rest::ListNotebooksResponse {
+            notebooks,
+            error: None,
+        }),
+        Err(e) => Json(rest::ListNotebooksResponse {
+            notebooks: vec![],
+            error: Some(format!("{:?}", e)),
+        }),
+    }
+}
+
+#[post("/notebooks", data = "<request>")]
+pub fn rest_create(
+    caller: auth::Caller,
+    request: Json<rest::NotebookRequest>,
+) -> Json<rest::NotebookResponse> {
+    tracing::info!("received create notebook request");
+    let request = request.into_inner();
+    let actor = caller.actor(None);
+    let notebook = owner(&actor, request.owner).and_then(|owner| {
+        create_notebook(
+            request.title,
+            owner,
+            request.tags,
+            request.variables,
+            request.cells,
+            &actor.principal,
+        )
+    });
+    rest_notebook_response(notebook)
+}
+
+#[get("/notebooks/<id>")]
+pub fn rest_get(caller: auth::Caller, id: String) -> Json<rest::NotebookResponse> {
+    rest_notebook_response(get_authorized(
+        &id,
+        &caller.actor(None).principal,
+        Permission::View,
+    ))
+}
+
+#[put("/notebooks/<id>", data = "<request>")]
+pub fn rest_update(
+    caller: auth::Caller,
+    id: String,
+    request: Json<rest::NotebookRequest>,
+) -> Json<rest::NotebookResponse> {
+    tracing::info!("received update notebook request");
+    let request = request.into_inner();
+    rest_notebook_response(required_base(request.updated_at).and_then(|base| {
+        update_notebook(
+            &id,
+            request.title,
+            request.tags,
+            request.variables,
+            request.cells,
+            Some(base),
+            &caller.actor(None),
+        )
+    }))
+}
+
+#[delete("/notebooks/<id>")]
+pub fn rest_delete(caller: auth::Caller, id: String) -> Json<rest::NotebookResponse> {
+    tracing::info!("received delete notebook request");
+    match delete_notebook(&id, &caller.actor(None).principal) {
+        Ok(()) => Json(rest::NotebookResponse {
+            notebook: None,
+            error: None,
+        }),
+        Err(e) => rest_notebook_response(Err(e)),
+    }
+}
+
+#[post("/notebooks/<id>/operations", data = "<request>")]
+pub fn rest_edit(
+    caller: auth::Caller,
+    id: String,
+    request: Json<rest::EditNotebookRequest>,
+) -> Json<rest::EditNotebookResponse> {
+    let request = request.into_inner();
+    match edit_notebook(&id, request.operations, &caller.actor(request.user)) {
+        Ok((notebook, operations)) => Json(rest::EditNotebookResponse {
+            notebook: Some(notebook),
+            operations,
+            error: None,
+        }),
+        Err(e) => {
+            tracing::error!("edit notebook request failed {:?}", e);
+            Json(rest::EditNotebookResponse {
+                notebook: None,
+                operations: vec![],
+                error: Some(format!("{:?}", e)),
+            })
+        }
+    }
+}
+
+#[get("/notebooks/<id>/operations?<since>")]
+pub fn rest_operations(
+    caller: auth::Caller,
+    id: String,
+    since: Option<u64>,
+) -> Json<rest::OperationsResponse> {
+    let operations = get_authorized(&id, &caller.actor(None).principal, Permission::View)
+        .and_then(|_| list_operations(&id, since.unwrap_or(0)));
+    match operations {
+        Ok(operations) => Json(rest::OperationsResponse {
+            operations,
+            error: None,
+        }),
+        Err(e) => Json(rest::OperationsResponse {
+            operations: vec![],
+            error: Some(format!("{:?}", e)),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_grpc_cell_roundtrip() {
+        let cell = Cell::plugin(
+            "prometheus",
+            BTreeMap::from([("query".to_string(), "up".to_string())]),
+        );
+        assert_eq!(cell, from_grpc_cell(to_grpc_cell(cell.clone())).unwrap());
+    }
+
+    #[test]
+    fn test_owner_is_the_identity() {
+        let anonymous = Actor {
+            principal: Principal::default(),
+            name: Some("mallory".into()),
+        };
+        assert!(matches!(
+            owner(&anonymous, "alice".into()),
+            Err(NotebookApiError::Forbidden(_))
+        ));
+        assert_eq!("", owner(&anonymous, String::new()).unwrap());
+        let alice = Actor::for_principal(Principal {
+            user: Some("alice".into()),
+            groups: vec![],
+        });
+        assert_eq!("alice", owner(&alice, "bob".into()).unwrap());
+    }
+
+    #[test]
+    fn test_grpc_cell_without_content() {
+        let cell = databook::Cell {
+            id: "a".into(),
+            content: None,
+        };
+        assert!(matches!(
+            from_grpc_cell(cell),
+            Err(NotebookApiError::InvalidRequest(_))
+        ));
+    }
+
+    #[test]
+    fn test_grpc_operation_roundtrip() {
+        let operations = NotebookDoc::default()
+            .diff(
+                &[
+                    Cell {
+                        id: "a".into(),
+                        ..Cell::markdown("# notes")
+                    },
+                    Cell {
+                        id: "b".into(),
+                        ..Cell::plugin(
+                            "prometheus",
+                            BTreeMap::from([("query".to_string(), "up".to_string())]),
+                        )
+                    },
+                ],
+                "alice",
+            )
+            .unwrap();
+        for operation in operations {
+            assert_eq!(
+                operation,
+                from_grpc_operation(to_grpc_operation(operation.clone())).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_duplicated_cell_ids() {
+        let cells = vec![
+            Cell {
+                id: "a".into(),
+                ..Cell::markdown("one")
+            },
+            Cell {
+                id: "a".into(),
+                ..Cell::markdown("two")
+            },
+        ];
+        assert!(matches!(
+            check_unique_cell_ids(&cells),
+            Err(NotebookApiError::InvalidRequest(_))
+        ));
+    }
+}
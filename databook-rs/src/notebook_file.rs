@@ -0,0 +1,331 @@
+use crate::incident::Incident;
+use crate::notebook::{Cell, CellContent, Notebook, NotebookMetadata, RunbookSource};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+// The notebook file is how notebooks are written to a git repository: a
+// Markdown document that reads well in a review and changes line by line
+// with the notebook. The metadata is toml front matter, every cell starts
+// with a marker line and plugin cells hold their options as toml:
+//
+//   +++
+//   id = "n1"
+//   title = "api latency"
+//   ...
+//   +++
+//
+//   <!-- cell {"id":"notes","type":"markdown"} -->
+//   ## Impact
+//
+//   <!-- cell {"id":"latency","type":"plugin","plugin":"prometheus"} -->
+//   ```toml
+//   query = "rate(errors[5m])"
+//   ```
+//
+// Reading a file gives back exactly the notebook that was written.
+
+const FRONT_MATTER: &str = "+++";
+const CELL_START: &str = "<!-- cell ";
+const CELL_END: &str = " -->";
+const OPTIONS_START: &str = "```toml\n";
+const OPTIONS_END: &str = "```";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum FileError {
+    MissingFrontMatter,
+    InvalidFrontMatter(String),
+    InvalidCell(usize, String),
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileError::MissingFrontMatter => write!(
+                f,
+                "the file does not start with {} front matter",
+                FRONT_MATTER
+            ),
+            FileError::InvalidFrontMatter(e) => write!(f, "invalid front matter {}", e),
+            FileError::InvalidCell(line, e) => write!(f, "invalid cell at line {} {}", line, e),
+        }
+    }
+}
+
+// scalars go before tables, toml needs them in that order
+#[derive(Debug, Serialize, Deserialize)]
+struct FrontMatter {
+    id: String,
+    title: String,
+    owner: String,
+    tags: Vec<String>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    #[serde(default)]
+    variables: BTreeMap<String, String>,
+    runbook: Option<RunbookSource>,
+    incident: Option<Incident>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CellMarker {
+    id: String,
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    plugin: Option<String>,
+}
+
+// lines of a cell that would read as a marker get a backslash, so do the
+// ones that already start with backslashes before a marker
+fn escape(text: &str) -> String {
+    text.split('\n')
+        .map(|line| {
+            if line.trim_start_matches('\\').starts_with(CELL_START) {
+                format!("\\{}", line)
+            } else {
+                line.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn unescape(text: &str) -> String {
+    text.split('\n')
+        .map(|line| match line.strip_prefix('\\') {
+            Some(rest) if rest.trim_start_matches('\\').starts_with(CELL_START) => rest,
+            _ => line,
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+pub fn write(notebook: &Notebook) -> Result<String, FileError> {
+    let metadata = &notebook.metadata;
+    let front_matter = FrontMatter {
+        id: notebook.id.clone(),
+        title: metadata.title.clone(),
+        owner: metadata.owner.clone(),
+        tags: metadata.tags.clone(),
+        created_at: metadata.created_at,
+        updated_at: metadata.updated_at,
+        variables: metadata.variables.clone(),
+        runbook: metadata.runbook.clone(),
+        incident: metadata.incident.clone(),
+    };
+    // not pretty: values stay on one line, so a +++ line is always the end
+    let front_matter =
+        toml::to_string(&front_matter).map_err(|e| FileError::InvalidFrontMatter(e.to_string()))?;
+    let mut file = format!("{}\n{}{}\n", FRONT_MATTER, front_matter, FRONT_MATTER);
+
+    for cell in &notebook.cells {
+        let (marker, body) = match &cell.content {
+            CellContent::Markdown { source } => (
+                CellMarker {
+                    id: cell.id.clone(),
+                    kind: "markdown".into(),
+                    plugin: None,
+                },
+                source.clone(),
+            ),
+            CellContent::Plugin { plugin, options } => {
+                let options = toml::to_string_pretty(options)
+                    .map_err(|e| FileError::InvalidCell(0, e.to_string()))?;
+                (
+                    CellMarker {
+                        id: cell.id.clone(),
+                        kind: "plugin".into(),
+                        plugin: Some(plugin.clone()),
+                    },
+                    format!("{}{}{}", OPTIONS_START, options, OPTIONS_END),
+                )
+            }
+        };
+        let marker =
+            serde_json::to_string(&marker).map_err(|e| FileError::InvalidCell(0, e.to_string()))?;
+        // the blank line after the body is part of the format, a body
+        // ending with new lines keeps them
+        file.push_str(&format!(
+            "\n{}{}{}\n{}\n",
+            CELL_START,
+            marker,
+            CELL_END,
+            escape(&body)
+        ));
+    }
+    Ok(file)
+}
+
+pub fn read(file: &str) -> Result<Notebook, FileError> {
+    let rest = file
+        .strip_prefix(FRONT_MATTER)
+        .and_then(|rest| rest.strip_prefix('\n'))
+        .ok_or(FileError::MissingFrontMatter)?;
+    let end = rest
+        .find(&format!("\n{}\n", FRONT_MATTER))
+        .ok_or(FileError::MissingFrontMatter)?;
+    let front_matter: FrontMatter =
+        toml::from_str(&rest[..end]).map_err(|e| FileError::InvalidFrontMatter(e.to_string()))?;
+    let mut line = rest[..end].matches('\n').count() + 3;
+    let body = &rest[end + FRONT_MATTER.len() + 2..];
+
+    let mut cells = vec![];
+    // every cell is "\n<marker>\n<body>\n"
+    let separator = format!("\n{}", CELL_START);
+    let mut sections = body.split(&separator);
+    if sections.next() != Some("") {
+        return Err(FileError::InvalidCell(
+            line,
+            "expected a cell marker".into(),
+        ));
+    }
+    for section in sections {
+        line += 1;
+        let (marker, body) = section.split_once('\n').unwrap_or((section, ""));
+        let marker: CellMarker = marker
+            .strip_suffix(CELL_END)
+            .ok_or_else(|| "the marker does not end with -->".to_string())
+            .and_then(|m| serde_json::from_str(m).map_err(|e| e.to_string()))
+            .map_err(|e| FileError::InvalidCell(line, e))?;
+        let body = body.strip_suffix('\n').ok_or_else(|| {
+            FileError::InvalidCell(line, "expected a blank line after the cell".into())
+        })?;
+        let body = unescape(body);
+        let content = match (marker.kind.as_str(), marker.plugin) {
+            ("markdown", None) => CellContent::Markdown { source: body },
+            ("plugin", Some(plugin)) => {
+                let options = body
+                    .strip_prefix(OPTIONS_START)
+                    .and_then(|o| o.strip_suffix(OPTIONS_END))
+                    .ok_or_else(|| {
+                        FileError::InvalidCell(line, "expected the options as a toml block".into())
+                    })?;
+                let options = toml::from_str(options)
+                    .map_err(|e| FileError::InvalidCell(line, e.to_string()))?;
+                CellContent::Plugin { plugin, options }
+            }
+            (kind, _) => {
+                return Err(FileError::InvalidCell(
+                    line,
+                    format!("unknown cell type {}", kind),
+                ))
+            }
+        };
+        cells.push(Cell {
+            id: marker.id,
+            content,
+        });
+        line += section.matches('\n').count();
+    }
+
+    Ok(Notebook {
+        id: front_matter.id,
+        metadata: NotebookMetadata {
+            title: front_matter.title,
+            owner: front_matter.owner,
+            tags: front_matter.tags,
+            variables: front_matter.variables,
+            runbook: front_matter.runbook,
+            incident: front_matter.incident,
+            created_at: front_matter.created_at,
+            updated_at: front_matter.updated_at,
+        },
+        cells,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::incident::{Severity, Status};
+
+    fn notebook() -> Notebook {
+        let mut notebook = Notebook::new(
+            "api \"latency\"".into(),
+            "elias".into(),
+            vec!["sev2".into()],
+            vec![
+                Cell {
+                    id: "notes".into(),
+                    content: CellContent::Markdown {
+                        source: "## Impact\nslow checkout\n".into(),
+                    },
+                },
+                Cell {
+                    id: "latency".into(),
+                    content: CellContent::Plugin {
+                        plugin: "prometheus".into(),
+                        options: BTreeMap::from([
+                            (
+                                "query".to_string(),
+                                "sum(rate(errors[5m]))\n  by (job)".to_string(),
+                            ),
+                            ("step".to_string(), "30s".to_string()),
+                        ]),
+                    },
+                },
+                Cell {
+                    id: "tricky".into(),
+                    content: CellContent::Markdown {
+                        source: "<!-- cell {} -->\n\\<!-- cell x\n\n+++\n```".into(),
+                    },
+                },
+                Cell {
+                    id: "empty".into(),
+                    content: CellContent::Markdown { source: "".into() },
+                },
+            ],
+        );
+        notebook
+            .metadata
+            .variables
+            .insert("cluster".into(), "prod-eu".into());
+        let mut incident =
+            Incident::declare(Severity::Sev2, Some("ana".into()), vec![], None, Utc::now());
+        incident
+            .transition(
+                Status::Mitigated,
+                Some("bo".into()),
+                Some("rolled back\n+++".into()),
+                Utc::now(),
+            )
+            .unwrap();
+        notebook.metadata.incident = Some(incident);
+        notebook
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let notebook = notebook();
+        let file = write(&notebook).unwrap();
+        assert_eq!(notebook, read(&file).unwrap());
+
+        let empty = Notebook::new("empty".into(), "elias".into(), vec![], vec![]);
+        assert_eq!(empty, read(&write(&empty).unwrap()).unwrap());
+    }
+
+    #[test]
+    fn test_layout() {
+        let file = write(&notebook()).unwrap();
+        assert!(file.starts_with("+++\nid = "));
+        assert!(file.contains("\n+++\n\n<!-- cell {\"id\":\"notes\",\"type\":\"markdown\"} -->\n## Impact\nslow checkout\n\n\n"));
+        assert!(file.contains(
+            "<!-- cell {\"id\":\"latency\",\"type\":\"plugin\",\"plugin\":\"prometheus\"} -->\n```toml\nquery = '''\nsum(rate(errors[5m]))\n  by (job)'''\nstep = '30s'\n```\n"
+        ));
+        assert!(file.contains("\\<!-- cell {} -->\n\\\\<!-- cell x\n"));
+    }
+
+    #[test]
+    fn test_invalid_files() {
+        assert_eq!(Err(FileError::MissingFrontMatter), read("# api latency"));
+        let file = write(&notebook())
+            .unwrap()
+            .replace("\"type\":\"plugin\"", "\"type\":\"chart\"");
+        assert!(
+            matches!(read(&file), Err(FileError::InvalidCell(_, e)) if e == "unknown cell type chart")
+        );
+    }
+}
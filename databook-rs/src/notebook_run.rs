@@ -0,0 +1,161 @@
+use crate::assertions::{Assertion, AssertionResult};
+use crate::notebook::new_id;
+use crate::notebook_store::StoreError;
+use crate::timeline::ExecutionEvent;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+// NotebookRun is the report of a headless run of a notebook: every plugin
+// cell executed in order and its assertions checked. The outputs are on
+// the timeline, steps point to their execution event.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NotebookRun {
+    pub id: String,
+    pub notebook_id: String,
+    pub user: Option<String>,
+    pub passed: bool,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub steps: Vec<StepResult>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StepResult {
+    pub cell_id: String,
+    pub plugin: String,
+    // None when the cell could not be executed, e.g. a broken reference
+    pub event_id: Option<String>,
+    pub passed: bool,
+    pub error: Option<String>,
+    pub assertions: Vec<AssertionResult>,
+}
+
+impl NotebookRun {
+    pub fn new(
+        notebook_id: &str,
+        user: Option<String>,
+        started_at: DateTime<Utc>,
+        steps: Vec<StepResult>,
+    ) -> Self {
+        Self {
+            id: new_id(),
+            notebook_id: notebook_id.to_string(),
+            user,
+            passed: steps.iter().all(|s| s.passed),
+            started_at,
+            finished_at: Utc::now(),
+            steps,
+        }
+    }
+}
+
+impl StepResult {
+    // failed is a step that did not get to execute the plugin
+    pub fn failed(cell_id: &str, plugin: &str, error: String) -> Self {
+        Self {
+            cell_id: cell_id.to_string(),
+            plugin: plugin.to_string(),
+            event_id: None,
+            passed: false,
+            error: Some(error),
+            assertions: vec![],
+        }
+    }
+
+    // checked is a step that executed, it passes if the plugin succeeded
+    // and all assertions hold on its output
+    pub fn checked(event: &ExecutionEvent, assertions: &[Assertion]) -> Self {
+        let results: Vec<AssertionResult> = match &event.output {
+            Some(output) => assertions.iter().map(|a| a.check(output)).collect(),
+            None => assertions
+                .iter()
+                .map(|a| AssertionResult {
+                    assertion: a.text.clone(),
+                    passed: false,
+                    actual: None,
+                    error: Some("the cell failed".to_string()),
+                })
+                .collect(),
+        };
+        Self {
+            cell_id: event.cell_id.clone().unwrap_or_default(),
+            plugin: event.plugin.clone(),
+            event_id: Some(event.id.clone()),
+            passed: event.error.is_none() && results.iter().all(|r| r.passed),
+            error: event.error.clone(),
+            assertions: results,
+        }
+    }
+}
+
+// RunStore keeps the reports of headless runs
+pub trait RunStore: Send + Sync {
+    fn append_run(&self, run: &NotebookRun) -> Result<(), StoreError>;
+    // runs returns the runs of a notebook, most recent first
+    fn runs(&self, notebook_id: &str) -> Result<Vec<NotebookRun>, StoreError>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assertions::parse_assertions;
+    use crate::plugin_manager::{Execution, InvocationError};
+    use crate::plugin_runtime::InvocationTrace;
+    use crate::timeline::ExecutionContext;
+    use std::collections::BTreeMap;
+
+    fn event(output: Result<String, InvocationError>) -> ExecutionEvent {
+        ExecutionEvent::new(
+            ExecutionContext {
+                notebook_id: Some("n1".into()),
+                cell_id: Some("status".into()),
+                ..Default::default()
+            },
+            "http",
+            BTreeMap::new(),
+            Execution {
+                plugin_version: None,
+                output,
+                trace: InvocationTrace::default(),
+            },
+            Utc::now(),
+            Utc::now(),
+        )
+    }
+
+    #[test]
+    fn test_checked_step() {
+        let assertions = parse_assertions("status == 200; rows < 10").unwrap();
+        let passing = StepResult::checked(
+            &event(Ok(r#"{"status": 200, "rows": []}"#.into())),
+            &assertions,
+        );
+        assert!(passing.passed);
+        assert_eq!("status", passing.cell_id);
+        assert_eq!(2, passing.assertions.len());
+
+        let failing = StepResult::checked(
+            &event(Ok(r#"{"status": 503, "rows": []}"#.into())),
+            &assertions,
+        );
+        assert!(!failing.passed);
+        assert!(!failing.assertions[0].passed);
+        assert!(failing.assertions[1].passed);
+
+        let errored = StepResult::checked(&event(Err(InvocationError::GenericError)), &[]);
+        assert!(!errored.passed);
+        assert_eq!(Some("GenericError".to_string()), errored.error);
+    }
+
+    #[test]
+    fn test_run_passes_when_all_steps_pass() {
+        let passed = StepResult::checked(&event(Ok("ok".into())), &[]);
+        let run = NotebookRun::new("n1", None, Utc::now(), vec![passed.clone()]);
+        assert!(run.passed);
+
+        let failed = StepResult::failed("logs", "loki", "cell find_pod has no output yet".into());
+        let run = NotebookRun::new("n1", None, Utc::now(), vec![passed, failed]);
+        assert!(!run.passed);
+    }
+}
@@ -0,0 +1,42 @@
+use crate::crdt::{Operation, SequencedOperation};
+use crate::notebook::Notebook;
+
+use chrono::{DateTime, Utc};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum StoreError {
+    NotFound(String),
+    AlreadyExists(String),
+    // the notebook changed since it was read, see NotebookStore::update
+    Conflict(String),
+    Backend(String),
+}
+
+// NotebookStore persists notebooks, implementations must be safe to share
+// between the grpc and the REST servers
+pub trait NotebookStore: Send + Sync {
+    fn create(&self, notebook: &Notebook) -> Result<(), StoreError>;
+    fn get(&self, id: &str) -> Result<Notebook, StoreError>;
+    // list returns all notebooks, most recently updated first
+    fn list(&self) -> Result<Vec<Notebook>, StoreError>;
+    // update replaces title, tags, variables, cells and the incident of an
+    // existing notebook, `author` is the user who made the change. It fails
+    // with Conflict unless the stored notebook is still at `previous`, the
+    // updated_at it was read with, so concurrent saves cannot overwrite
+    // each other. The operations that led to the new cells are appended in
+    // the same transaction, it returns the seq of the last one.
+    fn update(
+        &self,
+        notebook: &Notebook,
+        previous: DateTime<Utc>,
+        operations: &[Operation],
+        author: Option<&str>,
+    ) -> Result<u64, StoreError>;
+    // delete removes the notebook and its operations
+    fn delete(&self, id: &str) -> Result<(), StoreError>;
+    // append_operations adds to the edit history of the notebook, the cells
+    // are the result of replaying it. It returns the seq of the last one.
+    fn append_operations(&self, id: &str, operations: &[Operation]) -> Result<u64, StoreError>;
+    // operations lists the edits after `since`, oldest first
+    fn operations(&self, id: &str, since: u64) -> Result<Vec<SequencedOperation>, StoreError>;
+}
@@ -0,0 +1,587 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+// media type of the layer holding a .dbplugin package
+pub const PACKAGE_MEDIA_TYPE: &str = "application/vnd.databook.plugin.v1.tar+zstd";
+const MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+const DEFAULT_TAG: &str = "latest";
+// file in the cache folder mapping references to the digests last pulled
+const CACHE_INDEX_FILE: &str = "index.toml";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RegistryError {
+    InvalidReference(String),
+    Http(String),
+    InvalidManifest(String),
+    DigestMismatch { expected: String, actual: String },
+    NotCached(String),
+    Io(String),
+}
+
+impl From<std::io::Error> for RegistryError {
+    fn from(e: std::io::Error) -> Self {
+        RegistryError::Io(e.to_string())
+    }
+}
+
+// PluginReference points to a plugin in an OCI registry, e.g.
+// `registry.local/sre/prometheus:1.2` or `registry.local/sre/prometheus@sha256:...`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PluginReference {
+    pub registry: String,
+    pub repository: String,
+    pub tag: String,
+    pub digest: Option<String>,
+}
+
+impl PluginReference {
+    pub fn parse(reference: &str) -> Result<Self, RegistryError> {
+        let invalid = || RegistryError::InvalidReference(reference.to_string());
+
+        let (name, digest) = match reference.split_once('@') {
+            Some((name, digest)) => {
+                validate_digest(digest)?;
+                (name, Some(digest.to_string()))
+            }
+            None => (reference, None),
+        };
+
+        let (registry, path) = name.split_once('/').ok_or_else(invalid)?;
+
+        // the tag is after the last `:` of the path, a `:` in the registry is a port
+        let (repository, tag) = match path.rsplit_once(':') {
+            Some((repository, tag)) => (repository, tag),
+            None => (path, DEFAULT_TAG),
+        };
+
+        if registry.is_empty() || repository.is_empty() || tag.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            registry: registry.to_string(),
+            repository: repository.to_string(),
+            tag: tag.to_string(),
+            digest,
+        })
+    }
+
+    // a digest in the reference wins over the tag
+    fn manifest_reference(&self) -> &str {
+        self.digest.as_deref().unwrap_or(&self.tag)
+    }
+}
+
+impl fmt::Display for PluginReference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}:{}", self.registry, self.repository, self.tag)?;
+        if let Some(ref digest) = self.digest {
+            write!(f, "@{}", digest)?;
+        }
+        Ok(())
+    }
+}
+
+// PluginsManifest lists the plugins to pull at startup, e.g.
+//
+// [[plugins]]
+// reference = "registry.local/sre/prometheus:1.2"
+// digest = "sha256:..."
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone)]
+pub struct PluginsManifest {
+    pub plugins: Vec<PluginsManifestEntry>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone)]
+pub struct PluginsManifestEntry {
+    pub reference: String,
+    // pins the digest of the OCI manifest, pulls resolving to anything
+    // else are refused
+    pub digest: Option<String>,
+    // replaces an installed plugin with the same name, e.g. one of the
+    // plugin folder, the pull is refused otherwise
+    pub replace: Option<bool>,
+}
+
+// RegistryToken is the bearer token sent to a registry, e.g. a personal
+// access token
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone)]
+pub struct RegistryToken {
+    pub registry: String,
+    pub token: String,
+}
+
+impl PluginsManifest {
+    pub fn new_from_file(path: PathBuf) -> Option<Self> {
+        match fs::read_to_string(&path) {
+            Ok(manifest) => Self::new_from_str(&manifest),
+            Err(e) => {
+                tracing::warn!("unable to read plugins manifest {:?}", e);
+                None
+            }
+        }
+    }
+    pub fn new_from_str(manifest: &str) -> Option<Self> {
+        match toml::from_str::<PluginsManifest>(manifest) {
+            Ok(manifest) => Some(manifest),
+            Err(e) => {
+                tracing::warn!("unable to parse plugins manifest {:?}", e);
+                None
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OciManifest {
+    layers: Vec<OciDescriptor>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OciDescriptor {
+    media_type: String,
+    digest: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CacheIndex {
+    plugins: BTreeMap<String, CacheEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    manifest_digest: String,
+    package_digest: String,
+}
+
+// PulledPackage is a .dbplugin whose content matched its digest
+#[derive(Debug)]
+pub struct PulledPackage {
+    pub path: PathBuf,
+    pub digest: String,
+    pub bytes: Vec<u8>,
+}
+
+pub struct RegistryClient {
+    // pulled packages are kept in <cache>/blobs/sha256/<hex>
+    cache: PathBuf,
+    // registries reached with http instead of https
+    insecure_registries: Vec<String>,
+    // registry -> bearer token
+    tokens: BTreeMap<String, String>,
+    http: reqwest::blocking::Client,
+}
+
+impl RegistryClient {
+    pub fn new(
+        cache: PathBuf,
+        insecure_registries: Vec<String>,
+        tokens: Vec<RegistryToken>,
+    ) -> Self {
+        for token in &tokens {
+            if insecure_registries.contains(&token.registry) {
+                tracing::warn!("the token of registry {} is sent over http", token.registry);
+            }
+        }
+        Self {
+            cache,
+            insecure_registries,
+            tokens: tokens.into_iter().map(|t| (t.registry, t.token)).collect(),
+            http: reqwest::blocking::Client::new(),
+        }
+    }
+
+    // pull resolves the reference on the registry and returns its package,
+    // the package is only downloaded if it is not in the cache yet
+    pub fn pull(
+        &self,
+        reference: &PluginReference,
+        pinned: Option<&str>,
+    ) -> Result<PulledPackage, RegistryError> {
+        let manifest = self.get(
+            &format!(
+                "v2/{}/manifests/{}",
+                reference.repository,
+                reference.manifest_reference()
+            ),
+            reference,
+            Some(MANIFEST_MEDIA_TYPE),
+        )?;
+
+        let manifest_digest = sha256_digest(&manifest);
+        for expected in reference.digest.iter().map(String::as_str).chain(pinned) {
+            check_digest(expected, &manifest_digest)?;
+        }
+
+        let manifest: OciManifest = serde_json::from_slice(&manifest)
+            .map_err(|e| RegistryError::InvalidManifest(e.to_string()))?;
+        let layer = manifest
+            .layers
+            .iter()
+            .find(|l| l.media_type == PACKAGE_MEDIA_TYPE)
+            .ok_or_else(|| {
+                RegistryError::InvalidManifest(format!("no {} layer", PACKAGE_MEDIA_TYPE))
+            })?;
+        validate_digest(&layer.digest)?;
+
+        let path = self.blob_path(&layer.digest);
+        let package = match read_verified(&path, &layer.digest) {
+            Ok(bytes) => {
+                tracing::info!("using cached package {} for {}", layer.digest, reference);
+                PulledPackage {
+                    path,
+                    digest: layer.digest.clone(),
+                    bytes,
+                }
+            }
+            Err(_) => {
+                tracing::info!("pulling package {} for {}", layer.digest, reference);
+                let bytes = self.get(
+                    &format!("v2/{}/blobs/{}", reference.repository, layer.digest),
+                    reference,
+                    None,
+                )?;
+                check_digest(&layer.digest, &sha256_digest(&bytes))?;
+                fs::create_dir_all(path.parent().expect("blob path always has a parent"))?;
+                fs::write(&path, &bytes)?;
+                PulledPackage {
+                    path,
+                    digest: layer.digest.clone(),
+                    bytes,
+                }
+            }
+        };
+
+        self.update_index(
+            reference,
+            CacheEntry {
+                manifest_digest,
+                package_digest: package.digest.clone(),
+            },
+        )?;
+
+        Ok(package)
+    }
+
+    // cached returns the package last pulled for the reference, it is used
+    // when the registry cannot be reached
+    pub fn cached(
+        &self,
+        reference: &PluginReference,
+        pinned: Option<&str>,
+    ) -> Result<PulledPackage, RegistryError> {
+        let entry = self
+            .read_index()?
+            .plugins
+            .remove(&reference.to_string())
+            .ok_or_else(|| RegistryError::NotCached(reference.to_string()))?;
+
+        for expected in reference.digest.iter().map(String::as_str).chain(pinned) {
+            check_digest(expected, &entry.manifest_digest)?;
+        }
+        validate_digest(&entry.package_digest)?;
+
+        let path = self.blob_path(&entry.package_digest);
+        let bytes = read_verified(&path, &entry.package_digest)?;
+        Ok(PulledPackage {
+            path,
+            digest: entry.package_digest,
+            bytes,
+        })
+    }
+
+    fn get(
+        &self,
+        path: &str,
+        reference: &PluginReference,
+        accept: Option<&str>,
+    ) -> Result<Vec<u8>, RegistryError> {
+        let scheme = if self.insecure_registries.contains(&reference.registry) {
+            "http"
+        } else {
+            "https"
+        };
+        let mut request = self
+            .http
+            .get(format!("{}://{}/{}", scheme, reference.registry, path));
+        if let Some(accept) = accept {
+            request = request.header("Accept", accept);
+        }
+        // reqwest drops it on redirects to another host, e.g. blob storage
+        if let Some(token) = self.tokens.get(&reference.registry) {
+            request = request.bearer_auth(token);
+        }
+        let response = request
+            .send()
+            .map_err(|e| RegistryError::Http(e.to_string()))?;
+        if !response.status().is_success() {
+            return Err(RegistryError::Http(format!(
+                "{} returned {}",
+                path,
+                response.status()
+            )));
+        }
+        response
+            .bytes()
+            .map(|b| b.to_vec())
+            .map_err(|e| RegistryError::Http(e.to_string()))
+    }
+
+    fn blob_path(&self, digest: &str) -> PathBuf {
+        let hex = digest.trim_start_matches("sha256:");
+        self.cache.join("blobs").join("sha256").join(hex)
+    }
+
+    fn read_index(&self) -> Result<CacheIndex, RegistryError> {
+        let path = self.cache.join(CACHE_INDEX_FILE);
+        if !path.is_file() {
+            return Ok(CacheIndex::default());
+        }
+        toml::from_str(&fs::read_to_string(path)?).map_err(|e| RegistryError::Io(e.to_string()))
+    }
+
+    fn update_index(
+        &self,
+        reference: &PluginReference,
+        entry: CacheEntry,
+    ) -> Result<(), RegistryError> {
+        let mut index = self.read_index()?;
+        index.plugins.insert(reference.to_string(), entry);
+        let content = toml::to_string(&index).map_err(|e| RegistryError::Io(e.to_string()))?;
+        fs::create_dir_all(&self.cache)?;
+        fs::write(self.cache.join(CACHE_INDEX_FILE), content)?;
+        Ok(())
+    }
+}
+
+pub fn sha256_digest(bytes: &[u8]) -> String {
+    format!("sha256:{}", hex::encode(Sha256::digest(bytes)))
+}
+
+// read_verified reads a cached blob, refusing it if its content changed
+pub fn read_verified(path: &std::path::Path, digest: &str) -> Result<Vec<u8>, RegistryError> {
+    let bytes = fs::read(path)?;
+    check_digest(digest, &sha256_digest(&bytes))?;
+    Ok(bytes)
+}
+
+fn check_digest(expected: &str, actual: &str) -> Result<(), RegistryError> {
+    if expected == actual {
+        Ok(())
+    } else {
+        Err(RegistryError::DigestMismatch {
+            expected: expected.to_string(),
+            actual: actual.to_string(),
+        })
+    }
+}
+
+// only sha256 digests are supported, they are also used as file names
+fn validate_digest(digest: &str) -> Result<(), RegistryError> {
+    match digest.strip_prefix("sha256:") {
+        Some(hex) if hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit()) => Ok(()),
+        _ => Err(RegistryError::InvalidReference(format!(
+            "unsupported digest {:?}",
+            digest
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    const PACKAGE: &[u8] = b"not really a package";
+
+    fn manifest() -> String {
+        format!(
+            r#"{{"schemaVersion":2,"layers":[{{"mediaType":"{}","digest":"{}","size":{}}}]}}"#,
+            PACKAGE_MEDIA_TYPE,
+            sha256_digest(PACKAGE),
+            PACKAGE.len()
+        )
+    }
+
+    // a local registry stand-in serving one plugin under sre/hello:1.0
+    fn registry(package: &'static [u8]) -> MockServer {
+        let server = tokio_test::block_on(MockServer::start());
+        tokio_test::block_on(
+            Mock::given(method("GET"))
+                .and(path("/v2/sre/hello/manifests/1.0"))
+                .respond_with(ResponseTemplate::new(200).set_body_string(manifest()))
+                .mount(&server),
+        );
+        tokio_test::block_on(
+            Mock::given(method("GET"))
+                .and(path(format!(
+                    "/v2/sre/hello/blobs/{}",
+                    sha256_digest(PACKAGE)
+                )))
+                .respond_with(ResponseTemplate::new(200).set_body_bytes(package))
+                .mount(&server),
+        );
+        server
+    }
+
+    fn reference(server: &MockServer) -> PluginReference {
+        PluginReference::parse(&format!("{}/sre/hello:1.0", server.address())).unwrap()
+    }
+
+    fn client(server: &MockServer, cache: &tempfile::TempDir) -> RegistryClient {
+        RegistryClient::new(
+            cache.path().to_path_buf(),
+            vec![server.address().to_string()],
+            vec![],
+        )
+    }
+
+    #[test]
+    fn test_parse_reference() {
+        assert_eq!(
+            PluginReference {
+                registry: "registry.local".into(),
+                repository: "sre/prometheus".into(),
+                tag: "1.2".into(),
+                digest: None,
+            },
+            PluginReference::parse("registry.local/sre/prometheus:1.2").unwrap()
+        );
+        assert_eq!(
+            "latest",
+            PluginReference::parse("localhost:5000/prometheus")
+                .unwrap()
+                .tag
+        );
+        assert!(PluginReference::parse("prometheus").is_err());
+        assert!(PluginReference::parse("registry.local/prometheus@md5:abc").is_err());
+    }
+
+    #[test]
+    fn test_pull_and_cache() {
+        let server = registry(PACKAGE);
+        let cache = tempfile::tempdir().unwrap();
+        let client = client(&server, &cache);
+        let reference = reference(&server);
+
+        let pulled = client.pull(&reference, None).unwrap();
+        assert_eq!(PACKAGE, pulled.bytes.as_slice());
+        assert!(pulled.path.is_file());
+
+        let cached = client.cached(&reference, None).unwrap();
+        assert_eq!(pulled.digest, cached.digest);
+    }
+
+    #[test]
+    fn test_pinned_digest() {
+        let server = registry(PACKAGE);
+        let cache = tempfile::tempdir().unwrap();
+        let client = client(&server, &cache);
+        let reference = reference(&server);
+
+        let pinned = sha256_digest(manifest().as_bytes());
+        assert!(client.pull(&reference, Some(&pinned)).is_ok());
+
+        let other = sha256_digest(b"other manifest");
+        assert!(matches!(
+            client.pull(&reference, Some(&other)),
+            Err(RegistryError::DigestMismatch { .. })
+        ));
+        assert!(matches!(
+            client.cached(&reference, Some(&other)),
+            Err(RegistryError::DigestMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_tampered_blob() {
+        let server = registry(b"tampered package");
+        let cache = tempfile::tempdir().unwrap();
+        let client = client(&server, &cache);
+
+        assert!(matches!(
+            client.pull(&reference(&server), None),
+            Err(RegistryError::DigestMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_tampered_cache() {
+        let server = registry(PACKAGE);
+        let cache = tempfile::tempdir().unwrap();
+        let client = client(&server, &cache);
+        let reference = reference(&server);
+
+        let pulled = client.pull(&reference, None).unwrap();
+        fs::write(&pulled.path, b"changed on disk").unwrap();
+        assert!(matches!(
+            client.cached(&reference, None),
+            Err(RegistryError::DigestMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_registry_token() {
+        let server = tokio_test::block_on(MockServer::start());
+        tokio_test::block_on(
+            Mock::given(method("GET"))
+                .and(path("/v2/sre/hello/manifests/1.0"))
+                .and(header("Authorization", "Bearer s3cret"))
+                .respond_with(ResponseTemplate::new(200).set_body_string(manifest()))
+                .mount(&server),
+        );
+        tokio_test::block_on(
+            Mock::given(method("GET"))
+                .and(path(format!(
+                    "/v2/sre/hello/blobs/{}",
+                    sha256_digest(PACKAGE)
+                )))
+                .and(header("Authorization", "Bearer s3cret"))
+                .respond_with(ResponseTemplate::new(200).set_body_bytes(PACKAGE))
+                .mount(&server),
+        );
+        let cache = tempfile::tempdir().unwrap();
+        let reference = reference(&server);
+        assert!(matches!(
+            client(&server, &cache).pull(&reference, None),
+            Err(RegistryError::Http(_))
+        ));
+
+        let token = RegistryToken {
+            registry: server.address().to_string(),
+            token: "s3cret".into(),
+        };
+        let client = RegistryClient::new(
+            cache.path().to_path_buf(),
+            vec![server.address().to_string()],
+            vec![token],
+        );
+        assert_eq!(
+            PACKAGE,
+            client.pull(&reference, None).unwrap().bytes.as_slice()
+        );
+    }
+
+    #[test]
+    fn test_plugins_manifest_from_str() {
+        let manifest = PluginsManifest::new_from_str(
+            "[[plugins]]\nreference = 'registry.local/sre/prometheus:1.2'\n",
+        );
+        assert_eq!(
+            Some(PluginsManifest {
+                plugins: vec![PluginsManifestEntry {
+                    reference: "registry.local/sre/prometheus:1.2".into(),
+                    digest: None,
+                    replace: None,
+                }],
+            }),
+            manifest
+        );
+    }
+}
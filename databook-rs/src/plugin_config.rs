@@ -6,6 +6,57 @@ pub struct PluginConfig {
     pub name: String,
     pub allowed_env_vars: Option<Vec<String>>,
     pub allowed_domains: Option<Vec<String>>,
+    // host functions the plugin may import. Plugins without it are legacy
+    // and get every capability.
+    pub capabilities: Option<Vec<Capability>>,
+    // option receiving the body of a `@plugin=` cell, defaults to `query`
+    pub body_option: Option<String>,
+    // invocations wait until other users approve them, see approval.rs
+    pub requires_approval: Option<ApprovalConfig>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ApprovalConfig {
+    // group (role) the approvers must be in
+    pub group: String,
+    // approvers needed besides the one who invoked it, 1 by default
+    pub min_approvers: Option<u32>,
+    // approvals count for that long after the invocation, 60 by default
+    pub window_minutes: Option<u32>,
+}
+
+impl ApprovalConfig {
+    pub fn min_approvers(&self) -> u32 {
+        self.min_approvers.unwrap_or(1).max(1)
+    }
+
+    pub fn window(&self) -> chrono::Duration {
+        chrono::Duration::minutes(self.window_minutes.unwrap_or(60).into())
+    }
+}
+
+// Capability groups the host imports (runtime.wit and WASI) a plugin can use
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone, Copy, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Capability {
+    Http,
+    Env,
+    Fs,
+    Clock,
+    Random,
+    Log,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 6] = [
+        Capability::Http,
+        Capability::Env,
+        Capability::Fs,
+        Capability::Clock,
+        Capability::Random,
+        Capability::Log,
+    ];
 }
 
 impl PluginConfig {
@@ -18,6 +69,12 @@ impl PluginConfig {
             }
         }
     }
+    pub fn has_capability(&self, capability: Capability) -> bool {
+        self.capabilities
+            .as_ref()
+            .map_or(true, |c| c.contains(&capability))
+    }
+
     pub fn new_from_str(config: &str) -> Option<Self> {
         match toml::from_str::<PluginConfig>(config) {
             Ok(config) => Some(config),
@@ -43,8 +100,56 @@ mod tests {
                 name: "MyTest".into(),
                 allowed_env_vars: Some(vec!["A".to_string()]),
                 allowed_domains: Some(vec!["a.com".to_string()]),
+                capabilities: None,
+                body_option: None,
+                requires_approval: None,
             }),
             config
         );
     }
+
+    #[test]
+    fn test_requires_approval() {
+        let config = PluginConfig::new_from_str(
+            "name = 'restart'\n[requires_approval]\ngroup = 'sre-leads'\nmin_approvers = 2",
+        )
+        .unwrap();
+        let approval = config.requires_approval.unwrap();
+        assert_eq!("sre-leads", approval.group);
+        assert_eq!(2, approval.min_approvers());
+        assert_eq!(chrono::Duration::minutes(60), approval.window());
+
+        assert_eq!(
+            None,
+            PluginConfig::new_from_str("name = 'restart'\n[requires_approval]\napprovers = 2")
+        );
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let config =
+            PluginConfig::new_from_str("name = 'MyTest'\ncapabilities=['http', 'log']").unwrap();
+        assert_eq!(
+            Some(vec![Capability::Http, Capability::Log]),
+            config.capabilities
+        );
+        assert!(config.has_capability(Capability::Http));
+        assert!(!config.has_capability(Capability::Fs));
+
+        assert_eq!(
+            None,
+            PluginConfig::new_from_str("name = 'MyTest'\ncapabilities=['network']")
+        );
+        // no host function hands out secrets, so there is nothing to grant
+        assert_eq!(
+            None,
+            PluginConfig::new_from_str("name = 'MyTest'\ncapabilities=['secrets']")
+        );
+    }
+
+    #[test]
+    fn test_legacy_config_has_all_capabilities() {
+        let config = PluginConfig::new_from_str("name = 'MyTest'").unwrap();
+        assert!(Capability::ALL.iter().all(|c| config.has_capability(*c)));
+    }
 }
@@ -1,111 +1,1095 @@
-use crate::plugin_config::PluginConfig;
+use crate::approval::{self, Approval};
+use crate::audit::{self, Action, AuditLog, AuditRecord, Outcome};
+use crate::cell_parser;
+use crate::oci_registry::{PluginReference, PluginsManifest, RegistryClient, RegistryError};
+use crate::plugin_config::{ApprovalConfig, PluginConfig};
+use crate::plugin_package::{
+    is_package, PluginManifest, PluginPackage, CONFIG_FILE, PACKAGE_EXTENSION, WASM_FILE,
+};
+use crate::plugin_runtime::InvocationTrace;
+use crate::plugin_signature::{PluginSignature, SignatureError, TrustedKeys, SIGNATURE_FILE};
+use crate::policy::{PolicyStore, Principal};
+use crate::redaction::RedactionRules;
 use crate::wasm::WasmModule;
 
-use std::collections::HashMap;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// file on the plugin folder listing the plugins disabled by an admin,
+// their files are kept so they can be enabled again
+const DISABLED_FILE: &str = "disabled.toml";
 
 #[derive(Debug)]
 pub enum InvocationError {
     PluginDoesNotExist,
+    PluginDisabled,
+    // the policy does not let the caller invoke the plugin, with why
+    Forbidden(String),
+    // the plugin requires approval and the invocation does not have enough
+    ApprovalRequired(String),
+    // the audit log could not record the invocation, see AuditConfig::fail_open
+    AuditFailed(String),
     GenericError,
 }
 #[derive(Debug)]
 pub enum PluginError {
     InvalidFolder,
+    InvalidPlugin(String),
+    AlreadyInstalled(String),
+    PluginDoesNotExist(String),
+    Registry(String),
+    Io(String),
+}
+
+impl From<std::io::Error> for PluginError {
+    fn from(e: std::io::Error) -> Self {
+        PluginError::Io(e.to_string())
+    }
+}
+
+// PluginSource is what an admin uploads to install or upgrade a plugin
+#[derive(Debug, Clone)]
+pub enum PluginSource {
+    // a .dbplugin archive
+    Package(Vec<u8>),
+    // the loose files of a plugin folder
+    Files {
+        wasm: Vec<u8>,
+        config: Vec<u8>,
+        signature: Option<Vec<u8>>,
+    },
+}
+
+// StagedPlugin is a validated plugin written next to where it goes, see
+// PluginManager::stage
+#[derive(Debug)]
+pub struct StagedPlugin {
+    plugin: Plugin,
+    upgrade: bool,
+    staging: PathBuf,
+    destination: PathBuf,
+}
+
+// PluginInfo is the public view of a registered plugin
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PluginInfo {
+    pub name: String,
+    pub version: Option<String>,
+    pub enabled: bool,
+}
+
+// Execution is the result of invoking a plugin with what it did on the way
+#[derive(Debug)]
+pub struct Execution {
+    pub plugin_version: Option<String>,
+    pub output: Result<String, InvocationError>,
+    pub trace: InvocationTrace,
+}
+
+impl Execution {
+    fn failed(error: InvocationError) -> Self {
+        Self {
+            plugin_version: None,
+            output: Err(error),
+            trace: InvocationTrace::default(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct DisabledPlugins {
+    plugins: Vec<String>,
 }
 
 #[derive(Debug)]
 struct Plugin {
     config: PluginConfig,
     wasm: WasmModule,
+    // only plugins loaded from a .dbplugin package have a manifest
+    manifest: Option<PluginManifest>,
+    // the folder or package file the plugin was loaded from
+    source: PathBuf,
+    // the registry reference the plugin was pulled from. Its source is then
+    // a blob of the registry cache, which is not the plugin's to remove.
+    pulled_from: Option<String>,
 }
 
 impl Plugin {
     // new_from_folder returns a Plugin if a valid config.toml and plugin.wasm
     // can be found on the folder. If it cannot it will return None.
-    pub fn new_from_folder(path: std::path::PathBuf) -> Option<Self> {
-        let config_file = path.join("config.toml");
+    // When trusted_keys is set the folder must also contain a plugin.sig
+    // signed by one of those keys, otherwise the plugin is refused.
+    pub fn new_from_folder(path: PathBuf, trusted_keys: Option<&TrustedKeys>) -> Option<Self> {
+        let config_file = path.join(CONFIG_FILE);
 
         if !config_file.is_file() {
             tracing::info!("no config file found, ignoring");
             return None;
         }
 
-        let wasm_path = path.join("plugin.wasm");
+        let wasm_path = path.join(WASM_FILE);
 
         if !wasm_path.is_file() {
             tracing::info!("no wasm file found, ignoring");
             return None;
         }
 
-        let config = PluginConfig::new_from_file(config_file);
-
-        // loads the wasm module from the wasm_path
-        let wasm = match WasmModule::new(wasm_path.to_str().unwrap()) {
-            Ok(wasm) => wasm,
-            Err(_) => return None,
+        // the same bytes that are verified are the ones loaded, so the files
+        // cannot be swapped between the check and the load
+        let (config_bytes, wasm_bytes) = match (fs::read(&config_file), fs::read(&wasm_path)) {
+            (Ok(config), Ok(wasm)) => (config, wasm),
+            (Err(e), _) | (_, Err(e)) => {
+                tracing::warn!("unable to read plugin files {:?}", e);
+                return None;
+            }
         };
 
-        match config {
-            Some(config) => {
-                tracing::info!("valid plugin");
-                Some(Self { config, wasm })
+        let signature = PluginSignature::new_from_file(path.join(SIGNATURE_FILE));
+
+        Self::new_from_parts(
+            &config_bytes,
+            &wasm_bytes,
+            signature,
+            trusted_keys,
+            None,
+            path.clone(),
+        )
+        .map_err(|e| tracing::warn!("refusing plugin {:?} {:?}", path, e))
+        .ok()
+    }
+
+    // new_from_package loads a .dbplugin archive, the same signature rules
+    // of new_from_folder apply to the wasm and config inside of it
+    pub fn new_from_package(path: PathBuf, trusted_keys: Option<&TrustedKeys>) -> Option<Self> {
+        let package = match PluginPackage::new_from_file(&path) {
+            Ok(package) => package,
+            Err(e) => {
+                tracing::warn!("invalid plugin package {:?} {:?}", path, e);
+                return None;
             }
-            None => None,
+        };
+
+        Self::new_from_loaded_package(package, trusted_keys, path.clone())
+            .map_err(|e| tracing::warn!("refusing plugin {:?} {:?}", path, e))
+            .ok()
+    }
+
+    fn new_from_loaded_package(
+        package: PluginPackage,
+        trusted_keys: Option<&TrustedKeys>,
+        source: PathBuf,
+    ) -> Result<Self, PluginError> {
+        let signature = parse_signature(package.signature.as_deref());
+
+        let plugin = Self::new_from_parts(
+            &package.config,
+            &package.wasm,
+            signature,
+            trusted_keys,
+            Some(package.manifest.clone()),
+            source,
+        )?;
+
+        if plugin.config.name != package.manifest.name {
+            return Err(PluginError::InvalidPlugin(format!(
+                "manifest name {:?} does not match config name {:?}",
+                package.manifest.name, plugin.config.name
+            )));
+        }
+
+        Ok(plugin)
+    }
+
+    fn new_from_parts(
+        config_bytes: &[u8],
+        wasm_bytes: &[u8],
+        signature: Result<PluginSignature, SignatureError>,
+        trusted_keys: Option<&TrustedKeys>,
+        manifest: Option<PluginManifest>,
+        source: PathBuf,
+    ) -> Result<Self, PluginError> {
+        if let Some(trusted_keys) = trusted_keys {
+            signature
+                .and_then(|s| trusted_keys.verify(&s, wasm_bytes, config_bytes))
+                .map_err(|e| {
+                    PluginError::InvalidPlugin(format!("signature check failed {:?}", e))
+                })?;
+        }
+
+        let config = std::str::from_utf8(config_bytes)
+            .ok()
+            .and_then(PluginConfig::new_from_str)
+            .ok_or_else(|| PluginError::InvalidPlugin("invalid config.toml".into()))?;
+
+        if !cell_parser::is_valid_plugin_name(&config.name) {
+            return Err(PluginError::InvalidPlugin(format!(
+                "invalid plugin name {:?}",
+                config.name
+            )));
         }
+
+        // loads the wasm module from the bytes read above
+        let wasm = WasmModule::new(wasm_bytes, &config)
+            .map_err(|e| PluginError::InvalidPlugin(format!("invalid wasm module {:?}", e)))?;
+
+        tracing::info!("valid plugin");
+        Ok(Self {
+            config,
+            wasm,
+            manifest,
+            source,
+            pulled_from: None,
+        })
     }
 
     // instantiate the wasm module and calls (exported) invoke function
     // passing the input to it
-    pub fn invoke(&self, input: HashMap<String, String>) -> Result<String, InvocationError> {
-        self.wasm
-            .invoke(input, self.config.clone())
-            .map_err(|_| InvocationError::GenericError)
+    pub fn invoke(
+        &self,
+        input: HashMap<String, String>,
+        rules: &Arc<RedactionRules>,
+    ) -> (Result<String, InvocationError>, InvocationTrace) {
+        let (output, trace) = self.wasm.invoke(input, self.config.clone(), rules.clone());
+        (output.map_err(|_| InvocationError::GenericError), trace)
+    }
+}
+
+fn parse_signature(signature: Option<&[u8]>) -> Result<PluginSignature, SignatureError> {
+    match signature {
+        Some(signature) => std::str::from_utf8(signature)
+            .map_err(|e| SignatureError::Malformed(e.to_string()))
+            .and_then(PluginSignature::new_from_str),
+        None => Err(SignatureError::Missing),
     }
 }
 
 #[derive(Debug)]
 pub struct PluginManager {
     // any plugin (wasm files) in this folder will be registered
-    folder: std::path::PathBuf,
+    folder: PathBuf,
 
     //all plugins registered, <Name, Plugin>
     plugins: HashMap<String, Plugin>,
+
+    // when set, only plugins signed by one of those keys are registered
+    trusted_keys: Option<TrustedKeys>,
+
+    // plugins that are registered but cannot be invoked
+    disabled: HashSet<String>,
+
+    // when set, who may invoke which plugin
+    policy: Option<Arc<PolicyStore>>,
+
+    // when set, every invocation is recorded there
+    audit: Option<Arc<AuditLog>>,
+
+    // what is redacted from the outputs and logs of invocations
+    redaction: Arc<RedactionRules>,
 }
 
 impl PluginManager {
-    pub fn new(folder: std::path::PathBuf) -> Self {
+    pub fn new(folder: PathBuf, trusted_keys: Option<TrustedKeys>) -> Self {
+        if trusted_keys.is_none() {
+            tracing::warn!("no trusted keys configured, plugin signatures will not be verified");
+        }
         Self {
             folder,
             plugins: HashMap::new(),
+            trusted_keys,
+            disabled: HashSet::new(),
+            policy: None,
+            audit: None,
+            redaction: Arc::default(),
         }
     }
 
+    pub fn set_policy(&mut self, policy: Arc<PolicyStore>) {
+        self.policy = Some(policy);
+    }
+
+    pub fn set_audit(&mut self, audit: Arc<AuditLog>) {
+        self.audit = Some(audit);
+    }
+
+    pub fn set_redaction(&mut self, rules: Arc<RedactionRules>) {
+        self.redaction = rules;
+    }
+
     pub fn registry(&mut self) -> Result<(), PluginError> {
         let paths = fs::read_dir(&self.folder).map_err(|_| PluginError::InvalidFolder)?;
         for entry in paths {
             let entry = entry.map_err(|_| PluginError::InvalidFolder)?.path();
+            if is_hidden(&entry) {
+                // leftovers from an interrupted install
+                continue;
+            }
             if entry.is_dir() {
                 tracing::info!("trying to install plugin {:?}", entry.display());
                 // invalid plugins are silently ignored
-                Plugin::new_from_folder(entry)
-                    .map(|p| self.plugins.insert(p.config.name.clone(), p));
+                let plugin = Plugin::new_from_folder(entry, self.trusted_keys.as_ref());
+                self.register(plugin);
+            } else if is_package(&entry) {
+                tracing::info!("trying to install plugin package {:?}", entry.display());
+                let plugin = Plugin::new_from_package(entry, self.trusted_keys.as_ref());
+                self.register(plugin);
             }
         }
 
+        self.disabled = self.read_disabled()?;
+
         Ok(())
     }
 
-    // invokes the plugin using wasm
-    pub fn invoke(
+    // sync_registry pulls every plugin listed on the manifest, plugins that
+    // cannot be pulled or verified are skipped like invalid plugin folders
+    pub fn sync_registry(&mut self, manifest: &PluginsManifest, client: &RegistryClient) {
+        for entry in &manifest.plugins {
+            let replace = entry.replace.unwrap_or(false);
+            let installed = PluginReference::parse(&entry.reference)
+                .map_err(|e| PluginError::Registry(format!("{:?}", e)))
+                .and_then(|r| {
+                    self.install_from_registry(&r, entry.digest.as_deref(), replace, client)
+                });
+            match installed {
+                Ok(info) => tracing::info!("registered plugin {:?} from {}", info, entry.reference),
+                Err(e) => tracing::warn!("could not pull plugin {} {:?}", entry.reference, e),
+            }
+        }
+    }
+
+    // install_from_registry pulls the package from an OCI registry, falling
+    // back to the cached copy when the registry cannot be reached. A plugin
+    // with the same name already registered, e.g. from the plugin folder, is
+    // only replaced with `replace`.
+    pub fn install_from_registry(
+        &mut self,
+        reference: &PluginReference,
+        pinned: Option<&str>,
+        replace: bool,
+        client: &RegistryClient,
+    ) -> Result<PluginInfo, PluginError> {
+        let pulled = match client.pull(reference, pinned) {
+            Err(RegistryError::Http(e)) => {
+                tracing::warn!("registry unreachable for {} {}, using cache", reference, e);
+                client.cached(reference, pinned)
+            }
+            pulled => pulled,
+        }
+        .map_err(|e| PluginError::Registry(format!("{:?}", e)))?;
+
+        let package = PluginPackage::new_from_bytes(&pulled.bytes)
+            .map_err(|e| PluginError::InvalidPlugin(format!("{:?}", e)))?;
+        let mut plugin =
+            Plugin::new_from_loaded_package(package, self.trusted_keys.as_ref(), pulled.path)?;
+        plugin.pulled_from = Some(reference.to_string());
+        let name = plugin.config.name.clone();
+        if self.plugins.contains_key(&name) {
+            if !replace {
+                return Err(PluginError::AlreadyInstalled(name));
+            }
+            tracing::warn!(
+                "plugin {:?} from {} replaces the installed one",
+                name,
+                reference
+            );
+        }
+        self.register(Some(plugin));
+        Ok(self.info(&name))
+    }
+
+    fn register(&mut self, plugin: Option<Plugin>) {
+        if let Some(plugin) = plugin {
+            tracing::info!(
+                "registered plugin {:?} version {:?}",
+                plugin.config.name,
+                plugin.manifest.as_ref().map(|m| &m.version)
+            );
+            self.plugins.insert(plugin.config.name.clone(), plugin);
+        }
+    }
+
+    // execute invokes the plugin keeping the logs and host calls it made,
+    // once the policy lets the principal invoke it with that input and, for
+    // plugins requiring it, others approved it. Refused and failed
+    // invocations are audited as well. Unless the audit log fails open, a
+    // plugin only runs once its start is recorded and its output is only
+    // returned once the outcome is.
+    pub fn execute(
         &self,
         plugin_name: &str,
         input: HashMap<String, String>,
-    ) -> Result<String, InvocationError> {
+        principal: &Principal,
+        source: Option<&str>,
+        approvals: &[Approval],
+    ) -> Execution {
+        let started_at = Utc::now();
+        let mut record = self.audit.as_ref().map(|_| AuditRecord {
+            action: Action::Invoke,
+            user: principal.user.clone(),
+            groups: principal.groups.clone(),
+            source: source.map(str::to_string),
+            plugin: Some(plugin_name.to_string()),
+            options_hash: Some(audit::options_hash(&input)),
+            approvers: approvals.iter().map(|a| a.user.clone()).collect(),
+            outcome: Outcome::Started,
+            error: None,
+            started_at,
+            duration_ms: 0,
+        });
+        let mut execution =
+            self.execute_unaudited(plugin_name, input, principal, approvals, || match &record {
+                Some(record) => self.append_audit(record.clone()),
+                None => Ok(()),
+            });
+        if let Some(record) = &mut record {
+            let (outcome, error) = match &execution.output {
+                Ok(_) => (Outcome::Succeeded, None),
+                Err(InvocationError::Forbidden(reason)) => (Outcome::Refused, Some(reason.clone())),
+                Err(InvocationError::ApprovalRequired(reason)) => {
+                    (Outcome::Pending, Some(reason.clone()))
+                }
+                Err(e) => (Outcome::Failed, Some(format!("{:?}", e))),
+            };
+            record.outcome = outcome;
+            record.error = error;
+            record.duration_ms = (Utc::now() - started_at).num_milliseconds();
+            if let Err(e) = self.append_audit(record.clone()) {
+                if execution.output.is_ok() {
+                    execution.output = Err(e);
+                }
+            }
+        }
+        execution
+    }
+
+    // append_audit records the entry, failing only when the log fails closed
+    fn append_audit(&self, record: AuditRecord) -> Result<(), InvocationError> {
+        let audit = match &self.audit {
+            Some(audit) => audit,
+            None => return Ok(()),
+        };
+        let plugin = record.plugin.clone().unwrap_or_default();
+        match audit.append(record) {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                tracing::error!("could not audit the invocation of {}: {}", plugin, e);
+                if audit.fails_open() {
+                    Ok(())
+                } else {
+                    Err(InvocationError::AuditFailed(e.to_string()))
+                }
+            }
+        }
+    }
+
+    // execute_unaudited calls `before_invoke` right before the plugin runs,
+    // an error stops it
+    fn execute_unaudited(
+        &self,
+        plugin_name: &str,
+        input: HashMap<String, String>,
+        principal: &Principal,
+        approvals: &[Approval],
+        before_invoke: impl FnOnce() -> Result<(), InvocationError>,
+    ) -> Execution {
+        if self.disabled.contains(plugin_name) {
+            return Execution::failed(InvocationError::PluginDisabled);
+        }
+        if let Some(policy) = &self.policy {
+            if let Err(reason) = policy
+                .current()
+                .check_plugin(principal, plugin_name, &input)
+            {
+                tracing::warn!("refused invocation of {}: {}", plugin_name, reason);
+                return Execution::failed(InvocationError::Forbidden(reason));
+            }
+        }
+        match self.plugins.get(plugin_name) {
+            Some(plugin) => {
+                if let Some(config) = &plugin.config.requires_approval {
+                    let user = principal.user.as_deref();
+                    if let Err(reason) = approval::check(config, user, approvals, Utc::now()) {
+                        return Execution::failed(InvocationError::ApprovalRequired(reason));
+                    }
+                }
+                if let Err(e) = before_invoke() {
+                    return Execution::failed(e);
+                }
+                let (output, trace) = plugin.invoke(input, &self.redaction);
+                Execution {
+                    plugin_version: plugin.manifest.as_ref().map(|m| m.version.clone()),
+                    output,
+                    trace,
+                }
+            }
+            None => Execution::failed(InvocationError::PluginDoesNotExist),
+        }
+    }
+
+    // approval_config is the approval the plugin requires, if any
+    pub fn approval_config(&self, name: &str) -> Option<ApprovalConfig> {
+        self.plugins
+            .get(name)
+            .and_then(|p| p.config.requires_approval.clone())
+    }
+
+    pub fn list(&self) -> Vec<PluginInfo> {
+        let mut plugins: Vec<PluginInfo> = self.plugins.keys().map(|n| self.info(n)).collect();
+        plugins.sort_by(|a, b| a.name.cmp(&b.name));
+        plugins
+    }
+
+    // body_option is where the body of a `@plugin=` cell goes for the plugin
+    pub fn body_option(&self, name: &str) -> String {
         self.plugins
-            .get(plugin_name)
-            .map_or(Err(InvocationError::PluginDoesNotExist), |p| {
-                p.invoke(input)
+            .get(name)
+            .and_then(|p| p.config.body_option.clone())
+            .unwrap_or_else(|| cell_parser::DEFAULT_BODY_OPTION.to_string())
+    }
+
+    fn info(&self, name: &str) -> PluginInfo {
+        PluginInfo {
+            name: name.to_string(),
+            version: self
+                .plugins
+                .get(name)
+                .and_then(|p| p.manifest.as_ref())
+                .map(|m| m.version.clone()),
+            enabled: !self.disabled.contains(name),
+        }
+    }
+
+    // install validates the plugin (signature, config and wasm) and only then
+    // writes it to the plugin folder and registers it
+    pub fn install(&mut self, source: PluginSource) -> Result<PluginInfo, PluginError> {
+        let staged = self.stage(source, false)?;
+        self.commit(staged)
+    }
+
+    // upgrade replaces an installed plugin, keeping it disabled if it was
+    pub fn upgrade(&mut self, source: PluginSource) -> Result<PluginInfo, PluginError> {
+        let staged = self.stage(source, true)?;
+        self.commit(staged)
+    }
+
+    // stage validates the plugin, compiling its wasm, and writes it to a
+    // hidden staging path of the plugin folder. It only reads the manager, so
+    // invocations go on while a large plugin is built; commit swaps it in.
+    pub fn stage(&self, source: PluginSource, upgrade: bool) -> Result<StagedPlugin, PluginError> {
+        let plugin = self.load_source(&source)?;
+        let name = plugin.config.name.clone();
+        self.check_installed(&name, upgrade)?;
+
+        // concurrent installs of the same plugin each get their own path
+        let suffix = uuid::Uuid::new_v4().simple().to_string();
+        let (staging, destination) = match &source {
+            PluginSource::Package(bytes) => {
+                let file_name = format!("{}.{}", name, PACKAGE_EXTENSION);
+                let staging = self.folder.join(format!(".{}.{}", file_name, suffix));
+                fs::write(&staging, bytes)?;
+                (staging, self.folder.join(file_name))
+            }
+            PluginSource::Files {
+                wasm,
+                config,
+                signature,
+            } => {
+                let staging = self.folder.join(format!(".{}.{}", name, suffix));
+                let written = fs::create_dir_all(&staging)
+                    .and_then(|_| fs::write(staging.join(WASM_FILE), wasm))
+                    .and_then(|_| fs::write(staging.join(CONFIG_FILE), config))
+                    .and_then(|_| match signature {
+                        Some(signature) => fs::write(staging.join(SIGNATURE_FILE), signature),
+                        None => Ok(()),
+                    });
+                if let Err(e) = written {
+                    remove_leftover(&staging);
+                    return Err(e.into());
+                }
+                (staging, self.folder.join(&name))
+            }
+        };
+        Ok(StagedPlugin {
+            plugin,
+            upgrade,
+            staging,
+            destination,
+        })
+    }
+
+    // commit moves the staged plugin in place and registers it, replacing
+    // the previous version on upgrade. The staging path is removed when it
+    // cannot be, e.g. the plugin was installed meanwhile.
+    pub fn commit(&mut self, staged: StagedPlugin) -> Result<PluginInfo, PluginError> {
+        let StagedPlugin {
+            mut plugin,
+            upgrade,
+            staging,
+            destination,
+        } = staged;
+        let name = plugin.config.name.clone();
+        let previous = self
+            .plugins
+            .get(&name)
+            .filter(|p| p.pulled_from.is_none())
+            .map(|p| p.source.clone());
+        let moved = self
+            .check_installed(&name, upgrade)
+            .and_then(|_| self.move_in_place(&staging, &destination, previous.as_deref()));
+        if let Err(e) = moved {
+            remove_leftover(&staging);
+            return Err(e);
+        }
+        plugin.source = destination;
+
+        tracing::info!("installed plugin {:?}", name);
+        self.plugins.insert(name.clone(), plugin);
+        Ok(self.info(&name))
+    }
+
+    fn check_installed(&self, name: &str, upgrade: bool) -> Result<(), PluginError> {
+        match (self.plugins.contains_key(name), upgrade) {
+            (true, false) => Err(PluginError::AlreadyInstalled(name.to_string())),
+            (false, true) => Err(PluginError::PluginDoesNotExist(name.to_string())),
+            _ => Ok(()),
+        }
+    }
+
+    fn load_source(&self, source: &PluginSource) -> Result<Plugin, PluginError> {
+        match source {
+            PluginSource::Package(bytes) => {
+                let package = PluginPackage::new_from_bytes(bytes)
+                    .map_err(|e| PluginError::InvalidPlugin(format!("{:?}", e)))?;
+                Plugin::new_from_loaded_package(package, self.trusted_keys.as_ref(), PathBuf::new())
+            }
+            PluginSource::Files {
+                wasm,
+                config,
+                signature,
+            } => Plugin::new_from_parts(
+                config,
+                wasm,
+                parse_signature(signature.as_deref()),
+                self.trusted_keys.as_ref(),
+                None,
+                PathBuf::new(),
+            ),
+        }
+    }
+
+    // move_in_place renames the previous version (and whatever is at the
+    // destination) to hidden backups, renames the staged plugin in place and
+    // only then deletes the backups. When the last rename fails the backups
+    // are put back, so the plugin folder never loses the installed version.
+    fn move_in_place(
+        &self,
+        staging: &Path,
+        destination: &Path,
+        previous: Option<&Path>,
+    ) -> Result<(), PluginError> {
+        let mut replaced: Vec<&Path> = previous.into_iter().collect();
+        if !replaced.contains(&destination) {
+            replaced.push(destination);
+        }
+        let mut backups: Vec<(&Path, PathBuf)> = vec![];
+        let mut backed_up = Ok(());
+        for path in replaced.into_iter().filter(|p| p.exists()) {
+            let backup = self.backup_path(path);
+            if let Err(e) = fs::rename(path, &backup) {
+                backed_up = Err(e);
+                break;
+            }
+            backups.push((path, backup));
+        }
+        let moved = backed_up.and_then(|_| fs::rename(staging, destination));
+        if let Err(e) = moved {
+            for (path, backup) in backups {
+                if let Err(e) = fs::rename(&backup, path) {
+                    tracing::error!("could not restore {:?} from {:?} {:?}", path, backup, e);
+                }
+            }
+            return Err(e.into());
+        }
+        for (_, backup) in backups {
+            remove_leftover(&backup);
+        }
+        Ok(())
+    }
+
+    fn backup_path(&self, path: &Path) -> PathBuf {
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy())
+            .unwrap_or_default();
+        self.folder
+            .join(format!(".{}.{}.old", name, uuid::Uuid::new_v4().simple()))
+    }
+
+    // disable is the kill switch, the plugin stays installed but any
+    // invocation is refused until it is enabled again
+    pub fn disable(&mut self, name: &str) -> Result<PluginInfo, PluginError> {
+        self.set_enabled(name, false)
+    }
+
+    pub fn enable(&mut self, name: &str) -> Result<PluginInfo, PluginError> {
+        self.set_enabled(name, true)
+    }
+
+    fn set_enabled(&mut self, name: &str, enabled: bool) -> Result<PluginInfo, PluginError> {
+        if !self.plugins.contains_key(name) {
+            return Err(PluginError::PluginDoesNotExist(name.to_string()));
+        }
+        let mut disabled = self.disabled.clone();
+        if enabled {
+            disabled.remove(name);
+        } else {
+            disabled.insert(name.to_string());
+        }
+        self.write_disabled(&disabled)?;
+        self.disabled = disabled;
+        tracing::info!("plugin {:?} enabled: {}", name, enabled);
+        Ok(self.info(name))
+    }
+
+    // uninstall removes the plugin files from the plugin folder. A plugin
+    // pulled from a registry is only unregistered, its package stays in the
+    // registry cache and it is pulled again on start while it is listed in
+    // the plugins manifest.
+    pub fn uninstall(&mut self, name: &str) -> Result<(), PluginError> {
+        let plugin = self
+            .plugins
+            .get(name)
+            .ok_or_else(|| PluginError::PluginDoesNotExist(name.to_string()))?;
+        match &plugin.pulled_from {
+            Some(reference) => tracing::warn!(
+                "plugin {:?} was pulled from {}, remove it from the plugins manifest as well",
+                name,
+                reference
+            ),
+            None => remove_path(&plugin.source)?,
+        }
+        self.plugins.remove(name);
+
+        if self.disabled.contains(name) {
+            let mut disabled = self.disabled.clone();
+            disabled.remove(name);
+            self.write_disabled(&disabled)?;
+            self.disabled = disabled;
+        }
+        tracing::info!("uninstalled plugin {:?}", name);
+        Ok(())
+    }
+
+    fn read_disabled(&self) -> Result<HashSet<String>, PluginError> {
+        let path = self.folder.join(DISABLED_FILE);
+        if !path.is_file() {
+            return Ok(HashSet::new());
+        }
+        let disabled =
+            toml::from_str::<DisabledPlugins>(&fs::read_to_string(path)?).map_err(|e| {
+                PluginError::InvalidPlugin(format!("invalid {} {:?}", DISABLED_FILE, e))
+            })?;
+        Ok(disabled.plugins.into_iter().collect())
+    }
+
+    fn write_disabled(&self, disabled: &HashSet<String>) -> Result<(), PluginError> {
+        let mut plugins: Vec<String> = disabled.iter().cloned().collect();
+        plugins.sort();
+        let content = toml::to_string(&DisabledPlugins { plugins })
+            .map_err(|e| PluginError::Io(e.to_string()))?;
+        fs::write(self.folder.join(DISABLED_FILE), content)?;
+        Ok(())
+    }
+}
+
+fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .map_or(false, |n| n.to_string_lossy().starts_with('.'))
+}
+
+// remove_leftover removes a staging path or a backup, one that cannot be is
+// hidden and skipped by registry anyway
+fn remove_leftover(path: &Path) {
+    if let Err(e) = remove_path(path) {
+        tracing::warn!("could not remove {:?} {:?}", path, e);
+    }
+}
+
+fn remove_path(path: &Path) -> Result<(), PluginError> {
+    if path.is_dir() {
+        fs::remove_dir_all(path)?;
+    } else if path.is_file() {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hello_world() -> PluginSource {
+        PluginSource::Files {
+            wasm: fs::read("plugins/hello_world/plugin.wasm").unwrap(),
+            config: b"name = 'hello_world'".to_vec(),
+            signature: None,
+        }
+    }
+
+    fn manager() -> (tempfile::TempDir, PluginManager) {
+        let folder = tempfile::tempdir().unwrap();
+        let manager = PluginManager::new(folder.path().to_path_buf(), None);
+        (folder, manager)
+    }
+
+    #[test]
+    fn test_install_persists_plugin() {
+        let (folder, mut manager) = manager();
+        manager.install(hello_world()).unwrap();
+
+        assert!(folder.path().join("hello_world").join(WASM_FILE).is_file());
+        assert!(matches!(
+            manager.install(hello_world()),
+            Err(PluginError::AlreadyInstalled(_))
+        ));
+
+        let mut reloaded = PluginManager::new(folder.path().to_path_buf(), None);
+        reloaded.registry().unwrap();
+        assert_eq!(manager.list(), reloaded.list());
+    }
+
+    #[test]
+    fn test_upgrade_requires_installed_plugin() {
+        let (_folder, mut manager) = manager();
+        assert!(matches!(
+            manager.upgrade(hello_world()),
+            Err(PluginError::PluginDoesNotExist(_))
+        ));
+        manager.install(hello_world()).unwrap();
+        assert!(manager.upgrade(hello_world()).is_ok());
+    }
+
+    #[test]
+    fn test_commit_leaves_no_leftovers() {
+        let (folder, mut manager) = manager();
+        let first = manager.stage(hello_world(), false).unwrap();
+        let second = manager.stage(hello_world(), false).unwrap();
+        manager.commit(first).unwrap();
+        // installed meanwhile, the second staging path is removed
+        assert!(matches!(
+            manager.commit(second),
+            Err(PluginError::AlreadyInstalled(_))
+        ));
+        manager.upgrade(hello_world()).unwrap();
+
+        let mut files: Vec<String> = fs::read_dir(folder.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        files.sort();
+        assert_eq!(vec!["hello_world"], files);
+        assert!(folder.path().join("hello_world").join(WASM_FILE).is_file());
+    }
+
+    #[test]
+    fn test_disable_and_enable() {
+        let (folder, mut manager) = manager();
+        manager.install(hello_world()).unwrap();
+
+        assert!(!manager.disable("hello_world").unwrap().enabled);
+        assert!(matches!(
+            manager
+                .execute(
+                    "hello_world",
+                    HashMap::new(),
+                    &Principal::default(),
+                    None,
+                    &[]
+                )
+                .output,
+            Err(InvocationError::PluginDisabled)
+        ));
+
+        // the kill switch survives a restart
+        let mut reloaded = PluginManager::new(folder.path().to_path_buf(), None);
+        reloaded.registry().unwrap();
+        assert!(!reloaded.list()[0].enabled);
+
+        assert!(manager.enable("hello_world").unwrap().enabled);
+    }
+
+    #[test]
+    fn test_policy_refuses_invocation() {
+        let audit_folder = tempfile::tempdir().unwrap();
+        let (_folder, mut manager) = manager();
+        manager.install(hello_world()).unwrap();
+        let policy = tempfile::NamedTempFile::new().unwrap();
+        fs::write(
+            policy.path(),
+            "[[plugins]]\nplugins = ['hello_world']\nusers = ['alice']\n",
+        )
+        .unwrap();
+        manager.set_policy(Arc::new(
+            PolicyStore::open(policy.path().to_path_buf()).unwrap(),
+        ));
+        let audit = Arc::new(
+            AuditLog::open(&audit::AuditConfig {
+                directory: audit_folder.path().display().to_string(),
+                ..Default::default()
             })
+            .unwrap(),
+        );
+        manager.set_audit(audit.clone());
+
+        let bob = Principal {
+            user: Some("bob".into()),
+            groups: vec![],
+        };
+        assert!(matches!(
+            manager
+                .execute("hello_world", HashMap::new(), &bob, Some("10.0.0.7"), &[])
+                .output,
+            Err(InvocationError::Forbidden(_))
+        ));
+        let entries = audit.query(&audit::AuditFilter::default()).unwrap();
+        assert_eq!(1, entries.len());
+        assert_eq!(Outcome::Refused, entries[0].record.outcome);
+        assert_eq!(Some("10.0.0.7".to_string()), entries[0].record.source);
+    }
+
+    #[test]
+    fn test_audit_fails_closed() {
+        let audit_folder = tempfile::tempdir().unwrap();
+        let (_folder, mut manager) = manager();
+        manager.install(hello_world()).unwrap();
+        let audit = Arc::new(
+            AuditLog::open(&audit::AuditConfig {
+                directory: audit_folder.path().join("audit").display().to_string(),
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        manager.set_audit(audit);
+        // the log cannot be written anymore, the plugin does not run
+        fs::remove_dir_all(audit_folder.path().join("audit")).unwrap();
+        assert!(matches!(
+            manager
+                .execute(
+                    "hello_world",
+                    HashMap::new(),
+                    &Principal::default(),
+                    None,
+                    &[]
+                )
+                .output,
+            Err(InvocationError::AuditFailed(_))
+        ));
+    }
+
+    #[test]
+    fn test_uninstall() {
+        let (folder, mut manager) = manager();
+        manager.install(hello_world()).unwrap();
+        manager.uninstall("hello_world").unwrap();
+
+        assert!(!folder.path().join("hello_world").exists());
+        assert!(manager.list().is_empty());
+        assert!(matches!(
+            manager.uninstall("hello_world"),
+            Err(PluginError::PluginDoesNotExist(_))
+        ));
+    }
+
+    #[test]
+    fn test_refuses_invalid_plugin_name() {
+        let (_folder, mut manager) = manager();
+        let source = PluginSource::Files {
+            wasm: fs::read("plugins/hello_world/plugin.wasm").unwrap(),
+            config: b"name = '../escape'".to_vec(),
+            signature: None,
+        };
+        assert!(matches!(
+            manager.install(source),
+            Err(PluginError::InvalidPlugin(_))
+        ));
+        for name in ["hello.dbplugin", "..", "hello world", ""] {
+            assert!(!cell_parser::is_valid_plugin_name(name), "{:?}", name);
+        }
+        assert!(cell_parser::is_valid_plugin_name("hello_world-2"));
+    }
+
+    #[test]
+    fn test_registry_plugins() {
+        use crate::oci_registry::sha256_digest;
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let package = PluginPackage {
+            manifest: PluginManifest {
+                name: "hello_world".into(),
+                version: "0.2.0".into(),
+                description: None,
+                authors: None,
+            },
+            wasm: fs::read("plugins/hello_world/plugin.wasm").unwrap(),
+            config: b"name = 'hello_world'".to_vec(),
+            signature: None,
+            files: Default::default(),
+        }
+        .to_bytes()
+        .unwrap();
+        let manifest = format!(
+            r#"{{"schemaVersion":2,"layers":[{{"mediaType":"{}","digest":"{}"}}]}}"#,
+            crate::oci_registry::PACKAGE_MEDIA_TYPE,
+            sha256_digest(&package)
+        );
+        let server = tokio_test::block_on(MockServer::start());
+        tokio_test::block_on(
+            Mock::given(method("GET"))
+                .and(path("/v2/sre/hello/manifests/0.2"))
+                .respond_with(ResponseTemplate::new(200).set_body_string(manifest))
+                .mount(&server),
+        );
+        tokio_test::block_on(
+            Mock::given(method("GET"))
+                .and(path(format!(
+                    "/v2/sre/hello/blobs/{}",
+                    sha256_digest(&package)
+                )))
+                .respond_with(ResponseTemplate::new(200).set_body_bytes(package))
+                .mount(&server),
+        );
+        let cache = tempfile::tempdir().unwrap();
+        let client = RegistryClient::new(
+            cache.path().to_path_buf(),
+            vec![server.address().to_string()],
+            vec![],
+        );
+        let reference =
+            PluginReference::parse(&format!("{}/sre/hello:0.2", server.address())).unwrap();
+
+        let (folder, mut manager) = manager();
+        manager.install(hello_world()).unwrap();
+        // the local plugin is only replaced when asked to
+        assert!(matches!(
+            manager.install_from_registry(&reference, None, false, &client),
+            Err(PluginError::AlreadyInstalled(_))
+        ));
+        assert_eq!(None, manager.info("hello_world").version);
+        let info = manager
+            .install_from_registry(&reference, None, true, &client)
+            .unwrap();
+        assert_eq!(Some("0.2.0".to_string()), info.version);
+
+        // the cache is not the plugin folder, uninstalling keeps the blob
+        let blob = client.cached(&reference, None).unwrap().path;
+        manager.uninstall("hello_world").unwrap();
+        assert!(blob.is_file());
+        assert!(client.cached(&reference, None).is_ok());
+        assert!(folder.path().join("hello_world").is_dir());
     }
 }
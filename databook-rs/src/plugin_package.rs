@@ -0,0 +1,399 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+
+use crate::plugin_signature::SIGNATURE_FILE;
+
+// a .dbplugin is a tar archive compressed with zstd holding a whole plugin
+pub const PACKAGE_EXTENSION: &str = "dbplugin";
+
+pub const MANIFEST_FILE: &str = "manifest.toml";
+pub const CONFIG_FILE: &str = "config.toml";
+pub const WASM_FILE: &str = "plugin.wasm";
+
+// optional files and folders copied into the package when present
+const EXTRA_FILES: [&str; 2] = ["README.md", "icon.png"];
+const EXTRA_FOLDERS: [&str; 2] = ["examples", "widgets"];
+
+const ZSTD_LEVEL: i32 = 3;
+
+// Limits bound what reading a package may unpack in memory, a few KB of
+// zstd can expand to gigabytes
+#[derive(Debug, Clone, Copy)]
+struct Limits {
+    entries: usize,
+    // uncompressed size of a file, and of all of them
+    entry_size: u64,
+    total_size: u64,
+}
+
+const LIMITS: Limits = Limits {
+    entries: 1024,
+    entry_size: 64 * 1024 * 1024,
+    total_size: 128 * 1024 * 1024,
+};
+
+// a tar entry takes a 512 bytes header and pads its content to 512 bytes
+const TAR_OVERHEAD_PER_ENTRY: u64 = 1024;
+
+#[derive(Debug)]
+pub enum PackageError {
+    Io(String),
+    InvalidArchive(String),
+    MissingFile(&'static str),
+    InvalidManifest(String),
+    TooLarge(String),
+}
+
+impl From<std::io::Error> for PackageError {
+    fn from(e: std::io::Error) -> Self {
+        PackageError::Io(e.to_string())
+    }
+}
+
+// PluginManifest describes the package, it is the manifest.toml of the archive
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct PluginManifest {
+    pub name: String,
+    pub version: String,
+    pub description: Option<String>,
+    pub authors: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PluginPackage {
+    pub manifest: PluginManifest,
+    pub wasm: Vec<u8>,
+    // config.toml template, the permissions the plugin asks for
+    pub config: Vec<u8>,
+    // content of plugin.sig, if the plugin was signed before packing
+    pub signature: Option<Vec<u8>>,
+    // README, icon, examples and Malleable-View widgets, <path, content>
+    pub files: BTreeMap<String, Vec<u8>>,
+}
+
+impl PluginPackage {
+    // new_from_folder reads a plugin folder, it must contain at least
+    // manifest.toml, config.toml and plugin.wasm
+    pub fn new_from_folder(folder: &Path) -> Result<Self, PackageError> {
+        let read = |name: &'static str| {
+            fs::read(folder.join(name)).map_err(|_| PackageError::MissingFile(name))
+        };
+
+        let manifest = parse_manifest(&read(MANIFEST_FILE)?)?;
+        let wasm = read(WASM_FILE)?;
+        let config = read(CONFIG_FILE)?;
+        let signature = fs::read(folder.join(SIGNATURE_FILE)).ok();
+
+        let mut files = BTreeMap::new();
+        for name in EXTRA_FILES {
+            if let Ok(content) = fs::read(folder.join(name)) {
+                files.insert(name.to_string(), content);
+            }
+        }
+        for name in EXTRA_FOLDERS {
+            let dir = folder.join(name);
+            if dir.is_dir() {
+                for entry in fs::read_dir(&dir)? {
+                    let entry = entry?.path();
+                    if entry.is_file() {
+                        let file_name = entry.file_name().unwrap().to_string_lossy();
+                        files.insert(format!("{}/{}", name, file_name), fs::read(&entry)?);
+                    }
+                }
+            }
+        }
+
+        Ok(Self {
+            manifest,
+            wasm,
+            config,
+            signature,
+            files,
+        })
+    }
+
+    pub fn new_from_file(path: &Path) -> Result<Self, PackageError> {
+        Self::new_from_bytes(&fs::read(path)?)
+    }
+
+    pub fn new_from_bytes(bytes: &[u8]) -> Result<Self, PackageError> {
+        Self::new_from_bytes_with_limits(bytes, LIMITS)
+    }
+
+    fn new_from_bytes_with_limits(bytes: &[u8], limits: Limits) -> Result<Self, PackageError> {
+        let mut entries = read_entries(bytes, limits)?;
+        let manifest = parse_manifest(
+            &entries
+                .remove(MANIFEST_FILE)
+                .ok_or(PackageError::MissingFile(MANIFEST_FILE))?,
+        )?;
+        let wasm = entries
+            .remove(WASM_FILE)
+            .ok_or(PackageError::MissingFile(WASM_FILE))?;
+        let config = entries
+            .remove(CONFIG_FILE)
+            .ok_or(PackageError::MissingFile(CONFIG_FILE))?;
+        let signature = entries.remove(SIGNATURE_FILE);
+
+        Ok(Self {
+            manifest,
+            wasm,
+            config,
+            signature,
+            files: entries,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, PackageError> {
+        let manifest = toml::to_string(&self.manifest)
+            .map_err(|e| PackageError::InvalidManifest(e.to_string()))?;
+
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut append = |path: &str, content: &[u8]| -> Result<(), PackageError> {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, path, content)?;
+            Ok(())
+        };
+
+        append(MANIFEST_FILE, manifest.as_bytes())?;
+        append(CONFIG_FILE, &self.config)?;
+        append(WASM_FILE, &self.wasm)?;
+        if let Some(ref signature) = self.signature {
+            append(SIGNATURE_FILE, signature)?;
+        }
+        for (path, content) in &self.files {
+            append(path, content)?;
+        }
+
+        let tar = builder.into_inner()?;
+        Ok(zstd::stream::encode_all(tar.as_slice(), ZSTD_LEVEL)?)
+    }
+
+    // unpack writes the package back as a plugin folder
+    pub fn unpack(&self, folder: &Path) -> Result<(), PackageError> {
+        fs::create_dir_all(folder)?;
+        let manifest = toml::to_string(&self.manifest)
+            .map_err(|e| PackageError::InvalidManifest(e.to_string()))?;
+        fs::write(folder.join(MANIFEST_FILE), manifest)?;
+        fs::write(folder.join(CONFIG_FILE), &self.config)?;
+        fs::write(folder.join(WASM_FILE), &self.wasm)?;
+        if let Some(ref signature) = self.signature {
+            fs::write(folder.join(SIGNATURE_FILE), signature)?;
+        }
+        for (path, content) in &self.files {
+            let path = folder.join(path);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(path, content)?;
+        }
+        Ok(())
+    }
+
+    // file_name is the default name of the archive, <name>-<version>.dbplugin
+    pub fn file_name(&self) -> String {
+        format!(
+            "{}-{}.{}",
+            self.manifest.name, self.manifest.version, PACKAGE_EXTENSION
+        )
+    }
+}
+
+// read_entries decompresses the archive as it is read, stopping at the
+// limits rather than unpacking a whole archive bomb first
+fn read_entries(bytes: &[u8], limits: Limits) -> Result<BTreeMap<String, Vec<u8>>, PackageError> {
+    let decoder = zstd::stream::read::Decoder::new(bytes)
+        .map_err(|e| PackageError::InvalidArchive(e.to_string()))?;
+    // headers and padding included, the tar end marker takes two blocks
+    let bound = limits.total_size + (limits.entries as u64 + 2) * TAR_OVERHEAD_PER_ENTRY;
+    let mut archive = tar::Archive::new(decoder.take(bound));
+
+    let mut entries = BTreeMap::new();
+    let mut total = 0;
+    for (i, entry) in archive
+        .entries()
+        .map_err(|e| PackageError::InvalidArchive(e.to_string()))?
+        .enumerate()
+    {
+        if i >= limits.entries {
+            return Err(PackageError::TooLarge(format!(
+                "more than {} entries",
+                limits.entries
+            )));
+        }
+        let entry = entry.map_err(|e| PackageError::InvalidArchive(e.to_string()))?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry
+            .path()
+            .map_err(|e| PackageError::InvalidArchive(e.to_string()))?
+            .into_owned();
+        let path = safe_relative_path(&path)?;
+        // the header size is not trusted, the content read is capped as well
+        let mut content = Vec::new();
+        entry
+            .take(limits.entry_size + 1)
+            .read_to_end(&mut content)?;
+        let size = content.len() as u64;
+        if size > limits.entry_size {
+            return Err(PackageError::TooLarge(format!(
+                "{} is over {} bytes",
+                path, limits.entry_size
+            )));
+        }
+        total += size;
+        if total > limits.total_size {
+            return Err(PackageError::TooLarge(format!(
+                "the files are over {} bytes",
+                limits.total_size
+            )));
+        }
+        entries.insert(path, content);
+    }
+    Ok(entries)
+}
+
+fn parse_manifest(content: &[u8]) -> Result<PluginManifest, PackageError> {
+    let content =
+        std::str::from_utf8(content).map_err(|e| PackageError::InvalidManifest(e.to_string()))?;
+    toml::from_str(content).map_err(|e| PackageError::InvalidManifest(e.to_string()))
+}
+
+// archives must only contain relative paths without `..`, otherwise unpacking
+// them could write outside of the destination folder
+fn safe_relative_path(path: &Path) -> Result<String, PackageError> {
+    let mut clean = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(c) => clean.push(c),
+            Component::CurDir => {}
+            _ => {
+                return Err(PackageError::InvalidArchive(format!(
+                    "invalid path in archive {:?}",
+                    path
+                )))
+            }
+        }
+    }
+    clean
+        .to_str()
+        .map(|p| p.replace('\\', "/"))
+        .ok_or_else(|| PackageError::InvalidArchive(format!("invalid path in archive {:?}", path)))
+}
+
+pub fn is_package(path: &Path) -> bool {
+    path.is_file() && path.extension().map_or(false, |e| e == PACKAGE_EXTENSION)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn package() -> PluginPackage {
+        PluginPackage {
+            manifest: PluginManifest {
+                name: "hello_world".into(),
+                version: "0.1.0".into(),
+                description: Some("says hello".into()),
+                authors: None,
+            },
+            wasm: b"\0asm".to_vec(),
+            config: b"name = 'hello_world'".to_vec(),
+            signature: None,
+            files: BTreeMap::from([
+                ("README.md".to_string(), b"# hello".to_vec()),
+                (
+                    "examples/basic.txt".to_string(),
+                    b"@plugin=hello_world".to_vec(),
+                ),
+            ]),
+        }
+    }
+
+    #[test]
+    fn test_package_roundtrip() {
+        let package = package();
+        let restored = PluginPackage::new_from_bytes(&package.to_bytes().unwrap()).unwrap();
+        assert_eq!(package.manifest, restored.manifest);
+        assert_eq!(package.wasm, restored.wasm);
+        assert_eq!(package.config, restored.config);
+        assert_eq!(package.signature, restored.signature);
+        assert_eq!(package.files, restored.files);
+    }
+
+    #[test]
+    fn test_package_file_name() {
+        assert_eq!("hello_world-0.1.0.dbplugin", package().file_name());
+    }
+
+    #[test]
+    fn test_invalid_archive() {
+        assert!(matches!(
+            PluginPackage::new_from_bytes(b"not a package"),
+            Err(PackageError::InvalidArchive(_))
+        ));
+    }
+
+    #[test]
+    fn test_limits() {
+        let bytes = package().to_bytes().unwrap();
+        let limits = Limits {
+            entries: 5,
+            entry_size: 1024,
+            total_size: 4096,
+        };
+        assert!(PluginPackage::new_from_bytes_with_limits(&bytes, limits).is_ok());
+        let too_large = |limits| {
+            matches!(
+                PluginPackage::new_from_bytes_with_limits(&bytes, limits),
+                Err(PackageError::TooLarge(_))
+            )
+        };
+        assert!(too_large(Limits {
+            entries: 4,
+            ..limits
+        }));
+        assert!(too_large(Limits {
+            entry_size: 10,
+            ..limits
+        }));
+        assert!(too_large(Limits {
+            total_size: 50,
+            ..limits
+        }));
+
+        // a few bytes of zstd expanding to a large file
+        let mut bomb = package();
+        bomb.files
+            .insert("examples/zeros.txt".into(), vec![0; 1024 * 1024]);
+        let bytes = bomb.to_bytes().unwrap();
+        assert!(bytes.len() < 1024);
+        assert!(matches!(
+            PluginPackage::new_from_bytes_with_limits(
+                &bytes,
+                Limits {
+                    entries: 6,
+                    ..limits
+                }
+            ),
+            Err(PackageError::TooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn test_safe_relative_path() {
+        assert_eq!(
+            "widgets/chart.wasm",
+            safe_relative_path(Path::new("./widgets/chart.wasm")).unwrap()
+        );
+        assert!(safe_relative_path(Path::new("../etc/passwd")).is_err());
+        assert!(safe_relative_path(Path::new("/etc/passwd")).is_err());
+    }
+}
@@ -0,0 +1,183 @@
+use ed25519_dalek::{Keypair, PublicKey, Signature, Signer, Verifier};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+
+// name of the file, next to config.toml and plugin.wasm, holding the signature
+pub const SIGNATURE_FILE: &str = "plugin.sig";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SignatureError {
+    Missing,
+    Malformed(String),
+    UntrustedKey,
+    Invalid,
+}
+
+// PluginSignature is the content of plugin.sig. Both fields are hex encoded.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct PluginSignature {
+    pub public_key: String,
+    pub signature: String,
+}
+
+impl PluginSignature {
+    pub fn new_from_file(path: std::path::PathBuf) -> Result<Self, SignatureError> {
+        let content = fs::read_to_string(&path).map_err(|_| SignatureError::Missing)?;
+        Self::new_from_str(&content)
+    }
+
+    pub fn new_from_str(content: &str) -> Result<Self, SignatureError> {
+        toml::from_str::<PluginSignature>(content)
+            .map_err(|e| SignatureError::Malformed(e.to_string()))
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).expect("signature is always serializable")
+    }
+}
+
+// the message signed is sha256(sha256(wasm) || sha256(config)), so the
+// signature covers both the code and the permissions it asks for
+fn plugin_digest(wasm: &[u8], config: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(Sha256::digest(wasm));
+    hasher.update(Sha256::digest(config));
+    hasher.finalize().to_vec()
+}
+
+pub fn sign(keypair: &Keypair, wasm: &[u8], config: &[u8]) -> PluginSignature {
+    let signature = keypair.sign(&plugin_digest(wasm, config));
+    PluginSignature {
+        public_key: hex::encode(keypair.public.as_bytes()),
+        signature: hex::encode(signature.to_bytes()),
+    }
+}
+
+pub fn generate_keypair() -> Keypair {
+    Keypair::generate(&mut rand::rngs::OsRng)
+}
+
+// keypairs are stored as the hex encoding of secret key || public key
+pub fn keypair_from_hex(key: &str) -> Result<Keypair, SignatureError> {
+    let bytes = hex::decode(key.trim()).map_err(|e| SignatureError::Malformed(e.to_string()))?;
+    Keypair::from_bytes(&bytes).map_err(|e| SignatureError::Malformed(e.to_string()))
+}
+
+pub fn keypair_to_hex(keypair: &Keypair) -> String {
+    hex::encode(keypair.to_bytes())
+}
+
+// TrustedKeys are the public keys allowed to sign plugins for this server
+#[derive(Debug, Clone, Default)]
+pub struct TrustedKeys {
+    keys: Vec<PublicKey>,
+}
+
+impl TrustedKeys {
+    pub fn new_from_hex(keys: &[String]) -> Result<Self, SignatureError> {
+        let keys = keys
+            .iter()
+            .map(|k| {
+                hex::decode(k)
+                    .map_err(|e| SignatureError::Malformed(e.to_string()))
+                    .and_then(|b| {
+                        PublicKey::from_bytes(&b)
+                            .map_err(|e| SignatureError::Malformed(e.to_string()))
+                    })
+            })
+            .collect::<Result<Vec<PublicKey>, SignatureError>>()?;
+        Ok(Self { keys })
+    }
+
+    // verify returns Ok if the signature was made by one of the trusted keys
+    // over exactly this wasm and config
+    pub fn verify(
+        &self,
+        signature: &PluginSignature,
+        wasm: &[u8],
+        config: &[u8],
+    ) -> Result<(), SignatureError> {
+        let key = hex::decode(&signature.public_key)
+            .map_err(|e| SignatureError::Malformed(e.to_string()))
+            .and_then(|b| {
+                PublicKey::from_bytes(&b).map_err(|e| SignatureError::Malformed(e.to_string()))
+            })?;
+
+        if !self.keys.iter().any(|k| k == &key) {
+            return Err(SignatureError::UntrustedKey);
+        }
+
+        let signature = hex::decode(&signature.signature)
+            .map_err(|e| SignatureError::Malformed(e.to_string()))
+            .and_then(|b| {
+                Signature::from_bytes(&b).map_err(|e| SignatureError::Malformed(e.to_string()))
+            })?;
+
+        key.verify(&plugin_digest(wasm, config), &signature)
+            .map_err(|_| SignatureError::Invalid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trusted(keypair: &Keypair) -> TrustedKeys {
+        TrustedKeys::new_from_hex(&[hex::encode(keypair.public.as_bytes())]).unwrap()
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let keypair = generate_keypair();
+        let signature = sign(&keypair, b"wasm", b"name = 'a'");
+        assert_eq!(
+            Ok(()),
+            trusted(&keypair).verify(&signature, b"wasm", b"name = 'a'")
+        );
+    }
+
+    #[test]
+    fn test_tampered_plugin() {
+        let keypair = generate_keypair();
+        let signature = sign(&keypair, b"wasm", b"name = 'a'");
+        let keys = trusted(&keypair);
+        assert_eq!(
+            Err(SignatureError::Invalid),
+            keys.verify(&signature, b"wasm2", b"name = 'a'")
+        );
+        assert_eq!(
+            Err(SignatureError::Invalid),
+            keys.verify(
+                &signature,
+                b"wasm",
+                b"name = 'a'\nallowed_env_vars=['AWS_KEY']"
+            )
+        );
+    }
+
+    #[test]
+    fn test_untrusted_key() {
+        let signature = sign(&generate_keypair(), b"wasm", b"config");
+        assert_eq!(
+            Err(SignatureError::UntrustedKey),
+            trusted(&generate_keypair()).verify(&signature, b"wasm", b"config")
+        );
+    }
+
+    #[test]
+    fn test_signature_toml_roundtrip() {
+        let signature = sign(&generate_keypair(), b"wasm", b"config");
+        assert_eq!(
+            Ok(signature.clone()),
+            PluginSignature::new_from_str(&signature.to_toml())
+        );
+    }
+
+    #[test]
+    fn test_keypair_hex_roundtrip() {
+        let keypair = generate_keypair();
+        let restored = keypair_from_hex(&keypair_to_hex(&keypair)).unwrap();
+        assert_eq!(keypair.public, restored.public);
+    }
+}
@@ -0,0 +1,594 @@
+use crate::notebook::Notebook;
+use crate::POLICY;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+// The policy file decides which plugins and notebooks a user may use. It is
+// an allow list: once a policy is configured anything it does not grant is
+// refused. Without one everything is allowed, as before.
+//
+//   [groups]
+//   oncall = ["alice", "bob"]
+//
+//   [[plugins]]
+//   plugins = ["prometheus", "loki-*"]
+//   groups = ["*"]
+//
+//   [[plugins]]
+//   plugins = ["restart-service"]
+//   groups = ["oncall"]
+//   when = ["cluster != prod"]
+//
+//   [[notebooks]]
+//   tags = ["payments"]
+//   groups = ["payments"]
+//   permissions = ["create", "edit", "execute"]
+//
+// Users get the groups of their identity (e.g. the groups claim of a JWT)
+// plus the ones listed under [groups]. "*" in users or groups matches
+// everyone, anonymous callers included. The owner of a notebook always has
+// admin on it, creating it takes the create permission on its tags.
+
+const EVERYONE: &str = "*";
+// how often the policy file is checked for changes
+const RELOAD_SECONDS: u64 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    View,
+    Create,
+    Edit,
+    Execute,
+    Admin,
+}
+
+impl Permission {
+    // edit and execute both imply view, admin implies everything. Create
+    // is about new notebooks and grants nothing on existing ones.
+    fn grants(self, wanted: Permission) -> bool {
+        self == wanted
+            || self == Permission::Admin
+            || (wanted == Permission::View && self != Permission::Create)
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Permission::View => "view",
+            Permission::Create => "create",
+            Permission::Edit => "edit",
+            Permission::Execute => "execute",
+            Permission::Admin => "admin",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// Principal is who the policy is checked for: an authenticated user with
+// the groups of their identity, or an anonymous caller. It is stored with
+// the work done later on behalf of the user, like scheduled runs.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Principal {
+    pub user: Option<String>,
+    pub groups: Vec<String>,
+}
+
+// Actor is who a request runs as: the principal the policy is checked for
+// and the name recorded on what the request does. The name of an anonymous
+// caller is the user it claims, a label the policy never trusts.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Actor {
+    pub principal: Principal,
+    pub name: Option<String>,
+}
+
+impl Actor {
+    // for_principal runs as the principal, named after its user
+    pub fn for_principal(principal: Principal) -> Self {
+        let name = principal.user.clone();
+        Self { principal, name }
+    }
+}
+
+impl fmt::Display for Principal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.user.as_deref().unwrap_or("anonymous"))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolicyError {
+    Io(String),
+    Parse(String),
+    InvalidRule(String),
+}
+
+impl fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyError::Io(e) => write!(f, "could not read the policy: {}", e),
+            PolicyError::Parse(e) => write!(f, "could not parse the policy: {}", e),
+            PolicyError::InvalidRule(e) => write!(f, "invalid policy rule: {}", e),
+        }
+    }
+}
+
+// Constraint is a condition on an option of the invocation, an option the
+// invocation does not set never satisfies it
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Constraint {
+    text: String,
+    option: String,
+    equal: bool,
+    value: String,
+}
+
+impl Constraint {
+    fn parse(text: &str) -> Result<Self, PolicyError> {
+        let (option, equal, value) = if let Some((option, value)) = text.split_once("!=") {
+            (option, false, value)
+        } else if let Some((option, value)) = text.split_once("==") {
+            (option, true, value)
+        } else {
+            return Err(PolicyError::InvalidRule(format!(
+                "expected `<option> == <value>` or `<option> != <value>`, got {:?}",
+                text
+            )));
+        };
+        let (option, value) = (option.trim(), value.trim().trim_matches('"'));
+        if option.is_empty() {
+            return Err(PolicyError::InvalidRule(format!("no option in {:?}", text)));
+        }
+        Ok(Self {
+            text: text.trim().to_string(),
+            option: option.to_string(),
+            equal,
+            value: value.to_string(),
+        })
+    }
+
+    fn holds(&self, options: &HashMap<String, String>) -> bool {
+        match options.get(&self.option) {
+            Some(value) => (value.trim() == self.value) == self.equal,
+            None => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PluginRule {
+    // plugin names, a trailing * matches a prefix
+    plugins: Vec<String>,
+    #[serde(default)]
+    users: Vec<String>,
+    #[serde(default)]
+    groups: Vec<String>,
+    #[serde(default)]
+    when: Vec<String>,
+    #[serde(skip)]
+    constraints: Vec<Constraint>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NotebookRule {
+    // the rule covers every notebook when both are empty
+    #[serde(default)]
+    ids: Vec<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    users: Vec<String>,
+    #[serde(default)]
+    groups: Vec<String>,
+    permissions: Vec<Permission>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    // group -> users, added to the groups users get from their identity
+    #[serde(default)]
+    groups: BTreeMap<String, Vec<String>>,
+    #[serde(default)]
+    plugins: Vec<PluginRule>,
+    #[serde(default)]
+    notebooks: Vec<NotebookRule>,
+}
+
+fn matches_name(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => pattern == name,
+    }
+}
+
+impl Policy {
+    pub fn new_from_str(text: &str) -> Result<Self, PolicyError> {
+        let mut policy: Policy =
+            toml::from_str(text).map_err(|e| PolicyError::Parse(e.to_string()))?;
+        for (i, rule) in policy.plugins.iter_mut().enumerate() {
+            if rule.users.is_empty() && rule.groups.is_empty() {
+                return Err(PolicyError::InvalidRule(format!(
+                    "plugin rule {} names no users or groups",
+                    i + 1
+                )));
+            }
+            rule.constraints = rule
+                .when
+                .iter()
+                .map(|c| Constraint::parse(c))
+                .collect::<Result<_, _>>()?;
+        }
+        for (i, rule) in policy.notebooks.iter().enumerate() {
+            if rule.users.is_empty() && rule.groups.is_empty() {
+                return Err(PolicyError::InvalidRule(format!(
+                    "notebook rule {} names no users or groups",
+                    i + 1
+                )));
+            }
+        }
+        Ok(policy)
+    }
+
+    // applies is true when a rule naming those users and groups covers the
+    // principal
+    fn applies(&self, users: &[String], groups: &[String], principal: &Principal) -> bool {
+        if users.iter().chain(groups).any(|u| u == EVERYONE) {
+            return true;
+        }
+        let user = match &principal.user {
+            Some(user) => user,
+            None => return false,
+        };
+        users.contains(user) || groups.iter().any(|group| self.is_member(principal, group))
+    }
+
+    // is_member is true when the principal is in the group, through their
+    // identity or [groups]
+    pub fn is_member(&self, principal: &Principal, group: &str) -> bool {
+        let user = match &principal.user {
+            Some(user) => user,
+            None => return false,
+        };
+        principal.groups.iter().any(|g| g == group)
+            || self
+                .groups
+                .get(group)
+                .is_some_and(|members| members.contains(user))
+    }
+
+    // check_plugin tells why the principal may not invoke the plugin with
+    // those options
+    pub fn check_plugin(
+        &self,
+        principal: &Principal,
+        plugin: &str,
+        options: &HashMap<String, String>,
+    ) -> Result<(), String> {
+        let rules: Vec<&PluginRule> = self
+            .plugins
+            .iter()
+            .filter(|r| r.plugins.iter().any(|p| matches_name(p, plugin)))
+            .filter(|r| self.applies(&r.users, &r.groups, principal))
+            .collect();
+        if rules.is_empty() {
+            return Err(format!("{} may not invoke {}", principal, plugin));
+        }
+        if rules
+            .iter()
+            .any(|r| r.constraints.iter().all(|c| c.holds(options)))
+        {
+            return Ok(());
+        }
+        let failed: Vec<&str> = rules
+            .iter()
+            .flat_map(|r| r.constraints.iter().filter(|c| !c.holds(options)))
+            .map(|c| c.text.as_str())
+            .collect();
+        Err(format!(
+            "{} may only invoke {} when {}",
+            principal,
+            plugin,
+            failed.join(", ")
+        ))
+    }
+
+    pub fn allows(&self, principal: &Principal, notebook: &Notebook, wanted: Permission) -> bool {
+        principal.user.as_deref() == Some(notebook.metadata.owner.as_str())
+            || self.grants(principal, notebook, wanted)
+    }
+
+    // may_create is true when a rule lets the principal create the new
+    // notebook, whoever it will belong to
+    pub fn may_create(&self, principal: &Principal, notebook: &Notebook) -> bool {
+        self.grants(principal, notebook, Permission::Create)
+    }
+
+    fn grants(&self, principal: &Principal, notebook: &Notebook, wanted: Permission) -> bool {
+        self.notebooks
+            .iter()
+            .filter(|r| {
+                (r.ids.is_empty() && r.tags.is_empty())
+                    || r.ids.contains(&notebook.id)
+                    || r.tags.iter().any(|t| notebook.metadata.tags.contains(t))
+            })
+            .filter(|r| r.permissions.iter().any(|p| p.grants(wanted)))
+            .any(|r| self.applies(&r.users, &r.groups, principal))
+    }
+}
+
+// PolicyStore keeps the policy loaded from its file and loads it again when
+// the file changes, a policy that does not parse keeps the previous one
+#[derive(Debug)]
+pub struct PolicyStore {
+    path: PathBuf,
+    policy: RwLock<Arc<Policy>>,
+    // sha256 of the file last loaded, a modification time can stay the same
+    // across writes on coarse clocks
+    digest: Mutex<Option<Vec<u8>>>,
+}
+
+fn digest(path: &PathBuf) -> Option<Vec<u8>> {
+    fs::read(path)
+        .ok()
+        .map(|content| Sha256::digest(content).to_vec())
+}
+
+fn load(path: &PathBuf) -> Result<Policy, PolicyError> {
+    let text =
+        fs::read_to_string(path).map_err(|e| PolicyError::Io(format!("{:?}: {}", path, e)))?;
+    Policy::new_from_str(&text)
+}
+
+impl PolicyStore {
+    pub fn open(path: PathBuf) -> Result<Self, PolicyError> {
+        let policy = load(&path)?;
+        Ok(Self {
+            digest: Mutex::new(digest(&path)),
+            path,
+            policy: RwLock::new(Arc::new(policy)),
+        })
+    }
+
+    pub fn current(&self) -> Arc<Policy> {
+        match self.policy.read() {
+            Ok(policy) => policy.clone(),
+            Err(e) => e.into_inner().clone(),
+        }
+    }
+
+    // reload reads the file again if it changed since the last load and
+    // returns whether the policy was replaced
+    pub fn reload(&self) -> Result<bool, PolicyError> {
+        let mut last = self
+            .digest
+            .lock()
+            .map_err(|e| PolicyError::Io(format!("could not lock the policy {:?}", e)))?;
+        let current = digest(&self.path);
+        if current == *last {
+            return Ok(false);
+        }
+        *last = current;
+        let policy = load(&self.path)?;
+        *self
+            .policy
+            .write()
+            .map_err(|e| PolicyError::Io(format!("could not lock the policy {:?}", e)))? =
+            Arc::new(policy);
+        Ok(true)
+    }
+}
+
+// start reloads the policy file in the background when it changes, a file
+// that does not parse is logged and the previous policy stays in place
+pub fn start() {
+    if POLICY.get().is_none() {
+        return;
+    }
+    std::thread::spawn(|| loop {
+        std::thread::sleep(Duration::from_secs(RELOAD_SECONDS));
+        if let Some(store) = POLICY.get() {
+            match store.reload() {
+                Ok(true) => tracing::info!("reloaded the policy {:?}", store.path),
+                Ok(false) => {}
+                Err(e) => tracing::warn!("could not reload the policy {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notebook::NotebookMetadata;
+    use chrono::Utc;
+
+    const POLICY: &str = r#"
+        [groups]
+        oncall = ["alice"]
+
+        [[plugins]]
+        plugins = ["prometheus", "loki-*"]
+        groups = ["*"]
+
+        [[plugins]]
+        plugins = ["restart-service"]
+        groups = ["oncall"]
+        when = ["cluster != prod"]
+
+        [[plugins]]
+        plugins = ["restart-service"]
+        users = ["carol"]
+
+        [[notebooks]]
+        groups = ["*"]
+        permissions = ["view"]
+
+        [[notebooks]]
+        tags = ["payments"]
+        groups = ["payments"]
+        permissions = ["create", "edit", "execute"]
+    "#;
+
+    fn principal(user: &str, groups: &[&str]) -> Principal {
+        Principal {
+            user: Some(user.to_string()),
+            groups: groups.iter().map(|g| g.to_string()).collect(),
+        }
+    }
+
+    fn options(cluster: &str) -> HashMap<String, String> {
+        HashMap::from([("cluster".to_string(), cluster.to_string())])
+    }
+
+    fn notebook(owner: &str, tags: &[&str]) -> Notebook {
+        Notebook {
+            id: "nb".into(),
+            metadata: NotebookMetadata {
+                title: "checkout errors".into(),
+                owner: owner.into(),
+                tags: tags.iter().map(|t| t.to_string()).collect(),
+                variables: Default::default(),
+                runbook: None,
+                incident: None,
+                created_at: Utc::now(),
+                updated_at: Utc::now(),
+            },
+            cells: vec![],
+        }
+    }
+
+    #[test]
+    fn test_check_plugin() {
+        let policy = Policy::new_from_str(POLICY).unwrap();
+        let anonymous = Principal::default();
+        assert!(policy
+            .check_plugin(&anonymous, "prometheus", &HashMap::new())
+            .is_ok());
+        assert!(policy
+            .check_plugin(&anonymous, "loki-logs", &HashMap::new())
+            .is_ok());
+        assert!(policy
+            .check_plugin(&anonymous, "restart-service", &options("staging"))
+            .is_err());
+
+        // alice is on call through [groups], bob through his identity
+        let alice = principal("alice", &[]);
+        assert!(policy
+            .check_plugin(&alice, "restart-service", &options("staging"))
+            .is_ok());
+        assert_eq!(
+            Err("alice may only invoke restart-service when cluster != prod".to_string()),
+            policy.check_plugin(&alice, "restart-service", &options("prod"))
+        );
+        assert!(policy
+            .check_plugin(&alice, "restart-service", &HashMap::new())
+            .is_err());
+        let bob = principal("bob", &["oncall"]);
+        assert!(policy
+            .check_plugin(&bob, "restart-service", &options("staging"))
+            .is_ok());
+        assert!(policy.is_member(&alice, "oncall") && policy.is_member(&bob, "oncall"));
+        assert!(!policy.is_member(&principal("carol", &[]), "oncall"));
+
+        // any rule granting the plugin is enough
+        assert!(policy
+            .check_plugin(
+                &principal("carol", &[]),
+                "restart-service",
+                &options("prod")
+            )
+            .is_ok());
+        assert_eq!(
+            Err("dave may not invoke kubectl".to_string()),
+            policy.check_plugin(&principal("dave", &[]), "kubectl", &HashMap::new())
+        );
+    }
+
+    #[test]
+    fn test_notebook_permissions() {
+        let policy = Policy::new_from_str(POLICY).unwrap();
+        let payments = notebook("erin", &["payments"]);
+        let dave = principal("dave", &[]);
+        assert!(policy.allows(&dave, &payments, Permission::View));
+        assert!(!policy.allows(&dave, &payments, Permission::Edit));
+
+        let frank = principal("frank", &["payments"]);
+        assert!(policy.allows(&frank, &payments, Permission::Edit));
+        assert!(policy.allows(&frank, &payments, Permission::Execute));
+        assert!(!policy.allows(&frank, &payments, Permission::Admin));
+        assert!(!policy.allows(&frank, &notebook("erin", &[]), Permission::Execute));
+
+        assert!(policy.allows(&principal("erin", &[]), &payments, Permission::Admin));
+    }
+
+    #[test]
+    fn test_create_notebooks() {
+        let policy = Policy::new_from_str(POLICY).unwrap();
+        let frank = principal("frank", &["payments"]);
+        assert!(policy.may_create(&frank, &notebook("frank", &["payments"])));
+        assert!(!policy.may_create(&frank, &notebook("frank", &[])));
+        // owning the new notebook grants nothing yet
+        let dave = principal("dave", &[]);
+        assert!(!policy.may_create(&dave, &notebook("dave", &["payments"])));
+        assert!(!policy.may_create(&Principal::default(), &notebook("", &[])));
+    }
+
+    #[test]
+    fn test_invalid_policies() {
+        assert!(matches!(
+            Policy::new_from_str("[[plugins]]\nplugins = [\"x\"]\n"),
+            Err(PolicyError::InvalidRule(_))
+        ));
+        assert!(matches!(
+            Policy::new_from_str(
+                "[[plugins]]\nplugins = [\"x\"]\ngroups = [\"*\"]\nwhen = [\"cluster = prod\"]\n"
+            ),
+            Err(PolicyError::InvalidRule(_))
+        ));
+        assert!(matches!(
+            Policy::new_from_str("[[notebooks]]\ngroups = [\"*\"]\npermissions = [\"delete\"]\n"),
+            Err(PolicyError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn test_reload() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        fs::write(file.path(), "").unwrap();
+        let store = PolicyStore::open(file.path().to_path_buf()).unwrap();
+        assert!(store
+            .current()
+            .check_plugin(&Principal::default(), "prometheus", &HashMap::new())
+            .is_err());
+        assert_eq!(Ok(false), store.reload());
+
+        fs::write(file.path(), POLICY).unwrap();
+        assert_eq!(Ok(true), store.reload());
+        fs::write(file.path(), POLICY).unwrap();
+        assert_eq!(Ok(false), store.reload());
+        assert!(store
+            .current()
+            .check_plugin(&Principal::default(), "prometheus", &HashMap::new())
+            .is_ok());
+
+        // a broken policy keeps the previous one
+        fs::write(file.path(), "[[plugins]]").unwrap();
+        assert!(store.reload().is_err());
+        assert!(store
+            .current()
+            .check_plugin(&Principal::default(), "prometheus", &HashMap::new())
+            .is_ok());
+    }
+}
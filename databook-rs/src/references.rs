@@ -0,0 +1,618 @@
+use crate::notebook::{CellContent, Notebook};
+use crate::timeline::ExecutionEvent;
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+
+// References let a cell use notebook variables and the output of other
+// cells, they are resolved right before the plugin is invoked:
+//
+//   {{vars.cluster}}
+//   {{cells.find_pod.output}}
+//   {{cells.find_pod.output.json.items[0].name}}
+//
+// `cells.<id>` is the last successful run of that cell. After `json` the
+// output is parsed as json and walked with `.key` and `[index]`, strings
+// are inserted as is and anything else as json. Other `{{ }}`, e.g. the
+// templates of an alert query, are left as they are, and `\{{` is a literal
+// `{{` even before `vars.` or `cells.`.
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ReferenceError {
+    Syntax(String),
+    UnknownVariable(String),
+    // the cell has not run successfully yet
+    NoOutput(String),
+    NotJson(String),
+    PathNotFound(String),
+}
+
+impl fmt::Display for ReferenceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReferenceError::Syntax(message) => write!(f, "{}", message),
+            ReferenceError::UnknownVariable(name) => {
+                write!(f, "variable {} is not set on the notebook", name)
+            }
+            ReferenceError::NoOutput(cell_id) => {
+                write!(f, "cell {} has no output yet, run it first", cell_id)
+            }
+            ReferenceError::NotJson(cell_id) => {
+                write!(f, "the output of cell {} is not json", cell_id)
+            }
+            ReferenceError::PathNotFound(path) => write!(f, "no {}", path),
+        }
+    }
+}
+
+// CellState is how a plugin cell stands against the cells it refers to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CellState {
+    pub cell_id: String,
+    pub dependencies: Vec<String>,
+    pub last_event_id: Option<String>,
+    pub stale: bool,
+}
+
+// Segment is a step of a json path, `.key` or `[index]`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+impl fmt::Display for Segment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Segment::Key(key) => write!(f, ".{}", key),
+            Segment::Index(index) => write!(f, "[{}]", index),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Reference {
+    Variable(String),
+    Output {
+        cell_id: String,
+        // None for the raw output, Some (maybe empty) to read it as json
+        json_path: Option<Vec<Segment>>,
+    },
+}
+
+impl Reference {
+    // input is how the reference shows on the inputs of an execution
+    fn input(&self) -> String {
+        match self {
+            Reference::Variable(name) => format!("vars.{}", name),
+            Reference::Output { cell_id, .. } => format!("cells.{}", cell_id),
+        }
+    }
+}
+
+enum Part<'a> {
+    Text(&'a str),
+    Reference(Reference),
+}
+
+// is_name tells whether `name` can be used as a variable or cell id in a
+// reference
+pub fn is_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+// parse_path reads a json path like `.items[0].name`, `expression` is what
+// errors mention
+pub fn parse_path(path: &str, expression: &str) -> Result<Vec<Segment>, ReferenceError> {
+    let syntax = || ReferenceError::Syntax(format!("invalid json path in {{{{{}}}}}", expression));
+    let mut segments = vec![];
+    let mut rest = path;
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix('.') {
+            let end = after.find(['.', '[']).unwrap_or(after.len());
+            let key = &after[..end];
+            if !is_name(key) {
+                return Err(syntax());
+            }
+            segments.push(Segment::Key(key.to_string()));
+            rest = &after[end..];
+        } else if let Some(after) = rest.strip_prefix('[') {
+            let end = after.find(']').ok_or_else(syntax)?;
+            let index = after[..end].trim().parse().map_err(|_| syntax())?;
+            segments.push(Segment::Index(index));
+            rest = &after[end + 1..];
+        } else {
+            return Err(syntax());
+        }
+    }
+    Ok(segments)
+}
+
+fn parse_reference(expression: &str) -> Result<Reference, ReferenceError> {
+    if let Some(name) = expression.strip_prefix("vars.") {
+        if is_name(name) {
+            return Ok(Reference::Variable(name.to_string()));
+        }
+    } else if let Some(rest) = expression.strip_prefix("cells.") {
+        if let Some((cell_id, rest)) = rest.split_once(".output") {
+            if is_name(cell_id) {
+                let json_path = match rest {
+                    "" => None,
+                    _ => match rest.strip_prefix(".json") {
+                        Some(path) => Some(parse_path(path, expression)?),
+                        None => None,
+                    },
+                };
+                if json_path.is_some() || rest.is_empty() {
+                    return Ok(Reference::Output {
+                        cell_id: cell_id.to_string(),
+                        json_path,
+                    });
+                }
+            }
+        }
+    }
+    Err(ReferenceError::Syntax(format!(
+        "unknown reference {{{{{}}}}}, expected vars.<name> or cells.<id>.output",
+        expression
+    )))
+}
+
+// is_reference tells whether the expression of a `{{ }}` is meant as a
+// reference, which then must be a valid one
+fn is_reference(expression: &str) -> bool {
+    expression.starts_with("vars.") || expression.starts_with("cells.")
+}
+
+fn parse_template(text: &str) -> Result<Vec<Part<'_>>, ReferenceError> {
+    let mut parts = vec![];
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        let after = &rest[start + 2..];
+        if rest[..start].ends_with('\\') {
+            parts.push(Part::Text(&rest[..start - 1]));
+            parts.push(Part::Text("{{"));
+            rest = after;
+            continue;
+        }
+        let end = after.find("}}");
+        let expression = after[..end.unwrap_or(after.len())].trim();
+        if !is_reference(expression) {
+            parts.push(Part::Text(&rest[..start + 2]));
+            rest = after;
+            continue;
+        }
+        let end =
+            end.ok_or_else(|| ReferenceError::Syntax(format!("unclosed {{{{ in {:?}", text)))?;
+        if start > 0 {
+            parts.push(Part::Text(&rest[..start]));
+        }
+        parts.push(Part::Reference(parse_reference(expression)?));
+        rest = &after[end + 2..];
+    }
+    if !rest.is_empty() {
+        parts.push(Part::Text(rest));
+    }
+    Ok(parts)
+}
+
+// dependencies lists the cells whose output the options refer to
+pub fn dependencies(
+    options: &BTreeMap<String, String>,
+) -> Result<BTreeSet<String>, ReferenceError> {
+    let mut cells = BTreeSet::new();
+    for value in options.values() {
+        for part in parse_template(value)? {
+            if let Part::Reference(Reference::Output { cell_id, .. }) = part {
+                cells.insert(cell_id);
+            }
+        }
+    }
+    Ok(cells)
+}
+
+type Options = BTreeMap<String, String>;
+
+// ReferenceContext is what references are resolved against
+pub struct ReferenceContext<'a> {
+    pub variables: &'a BTreeMap<String, String>,
+    // the last successful execution of every cell of the notebook
+    pub outputs: HashMap<&'a str, &'a ExecutionEvent>,
+}
+
+impl<'a> ReferenceContext<'a> {
+    // new takes the executions of the notebook in the order they happened
+    pub fn new(variables: &'a BTreeMap<String, String>, events: &'a [ExecutionEvent]) -> Self {
+        let mut outputs = HashMap::new();
+        for event in events.iter().filter(|e| e.output.is_some()) {
+            if let Some(cell_id) = &event.cell_id {
+                outputs.insert(cell_id.as_str(), event);
+            }
+        }
+        Self { variables, outputs }
+    }
+
+    // current returns what an input of an execution is worth now
+    fn current(&self, input: &str) -> Option<String> {
+        if let Some(name) = input.strip_prefix("vars.") {
+            return self.variables.get(name).cloned();
+        }
+        let cell_id = input.strip_prefix("cells.")?;
+        self.outputs.get(cell_id).map(|e| e.id.clone())
+    }
+
+    fn resolve_reference(&self, reference: &Reference) -> Result<(String, String), ReferenceError> {
+        match reference {
+            Reference::Variable(name) => {
+                let value = self
+                    .variables
+                    .get(name)
+                    .ok_or_else(|| ReferenceError::UnknownVariable(name.clone()))?;
+                Ok((value.clone(), value.clone()))
+            }
+            Reference::Output { cell_id, json_path } => {
+                let event = self
+                    .outputs
+                    .get(cell_id.as_str())
+                    .ok_or_else(|| ReferenceError::NoOutput(cell_id.clone()))?;
+                let output = event.output.clone().unwrap_or_default();
+                let value = match json_path {
+                    None => output,
+                    Some(path) => {
+                        let json: serde_json::Value = serde_json::from_str(&output)
+                            .map_err(|_| ReferenceError::NotJson(cell_id.clone()))?;
+                        walk(&json, path, cell_id)?
+                    }
+                };
+                Ok((value, event.id.clone()))
+            }
+        }
+    }
+
+    // resolve replaces the references of every option. It also returns the
+    // inputs used: the value of each variable and the execution id of each
+    // cell output, so later runs can tell whether the result is stale.
+    pub fn resolve(
+        &self,
+        options: &BTreeMap<String, String>,
+    ) -> Result<(Options, Options), ReferenceError> {
+        let mut resolved = BTreeMap::new();
+        let mut inputs = BTreeMap::new();
+        for (key, value) in options {
+            let mut result = String::new();
+            for part in parse_template(value)? {
+                match part {
+                    Part::Text(text) => result.push_str(text),
+                    Part::Reference(reference) => {
+                        let (value, input) = self.resolve_reference(&reference)?;
+                        result.push_str(&value);
+                        inputs.insert(reference.input(), input);
+                    }
+                }
+            }
+            resolved.insert(key.clone(), result);
+        }
+        Ok((resolved, inputs))
+    }
+
+    // stale_cells lists the cells whose last run used inputs that changed
+    // since (an upstream cell re-run, a variable edited), or that depend on
+    // a stale cell. `cells` are the cells with their dependencies.
+    fn stale_cells(
+        &self,
+        cells: &[(String, BTreeSet<String>)],
+        events: &[ExecutionEvent],
+    ) -> BTreeSet<String> {
+        let mut last_runs: HashMap<&str, &ExecutionEvent> = HashMap::new();
+        for event in events {
+            if let Some(cell_id) = &event.cell_id {
+                last_runs.insert(cell_id.as_str(), event);
+            }
+        }
+
+        let mut stale: BTreeSet<String> = cells
+            .iter()
+            .filter(|(cell_id, _)| {
+                matches!(last_runs.get(cell_id.as_str()), Some(run) if run
+                    .inputs
+                    .iter()
+                    .any(|(input, used)| self.current(input).as_ref() != Some(used)))
+            })
+            .map(|(cell_id, _)| cell_id.clone())
+            .collect();
+
+        // anything downstream of a stale cell is stale too
+        loop {
+            let before = stale.len();
+            for (cell_id, dependencies) in cells {
+                if last_runs.contains_key(cell_id.as_str())
+                    && dependencies.iter().any(|d| stale.contains(d))
+                {
+                    stale.insert(cell_id.clone());
+                }
+            }
+            if stale.len() == before {
+                return stale;
+            }
+        }
+    }
+}
+
+// cell_states returns the state of every plugin cell of the notebook,
+// `events` are its executions in the order they happened
+pub fn cell_states(notebook: &Notebook, events: &[ExecutionEvent]) -> Vec<CellState> {
+    let cells: Vec<(String, BTreeSet<String>)> = notebook
+        .cells
+        .iter()
+        .filter_map(|cell| match &cell.content {
+            // a cell with a broken reference fails when run, it has no
+            // dependencies until then
+            CellContent::Plugin { options, .. } => {
+                Some((cell.id.clone(), dependencies(options).unwrap_or_default()))
+            }
+            CellContent::Markdown { .. } => None,
+        })
+        .collect();
+    let stale =
+        ReferenceContext::new(&notebook.metadata.variables, events).stale_cells(&cells, events);
+
+    let mut last_events = HashMap::new();
+    for event in events {
+        if let Some(cell_id) = &event.cell_id {
+            last_events.insert(cell_id.as_str(), event.id.clone());
+        }
+    }
+    cells
+        .into_iter()
+        .map(|(cell_id, dependencies)| CellState {
+            stale: stale.contains(&cell_id),
+            last_event_id: last_events.get(cell_id.as_str()).cloned(),
+            dependencies: dependencies.into_iter().collect(),
+            cell_id,
+        })
+        .collect()
+}
+
+// select walks `path` into `json`, failing with the first missing segment
+pub fn select<'a, 'p>(
+    json: &'a serde_json::Value,
+    path: &'p [Segment],
+) -> Result<&'a serde_json::Value, &'p Segment> {
+    let mut current = json;
+    for segment in path {
+        let next = match segment {
+            Segment::Key(key) => current.get(key),
+            Segment::Index(index) => current.get(index),
+        };
+        current = next.ok_or(segment)?;
+    }
+    Ok(current)
+}
+
+fn walk(
+    json: &serde_json::Value,
+    path: &[Segment],
+    cell_id: &str,
+) -> Result<String, ReferenceError> {
+    let value = select(json, path).map_err(|segment| {
+        ReferenceError::PathNotFound(format!("{} in the output of {}", segment, cell_id))
+    })?;
+    Ok(match value {
+        serde_json::Value::String(value) => value.clone(),
+        value => value.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notebook::Cell;
+    use crate::plugin_manager::Execution;
+    use crate::plugin_runtime::InvocationTrace;
+    use crate::timeline::ExecutionContext;
+    use chrono::Utc;
+
+    fn run(cell_id: &str, output: &str, inputs: &[(&str, &str)]) -> ExecutionEvent {
+        let mut event = ExecutionEvent::new(
+            ExecutionContext {
+                notebook_id: Some("n1".into()),
+                cell_id: Some(cell_id.into()),
+                ..Default::default()
+            },
+            "kubectl",
+            BTreeMap::new(),
+            Execution {
+                plugin_version: None,
+                output: Ok(output.into()),
+                trace: InvocationTrace::default(),
+            },
+            Utc::now(),
+            Utc::now(),
+        );
+        event.inputs = inputs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        event
+    }
+
+    fn options(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_resolve() {
+        let variables = options(&[("cluster", "prod-eu")]);
+        let events = vec![
+            run("find_pod", r#"{"items": [{"name": "api-7f9c"}]}"#, &[]),
+            run("trace", "abc123", &[]),
+        ];
+        let context = ReferenceContext::new(&variables, &events);
+
+        let (resolved, inputs) = context
+            .resolve(&options(&[
+                (
+                    "query",
+                    "logs {{ cells.find_pod.output.json.items[0].name }} on {{vars.cluster}}",
+                ),
+                ("trace", "{{cells.trace.output}}"),
+                ("items", "{{cells.find_pod.output.json.items}}"),
+            ]))
+            .unwrap();
+        assert_eq!(
+            options(&[
+                ("query", "logs api-7f9c on prod-eu"),
+                ("trace", "abc123"),
+                ("items", r#"[{"name":"api-7f9c"}]"#),
+            ]),
+            resolved
+        );
+        assert_eq!(
+            options(&[
+                ("cells.find_pod", events[0].id.as_str()),
+                ("cells.trace", events[1].id.as_str()),
+                ("vars.cluster", "prod-eu"),
+            ]),
+            inputs
+        );
+    }
+
+    #[test]
+    fn test_template_text() {
+        let variables = options(&[("cluster", "prod-eu")]);
+        let context = ReferenceContext::new(&variables, &[]);
+        let resolve = |value: &str| {
+            context.resolve(&options(&[("query", value)])).unwrap().0["query"].clone()
+        };
+
+        assert_eq!(
+            "summary: {{ $labels.instance }} on prod-eu",
+            resolve("summary: {{ $labels.instance }} on {{vars.cluster}}")
+        );
+        assert_eq!(
+            "{{vars.cluster}} is prod-eu",
+            resolve("\\{{vars.cluster}} is {{vars.cluster}}")
+        );
+        assert_eq!("{{{{ prod-eu }}", resolve("{{{{ {{vars.cluster}} }}"));
+        assert_eq!("a {{ b", resolve("a {{ b"));
+        assert_eq!(
+            BTreeSet::new(),
+            dependencies(&options(&[(
+                "query",
+                "\\{{cells.other.output}} {{ .Values }}"
+            )]))
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_resolve_errors() {
+        let variables = BTreeMap::new();
+        let events = vec![run("text", "not json", &[])];
+        let context = ReferenceContext::new(&variables, &events);
+        let error = |value: &str| context.resolve(&options(&[("query", value)])).unwrap_err();
+
+        assert_eq!(
+            ReferenceError::UnknownVariable("cluster".into()),
+            error("{{vars.cluster}}")
+        );
+        assert_eq!(
+            ReferenceError::NoOutput("other".into()),
+            error("{{cells.other.output}}")
+        );
+        assert_eq!(
+            ReferenceError::NotJson("text".into()),
+            error("{{cells.text.output.json}}")
+        );
+        assert!(matches!(error("{{cells.text}}"), ReferenceError::Syntax(_)));
+        assert!(matches!(error("{{vars.cluster"), ReferenceError::Syntax(_)));
+        assert!(matches!(
+            error("{{cells.text.output.json.items[x]}}"),
+            ReferenceError::Syntax(_)
+        ));
+    }
+
+    #[test]
+    fn test_dependencies() {
+        assert_eq!(
+            BTreeSet::from(["a".to_string(), "b".to_string()]),
+            dependencies(&options(&[
+                ("query", "{{cells.a.output}} {{vars.x}}"),
+                ("other", "{{cells.b.output.json[0]}}"),
+            ]))
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_stale_cells() {
+        let variables = options(&[("cluster", "prod-us")]);
+        let first_pod = run("find_pod", "api-1", &[]);
+        let logs = run("logs", "...", &[("cells.find_pod", &first_pod.id)]);
+        let summary = run("summary", "...", &[("cells.logs", &logs.id)]);
+        let metrics = run("metrics", "...", &[("vars.cluster", "prod-us")]);
+        let second_pod = run("find_pod", "api-2", &[]);
+        let events = vec![first_pod, logs, summary, metrics, second_pod];
+
+        let cells = vec![
+            ("find_pod".to_string(), BTreeSet::new()),
+            ("logs".to_string(), BTreeSet::from(["find_pod".to_string()])),
+            ("summary".to_string(), BTreeSet::from(["logs".to_string()])),
+            ("metrics".to_string(), BTreeSet::new()),
+            (
+                "never_run".to_string(),
+                BTreeSet::from(["logs".to_string()]),
+            ),
+        ];
+        let context = ReferenceContext::new(&variables, &events);
+        assert_eq!(
+            BTreeSet::from(["logs".to_string(), "summary".to_string()]),
+            context.stale_cells(&cells, &events)
+        );
+
+        let edited = options(&[("cluster", "prod-eu")]);
+        let context = ReferenceContext::new(&edited, &events);
+        assert!(context.stale_cells(&cells, &events).contains("metrics"));
+    }
+
+    #[test]
+    fn test_cell_states() {
+        let mut find_pod = Cell::plugin("kubectl", options(&[("query", "get pods")]));
+        find_pod.id = "find_pod".into();
+        let mut logs = Cell::plugin(
+            "loki",
+            options(&[("query", "{pod=\"{{cells.find_pod.output}}\"}")]),
+        );
+        logs.id = "logs".into();
+        let notebook = Notebook::new(
+            "api errors".into(),
+            "elias".into(),
+            vec![],
+            vec![Cell::markdown("# pods"), find_pod, logs],
+        );
+
+        let first = run("find_pod", "api-1", &[]);
+        let events = vec![
+            first.clone(),
+            run("logs", "...", &[("cells.find_pod", &first.id)]),
+        ];
+        let states = cell_states(&notebook, &events);
+        assert_eq!(2, states.len());
+        assert_eq!(vec!["find_pod".to_string()], states[1].dependencies);
+        assert_eq!(Some(events[1].id.clone()), states[1].last_event_id);
+        assert!(!states[1].stale);
+
+        let mut events = events;
+        events.push(run("find_pod", "api-2", &[]));
+        let states = cell_states(&notebook, &events);
+        assert!(!states[0].stale);
+        assert!(states[1].stale);
+    }
+}
@@ -1,5 +1,19 @@
+use crate::approval::ApprovalRequest;
+use crate::audit::AuditEntry;
+use crate::cell_parser::Position;
+use crate::crdt::{Operation, SequencedOperation};
+use crate::incident::{Incident, Metrics};
+use crate::notebook::{Cell, Notebook};
+use crate::notebook_run::NotebookRun;
+use crate::references::CellState;
+use crate::revision::{NotebookDiff, Revision, RevisionInfo};
+use crate::runbook::{Parameter, Runbook};
+use crate::schedule::Schedule;
+use crate::search::SearchHit;
+use crate::timeline::ExecutionEvent;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct InvokePluginRequest {
@@ -12,3 +26,351 @@ pub struct InvokePluginResponse {
     pub output: Option<String>,
     pub error: Option<String>,
 }
+
+// InvokeCellRequest holds a raw `@plugin=` cell
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InvokeCellRequest {
+    pub text: String,
+    pub user: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ParseCellRequest {
+    pub text: String,
+}
+
+// InvokeCellResponse answers both invoke_cell and cells/parse (without
+// output), position is set for parse errors
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct InvokeCellResponse {
+    pub plugin: Option<String>,
+    pub options: BTreeMap<String, String>,
+    pub output: Option<String>,
+    pub error: Option<String>,
+    pub position: Option<Position>,
+}
+
+// InstallPluginRequest is used to install or upgrade a plugin, either
+// `package` or `wasm` and `config` must be set. Binary content is base64.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InstallPluginRequest {
+    pub package: Option<String>,
+    pub wasm: Option<String>,
+    pub config: Option<String>,
+    pub signature: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PluginStatus {
+    pub name: String,
+    pub version: Option<String>,
+    pub enabled: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PluginStatusResponse {
+    pub plugin: Option<PluginStatus>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ListPluginsResponse {
+    pub plugins: Vec<PluginStatus>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AuditResponse {
+    pub entries: Vec<AuditEntry>,
+    pub error: Option<String>,
+}
+
+// NotebookRequest creates or updates a notebook, on update the owner is ignored
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NotebookRequest {
+    pub title: String,
+    pub owner: String,
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub variables: BTreeMap<String, String>,
+    pub cells: Vec<Cell>,
+    // on update, the updated_at of the notebook the change was made on
+    #[serde(default)]
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NotebookResponse {
+    pub notebook: Option<Notebook>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ListNotebooksResponse {
+    pub notebooks: Vec<Notebook>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EditNotebookRequest {
+    pub user: Option<String>,
+    pub operations: Vec<Operation>,
+}
+
+// EditNotebookResponse holds the operations that were new to the server
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EditNotebookResponse {
+    pub notebook: Option<Notebook>,
+    pub operations: Vec<SequencedOperation>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OperationsResponse {
+    pub operations: Vec<SequencedOperation>,
+    pub error: Option<String>,
+}
+
+// LiveTicketRequest exchanges the credentials of the request for a ticket
+// to follow notebooks over websocket, user names anonymous viewers
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct LiveTicketRequest {
+    pub user: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LiveTicketResponse {
+    pub ticket: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RunCellRequest {
+    pub user: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ExecutionEventResponse {
+    pub event: Option<ExecutionEvent>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TimelineResponse {
+    pub events: Vec<ExecutionEvent>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CellStatesResponse {
+    pub cells: Vec<CellState>,
+    pub error: Option<String>,
+}
+
+// RunbookRequest creates or updates a runbook, every update is a new version
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RunbookRequest {
+    // only read on create, generated if missing
+    pub id: Option<String>,
+    pub title: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub parameters: Vec<Parameter>,
+    pub cells: Vec<Cell>,
+    pub author: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RunbookResponse {
+    pub runbook: Option<Runbook>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ListRunbooksResponse {
+    pub runbooks: Vec<Runbook>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InstantiateRunbookRequest {
+    // the latest version if missing
+    pub version: Option<u32>,
+    #[serde(default)]
+    pub parameters: BTreeMap<String, String>,
+    pub owner: String,
+    // defaults to the title of the runbook
+    pub title: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RunNotebookRequest {
+    pub user: Option<String>,
+    // stop at the first failed step
+    #[serde(default)]
+    pub fail_fast: bool,
+}
+
+// RunRunbookRequest instantiates a runbook and runs the new notebook
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RunRunbookRequest {
+    #[serde(flatten)]
+    pub runbook: InstantiateRunbookRequest,
+    pub user: Option<String>,
+    #[serde(default)]
+    pub fail_fast: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RunResponse {
+    pub run: Option<NotebookRun>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ListRunsResponse {
+    pub runs: Vec<NotebookRun>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ExportRequest {
+    // markdown (the default) or html
+    pub format: Option<String>,
+    // a template to use instead of the default one
+    pub template: Option<String>,
+    // or the name of a template in the export_templates directory
+    pub template_name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ExportResponse {
+    pub content: Option<String>,
+    pub content_type: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImportNotebookRequest {
+    // jupyter or markdown, guessed from the content when missing
+    pub format: Option<String>,
+    pub content: String,
+    pub owner: String,
+    // defaults to the title found in the document
+    pub title: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    // language -> plugin, on top of the server table
+    #[serde(default)]
+    pub languages: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ListRevisionsResponse {
+    pub revisions: Vec<RevisionInfo>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RevisionResponse {
+    pub revision: Option<Revision>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DiffResponse {
+    pub diff: Option<NotebookDiff>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RestoreRevisionRequest {
+    pub user: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ForkRevisionRequest {
+    pub owner: String,
+    // defaults to the title with the revision number
+    pub title: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeclareIncidentRequest {
+    // sev1 to sev4
+    pub severity: String,
+    pub commander: Option<String>,
+    #[serde(default)]
+    pub responders: Vec<String>,
+    pub user: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TransitionIncidentRequest {
+    // investigating, identified, mitigated or resolved
+    pub status: String,
+    pub user: Option<String>,
+    pub note: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct UpdateIncidentRequest {
+    // missing fields are left as they are
+    pub severity: Option<String>,
+    pub commander: Option<String>,
+    #[serde(default)]
+    pub add_responders: Vec<String>,
+    pub user: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct IncidentResponse {
+    pub incident: Option<Incident>,
+    pub metrics: Option<Metrics>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SearchResponse {
+    pub hits: Vec<SearchHit>,
+    pub error: Option<String>,
+}
+
+// CreateScheduleRequest takes one of every (e.g. 1m) and cron
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CreateScheduleRequest {
+    // the whole notebook runs when missing
+    pub cell_id: Option<String>,
+    pub every: Option<String>,
+    pub cron: Option<String>,
+    pub condition: Option<String>,
+    pub webhook: Option<String>,
+    pub user: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ScheduleResponse {
+    pub schedule: Option<Schedule>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ListSchedulesResponse {
+    pub schedules: Vec<Schedule>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ApprovalResponse {
+    pub approval: Option<ApprovalRequest>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ListApprovalsResponse {
+    pub approvals: Vec<ApprovalRequest>,
+    pub error: Option<String>,
+}
@@ -0,0 +1,462 @@
+use crate::notebook::{CellContent, Notebook};
+use crate::notebook_store::StoreError;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+// A revision is a snapshot of the notebook taken on every save, so the
+// notebook can be seen as it was when a decision was made, compared with
+// another revision, restored or forked.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Revision {
+    pub notebook_id: String,
+    // numbered from 1 for every notebook
+    pub number: u64,
+    // who saved, None for saves without a user (e.g. a whole update)
+    pub author: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub notebook: Notebook,
+}
+
+// RevisionInfo is a revision without its snapshot, for listings
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RevisionInfo {
+    pub number: u64,
+    pub author: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub title: String,
+    pub cells: usize,
+}
+
+impl Revision {
+    pub fn info(&self) -> RevisionInfo {
+        RevisionInfo {
+            number: self.number,
+            author: self.author.clone(),
+            created_at: self.created_at,
+            title: self.notebook.metadata.title.clone(),
+            cells: self.notebook.cells.len(),
+        }
+    }
+
+    // same_content tells if saving the notebook would not change anything
+    pub fn same_content(&self, notebook: &Notebook) -> bool {
+        let (a, b) = (&self.notebook.metadata, &notebook.metadata);
+        a.title == b.title
+            && a.tags == b.tags
+            && a.variables == b.variables
+            && self.notebook.cells == notebook.cells
+    }
+}
+
+// Retention bounds the revisions kept for every notebook, the latest one
+// is always kept
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Retention {
+    pub keep: Option<usize>,
+    pub max_age_days: Option<u32>,
+}
+
+// RevisionStore keeps the revisions next to the notebooks
+pub trait RevisionStore: Send + Sync {
+    // append_revision stores the notebook as its next revision
+    fn append_revision(
+        &self,
+        notebook: &Notebook,
+        author: Option<&str>,
+    ) -> Result<Revision, StoreError>;
+    // revision returns a revision, or the latest one
+    fn revision(&self, notebook_id: &str, number: Option<u64>) -> Result<Revision, StoreError>;
+    // revisions lists the revisions of a notebook, most recent first
+    fn revisions(&self, notebook_id: &str) -> Result<Vec<RevisionInfo>, StoreError>;
+    // prune_revisions removes the revisions retention does not keep and
+    // returns how many were removed
+    fn prune_revisions(&self, notebook_id: &str, retention: Retention)
+        -> Result<usize, StoreError>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Change {
+    Added,
+    Removed,
+    Modified,
+    // same content, another place among the cells
+    Moved,
+}
+
+// FieldChange is a value that differs, e.g. `title`, `tags`,
+// `variables.cluster`, `plugin` or `options.query`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FieldChange {
+    pub field: String,
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LineChange {
+    Same(String),
+    Added(String),
+    Removed(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CellDiff {
+    pub cell_id: String,
+    pub change: Change,
+    // positions among the cells, counted from 0
+    pub position_before: Option<usize>,
+    pub position_after: Option<usize>,
+    // type, plugin and options
+    pub fields: Vec<FieldChange>,
+    // line by line diff of the markdown source, empty if it did not change
+    pub source: Vec<LineChange>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NotebookDiff {
+    pub from: u64,
+    pub to: u64,
+    pub metadata: Vec<FieldChange>,
+    // changed cells in the order of the `to` revision, removed ones last
+    pub cells: Vec<CellDiff>,
+}
+
+// common returns the indexes of a longest common subsequence of a and b
+fn common<T: PartialEq>(a: &[T], b: &[T]) -> Vec<(usize, usize)> {
+    let mut lengths = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lengths[i][j] = if a[i] == b[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j, mut pairs) = (0, 0, vec![]);
+    while i < a.len() && j < b.len() {
+        if a[i] == b[j] {
+            pairs.push((i, j));
+            i += 1;
+            j += 1;
+        } else if lengths[i + 1][j] >= lengths[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    pairs
+}
+
+pub fn diff_lines(before: &str, after: &str) -> Vec<LineChange> {
+    let (a, b): (Vec<&str>, Vec<&str>) = (before.lines().collect(), after.lines().collect());
+    let mut lines = vec![];
+    let (mut i, mut j) = (0, 0);
+    for (ci, cj) in common(&a, &b).into_iter().chain([(a.len(), b.len())]) {
+        lines.extend(a[i..ci].iter().map(|l| LineChange::Removed(l.to_string())));
+        lines.extend(b[j..cj].iter().map(|l| LineChange::Added(l.to_string())));
+        if ci < a.len() {
+            lines.push(LineChange::Same(a[ci].to_string()));
+        }
+        i = ci + 1;
+        j = cj + 1;
+    }
+    lines
+}
+
+fn diff_fields(
+    before: &BTreeMap<String, String>,
+    after: &BTreeMap<String, String>,
+) -> Vec<FieldChange> {
+    let mut fields: Vec<&String> = before.keys().chain(after.keys()).collect();
+    fields.sort();
+    fields.dedup();
+    fields
+        .into_iter()
+        .filter(|f| before.get(*f) != after.get(*f))
+        .map(|f| FieldChange {
+            field: f.clone(),
+            before: before.get(f).cloned(),
+            after: after.get(f).cloned(),
+        })
+        .collect()
+}
+
+// cell_fields flattens a cell into the fields compared, the markdown
+// source is diffed by line instead
+fn cell_fields(content: Option<&CellContent>) -> (BTreeMap<String, String>, &str) {
+    let mut fields = BTreeMap::new();
+    match content {
+        Some(CellContent::Markdown { source }) => {
+            fields.insert("type".to_string(), "markdown".to_string());
+            (fields, source)
+        }
+        Some(CellContent::Plugin { plugin, options }) => {
+            fields.insert("type".to_string(), "plugin".to_string());
+            fields.insert("plugin".to_string(), plugin.clone());
+            for (name, value) in options {
+                fields.insert(format!("options.{}", name), value.clone());
+            }
+            (fields, "")
+        }
+        None => (fields, ""),
+    }
+}
+
+fn diff_cell(
+    cell_id: &str,
+    change: Change,
+    before: Option<(usize, &CellContent)>,
+    after: Option<(usize, &CellContent)>,
+) -> CellDiff {
+    let (fields_before, source_before) = cell_fields(before.map(|b| b.1));
+    let (fields_after, source_after) = cell_fields(after.map(|a| a.1));
+    let source = if source_before == source_after {
+        vec![]
+    } else {
+        diff_lines(source_before, source_after)
+    };
+    CellDiff {
+        cell_id: cell_id.to_string(),
+        change,
+        position_before: before.map(|b| b.0),
+        position_after: after.map(|a| a.0),
+        fields: diff_fields(&fields_before, &fields_after),
+        source,
+    }
+}
+
+fn metadata_fields(notebook: &Notebook) -> BTreeMap<String, String> {
+    let metadata = &notebook.metadata;
+    let mut fields = BTreeMap::from([
+        ("title".to_string(), metadata.title.clone()),
+        ("tags".to_string(), metadata.tags.join(", ")),
+    ]);
+    for (name, value) in &metadata.variables {
+        fields.insert(format!("variables.{}", name), value.clone());
+    }
+    fields
+}
+
+// diff compares two revisions cell by cell, cells are matched by id
+pub fn diff(from: &Revision, to: &Revision) -> NotebookDiff {
+    let (before, after) = (&from.notebook.cells, &to.notebook.cells);
+    let positions_before: HashMap<&str, usize> = before
+        .iter()
+        .enumerate()
+        .map(|(i, c)| (c.id.as_str(), i))
+        .collect();
+    let positions_after: HashMap<&str, usize> = after
+        .iter()
+        .enumerate()
+        .map(|(i, c)| (c.id.as_str(), i))
+        .collect();
+
+    // cells kept in the same relative order did not move, the others did
+    let kept_before: Vec<&str> = before
+        .iter()
+        .map(|c| c.id.as_str())
+        .filter(|id| positions_after.contains_key(id))
+        .collect();
+    let kept_after: Vec<&str> = after
+        .iter()
+        .map(|c| c.id.as_str())
+        .filter(|id| positions_before.contains_key(id))
+        .collect();
+    let in_order: Vec<&str> = common(&kept_before, &kept_after)
+        .into_iter()
+        .map(|(i, _)| kept_before[i])
+        .collect();
+
+    let mut cells = vec![];
+    for (position, cell) in after.iter().enumerate() {
+        let after = Some((position, &cell.content));
+        match positions_before.get(cell.id.as_str()) {
+            None => cells.push(diff_cell(&cell.id, Change::Added, None, after)),
+            Some(&old) => {
+                let change = if before[old].content != cell.content {
+                    Change::Modified
+                } else if !in_order.contains(&cell.id.as_str()) {
+                    Change::Moved
+                } else {
+                    continue;
+                };
+                cells.push(diff_cell(
+                    &cell.id,
+                    change,
+                    Some((old, &before[old].content)),
+                    after,
+                ));
+            }
+        }
+    }
+    for (position, cell) in before.iter().enumerate() {
+        if !positions_after.contains_key(cell.id.as_str()) {
+            cells.push(diff_cell(
+                &cell.id,
+                Change::Removed,
+                Some((position, &cell.content)),
+                None,
+            ));
+        }
+    }
+
+    NotebookDiff {
+        from: from.number,
+        to: to.number,
+        metadata: diff_fields(
+            &metadata_fields(&from.notebook),
+            &metadata_fields(&to.notebook),
+        ),
+        cells,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notebook::Cell;
+
+    fn revision(number: u64, title: &str, cells: Vec<Cell>) -> Revision {
+        let mut notebook = Notebook::new(title.into(), "elias".into(), vec![], cells);
+        notebook.id = "n1".into();
+        Revision {
+            notebook_id: "n1".into(),
+            number,
+            author: None,
+            created_at: Utc::now(),
+            notebook,
+        }
+    }
+
+    fn cell(id: &str, content: CellContent) -> Cell {
+        Cell {
+            id: id.into(),
+            content,
+        }
+    }
+
+    fn markdown(source: &str) -> CellContent {
+        CellContent::Markdown {
+            source: source.into(),
+        }
+    }
+
+    fn plugin(query: &str) -> CellContent {
+        CellContent::Plugin {
+            plugin: "prometheus".into(),
+            options: BTreeMap::from([("query".to_string(), query.to_string())]),
+        }
+    }
+
+    #[test]
+    fn test_diff_lines() {
+        use LineChange::*;
+        assert_eq!(
+            vec![
+                Same("a".into()),
+                Removed("b".into()),
+                Added("B".into()),
+                Same("c".into()),
+                Added("d".into())
+            ],
+            diff_lines("a\nb\nc", "a\nB\nc\nd")
+        );
+        assert_eq!(vec![Added("x".into())], diff_lines("", "x"));
+    }
+
+    #[test]
+    fn test_diff() {
+        let from = revision(
+            1,
+            "api down",
+            vec![
+                cell("notes", markdown("p99 is up\nsince 10:00")),
+                cell("latency", plugin("latency[5m]")),
+                cell("logs", plugin("{app=\"api\"}")),
+                cell("old", markdown("to remove")),
+            ],
+        );
+        let mut to = revision(
+            2,
+            "api latency",
+            vec![
+                cell("logs", plugin("{app=\"api\"}")),
+                cell("notes", markdown("p99 is up\nsince 10:05")),
+                cell("latency", plugin("latency[1m]")),
+                cell("new", markdown("rolled back")),
+            ],
+        );
+        to.notebook
+            .metadata
+            .variables
+            .insert("cluster".into(), "prod".into());
+
+        let diff = diff(&from, &to);
+        assert_eq!(
+            vec![
+                FieldChange {
+                    field: "title".into(),
+                    before: Some("api down".into()),
+                    after: Some("api latency".into())
+                },
+                FieldChange {
+                    field: "variables.cluster".into(),
+                    before: None,
+                    after: Some("prod".into())
+                },
+            ],
+            diff.metadata
+        );
+        let changes: Vec<(&str, Change)> = diff
+            .cells
+            .iter()
+            .map(|c| (c.cell_id.as_str(), c.change.clone()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("logs", Change::Moved),
+                ("notes", Change::Modified),
+                ("latency", Change::Modified),
+                ("new", Change::Added),
+                ("old", Change::Removed),
+            ],
+            changes
+        );
+        assert_eq!(
+            vec![
+                LineChange::Same("p99 is up".into()),
+                LineChange::Removed("since 10:00".into()),
+                LineChange::Added("since 10:05".into())
+            ],
+            diff.cells[1].source
+        );
+        assert_eq!(
+            vec![FieldChange {
+                field: "options.query".into(),
+                before: Some("latency[5m]".into()),
+                after: Some("latency[1m]".into())
+            }],
+            diff.cells[2].fields
+        );
+        assert_eq!(
+            (Some(2), Some(0)),
+            (diff.cells[0].position_before, diff.cells[0].position_after)
+        );
+        assert_eq!(Some(3), diff.cells[4].position_before);
+    }
+
+    #[test]
+    fn test_same_content() {
+        let revision = revision(1, "api down", vec![cell("notes", markdown("p99"))]);
+        let mut notebook = revision.notebook.clone();
+        notebook.metadata.updated_at = Utc::now();
+        assert!(revision.same_content(&notebook));
+        notebook.metadata.tags.push("sev1".into());
+        assert!(!revision.same_content(&notebook));
+    }
+}
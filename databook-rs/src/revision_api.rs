@@ -0,0 +1,374 @@
+use crate::auth;
+use crate::databook;
+use crate::databook::revisions_server::Revisions;
+use crate::notebook::Notebook;
+use crate::notebook_api::{self, NotebookApiError};
+use crate::notebook_store::StoreError;
+use crate::policy::{Actor, Permission, Principal};
+use crate::revision::{self, CellDiff, Change, FieldChange, LineChange, NotebookDiff, Retention};
+use crate::revision::{Revision, RevisionInfo, RevisionStore};
+use crate::timeline_api::empty_as_none;
+use crate::{rest, run_blocking, CONFIG, REVISIONS};
+
+use rocket_contrib::json::Json;
+use tonic::{Request, Response, Status};
+use tracing::instrument;
+
+pub fn store() -> Result<&'static dyn RevisionStore, NotebookApiError> {
+    REVISIONS
+        .get()
+        .map(|s| s.as_ref())
+        .ok_or_else(|| NotebookApiError::Internal("No revision store setup".into()))
+}
+
+fn revision_error(e: StoreError) -> NotebookApiError {
+    match e {
+        StoreError::NotFound(revision) => {
+            NotebookApiError::NotFound(format!("revision {} does not exist", revision))
+        }
+        e => NotebookApiError::Store(e),
+    }
+}
+
+fn retention() -> Retention {
+    CONFIG
+        .get()
+        .map(|c| Retention {
+            keep: c.revisions_keep,
+            max_age_days: c.revisions_max_age_days,
+        })
+        .unwrap_or_default()
+}
+
+// record stores the saved notebook as a new revision, unless nothing but
+// updated_at changed, and applies the retention. The notebook is already
+// saved, so failures are logged rather than returned.
+pub fn record(notebook: &Notebook, author: Option<&str>) {
+    let result = store().and_then(|store| {
+        match store.revision(&notebook.id, None) {
+            Ok(latest) if latest.same_content(notebook) => return Ok(()),
+            Ok(_) | Err(StoreError::NotFound(_)) => {}
+            Err(e) => return Err(e.into()),
+        }
+        store.append_revision(notebook, author)?;
+        store.prune_revisions(&notebook.id, retention())?;
+        Ok(())
+    });
+    if let Err(e) = result {
+        tracing::error!(
+            "could not record a revision of notebook {} {:?}",
+            notebook.id,
+            e
+        );
+    }
+}
+
+pub fn list_revisions(notebook_id: &str) -> Result<Vec<RevisionInfo>, NotebookApiError> {
+    // tells a missing notebook from one without revisions
+    notebook_api::store()?.get(notebook_id)?;
+    Ok(store()?.revisions(notebook_id)?)
+}
+
+pub fn get_revision(notebook_id: &str, number: u64) -> Result<Revision, NotebookApiError> {
+    store()?
+        .revision(notebook_id, Some(number))
+        .map_err(revision_error)
+}
+
+// diff_revisions compares two revisions, `to` defaults to the latest one
+pub fn diff_revisions(
+    notebook_id: &str,
+    from: u64,
+    to: Option<u64>,
+) -> Result<NotebookDiff, NotebookApiError> {
+    let store = store()?;
+    let from = store
+        .revision(notebook_id, Some(from))
+        .map_err(revision_error)?;
+    let to = store.revision(notebook_id, to).map_err(revision_error)?;
+    Ok(revision::diff(&from, &to))
+}
+
+// restore_revision saves the content of an old revision as the current
+// one, which makes a new revision: nothing in between is lost
+pub fn restore_revision(
+    notebook_id: &str,
+    number: u64,
+    actor: &Actor,
+) -> Result<Notebook, NotebookApiError> {
+    let revision = get_revision(notebook_id, number)?;
+    let metadata = revision.notebook.metadata;
+    notebook_api::update_notebook(
+        notebook_id,
+        metadata.title,
+        metadata.tags,
+        metadata.variables,
+        revision.notebook.cells,
+        None,
+        actor,
+    )
+}
+
+// fork_revision creates a new notebook from the content of a revision
+pub fn fork_revision(
+    notebook_id: &str,
+    number: u64,
+    owner: String,
+    title: Option<String>,
+    principal: &Principal,
+) -> Result<Notebook, NotebookApiError> {
+    let revision = get_revision(notebook_id, number)?;
+    let metadata = revision.notebook.metadata;
+    let title = title.unwrap_or_else(|| format!("{} (revision {})", metadata.title, number));
+    let mut notebook = Notebook::new(title, owner, metadata.tags, revision.notebook.cells);
+    notebook.metadata.variables = metadata.variables;
+    notebook.metadata.runbook = metadata.runbook;
+    notebook_api::insert_notebook(notebook, principal)
+}
+
+fn to_grpc_info(info: RevisionInfo) -> databook::RevisionInfo {
+    databook::RevisionInfo {
+        number: info.number,
+        author: info.author.unwrap_or_default(),
+        created_at: info.created_at.timestamp_millis(),
+        title: info.title,
+        cells: info.cells as u32,
+    }
+}
+
+fn to_grpc_field(field: FieldChange) -> databook::FieldChange {
+    databook::FieldChange {
+        field: field.field,
+        before: field.before.unwrap_or_default(),
+        after: field.after.unwrap_or_default(),
+    }
+}
+
+fn to_grpc_line(line: LineChange) -> databook::LineChange {
+    let (kind, text) = match line {
+        LineChange::Same(text) => ("same", text),
+        LineChange::Added(text) => ("added", text),
+        LineChange::Removed(text) => ("removed", text),
+    };
+    databook::LineChange {
+        kind: kind.to_string(),
+        text,
+    }
+}
+
+fn to_grpc_cell_diff(cell: CellDiff) -> databook::CellDiff {
+    let position = |p: Option<usize>| p.map(|p| p as i32).unwrap_or(-1);
+    databook::CellDiff {
+        cell_id: cell.cell_id,
+        change: match cell.change {
+            Change::Added => "added",
+            Change::Removed => "removed",
+            Change::Modified => "modified",
+            Change::Moved => "moved",
+        }
+        .to_string(),
+        position_before: position(cell.position_before),
+        position_after: position(cell.position_after),
+        fields: cell.fields.into_iter().map(to_grpc_field).collect(),
+        source: cell.source.into_iter().map(to_grpc_line).collect(),
+    }
+}
+
+fn to_grpc_diff(diff: NotebookDiff) -> databook::NotebookDiff {
+    databook::NotebookDiff {
+        from: diff.from,
+        to: diff.to,
+        metadata: diff.metadata.into_iter().map(to_grpc_field).collect(),
+        cells: diff.cells.into_iter().map(to_grpc_cell_diff).collect(),
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RevisionsGrpc {}
+
+#[tonic::async_trait]
+impl Revisions for RevisionsGrpc {
+    #[instrument]
+    async fn list_revisions(
+        &self,
+        request: Request<databook::ListRevisionsRequest>,
+    ) -> Result<Response<databook::ListRevisionsResponse>, Status> {
+        let principal = auth::actor(&request, "").principal;
+        run_blocking(move || {
+            let notebook_id = request.into_inner().notebook_id;
+            notebook_api::get_authorized(&notebook_id, &principal, Permission::View)?;
+            let revisions = list_revisions(&notebook_id)?;
+            Ok::<_, NotebookApiError>(databook::ListRevisionsResponse {
+                revisions: revisions.into_iter().map(to_grpc_info).collect(),
+            })
+        })
+        .await
+    }
+
+    #[instrument]
+    async fn get_revision(
+        &self,
+        request: Request<databook::GetRevisionRequest>,
+    ) -> Result<Response<databook::Revision>, Status> {
+        let principal = auth::actor(&request, "").principal;
+        run_blocking(move || {
+            let request = request.into_inner();
+            notebook_api::get_authorized(&request.notebook_id, &principal, Permission::View)?;
+            let revision = get_revision(&request.notebook_id, request.number)?;
+            Ok::<_, NotebookApiError>(databook::Revision {
+                info: Some(to_grpc_info(revision.info())),
+                notebook: Some(notebook_api::to_grpc_notebook(revision.notebook)),
+            })
+        })
+        .await
+    }
+
+    #[instrument]
+    async fn diff_revisions(
+        &self,
+        request: Request<databook::DiffRevisionsRequest>,
+    ) -> Result<Response<databook::NotebookDiff>, Status> {
+        let principal = auth::actor(&request, "").principal;
+        run_blocking(move || {
+            let request = request.into_inner();
+            notebook_api::get_authorized(&request.notebook_id, &principal, Permission::View)?;
+            let to = if request.to == 0 {
+                None
+            } else {
+                Some(request.to)
+            };
+            diff_revisions(&request.notebook_id, request.from, to).map(to_grpc_diff)
+        })
+        .await
+    }
+
+    #[instrument]
+    async fn restore_revision(
+        &self,
+        request: Request<databook::RestoreRevisionRequest>,
+    ) -> Result<Response<databook::Notebook>, Status> {
+        tracing::info!("received restore revision request");
+        let actor = auth::actor(&request, &request.get_ref().user);
+        run_blocking(move || {
+            let request = request.into_inner();
+            restore_revision(&request.notebook_id, request.number, &actor)
+                .map(notebook_api::to_grpc_notebook)
+        })
+        .await
+    }
+
+    #[instrument]
+    async fn fork_revision(
+        &self,
+        request: Request<databook::ForkRevisionRequest>,
+    ) -> Result<Response<databook::Notebook>, Status> {
+        tracing::info!("received fork revision request");
+        let actor = auth::actor(&request, "");
+        run_blocking(move || {
+            let request = request.into_inner();
+            notebook_api::get_authorized(&request.notebook_id, &actor.principal, Permission::View)?;
+            fork_revision(
+                &request.notebook_id,
+                request.number,
+                notebook_api::owner(&actor, request.owner)?,
+                empty_as_none(request.title),
+                &actor.principal,
+            )
+            .map(notebook_api::to_grpc_notebook)
+        })
+        .await
+    }
+}
+
+#[get("/notebooks/<id>/revisions")]
+pub fn rest_list(caller: auth::Caller, id: String) -> Json<rest::ListRevisionsResponse> {
+    let revisions =
+        notebook_api::get_authorized(&id, &caller.actor(None).principal, Permission::View)
+            .and_then(|_| list_revisions(&id));
+    match revisions {
+        Ok(revisions) => Json(rest::ListRevisionsResponse {
+            revisions,
+            error: None,
+        }),
+        Err(e) => Json(rest::ListRevisionsResponse {
+            revisions: vec![],
+            error: Some(format!("{:?}", e)),
+        }),
+    }
+}
+
+#[get("/notebooks/<id>/revisions/<number>")]
+pub fn rest_get(caller: auth::Caller, id: String, number: u64) -> Json<rest::RevisionResponse> {
+    let revision =
+        notebook_api::get_authorized(&id, &caller.actor(None).principal, Permission::View)
+            .and_then(|_| get_revision(&id, number));
+    match revision {
+        Ok(revision) => Json(rest::RevisionResponse {
+            revision: Some(revision),
+            error: None,
+        }),
+        Err(e) => Json(rest::RevisionResponse {
+            revision: None,
+            error: Some(format!("{:?}", e)),
+        }),
+    }
+}
+
+#[get("/notebooks/<id>/diff?<from>&<to>")]
+pub fn rest_diff(
+    caller: auth::Caller,
+    id: String,
+    from: u64,
+    to: Option<u64>,
+) -> Json<rest::DiffResponse> {
+    let diff = notebook_api::get_authorized(&id, &caller.actor(None).principal, Permission::View)
+        .and_then(|_| diff_revisions(&id, from, to));
+    match diff {
+        Ok(diff) => Json(rest::DiffResponse {
+            diff: Some(diff),
+            error: None,
+        }),
+        Err(e) => Json(rest::DiffResponse {
+            diff: None,
+            error: Some(format!("{:?}", e)),
+        }),
+    }
+}
+
+#[post("/notebooks/<id>/revisions/<number>/restore", data = "<request>")]
+pub fn rest_restore(
+    caller: auth::Caller,
+    id: String,
+    number: u64,
+    request: Json<rest::RestoreRevisionRequest>,
+) -> Json<rest::NotebookResponse> {
+    tracing::info!("received restore revision request");
+    notebook_api::rest_notebook_response(restore_revision(
+        &id,
+        number,
+        &caller.actor(request.into_inner().user),
+    ))
+}
+
+#[post("/notebooks/<id>/revisions/<number>/fork", data = "<request>")]
+pub fn rest_fork(
+    caller: auth::Caller,
+    id: String,
+    number: u64,
+    request: Json<rest::ForkRevisionRequest>,
+) -> Json<rest::NotebookResponse> {
+    tracing::info!("received fork revision request");
+    let request = request.into_inner();
+    let actor = caller.actor(None);
+    notebook_api::rest_notebook_response(
+        notebook_api::get_authorized(&id, &actor.principal, Permission::View).and_then(|_| {
+            fork_revision(
+                &id,
+                number,
+                notebook_api::owner(&actor, request.owner)?,
+                request.title,
+                &actor.principal,
+            )
+        }),
+    )
+}
@@ -0,0 +1,253 @@
+use crate::assertions::{parse_assertions, AssertionResult, EXPECT_OPTION};
+use crate::auth;
+use crate::databook;
+use crate::databook::runs_server::Runs;
+use crate::notebook::CellContent;
+use crate::notebook_api::{self, NotebookApiError};
+use crate::notebook_run::{NotebookRun, RunStore, StepResult};
+use crate::policy::{Actor, Permission};
+use crate::runbook_api;
+use crate::timeline_api::{self, empty_as_none};
+use crate::{rest, run_blocking, RUNS};
+
+use chrono::Utc;
+use rocket_contrib::json::Json;
+use std::collections::BTreeMap;
+use tonic::{Request, Response, Status};
+use tracing::instrument;
+
+pub fn store() -> Result<&'static dyn RunStore, NotebookApiError> {
+    RUNS.get()
+        .map(|s| s.as_ref())
+        .ok_or_else(|| NotebookApiError::Internal("No run store setup".into()))
+}
+
+fn step_error(e: NotebookApiError) -> String {
+    match e {
+        NotebookApiError::InvalidReference(e) => e.to_string(),
+        NotebookApiError::InvalidRequest(message) => message,
+        NotebookApiError::ApprovalPending(message) => message,
+        e => format!("{:?}", e),
+    }
+}
+
+fn run_step(
+    notebook_id: &str,
+    cell_id: &str,
+    plugin: &str,
+    options: &BTreeMap<String, String>,
+    actor: Actor,
+    source: Option<String>,
+) -> StepResult {
+    let assertions = match options.get(EXPECT_OPTION).map(|e| parse_assertions(e)) {
+        Some(Ok(assertions)) => assertions,
+        Some(Err(e)) => return StepResult::failed(cell_id, plugin, e.to_string()),
+        None => vec![],
+    };
+    match timeline_api::run_cell(notebook_id, cell_id, actor, source) {
+        Ok(event) => StepResult::checked(&event, &assertions),
+        Err(e) => StepResult::failed(cell_id, plugin, step_error(e)),
+    }
+}
+
+// run_notebook executes the plugin cells of the notebook in order, the same
+// way as running them one by one, and checks their assertions. With
+// fail_fast the run stops at the first failed step. The report is stored.
+pub fn run_notebook(
+    notebook_id: &str,
+    actor: Actor,
+    source: Option<String>,
+    fail_fast: bool,
+) -> Result<NotebookRun, NotebookApiError> {
+    let notebook =
+        notebook_api::get_authorized(notebook_id, &actor.principal, Permission::Execute)?;
+    let started_at = Utc::now();
+    let mut steps = vec![];
+    for cell in &notebook.cells {
+        if let CellContent::Plugin { plugin, options } = &cell.content {
+            let step = run_step(
+                notebook_id,
+                &cell.id,
+                plugin,
+                options,
+                actor.clone(),
+                source.clone(),
+            );
+            let stop = fail_fast && !step.passed;
+            steps.push(step);
+            if stop {
+                break;
+            }
+        }
+    }
+    let run = NotebookRun::new(notebook_id, actor.name, started_at, steps);
+    store()?.append_run(&run)?;
+    Ok(run)
+}
+
+// run_runbook instantiates a runbook into a new notebook and runs it
+fn run_runbook(
+    runbook: databook::InstantiateRunbookRequest,
+    actor: Actor,
+    source: Option<String>,
+    fail_fast: bool,
+) -> Result<NotebookRun, NotebookApiError> {
+    let version = if runbook.version == 0 {
+        None
+    } else {
+        Some(runbook.version)
+    };
+    let notebook = runbook_api::instantiate_runbook(
+        &runbook.id,
+        version,
+        runbook.parameters.into_iter().collect(),
+        notebook_api::owner(&actor, runbook.owner)?,
+        empty_as_none(runbook.title),
+        &actor.principal,
+    )?;
+    run_notebook(&notebook.id, actor, source, fail_fast)
+}
+
+fn to_grpc_assertion(result: AssertionResult) -> databook::AssertionResult {
+    databook::AssertionResult {
+        assertion: result.assertion,
+        passed: result.passed,
+        actual: result.actual.unwrap_or_default(),
+        error: result.error.unwrap_or_default(),
+    }
+}
+
+fn to_grpc_step(step: StepResult) -> databook::StepResult {
+    databook::StepResult {
+        cell_id: step.cell_id,
+        plugin: step.plugin,
+        event_id: step.event_id.unwrap_or_default(),
+        passed: step.passed,
+        error: step.error.unwrap_or_default(),
+        assertions: step.assertions.into_iter().map(to_grpc_assertion).collect(),
+    }
+}
+
+fn to_grpc_run(run: NotebookRun) -> databook::NotebookRun {
+    databook::NotebookRun {
+        id: run.id,
+        notebook_id: run.notebook_id,
+        user: run.user.unwrap_or_default(),
+        passed: run.passed,
+        started_at: run.started_at.timestamp_millis(),
+        finished_at: run.finished_at.timestamp_millis(),
+        steps: run.steps.into_iter().map(to_grpc_step).collect(),
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RunsGrpc {}
+
+#[tonic::async_trait]
+impl Runs for RunsGrpc {
+    #[instrument]
+    async fn run_notebook(
+        &self,
+        request: Request<databook::RunNotebookRequest>,
+    ) -> Result<Response<databook::NotebookRun>, Status> {
+        tracing::info!("received run notebook request");
+        let actor = auth::actor(&request, &request.get_ref().user);
+        let source = auth::source(&request);
+        run_blocking(|| {
+            let request = request.into_inner();
+            let run = match request.runbook {
+                Some(runbook) => run_runbook(runbook, actor, source, request.fail_fast),
+                None => run_notebook(&request.notebook_id, actor, source, request.fail_fast),
+            };
+            run.map(to_grpc_run)
+        })
+        .await
+    }
+
+    #[instrument]
+    async fn list_runs(
+        &self,
+        request: Request<databook::ListRunsRequest>,
+    ) -> Result<Response<databook::ListRunsResponse>, Status> {
+        let principal = auth::actor(&request, "").principal;
+        run_blocking(move || {
+            let notebook_id = request.into_inner().notebook_id;
+            notebook_api::get_authorized(&notebook_id, &principal, Permission::View)?;
+            let runs = store()?.runs(&notebook_id)?;
+            Ok::<_, NotebookApiError>(databook::ListRunsResponse {
+                runs: runs.into_iter().map(to_grpc_run).collect(),
+            })
+        })
+        .await
+    }
+}
+
+fn rest_run_response(response: Result<NotebookRun, NotebookApiError>) -> Json<rest::RunResponse> {
+    match response {
+        Ok(run) => Json(rest::RunResponse {
+            run: Some(run),
+            error: None,
+        }),
+        Err(e) => {
+            tracing::error!("run request failed {:?}", e);
+            Json(rest::RunResponse {
+                run: None,
+                error: Some(format!("{:?}", e)),
+            })
+        }
+    }
+}
+
+#[post("/notebooks/<id>/runs", data = "<request>")]
+pub fn rest_run_notebook(
+    caller: auth::Caller,
+    id: String,
+    request: Json<rest::RunNotebookRequest>,
+) -> Json<rest::RunResponse> {
+    tracing::info!("received run notebook request");
+    let request = request.into_inner();
+    rest_run_response(run_notebook(
+        &id,
+        caller.actor(request.user),
+        caller.source,
+        request.fail_fast,
+    ))
+}
+
+#[post("/runbooks/<id>/runs", data = "<request>")]
+pub fn rest_run_runbook(
+    caller: auth::Caller,
+    id: String,
+    request: Json<rest::RunRunbookRequest>,
+) -> Json<rest::RunResponse> {
+    tracing::info!("received run runbook request");
+    let request = request.into_inner();
+    let runbook = request.runbook;
+    let actor = caller.actor(request.user);
+    let notebook = notebook_api::owner(&actor, runbook.owner).and_then(|owner| {
+        runbook_api::instantiate_runbook(
+            &id,
+            runbook.version,
+            runbook.parameters,
+            owner,
+            runbook.title,
+            &actor.principal,
+        )
+    });
+    rest_run_response(
+        notebook.and_then(|n| run_notebook(&n.id, actor, caller.source, request.fail_fast)),
+    )
+}
+
+#[get("/notebooks/<id>/runs")]
+pub fn rest_runs(caller: auth::Caller, id: String) -> Json<rest::ListRunsResponse> {
+    let runs = notebook_api::get_authorized(&id, &caller.actor(None).principal, Permission::View)
+        .and_then(|_| store()?.runs(&id).map_err(NotebookApiError::from));
+    match runs {
+        Ok(runs) => Json(rest::ListRunsResponse { runs, error: None }),
+        Err(e) => Json(rest::ListRunsResponse {
+            runs: vec![],
+            error: Some(format!("{:?}", e)),
+        }),
+    }
+}
@@ -0,0 +1,360 @@
+use crate::notebook::{Cell, CellContent, RunbookSource};
+use crate::notebook_store::StoreError;
+use crate::references;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+// Runbook is a notebook template: step cells, markdown or plugin ones, that
+// refer to parameters as `{{params.<name>}}`. Instantiating it fills the
+// parameters in and creates an incident notebook. Every save is a new
+// version, a version never changes once stored.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Runbook {
+    pub id: String,
+    pub version: u32,
+    pub title: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub parameters: Vec<Parameter>,
+    pub cells: Vec<Cell>,
+    pub author: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Parameter {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    // parameters without a default are required
+    #[serde(default)]
+    pub default: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RunbookError {
+    InvalidParameterName(String),
+    DuplicateParameter(String),
+    MissingParameter(String),
+    UnknownParameter(String),
+    Syntax(String),
+}
+
+impl fmt::Display for RunbookError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunbookError::InvalidParameterName(name) => {
+                write!(f, "invalid parameter name {:?}", name)
+            }
+            RunbookError::DuplicateParameter(name) => {
+                write!(f, "parameter {} is declared more than once", name)
+            }
+            RunbookError::MissingParameter(name) => write!(f, "parameter {} is required", name),
+            RunbookError::UnknownParameter(name) => {
+                write!(f, "parameter {} is not declared by the runbook", name)
+            }
+            RunbookError::Syntax(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+const PARAMS_PREFIX: &str = "params.";
+
+// fill replaces the `{{params.<name>}}` of `text`, other references (to
+// variables or cells) and escaped ones (`\{{`) are left for when the cell
+// runs. With `escape` (plugin options, where references are resolved) the
+// `{{` of the values are escaped, so a value cannot add a reference.
+fn fill(
+    text: &str,
+    values: &BTreeMap<String, String>,
+    escape: bool,
+) -> Result<String, RunbookError> {
+    let mut result = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        if rest[..start].ends_with('\\') {
+            result.push_str(&rest[..start + 2]);
+            rest = &rest[start + 2..];
+            continue;
+        }
+        let end = match rest[start..].find("}}") {
+            Some(end) => end,
+            None if rest[start + 2..].trim_start().starts_with(PARAMS_PREFIX) => {
+                return Err(RunbookError::Syntax(format!("unclosed {{{{ in {:?}", text)))
+            }
+            None => break,
+        };
+        let reference = &rest[start..start + end + 2];
+        result.push_str(&rest[..start]);
+        match reference[2..reference.len() - 2]
+            .trim()
+            .strip_prefix(PARAMS_PREFIX)
+        {
+            Some(name) => {
+                let value = values
+                    .get(name)
+                    .ok_or_else(|| RunbookError::UnknownParameter(name.to_string()))?;
+                if escape {
+                    result.push_str(&value.replace("{{", "\\{{"));
+                } else {
+                    result.push_str(value);
+                }
+            }
+            None => result.push_str(reference),
+        }
+        rest = &rest[start + end + 2..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+// Instance is what a runbook becomes once its parameters are filled in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instance {
+    pub title: String,
+    pub cells: Vec<Cell>,
+    pub source: RunbookSource,
+}
+
+impl Runbook {
+    // check validates the declared parameters and that the cells only use
+    // those
+    pub fn check(&self) -> Result<(), RunbookError> {
+        let mut values = BTreeMap::new();
+        for parameter in &self.parameters {
+            if !references::is_name(&parameter.name) {
+                return Err(RunbookError::InvalidParameterName(parameter.name.clone()));
+            }
+            if values
+                .insert(parameter.name.clone(), String::new())
+                .is_some()
+            {
+                return Err(RunbookError::DuplicateParameter(parameter.name.clone()));
+            }
+        }
+        self.fill_cells(&values).map(|_| ())
+    }
+
+    fn fill_cells(
+        &self,
+        values: &BTreeMap<String, String>,
+    ) -> Result<(String, Vec<Cell>), RunbookError> {
+        let cells = self
+            .cells
+            .iter()
+            .map(|cell| {
+                let content = match &cell.content {
+                    CellContent::Markdown { source } => CellContent::Markdown {
+                        source: fill(source, values, false)?,
+                    },
+                    CellContent::Plugin { plugin, options } => CellContent::Plugin {
+                        plugin: plugin.clone(),
+                        options: options
+                            .iter()
+                            .map(|(k, v)| Ok((k.clone(), fill(v, values, true)?)))
+                            .collect::<Result<_, RunbookError>>()?,
+                    },
+                };
+                Ok(Cell {
+                    id: cell.id.clone(),
+                    content,
+                })
+            })
+            .collect::<Result<_, RunbookError>>()?;
+        Ok((fill(&self.title, values, false)?, cells))
+    }
+
+    // instantiate fills the parameters in, defaults are used for those not
+    // given. Cells keep their ids so references between them still work.
+    pub fn instantiate(
+        &self,
+        mut given: BTreeMap<String, String>,
+    ) -> Result<Instance, RunbookError> {
+        let mut values = BTreeMap::new();
+        for parameter in &self.parameters {
+            let value = given
+                .remove(&parameter.name)
+                .or_else(|| parameter.default.clone())
+                .ok_or_else(|| RunbookError::MissingParameter(parameter.name.clone()))?;
+            values.insert(parameter.name.clone(), value);
+        }
+        if let Some(name) = given.into_keys().next() {
+            return Err(RunbookError::UnknownParameter(name));
+        }
+
+        let (title, cells) = self.fill_cells(&values)?;
+        Ok(Instance {
+            title,
+            cells,
+            source: RunbookSource {
+                id: self.id.clone(),
+                version: self.version,
+                parameters: values,
+            },
+        })
+    }
+}
+
+// RunbookStore keeps every version of the runbooks
+pub trait RunbookStore: Send + Sync {
+    // add_runbook_version fails with AlreadyExists if the version is taken
+    fn add_runbook_version(&self, runbook: &Runbook) -> Result<(), StoreError>;
+    // runbook returns the given version, or the latest one
+    fn runbook(&self, id: &str, version: Option<u32>) -> Result<Runbook, StoreError>;
+    // runbooks returns the latest version of every runbook
+    fn runbooks(&self) -> Result<Vec<Runbook>, StoreError>;
+    // runbook_versions returns all versions of a runbook, oldest first
+    fn runbook_versions(&self, id: &str) -> Result<Vec<Runbook>, StoreError>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn runbook() -> Runbook {
+        let mut find_pod = Cell::plugin(
+            "kubectl",
+            BTreeMap::from([(
+                "query".to_string(),
+                "get pods -l app={{params.service}} --context {{params.region}}".to_string(),
+            )]),
+        );
+        find_pod.id = "find_pod".into();
+        Runbook {
+            id: "api-latency".into(),
+            version: 3,
+            title: "{{params.service}} latency".into(),
+            description: String::new(),
+            tags: vec![],
+            parameters: vec![
+                Parameter {
+                    name: "service".into(),
+                    description: "name of the deployment".into(),
+                    default: None,
+                },
+                Parameter {
+                    name: "region".into(),
+                    description: String::new(),
+                    default: Some("eu-west-1".into()),
+                },
+            ],
+            cells: vec![
+                Cell::markdown("Check {{ params.service }} using {{cells.find_pod.output}}"),
+                find_pod,
+            ],
+            author: "elias".into(),
+            created_at: Utc::now(),
+        }
+    }
+
+    fn values(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_instantiate() {
+        let runbook = runbook();
+        let instance = runbook.instantiate(values(&[("service", "api")])).unwrap();
+
+        assert_eq!("api latency", instance.title);
+        assert_eq!(
+            CellContent::Markdown {
+                source: "Check api using {{cells.find_pod.output}}".into()
+            },
+            instance.cells[0].content
+        );
+        assert_eq!(
+            CellContent::Plugin {
+                plugin: "kubectl".into(),
+                options: values(&[("query", "get pods -l app=api --context eu-west-1")]),
+            },
+            instance.cells[1].content
+        );
+        assert_eq!("find_pod", instance.cells[1].id);
+        assert_eq!(
+            RunbookSource {
+                id: "api-latency".into(),
+                version: 3,
+                parameters: values(&[("region", "eu-west-1"), ("service", "api")]),
+            },
+            instance.source
+        );
+    }
+
+    #[test]
+    fn test_instantiate_errors() {
+        let runbook = runbook();
+        assert_eq!(
+            Err(RunbookError::MissingParameter("service".into())),
+            runbook.instantiate(BTreeMap::new())
+        );
+        assert_eq!(
+            Err(RunbookError::UnknownParameter("window".into())),
+            runbook.instantiate(values(&[("service", "api"), ("window", "1h")]))
+        );
+    }
+
+    #[test]
+    fn test_check() {
+        let mut runbook = runbook();
+        assert_eq!(Ok(()), runbook.check());
+
+        runbook.cells.push(Cell::markdown("last {{params.window}}"));
+        assert_eq!(
+            Err(RunbookError::UnknownParameter("window".into())),
+            runbook.check()
+        );
+
+        runbook.parameters.push(runbook.parameters[0].clone());
+        assert_eq!(
+            Err(RunbookError::DuplicateParameter("service".into())),
+            runbook.check()
+        );
+    }
+
+    #[test]
+    fn test_fill_keeps_escaped_and_other_templates() {
+        let values = values(&[("service", "api")]);
+        assert_eq!(
+            Ok("\\{{params.service}} is api, {{ $labels.job }} {{".to_string()),
+            fill(
+                "\\{{params.service}} is {{params.service}}, {{ $labels.job }} {{",
+                &values,
+                true
+            )
+        );
+        assert!(matches!(
+            fill("{{params.service", &values, true),
+            Err(RunbookError::Syntax(_))
+        ));
+    }
+
+    #[test]
+    fn test_values_cannot_add_references() {
+        let runbook = runbook();
+        let instance = runbook
+            .instantiate(values(&[("service", "{{vars.token}}")]))
+            .unwrap();
+
+        assert_eq!("{{vars.token}} latency", instance.title);
+        assert_eq!(
+            CellContent::Plugin {
+                plugin: "kubectl".into(),
+                options: values(&[(
+                    "query",
+                    "get pods -l app=\\{{vars.token}} --context eu-west-1"
+                )]),
+            },
+            instance.cells[1].content
+        );
+    }
+}
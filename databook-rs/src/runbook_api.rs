@@ -0,0 +1,369 @@
+use crate::auth;
+use crate::databook;
+use crate::databook::runbooks_server::Runbooks;
+use crate::notebook::{new_id, Notebook};
+use crate::notebook_api::{self, NotebookApiError};
+use crate::notebook_store::StoreError;
+use crate::policy::Principal;
+use crate::references;
+use crate::runbook::{Parameter, Runbook, RunbookStore};
+use crate::timeline_api::empty_as_none;
+use crate::{rest, run_blocking, RUNBOOKS};
+
+use chrono::Utc;
+use rocket_contrib::json::Json;
+use std::collections::BTreeMap;
+use tonic::{Request, Response, Status};
+use tracing::instrument;
+
+pub fn store() -> Result<&'static dyn RunbookStore, NotebookApiError> {
+    RUNBOOKS
+        .get()
+        .map(|s| s.as_ref())
+        .ok_or_else(|| NotebookApiError::Internal("No runbook store setup".into()))
+}
+
+// runbook_error keeps store errors from being reported as notebook ones
+fn runbook_error(e: StoreError) -> NotebookApiError {
+    match e {
+        StoreError::NotFound(id) => {
+            NotebookApiError::NotFound(format!("runbook {} does not exist", id))
+        }
+        StoreError::AlreadyExists(id) => {
+            NotebookApiError::AlreadyExists(format!("runbook {} already exists", id))
+        }
+        e => NotebookApiError::Store(e),
+    }
+}
+
+fn get_runbook(id: &str, version: Option<u32>) -> Result<Runbook, NotebookApiError> {
+    store()?.runbook(id, version).map_err(runbook_error)
+}
+
+// create_runbook stores version 1 of a new runbook, an id is generated if
+// none is given
+pub fn create_runbook(mut runbook: Runbook) -> Result<Runbook, NotebookApiError> {
+    if runbook.id.is_empty() {
+        runbook.id = new_id();
+    }
+    if !references::is_name(&runbook.id) {
+        return Err(NotebookApiError::InvalidRequest(format!(
+            "invalid runbook id {:?}",
+            runbook.id
+        )));
+    }
+    match store()?.runbook(&runbook.id, None) {
+        Ok(_) => return Err(runbook_error(StoreError::AlreadyExists(runbook.id))),
+        Err(StoreError::NotFound(_)) => {}
+        Err(e) => return Err(e.into()),
+    }
+    add_version(runbook, 1)
+}
+
+// update_runbook stores the runbook as a new version, notebooks created
+// from the previous ones keep pointing to them
+pub fn update_runbook(runbook: Runbook) -> Result<Runbook, NotebookApiError> {
+    let latest = get_runbook(&runbook.id, None)?;
+    add_version(runbook, latest.version + 1)
+}
+
+fn add_version(mut runbook: Runbook, version: u32) -> Result<Runbook, NotebookApiError> {
+    runbook.version = version;
+    runbook.created_at = Utc::now();
+    runbook
+        .check()
+        .map_err(|e| NotebookApiError::InvalidRequest(e.to_string()))?;
+    store()?
+        .add_runbook_version(&runbook)
+        .map_err(runbook_error)?;
+    Ok(runbook)
+}
+
+// instantiate_runbook creates an incident notebook from a runbook version,
+// the latest one if none is given. The notebook records the version and
+// the parameters used.
+pub fn instantiate_runbook(
+    id: &str,
+    version: Option<u32>,
+    parameters: BTreeMap<String, String>,
+    owner: String,
+    title: Option<String>,
+    principal: &Principal,
+) -> Result<Notebook, NotebookApiError> {
+    let runbook = get_runbook(id, version)?;
+    let instance = runbook
+        .instantiate(parameters)
+        .map_err(|e| NotebookApiError::InvalidRequest(e.to_string()))?;
+    let mut notebook = Notebook::new(
+        title.unwrap_or(instance.title),
+        owner,
+        runbook.tags,
+        instance.cells,
+    );
+    notebook.metadata.runbook = Some(instance.source);
+    notebook_api::insert_notebook(notebook, principal)
+}
+
+fn zero_as_latest(version: u32) -> Option<u32> {
+    if version == 0 {
+        None
+    } else {
+        Some(version)
+    }
+}
+
+fn to_grpc_runbook(runbook: Runbook) -> databook::Runbook {
+    databook::Runbook {
+        id: runbook.id,
+        version: runbook.version,
+        title: runbook.title,
+        description: runbook.description,
+        tags: runbook.tags,
+        parameters: runbook
+            .parameters
+            .into_iter()
+            .map(|p| databook::RunbookParameter {
+                name: p.name,
+                description: p.description,
+                required: p.default.is_none(),
+                default_value: p.default.unwrap_or_default(),
+            })
+            .collect(),
+        cells: runbook
+            .cells
+            .into_iter()
+            .map(notebook_api::to_grpc_cell)
+            .collect(),
+        author: runbook.author,
+        created_at: runbook.created_at.timestamp_millis(),
+    }
+}
+
+fn from_grpc_runbook(runbook: databook::Runbook) -> Result<Runbook, NotebookApiError> {
+    Ok(Runbook {
+        id: runbook.id,
+        version: runbook.version,
+        title: runbook.title,
+        description: runbook.description,
+        tags: runbook.tags,
+        parameters: runbook
+            .parameters
+            .into_iter()
+            .map(|p| Parameter {
+                name: p.name,
+                description: p.description,
+                default: if p.required {
+                    None
+                } else {
+                    Some(p.default_value)
+                },
+            })
+            .collect(),
+        cells: notebook_api::from_grpc_cells(runbook.cells)?,
+        author: runbook.author,
+        // set by the server when the version is stored
+        created_at: Utc::now(),
+    })
+}
+
+fn to_grpc_runbooks(runbooks: Vec<Runbook>) -> databook::ListRunbooksResponse {
+    databook::ListRunbooksResponse {
+        runbooks: runbooks.into_iter().map(to_grpc_runbook).collect(),
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RunbooksGrpc {}
+
+#[tonic::async_trait]
+impl Runbooks for RunbooksGrpc {
+    #[instrument]
+    async fn create(
+        &self,
+        request: Request<databook::Runbook>,
+    ) -> Result<Response<databook::Runbook>, Status> {
+        tracing::info!("received create runbook request");
+        run_blocking(|| {
+            create_runbook(from_grpc_runbook(request.into_inner())?).map(to_grpc_runbook)
+        })
+        .await
+    }
+
+    #[instrument]
+    async fn update(
+        &self,
+        request: Request<databook::Runbook>,
+    ) -> Result<Response<databook::Runbook>, Status> {
+        tracing::info!("received update runbook request");
+        run_blocking(|| {
+            update_runbook(from_grpc_runbook(request.into_inner())?).map(to_grpc_runbook)
+        })
+        .await
+    }
+
+    #[instrument]
+    async fn get(
+        &self,
+        request: Request<databook::GetRunbookRequest>,
+    ) -> Result<Response<databook::Runbook>, Status> {
+        run_blocking(|| {
+            let request = request.into_inner();
+            let runbook = get_runbook(&request.id, zero_as_latest(request.version))?;
+            Ok::<_, NotebookApiError>(to_grpc_runbook(runbook))
+        })
+        .await
+    }
+
+    #[instrument]
+    async fn list(
+        &self,
+        _request: Request<databook::ListRunbooksRequest>,
+    ) -> Result<Response<databook::ListRunbooksResponse>, Status> {
+        run_blocking(|| Ok::<_, NotebookApiError>(to_grpc_runbooks(store()?.runbooks()?))).await
+    }
+
+    #[instrument]
+    async fn list_versions(
+        &self,
+        request: Request<databook::ListRunbookVersionsRequest>,
+    ) -> Result<Response<databook::ListRunbooksResponse>, Status> {
+        run_blocking(|| {
+            let versions = store()?.runbook_versions(&request.into_inner().id)?;
+            Ok::<_, NotebookApiError>(to_grpc_runbooks(versions))
+        })
+        .await
+    }
+
+    #[instrument]
+    async fn instantiate_runbook(
+        &self,
+        request: Request<databook::InstantiateRunbookRequest>,
+    ) -> Result<Response<databook::Notebook>, Status> {
+        tracing::info!("received instantiate runbook request");
+        let actor = auth::actor(&request, "");
+        run_blocking(move || {
+            let request = request.into_inner();
+            instantiate_runbook(
+                &request.id,
+                zero_as_latest(request.version),
+                request.parameters.into_iter().collect(),
+                notebook_api::owner(&actor, request.owner)?,
+                empty_as_none(request.title),
+                &actor.principal,
+            )
+            .map(notebook_api::to_grpc_notebook)
+        })
+        .await
+    }
+}
+
+fn rest_runbook_response(
+    response: Result<Runbook, NotebookApiError>,
+) -> Json<rest::RunbookResponse> {
+    match response {
+        Ok(runbook) => Json(rest::RunbookResponse {
+            runbook: Some(runbook),
+            error: None,
+        }),
+        Err(e) => {
+            tracing::error!("runbook request failed {:?}", e);
+            Json(rest::RunbookResponse {
+                runbook: None,
+                error: Some(format!("{:?}", e)),
+            })
+        }
+    }
+}
+
+fn rest_runbooks_response(
+    response: Result<Vec<Runbook>, NotebookApiError>,
+) -> Json<rest::ListRunbooksResponse> {
+    match response {
+        Ok(runbooks) => Json(rest::ListRunbooksResponse {
+            runbooks,
+            error: None,
+        }),
+        Err(e) => Json(rest::ListRunbooksResponse {
+            runbooks: vec![],
+            error: Some(format!("{:?}", e)),
+        }),
+    }
+}
+
+fn from_rest_runbook(id: String, request: rest::RunbookRequest) -> Runbook {
+    Runbook {
+        id,
+        version: 0,
+        title: request.title,
+        description: request.description,
+        tags: request.tags,
+        parameters: request.parameters,
+        cells: request.cells,
+        author: request.author,
+        created_at: Utc::now(),
+    }
+}
+
+#[get("/runbooks")]
+pub fn rest_list(_caller: auth::Caller) -> Json<rest::ListRunbooksResponse> {
+    rest_runbooks_response(store().and_then(|s| s.runbooks().map_err(NotebookApiError::from)))
+}
+
+#[post("/runbooks", data = "<request>")]
+pub fn rest_create(
+    _caller: auth::Caller,
+    request: Json<rest::RunbookRequest>,
+) -> Json<rest::RunbookResponse> {
+    tracing::info!("received create runbook request");
+    let request = request.into_inner();
+    let id = request.id.clone().unwrap_or_default();
+    rest_runbook_response(create_runbook(from_rest_runbook(id, request)))
+}
+
+#[get("/runbooks/<id>?<version>")]
+pub fn rest_get(
+    _caller: auth::Caller,
+    id: String,
+    version: Option<u32>,
+) -> Json<rest::RunbookResponse> {
+    rest_runbook_response(get_runbook(&id, version))
+}
+
+#[put("/runbooks/<id>", data = "<request>")]
+pub fn rest_update(
+    _caller: auth::Caller,
+    id: String,
+    request: Json<rest::RunbookRequest>,
+) -> Json<rest::RunbookResponse> {
+    tracing::info!("received update runbook request");
+    rest_runbook_response(update_runbook(from_rest_runbook(id, request.into_inner())))
+}
+
+#[get("/runbooks/<id>/versions")]
+pub fn rest_versions(_caller: auth::Caller, id: String) -> Json<rest::ListRunbooksResponse> {
+    rest_runbooks_response(
+        store().and_then(|s| s.runbook_versions(&id).map_err(NotebookApiError::from)),
+    )
+}
+
+#[post("/runbooks/<id>/instantiate", data = "<request>")]
+pub fn rest_instantiate(
+    caller: auth::Caller,
+    id: String,
+    request: Json<rest::InstantiateRunbookRequest>,
+) -> Json<rest::NotebookResponse> {
+    tracing::info!("received instantiate runbook request");
+    let request = request.into_inner();
+    let actor = caller.actor(None);
+    let notebook = notebook_api::owner(&actor, request.owner).and_then(|owner| {
+        instantiate_runbook(
+            &id,
+            request.version,
+            request.parameters,
+            owner,
+            request.title,
+            &actor.principal,
+        )
+    });
+    notebook_api::rest_notebook_response(notebook)
+}
@@ -0,0 +1,626 @@
+use crate::assertions::{parse_assertions, Assertion};
+use crate::notebook::new_id;
+use crate::notebook_store::StoreError;
+use crate::policy::{Actor, Principal};
+
+use chrono::{DateTime, Datelike, Duration, TimeZone, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use url::Url;
+
+// A schedule re-runs a plugin cell, or a whole notebook, on an interval or
+// a cron expression, like someone watching a query during an incident.
+// Every run is an execution on the timeline (or a run report for a
+// notebook) as if it was started by hand. A cell schedule can carry a
+// condition, assertions as in the `expect` option of cells:
+//
+//   error_rate < 0.01
+//
+// and notifies once when it starts to hold after not holding (or on the
+// first run). Schedules stop when the incident of the notebook is resolved.
+
+// schedules running more often than that would hammer the data sources
+pub const MIN_INTERVAL_SECONDS: i64 = 10;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Trigger {
+    Every { seconds: i64 },
+    // minute hour day-of-month month day-of-week, in UTC
+    Cron { expression: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Schedule {
+    pub id: String,
+    pub notebook_id: String,
+    // the whole notebook runs when None
+    pub cell_id: Option<String>,
+    pub trigger: Trigger,
+    pub condition: Option<String>,
+    // url the notification is posted to (as json) when the condition is met
+    pub webhook: Option<String>,
+    // who created the schedule, runs are made on their behalf
+    pub user: Option<String>,
+    // the identity of who created it, the policy of each run is checked for
+    #[serde(default)]
+    pub principal: Principal,
+    pub created_at: DateTime<Utc>,
+    pub next_run_at: DateTime<Utc>,
+    pub last_run_at: Option<DateTime<Utc>>,
+    // the execution event (cell) or the run (notebook) of the last run
+    pub last_result: Option<String>,
+    // whether the condition held when last checked
+    pub condition_met: Option<bool>,
+    // why the schedule stopped, it does not run once set
+    pub stopped: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ScheduleError {
+    MissingTrigger,
+    InvalidInterval(String),
+    IntervalTooShort(i64),
+    InvalidCron(String),
+    InvalidCondition(String),
+    ConditionOnNotebook,
+    InvalidWebhook(String),
+    WebhookNotAllowed(String),
+}
+
+impl fmt::Display for ScheduleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScheduleError::MissingTrigger => {
+                write!(f, "set either every (e.g. 1m) or cron (e.g. */5 * * * *)")
+            }
+            ScheduleError::InvalidInterval(every) => {
+                write!(
+                    f,
+                    "invalid interval {:?}, use e.g. 30s, 5m, 1h or 1h30m",
+                    every
+                )
+            }
+            ScheduleError::IntervalTooShort(seconds) => write!(
+                f,
+                "an interval of {}s is too short, the minimum is {}s",
+                seconds, MIN_INTERVAL_SECONDS
+            ),
+            ScheduleError::InvalidCron(e) => write!(f, "invalid cron expression {}", e),
+            ScheduleError::InvalidCondition(e) => write!(f, "invalid condition {}", e),
+            ScheduleError::ConditionOnNotebook => {
+                write!(f, "conditions apply to a cell, notebook runs check the expect option of their cells")
+            }
+            ScheduleError::InvalidWebhook(e) => write!(f, "invalid webhook {}", e),
+            ScheduleError::WebhookNotAllowed(host) => {
+                write!(
+                    f,
+                    "webhooks may not post to {}, see webhook_hosts in the server config",
+                    host
+                )
+            }
+        }
+    }
+}
+
+// parse_interval reads durations like 90s, 5m or 1h30m
+pub fn parse_interval(every: &str) -> Result<i64, ScheduleError> {
+    let invalid = || ScheduleError::InvalidInterval(every.to_string());
+    let mut seconds = 0i64;
+    let mut number = String::new();
+    for c in every.trim().chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            _ => return Err(invalid()),
+        };
+        let n: i64 = number.parse().map_err(|_| invalid())?;
+        seconds = n
+            .checked_mul(unit)
+            .and_then(|s| s.checked_add(seconds))
+            .ok_or_else(invalid)?;
+        number.clear();
+    }
+    if !number.is_empty() || seconds == 0 {
+        return Err(invalid());
+    }
+    if seconds < MIN_INTERVAL_SECONDS {
+        return Err(ScheduleError::IntervalTooShort(seconds));
+    }
+    Ok(seconds)
+}
+
+// Cron is a parsed expression, a bit per allowed value of every field
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // a `*` day field matches every day, when both are restricted a day
+    // matching either is enough
+    any_day: bool,
+    any_weekday: bool,
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut bits = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (
+                range,
+                step.parse::<u32>()
+                    .ok()
+                    .filter(|s| *s > 0)
+                    .ok_or_else(|| format!("invalid step in {:?}", part))?,
+            ),
+            None => (part, 1),
+        };
+        let value = |v: &str| {
+            v.parse::<u32>()
+                .ok()
+                .filter(|v| (min..=max).contains(v))
+                .ok_or_else(|| format!("{:?} is not within {}-{}", v, min, max))
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (value(start)?, value(end)?),
+            // a/n goes from a to the end of the field
+            None if part.contains('/') => (value(range)?, max),
+            None => (value(range)?, value(range)?),
+        };
+        if start > end {
+            return Err(format!("empty range {:?}", part));
+        }
+        for v in (start..=end).step_by(step as usize) {
+            bits |= 1 << v;
+        }
+    }
+    Ok(bits)
+}
+
+impl Cron {
+    pub fn parse(expression: &str) -> Result<Self, ScheduleError> {
+        let expression = match expression.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            expression => expression,
+        };
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(ScheduleError::InvalidCron(format!(
+                "{:?}, expected 5 fields: minute hour day-of-month month day-of-week",
+                expression
+            )));
+        }
+        let field = |i: usize, min, max| {
+            parse_field(fields[i], min, max).map_err(ScheduleError::InvalidCron)
+        };
+        let mut weekdays = field(4, 0, 7)?;
+        // 7 is sunday too
+        if weekdays & (1 << 7) != 0 {
+            weekdays |= 1;
+        }
+        Ok(Self {
+            minutes: field(0, 0, 59)?,
+            hours: field(1, 0, 23)?,
+            days: field(2, 1, 31)?,
+            months: field(3, 1, 12)?,
+            weekdays,
+            any_day: fields[2].starts_with('*'),
+            any_weekday: fields[4].starts_with('*'),
+        })
+    }
+
+    fn matches_day(&self, time: DateTime<Utc>) -> bool {
+        let day = self.days & (1 << time.day()) != 0;
+        let weekday = self.weekdays & (1 << time.weekday().num_days_from_sunday()) != 0;
+        match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (true, false) => weekday,
+            (false, true) => day,
+            (false, false) => day || weekday,
+        }
+    }
+
+    // next is the first minute after `after` the expression matches, None
+    // when it never does (e.g. on February 30)
+    pub fn next(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let start = after.with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        let mut time = start;
+        while time.year() <= start.year() + 4 {
+            if self.months & (1 << time.month()) == 0 {
+                let (year, month) = if time.month() == 12 {
+                    (time.year() + 1, 1)
+                } else {
+                    (time.year(), time.month() + 1)
+                };
+                time = Utc.with_ymd_and_hms(year, month, 1, 0, 0, 0).single()?;
+            } else if !self.matches_day(time) {
+                time = time.with_hour(0)?.with_minute(0)? + Duration::days(1);
+            } else if self.hours & (1 << time.hour()) == 0 {
+                time = time.with_minute(0)? + Duration::hours(1);
+            } else if self.minutes & (1 << time.minute()) == 0 {
+                time += Duration::minutes(1);
+            } else {
+                return Some(time);
+            }
+        }
+        None
+    }
+}
+
+impl Trigger {
+    // parse takes exactly one of an interval or a cron expression
+    pub fn parse(every: Option<&str>, cron: Option<&str>) -> Result<Self, ScheduleError> {
+        match (every, cron) {
+            (Some(every), None) => Ok(Trigger::Every {
+                seconds: parse_interval(every)?,
+            }),
+            (None, Some(cron)) => {
+                Cron::parse(cron)?;
+                Ok(Trigger::Cron {
+                    expression: cron.trim().to_string(),
+                })
+            }
+            _ => Err(ScheduleError::MissingTrigger),
+        }
+    }
+
+    pub fn next(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Trigger::Every { seconds } => Some(after + Duration::seconds(*seconds)),
+            Trigger::Cron { expression } => Cron::parse(expression).ok()?.next(after),
+        }
+    }
+}
+
+impl Schedule {
+    pub fn new(
+        notebook_id: &str,
+        cell_id: Option<String>,
+        trigger: Trigger,
+        condition: Option<String>,
+        webhook: Option<String>,
+        actor: Actor,
+        now: DateTime<Utc>,
+    ) -> Result<Self, ScheduleError> {
+        if let Some(condition) = &condition {
+            if cell_id.is_none() {
+                return Err(ScheduleError::ConditionOnNotebook);
+            }
+            parse_assertions(condition)
+                .map_err(|e| ScheduleError::InvalidCondition(e.to_string()))?;
+        }
+        let next_run_at = trigger
+            .next(now)
+            .ok_or_else(|| ScheduleError::InvalidCron("it never matches".into()))?;
+        Ok(Self {
+            id: new_id(),
+            notebook_id: notebook_id.to_string(),
+            cell_id,
+            trigger,
+            condition,
+            webhook,
+            user: actor.name,
+            principal: actor.principal,
+            created_at: now,
+            next_run_at,
+            last_run_at: None,
+            last_result: None,
+            condition_met: None,
+            stopped: None,
+        })
+    }
+
+    // actor is who the runs are made as
+    pub fn actor(&self) -> Actor {
+        Actor {
+            principal: self.principal.clone(),
+            name: self.user.clone(),
+        }
+    }
+
+    pub fn is_due(&self, now: DateTime<Utc>) -> bool {
+        self.stopped.is_none() && self.next_run_at <= now
+    }
+
+    // condition parses the condition, checked when the schedule was made
+    pub fn condition(&self) -> Vec<Assertion> {
+        self.condition
+            .as_deref()
+            .and_then(|c| parse_assertions(c).ok())
+            .unwrap_or_default()
+    }
+
+    // record notes a run and plans the next one, condition_met is None when
+    // the condition was not checked (e.g. the cell could not run). It
+    // returns whether the condition was crossed, i.e. it holds and did not
+    // on the last check.
+    pub fn record(
+        &mut self,
+        now: DateTime<Utc>,
+        result: Option<String>,
+        condition_met: Option<bool>,
+    ) -> bool {
+        let crossed = condition_met == Some(true) && self.condition_met != Some(true);
+        self.last_run_at = Some(now);
+        self.last_result = result;
+        if condition_met.is_some() {
+            self.condition_met = condition_met;
+        }
+        // runs late because of a slow plugin are not caught up
+        match self.trigger.next(now) {
+            Some(next) => self.next_run_at = next,
+            None => self.stop("the cron expression does not match any more"),
+        }
+        crossed
+    }
+
+    pub fn stop(&mut self, reason: &str) {
+        self.stopped = Some(reason.to_string());
+    }
+}
+
+// check_webhook makes sure the webhook posts over http(s) to one of the
+// allowed hosts, `host` or `host:port` entries. The server would otherwise
+// post to any address a user gives it, internal ones included.
+pub fn check_webhook(webhook: &str, allowed: &[String]) -> Result<(), ScheduleError> {
+    let url = Url::parse(webhook)
+        .map_err(|e| ScheduleError::InvalidWebhook(format!("{}: {}", webhook, e)))?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(ScheduleError::InvalidWebhook(format!(
+            "{}: use http or https",
+            webhook
+        )));
+    }
+    let host = url
+        .host_str()
+        .ok_or_else(|| ScheduleError::InvalidWebhook(format!("{}: it has no host", webhook)))?;
+    let port = url.port_or_known_default();
+    let allows = |entry: &str| match entry.rsplit_once(':').map(|(h, p)| (h, p.parse::<u16>())) {
+        Some((entry_host, Ok(entry_port))) => {
+            entry_host.eq_ignore_ascii_case(host) && Some(entry_port) == port
+        }
+        _ => entry.eq_ignore_ascii_case(host),
+    };
+    if allowed.iter().any(|entry| allows(entry)) {
+        Ok(())
+    } else {
+        Err(ScheduleError::WebhookNotAllowed(match url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        }))
+    }
+}
+
+// ScheduleStore keeps the schedules and where they are at
+pub trait ScheduleStore: Send + Sync {
+    // save_schedule adds or replaces the schedule
+    fn save_schedule(&self, schedule: &Schedule) -> Result<(), StoreError>;
+    fn schedule(&self, id: &str) -> Result<Schedule, StoreError>;
+    // schedules lists the schedules of a notebook, or all of them, oldest
+    // first
+    fn schedules(&self, notebook_id: Option<&str>) -> Result<Vec<Schedule>, StoreError>;
+    fn delete_schedule(&self, id: &str) -> Result<(), StoreError>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(time: &str) -> DateTime<Utc> {
+        time.parse().unwrap()
+    }
+
+    fn next(cron: &str, after: &str) -> Option<DateTime<Utc>> {
+        Cron::parse(cron).unwrap().next(at(after))
+    }
+
+    #[test]
+    fn test_parse_interval() {
+        assert_eq!(Ok(90), parse_interval("90s"));
+        assert_eq!(Ok(5400), parse_interval("1h30m"));
+        assert_eq!(Ok(86400), parse_interval("1d"));
+        assert_eq!(
+            Err(ScheduleError::IntervalTooShort(5)),
+            parse_interval("5s")
+        );
+        for invalid in ["", "5", "m", "1w", "1.5h", "0m"] {
+            assert_eq!(
+                Err(ScheduleError::InvalidInterval(invalid.into())),
+                parse_interval(invalid)
+            );
+        }
+    }
+
+    #[test]
+    fn test_cron() {
+        assert_eq!(
+            Some(at("2026-03-04T10:01:00Z")),
+            next("* * * * *", "2026-03-04T10:00:30Z")
+        );
+        assert_eq!(
+            Some(at("2026-03-04T10:05:00Z")),
+            next("*/5 * * * *", "2026-03-04T10:00:00Z")
+        );
+        assert_eq!(
+            Some(at("2026-03-04T14:30:00Z")),
+            next("30 9-17/5 * * *", "2026-03-04T10:00:00Z")
+        );
+        assert_eq!(
+            Some(at("2026-03-05T09:00:00Z")),
+            next("0 9 * * *", "2026-03-04T10:00:00Z")
+        );
+        // 2026-03-04 is a wednesday
+        assert_eq!(
+            Some(at("2026-03-09T08:00:00Z")),
+            next("0 8 * * 1", "2026-03-04T10:00:00Z")
+        );
+        assert_eq!(
+            Some(at("2026-03-08T00:00:00Z")),
+            next("@weekly", "2026-03-04T10:00:00Z")
+        );
+        assert_eq!(
+            Some(at("2027-01-01T00:00:00Z")),
+            next("0 0 1 1 *", "2026-03-04T10:00:00Z")
+        );
+        // either the day of the month or the weekday
+        assert_eq!(
+            Some(at("2026-03-06T00:00:00Z")),
+            next("0 0 15 * 5", "2026-03-04T10:00:00Z")
+        );
+        assert_eq!(
+            Some(at("2028-02-29T00:00:00Z")),
+            next("0 0 29 2 *", "2026-03-04T10:00:00Z")
+        );
+        assert_eq!(None, next("0 0 30 2 *", "2026-03-04T10:00:00Z"));
+
+        for invalid in [
+            "* * * *",
+            "60 * * * *",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "a * * * *",
+        ] {
+            assert!(
+                matches!(Cron::parse(invalid), Err(ScheduleError::InvalidCron(_))),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_new_schedule() {
+        let now = at("2026-03-04T10:00:00Z");
+        let every = Trigger::parse(Some("1m"), None).unwrap();
+        let schedule = Schedule::new(
+            "n1",
+            Some("errors".into()),
+            every.clone(),
+            None,
+            None,
+            Actor::default(),
+            now,
+        )
+        .unwrap();
+        assert_eq!(at("2026-03-04T10:01:00Z"), schedule.next_run_at);
+        assert!(!schedule.is_due(now));
+        assert!(schedule.is_due(at("2026-03-04T10:01:00Z")));
+
+        assert_eq!(
+            Err(ScheduleError::MissingTrigger),
+            Trigger::parse(None, None)
+        );
+        assert_eq!(
+            Err(ScheduleError::MissingTrigger),
+            Trigger::parse(Some("1m"), Some("* * * * *"))
+        );
+        assert_eq!(
+            Err(ScheduleError::ConditionOnNotebook),
+            Schedule::new(
+                "n1",
+                None,
+                every.clone(),
+                Some("rate < 1".into()),
+                None,
+                Actor::default(),
+                now
+            )
+        );
+        assert!(matches!(
+            Schedule::new(
+                "n1",
+                Some("errors".into()),
+                every,
+                Some("rate ~ 1".into()),
+                None,
+                Actor::default(),
+                now
+            ),
+            Err(ScheduleError::InvalidCondition(_))
+        ));
+        let never = Trigger::parse(None, Some("0 0 30 2 *")).unwrap();
+        assert!(Schedule::new("n1", None, never, None, None, Actor::default(), now).is_err());
+    }
+
+    #[test]
+    fn test_record_crossings() {
+        let now = at("2026-03-04T10:00:00Z");
+        let cron = Trigger::parse(None, Some("*/5 * * * *")).unwrap();
+        let mut schedule = Schedule::new(
+            "n1",
+            Some("errors".into()),
+            cron,
+            Some("rate < 0.01".into()),
+            None,
+            Actor::default(),
+            now,
+        )
+        .unwrap();
+        assert_eq!(1, schedule.condition().len());
+
+        let runs = [
+            Some(false),
+            Some(true),
+            Some(true),
+            None,
+            Some(true),
+            Some(false),
+            Some(true),
+        ];
+        let crossed: Vec<bool> = runs
+            .iter()
+            .enumerate()
+            .map(|(i, met)| schedule.record(now + Duration::minutes(5 * i as i64 + 5), None, *met))
+            .collect();
+        assert_eq!(vec![false, true, false, false, false, false, true], crossed);
+        assert_eq!(at("2026-03-04T10:40:00Z"), schedule.next_run_at);
+
+        schedule.stop("the incident is resolved");
+        assert!(!schedule.is_due(at("2026-03-04T11:00:00Z")));
+    }
+
+    #[test]
+    fn test_check_webhook() {
+        let allowed = vec![
+            "hooks.example.com".to_string(),
+            "alerts.internal:8443".to_string(),
+        ];
+        assert!(check_webhook("https://hooks.example.com/T01/abc", &allowed).is_ok());
+        assert!(check_webhook("https://HOOKS.example.com:443/abc", &allowed).is_ok());
+        assert!(check_webhook("https://alerts.internal:8443/notify", &allowed).is_ok());
+        assert_eq!(
+            Err(ScheduleError::WebhookNotAllowed("alerts.internal".into())),
+            check_webhook("https://alerts.internal/notify", &allowed)
+        );
+        assert_eq!(
+            Err(ScheduleError::WebhookNotAllowed(
+                "hooks.example.com.evil.com".into()
+            )),
+            check_webhook("https://hooks.example.com.evil.com/", &allowed)
+        );
+        assert_eq!(
+            Err(ScheduleError::WebhookNotAllowed("169.254.169.254".into())),
+            check_webhook("http://169.254.169.254/latest/meta-data", &allowed)
+        );
+        assert!(matches!(
+            check_webhook("file:///etc/passwd", &allowed),
+            Err(ScheduleError::InvalidWebhook(_))
+        ));
+        assert!(matches!(
+            check_webhook("not a url", &allowed),
+            Err(ScheduleError::InvalidWebhook(_))
+        ));
+        assert!(check_webhook("https://hooks.example.com/", &[]).is_err());
+    }
+}
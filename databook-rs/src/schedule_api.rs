@@ -0,0 +1,470 @@
+use crate::auth;
+use crate::databook;
+use crate::databook::schedules_server::Schedules;
+use crate::incident::Status as IncidentStatus;
+use crate::live::LiveEvent;
+use crate::live_api;
+use crate::notebook::{CellContent, Notebook};
+use crate::notebook_api::{self, NotebookApiError};
+use crate::notebook_run::StepResult;
+use crate::notebook_store::StoreError;
+use crate::policy::{Actor, Permission, Principal};
+use crate::run_api;
+use crate::schedule::{check_webhook, Schedule, ScheduleError, ScheduleStore, Trigger};
+use crate::timeline::ExecutionEvent;
+use crate::timeline_api::{self, empty_as_none, millis, not_found};
+use crate::{rest, run_blocking, CONFIG, SCHEDULES};
+
+use chrono::{DateTime, Utc};
+use rocket_contrib::json::Json;
+use serde::Serialize;
+use std::time::Duration;
+use tonic::{Request, Response, Status};
+use tracing::instrument;
+
+// how often the scheduler looks for due schedules
+const TICK: Duration = Duration::from_secs(1);
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+pub const INCIDENT_RESOLVED: &str = "the incident is resolved";
+
+pub fn store() -> Result<&'static dyn ScheduleStore, NotebookApiError> {
+    SCHEDULES
+        .get()
+        .map(|s| s.as_ref())
+        .ok_or_else(|| NotebookApiError::Internal("No schedule store setup".into()))
+}
+
+impl From<ScheduleError> for NotebookApiError {
+    fn from(e: ScheduleError) -> Self {
+        NotebookApiError::InvalidRequest(e.to_string())
+    }
+}
+
+fn webhook_hosts() -> &'static [String] {
+    CONFIG
+        .get()
+        .and_then(|c| c.webhook_hosts.as_deref())
+        .unwrap_or_default()
+}
+
+fn incident_resolved(notebook: &Notebook) -> bool {
+    notebook
+        .metadata
+        .incident
+        .as_ref()
+        .map(|i| i.status == IncidentStatus::Resolved)
+        .unwrap_or(false)
+}
+
+pub fn create_schedule(
+    notebook_id: &str,
+    cell_id: Option<String>,
+    every: Option<&str>,
+    cron: Option<&str>,
+    condition: Option<String>,
+    webhook: Option<String>,
+    actor: Actor,
+) -> Result<Schedule, NotebookApiError> {
+    let notebook =
+        notebook_api::get_authorized(notebook_id, &actor.principal, Permission::Execute)?;
+    if let Some(cell_id) = &cell_id {
+        match notebook.cell(cell_id).map(|c| &c.content) {
+            Some(CellContent::Plugin { .. }) => {}
+            Some(CellContent::Markdown { .. }) => {
+                return Err(NotebookApiError::InvalidRequest(format!(
+                    "cell {} is not a plugin cell",
+                    cell_id
+                )))
+            }
+            None => {
+                return Err(NotebookApiError::InvalidRequest(format!(
+                    "cell {} does not exist on notebook {}",
+                    cell_id, notebook_id
+                )))
+            }
+        }
+    }
+    if incident_resolved(&notebook) {
+        return Err(NotebookApiError::InvalidRequest(format!(
+            "{} on notebook {}",
+            INCIDENT_RESOLVED, notebook_id
+        )));
+    }
+    if let Some(webhook) = &webhook {
+        check_webhook(webhook, webhook_hosts())?;
+    }
+    let trigger = Trigger::parse(every, cron)?;
+    let schedule = Schedule::new(
+        notebook_id,
+        cell_id,
+        trigger,
+        condition,
+        webhook,
+        actor,
+        Utc::now(),
+    )?;
+    store()?.save_schedule(&schedule)?;
+    Ok(schedule)
+}
+
+pub fn list_schedules(
+    notebook_id: &str,
+    principal: &Principal,
+) -> Result<Vec<Schedule>, NotebookApiError> {
+    notebook_api::get_authorized(notebook_id, principal, Permission::View)?;
+    Ok(store()?.schedules(Some(notebook_id))?)
+}
+
+// delete_schedule needs the same permission as creating it, the schedule
+// of a deleted notebook can be removed by anyone
+pub fn delete_schedule(id: &str, principal: &Principal) -> Result<(), NotebookApiError> {
+    let store = store()?;
+    let schedule = store
+        .schedule(id)
+        .map_err(|e| not_found("schedule", id, e))?;
+    match notebook_api::get_authorized(&schedule.notebook_id, principal, Permission::Execute) {
+        Ok(_) | Err(NotebookApiError::Store(StoreError::NotFound(_))) => {}
+        Err(e) => return Err(e),
+    }
+    store
+        .delete_schedule(id)
+        .map_err(|e| not_found("schedule", id, e))
+}
+
+// stop_schedules stops the running schedules of the notebook, e.g. when
+// its incident is resolved
+pub fn stop_schedules(notebook_id: &str, reason: &str) -> Result<(), NotebookApiError> {
+    let store = store()?;
+    for mut schedule in store.schedules(Some(notebook_id))? {
+        if schedule.stopped.is_none() {
+            schedule.stop(reason);
+            store.save_schedule(&schedule)?;
+        }
+    }
+    Ok(())
+}
+
+// Notification is what the webhook of a schedule receives
+#[derive(Debug, Serialize)]
+struct Notification<'a> {
+    schedule_id: &'a str,
+    notebook_id: &'a str,
+    notebook_title: &'a str,
+    cell_id: Option<&'a str>,
+    condition: &'a str,
+    event_id: &'a str,
+    output: Option<&'a str>,
+    at: DateTime<Utc>,
+}
+
+fn notify(schedule: &Schedule, notebook: &Notebook, event: &ExecutionEvent) {
+    let condition = schedule.condition.clone().unwrap_or_default();
+    tracing::info!(
+        "condition {:?} of schedule {} met on notebook {}",
+        condition,
+        schedule.id,
+        schedule.notebook_id
+    );
+    live_api::publish(
+        &schedule.notebook_id,
+        LiveEvent::ConditionMet {
+            schedule_id: schedule.id.clone(),
+            cell_id: schedule.cell_id.clone(),
+            condition: condition.clone(),
+            event_id: Some(event.id.clone()),
+        },
+    );
+    if let Some(webhook) = &schedule.webhook {
+        // the allowed hosts may have changed since the schedule was made
+        if let Err(e) = check_webhook(webhook, webhook_hosts()) {
+            return tracing::error!("could not notify for schedule {} {}", schedule.id, e);
+        }
+        let notification = Notification {
+            schedule_id: &schedule.id,
+            notebook_id: &schedule.notebook_id,
+            notebook_title: &notebook.metadata.title,
+            cell_id: schedule.cell_id.as_deref(),
+            condition: &condition,
+            event_id: &event.id,
+            output: event.output.as_deref(),
+            at: event.finished_at,
+        };
+        let body = match serde_json::to_string(&notification) {
+            Ok(body) => body,
+            Err(e) => return tracing::error!("could not write the notification {:?}", e),
+        };
+        let sent = reqwest::blocking::Client::builder()
+            .timeout(WEBHOOK_TIMEOUT)
+            // a redirect could lead anywhere, past the allowed hosts
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .and_then(|client| {
+                client
+                    .post(webhook)
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .body(body)
+                    .send()
+            })
+            .and_then(|response| response.error_for_status());
+        if let Err(e) = sent {
+            tracing::error!(
+                "could not notify {} for schedule {} {:?}",
+                webhook,
+                schedule.id,
+                e
+            );
+        }
+    }
+}
+
+// NO_EXECUTE is why a schedule stops once who made it may no longer execute
+// the notebook (or invoke its plugins), its runs would only be refused
+const NO_EXECUTE: &str = "its user may no longer execute the notebook";
+
+// run_schedule runs the cell or the notebook of the schedule as who made
+// it, or stops it when there is nothing left to run
+fn run_schedule(mut schedule: Schedule, now: DateTime<Utc>) -> Result<Schedule, NotebookApiError> {
+    let notebook = match notebook_api::store()?.get(&schedule.notebook_id) {
+        Ok(notebook) => notebook,
+        Err(StoreError::NotFound(_)) => {
+            schedule.stop("the notebook was deleted");
+            return Ok(schedule);
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if incident_resolved(&notebook) {
+        schedule.stop(INCIDENT_RESOLVED);
+        return Ok(schedule);
+    }
+    let actor = schedule.actor();
+    if let Err(e) = notebook_api::authorize(&notebook, &actor.principal, Permission::Execute) {
+        tracing::warn!("stopping schedule {} {:?}", schedule.id, e);
+        schedule.stop(NO_EXECUTE);
+        return Ok(schedule);
+    }
+
+    match schedule.cell_id.clone() {
+        Some(cell_id) => {
+            if notebook.cell(&cell_id).is_none() {
+                schedule.stop("the cell was removed");
+                return Ok(schedule);
+            }
+            match timeline_api::run_cell(&schedule.notebook_id, &cell_id, actor, None) {
+                Ok(event) => {
+                    let met = schedule
+                        .condition
+                        .as_ref()
+                        .map(|_| StepResult::checked(&event, &schedule.condition()).passed);
+                    if schedule.record(now, Some(event.id.clone()), met) {
+                        notify(&schedule, &notebook, &event);
+                    }
+                }
+                Err(e @ NotebookApiError::Forbidden(_)) => {
+                    tracing::warn!("stopping schedule {} {:?}", schedule.id, e);
+                    schedule.stop(NO_EXECUTE);
+                }
+                // e.g. a reference to a cell without output yet, the next
+                // run may do better
+                Err(e) => {
+                    tracing::warn!(
+                        "schedule {} could not run cell {} {:?}",
+                        schedule.id,
+                        cell_id,
+                        e
+                    );
+                    schedule.record(now, None, None);
+                }
+            }
+        }
+        None => match run_api::run_notebook(&schedule.notebook_id, actor, None, false) {
+            Ok(run) => {
+                schedule.record(now, Some(run.id), None);
+            }
+            Err(e @ NotebookApiError::Forbidden(_)) => {
+                tracing::warn!("stopping schedule {} {:?}", schedule.id, e);
+                schedule.stop(NO_EXECUTE);
+            }
+            Err(e) => return Err(e),
+        },
+    }
+    Ok(schedule)
+}
+
+// run_due runs the schedules due at `now`, one after the other
+pub fn run_due(now: DateTime<Utc>) -> Result<(), NotebookApiError> {
+    let store = store()?;
+    for schedule in store.schedules(None)? {
+        if !schedule.is_due(now) {
+            continue;
+        }
+        let id = schedule.id.clone();
+        let schedule = match run_schedule(schedule.clone(), now) {
+            Ok(schedule) => schedule,
+            Err(e) => {
+                tracing::error!("schedule {} failed {:?}", id, e);
+                let mut schedule = schedule;
+                schedule.record(now, None, None);
+                schedule
+            }
+        };
+        // the schedule may have been deleted or stopped while it ran
+        match store.schedule(&id) {
+            Ok(current) if current.stopped.is_some() => {}
+            Ok(_) => store.save_schedule(&schedule)?,
+            Err(StoreError::NotFound(_)) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+// start runs the scheduler on its own thread for as long as the server runs
+pub fn start() {
+    std::thread::Builder::new()
+        .name("scheduler".into())
+        .spawn(|| loop {
+            if let Err(e) = run_due(Utc::now()) {
+                tracing::error!("could not run the schedules {:?}", e);
+            }
+            std::thread::sleep(TICK);
+        })
+        .expect("could not start the scheduler");
+}
+
+fn to_grpc_schedule(schedule: Schedule) -> databook::Schedule {
+    let (every, cron) = match schedule.trigger {
+        Trigger::Every { seconds } => (seconds, String::new()),
+        Trigger::Cron { expression } => (0, expression),
+    };
+    databook::Schedule {
+        id: schedule.id,
+        notebook_id: schedule.notebook_id,
+        cell_id: schedule.cell_id.unwrap_or_default(),
+        every,
+        cron,
+        condition: schedule.condition.unwrap_or_default(),
+        webhook: schedule.webhook.unwrap_or_default(),
+        user: schedule.user.unwrap_or_default(),
+        created_at: millis(schedule.created_at),
+        next_run_at: millis(schedule.next_run_at),
+        last_run_at: schedule.last_run_at.map(millis).unwrap_or_default(),
+        last_result: schedule.last_result.unwrap_or_default(),
+        condition_met: schedule.condition_met.unwrap_or_default(),
+        stopped: schedule.stopped.unwrap_or_default(),
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SchedulesGrpc {}
+
+#[tonic::async_trait]
+impl Schedules for SchedulesGrpc {
+    #[instrument]
+    async fn create_schedule(
+        &self,
+        request: Request<databook::CreateScheduleRequest>,
+    ) -> Result<Response<databook::Schedule>, Status> {
+        tracing::info!("received create schedule request");
+        let actor = auth::actor(&request, &request.get_ref().user);
+        run_blocking(|| {
+            let request = request.into_inner();
+            create_schedule(
+                &request.notebook_id,
+                empty_as_none(request.cell_id),
+                empty_as_none(request.every).as_deref(),
+                empty_as_none(request.cron).as_deref(),
+                empty_as_none(request.condition),
+                empty_as_none(request.webhook),
+                actor,
+            )
+            .map(to_grpc_schedule)
+        })
+        .await
+    }
+
+    #[instrument]
+    async fn list_schedules(
+        &self,
+        request: Request<databook::ListSchedulesRequest>,
+    ) -> Result<Response<databook::ListSchedulesResponse>, Status> {
+        let principal = auth::actor(&request, "").principal;
+        run_blocking(move || {
+            let schedules = list_schedules(&request.into_inner().notebook_id, &principal)?;
+            Ok::<_, NotebookApiError>(databook::ListSchedulesResponse {
+                schedules: schedules.into_iter().map(to_grpc_schedule).collect(),
+            })
+        })
+        .await
+    }
+
+    #[instrument]
+    async fn delete_schedule(
+        &self,
+        request: Request<databook::DeleteScheduleRequest>,
+    ) -> Result<Response<databook::DeleteScheduleResponse>, Status> {
+        tracing::info!("received delete schedule request");
+        let principal = auth::actor(&request, "").principal;
+        run_blocking(move || {
+            delete_schedule(&request.into_inner().id, &principal)?;
+            Ok::<_, NotebookApiError>(databook::DeleteScheduleResponse {})
+        })
+        .await
+    }
+}
+
+fn rest_schedule_response(
+    result: Result<Option<Schedule>, NotebookApiError>,
+) -> Json<rest::ScheduleResponse> {
+    match result {
+        Ok(schedule) => Json(rest::ScheduleResponse {
+            schedule,
+            error: None,
+        }),
+        Err(e) => Json(rest::ScheduleResponse {
+            schedule: None,
+            error: Some(format!("{:?}", e)),
+        }),
+    }
+}
+
+#[post("/notebooks/<id>/schedules", data = "<request>")]
+pub fn rest_create(
+    caller: auth::Caller,
+    id: String,
+    request: Json<rest::CreateScheduleRequest>,
+) -> Json<rest::ScheduleResponse> {
+    tracing::info!("received create schedule request");
+    let request = request.into_inner();
+    rest_schedule_response(
+        create_schedule(
+            &id,
+            request.cell_id,
+            request.every.as_deref(),
+            request.cron.as_deref(),
+            request.condition,
+            request.webhook,
+            caller.actor(request.user),
+        )
+        .map(Some),
+    )
+}
+
+#[get("/notebooks/<id>/schedules")]
+pub fn rest_list(caller: auth::Caller, id: String) -> Json<rest::ListSchedulesResponse> {
+    match list_schedules(&id, &caller.actor(None).principal) {
+        Ok(schedules) => Json(rest::ListSchedulesResponse {
+            schedules,
+            error: None,
+        }),
+        Err(e) => Json(rest::ListSchedulesResponse {
+            schedules: vec![],
+            error: Some(format!("{:?}", e)),
+        }),
+    }
+}
+
+#[delete("/schedules/<id>")]
+pub fn rest_delete(caller: auth::Caller, id: String) -> Json<rest::ScheduleResponse> {
+    tracing::info!("received delete schedule request");
+    rest_schedule_response(delete_schedule(&id, &caller.actor(None).principal).map(|_| None))
+}
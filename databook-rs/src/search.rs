@@ -0,0 +1,358 @@
+use crate::incident::Severity;
+use crate::notebook::{CellContent, Notebook};
+use crate::notebook_store::StoreError;
+use crate::timeline::{EventKind, ExecutionEvent};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+// Search finds notebooks by their title and tags, their markdown cells,
+// the options of their plugin cells and the outputs (and errors) of every
+// execution kept on the timeline. The index is updated as notebooks are
+// saved and executions appended, see search_api.
+
+// matched terms are wrapped in the snippets with these markers
+pub const HIGHLIGHT_START: &str = "**";
+pub const HIGHLIGHT_END: &str = "**";
+
+pub const DEFAULT_LIMIT: usize = 20;
+pub const MAX_LIMIT: usize = 100;
+
+// outputs are only indexed up to this many bytes, a log dump does not
+// need to be found past its first pages
+const MAX_INDEXED_TEXT: usize = 256 * 1024;
+
+// Source is what a document of the index comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    // the title and tags of the notebook
+    Notebook,
+    Markdown,
+    // the options of a plugin cell
+    Input,
+    // the output or error of an execution, or an incident change
+    Output,
+}
+
+impl Source {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Source::Notebook => "notebook",
+            Source::Markdown => "markdown",
+            Source::Input => "input",
+            Source::Output => "output",
+        }
+    }
+
+    pub fn parse(source: &str) -> Option<Self> {
+        match source {
+            "notebook" => Some(Source::Notebook),
+            "markdown" => Some(Source::Markdown),
+            "input" => Some(Source::Input),
+            "output" => Some(Source::Output),
+            _ => None,
+        }
+    }
+}
+
+// Document is one entry of the index
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Document {
+    pub notebook_id: Option<String>,
+    pub source: Source,
+    pub cell_id: Option<String>,
+    pub event_id: Option<String>,
+    pub plugin: Option<String>,
+    // when the notebook was saved or the execution started
+    pub at: DateTime<Utc>,
+    pub text: String,
+}
+
+// SearchQuery filters are all optional, `text` is not
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SearchQuery {
+    pub text: String,
+    pub plugin: Option<String>,
+    pub tag: Option<String>,
+    pub severity: Option<Severity>,
+    pub notebook_id: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    // DEFAULT_LIMIT when 0, at most MAX_LIMIT
+    pub limit: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SearchHit {
+    // None for direct invocations, which belong to no notebook
+    pub notebook_id: Option<String>,
+    pub notebook_title: Option<String>,
+    pub source: Source,
+    pub cell_id: Option<String>,
+    pub event_id: Option<String>,
+    pub plugin: Option<String>,
+    pub at: DateTime<Utc>,
+    // the text around the matches, see HIGHLIGHT_START
+    pub snippet: String,
+}
+
+// SearchIndex is an index kept next to the notebooks and the timeline
+pub trait SearchIndex: Send + Sync {
+    // index_notebook replaces the documents of the notebook itself, the
+    // ones of its executions are kept
+    fn index_notebook(&self, notebook: &Notebook) -> Result<(), StoreError>;
+    // index_event replaces the document of the event, if it has one
+    fn index_event(&self, event: &ExecutionEvent) -> Result<(), StoreError>;
+    // remove_notebook drops the notebook and the outputs of its executions
+    fn remove_notebook(&self, notebook_id: &str) -> Result<(), StoreError>;
+    fn is_empty(&self) -> Result<bool, StoreError>;
+    // search returns the best matches first
+    fn search(&self, query: &SearchQuery) -> Result<Vec<SearchHit>, StoreError>;
+}
+
+impl SearchQuery {
+    pub fn limit(&self) -> usize {
+        match self.limit {
+            0 => DEFAULT_LIMIT,
+            limit => limit.min(MAX_LIMIT),
+        }
+    }
+}
+
+fn truncate(mut text: String) -> String {
+    if text.len() > MAX_INDEXED_TEXT {
+        let mut end = MAX_INDEXED_TEXT;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text.truncate(end);
+    }
+    text
+}
+
+// notebook_documents lists what is indexed from the notebook itself
+pub fn notebook_documents(notebook: &Notebook) -> Vec<Document> {
+    let document = |source, cell_id: Option<&str>, plugin: Option<&str>, text: String| Document {
+        notebook_id: Some(notebook.id.clone()),
+        source,
+        cell_id: cell_id.map(str::to_string),
+        event_id: None,
+        plugin: plugin.map(str::to_string),
+        at: notebook.metadata.updated_at,
+        text: truncate(text),
+    };
+    let mut title = notebook.metadata.title.clone();
+    for tag in &notebook.metadata.tags {
+        title.push('\n');
+        title.push_str(tag);
+    }
+    let mut documents = vec![document(Source::Notebook, None, None, title)];
+    for cell in &notebook.cells {
+        match &cell.content {
+            CellContent::Markdown { source } if !source.trim().is_empty() => documents.push(
+                document(Source::Markdown, Some(&cell.id), None, source.clone()),
+            ),
+            CellContent::Markdown { .. } => {}
+            CellContent::Plugin { plugin, options } => {
+                let text = options
+                    .iter()
+                    .map(|(name, value)| format!("{}={}", name, value))
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                documents.push(document(Source::Input, Some(&cell.id), Some(plugin), text));
+            }
+        }
+    }
+    documents
+}
+
+// event_document is what is indexed from an execution: its output and
+// error, and the details (e.g. the note) of incident changes
+pub fn event_document(event: &ExecutionEvent) -> Option<Document> {
+    let mut parts: Vec<&str> = event
+        .output
+        .iter()
+        .chain(&event.error)
+        .map(String::as_str)
+        .collect();
+    if event.kind == EventKind::Incident {
+        parts.extend(event.options.values().map(String::as_str));
+    }
+    let text = parts.join("\n");
+    if text.trim().is_empty() {
+        return None;
+    }
+    Some(Document {
+        notebook_id: event.notebook_id.clone(),
+        source: Source::Output,
+        cell_id: event.cell_id.clone(),
+        event_id: Some(event.id.clone()),
+        plugin: Some(event.plugin.clone()),
+        at: event.started_at,
+        text: truncate(text),
+    })
+}
+
+// match_expression turns what someone typed into a full-text query:
+// words must all match, "quoted words" match as a phrase, a trailing *
+// matches words starting with it and OR between two terms matches either.
+// Everything else is taken literally, so errors pasted as they are (with
+// quotes, colons or parenthesis) do not break the query.
+pub fn match_expression(text: &str) -> Option<String> {
+    let mut terms: Vec<String> = vec![];
+    let mut rest = text.trim();
+    while !rest.is_empty() {
+        let (term, prefix, next) = if let Some(quoted) = rest.strip_prefix('"') {
+            let end = quoted.find('"').unwrap_or(quoted.len());
+            let next = quoted.get(end + 1..).unwrap_or("");
+            (&quoted[..end], false, next)
+        } else {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let word = &rest[..end];
+            match word.strip_suffix('*') {
+                Some(word) => (word, true, &rest[end..]),
+                None => (word, false, &rest[end..]),
+            }
+        };
+        rest = next.trim_start();
+
+        if term == "OR" && !prefix {
+            if !terms.is_empty() && !rest.is_empty() {
+                terms.push("OR".to_string());
+            }
+            continue;
+        }
+        if !term.chars().any(char::is_alphanumeric) {
+            continue;
+        }
+        let quoted = format!("\"{}\"", term.replace('"', "\"\""));
+        terms.push(if prefix {
+            format!("{}*", quoted)
+        } else {
+            quoted
+        });
+    }
+    while terms.last().map(|t| t == "OR").unwrap_or(false) {
+        terms.pop();
+    }
+    if terms.is_empty() {
+        None
+    } else {
+        Some(terms.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notebook::Cell;
+    use crate::plugin_manager::{Execution, InvocationError};
+    use crate::plugin_runtime::InvocationTrace;
+    use crate::timeline::ExecutionContext;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_match_expression() {
+        assert_eq!(
+            Some(r#""connection" "refused""#.into()),
+            match_expression("connection refused")
+        );
+        assert_eq!(
+            Some(r#""exit code 137" "oom"*"#.into()),
+            match_expression(r#" "exit code 137"  oom* "#)
+        );
+        assert_eq!(
+            Some(r#""timeout" OR "deadline""#.into()),
+            match_expression("timeout OR deadline")
+        );
+        assert_eq!(
+            Some(r#""dial" "tcp:""#.into()),
+            match_expression("OR dial tcp: - OR")
+        );
+        assert_eq!(
+            Some(r#""error(" "unterminated""#.into()),
+            match_expression(r#"error( "unterminated"#)
+        );
+        assert_eq!(None, match_expression("  -- : "));
+    }
+
+    #[test]
+    fn test_notebook_documents() {
+        let mut notebook = Notebook::new(
+            "api latency".into(),
+            "elias".into(),
+            vec!["sev2".into(), "api".into()],
+            vec![
+                Cell::markdown("checkout is slow"),
+                Cell::markdown("  "),
+                Cell {
+                    id: "pods".into(),
+                    content: CellContent::Plugin {
+                        plugin: "k8s".into(),
+                        options: BTreeMap::from([("namespace".to_string(), "api".to_string())]),
+                    },
+                },
+            ],
+        );
+        notebook.assign_cell_ids();
+        let documents = notebook_documents(&notebook);
+        assert_eq!(3, documents.len());
+        assert_eq!("api latency\nsev2\napi", documents[0].text);
+        assert_eq!(Source::Markdown, documents[1].source);
+        assert_eq!(
+            (Source::Input, Some("k8s".to_string()), "namespace=api"),
+            (
+                documents[2].source,
+                documents[2].plugin.clone(),
+                documents[2].text.as_str()
+            )
+        );
+    }
+
+    #[test]
+    fn test_event_document() {
+        let event = |output| {
+            ExecutionEvent::new(
+                ExecutionContext {
+                    notebook_id: Some("n1".into()),
+                    cell_id: Some("pods".into()),
+                    ..Default::default()
+                },
+                "k8s",
+                BTreeMap::new(),
+                Execution {
+                    plugin_version: None,
+                    output,
+                    trace: InvocationTrace::default(),
+                },
+                Utc::now(),
+                Utc::now(),
+            )
+        };
+        let document = event_document(&event(Ok("CrashLoopBackOff".into()))).unwrap();
+        assert_eq!(
+            (Source::Output, "CrashLoopBackOff"),
+            (document.source, document.text.as_str())
+        );
+        assert_eq!(
+            "GenericError",
+            event_document(&event(Err(InvocationError::GenericError)))
+                .unwrap()
+                .text
+        );
+        assert_eq!(None, event_document(&event(Ok("".into()))));
+
+        let incident = ExecutionEvent::incident(
+            "n1",
+            None,
+            "incident mitigated".into(),
+            BTreeMap::from([("note".to_string(), "rolled back".to_string())]),
+            Utc::now(),
+        );
+        assert_eq!(
+            "incident mitigated\nrolled back",
+            event_document(&incident).unwrap().text
+        );
+    }
+}
@@ -0,0 +1,189 @@
+use crate::auth;
+use crate::databook;
+use crate::databook::search_server::Search;
+use crate::incident::Severity;
+use crate::notebook::Notebook;
+use crate::notebook_api::{self, NotebookApiError};
+use crate::policy::Principal;
+use crate::search::{SearchHit, SearchIndex, SearchQuery};
+use crate::timeline::{ExecutionEvent, TimelineFilter};
+use crate::timeline_api::{self, empty_as_none, from_millis};
+use crate::{rest, run_blocking, SEARCH};
+
+use chrono::{DateTime, Utc};
+use rocket_contrib::json::Json;
+use tonic::{Request, Response, Status};
+use tracing::instrument;
+
+pub fn index() -> Result<&'static dyn SearchIndex, NotebookApiError> {
+    SEARCH
+        .get()
+        .map(|s| s.as_ref())
+        .ok_or_else(|| NotebookApiError::Internal("No search index setup".into()))
+}
+
+// index_notebook and index_event are called once the notebook or the
+// event is stored, failures are logged rather than returned
+pub fn index_notebook(notebook: &Notebook) {
+    if let Err(e) = index().and_then(|index| Ok(index.index_notebook(notebook)?)) {
+        tracing::error!("could not index notebook {} {:?}", notebook.id, e);
+    }
+}
+
+pub fn index_event(event: &ExecutionEvent) {
+    if let Err(e) = index().and_then(|index| Ok(index.index_event(event)?)) {
+        tracing::error!("could not index execution {} {:?}", event.id, e);
+    }
+}
+
+// backfill indexes the stored notebooks and timeline when the index is
+// empty, e.g. on the first start of a server with an older database or
+// with notebooks in a git repository
+pub fn backfill() -> Result<(), NotebookApiError> {
+    let index = index()?;
+    if !index.is_empty()? {
+        return Ok(());
+    }
+    let notebooks = notebook_api::store()?.list()?;
+    for notebook in &notebooks {
+        index.index_notebook(notebook)?;
+    }
+    let events = timeline_api::list_events(&TimelineFilter::default())?;
+    for event in &events {
+        // the timeline outlives deleted notebooks
+        let deleted = event
+            .notebook_id
+            .as_ref()
+            .map(|id| !notebooks.iter().any(|n| &n.id == id))
+            .unwrap_or(false);
+        if !deleted {
+            index.index_event(event)?;
+        }
+    }
+    tracing::info!(
+        "indexed {} notebooks and {} executions",
+        notebooks.len(),
+        events.len()
+    );
+    Ok(())
+}
+
+// search leaves out the hits of notebooks the principal may not view
+pub fn search(
+    query: &SearchQuery,
+    principal: &Principal,
+) -> Result<Vec<SearchHit>, NotebookApiError> {
+    if query.text.trim().is_empty() {
+        return Err(NotebookApiError::InvalidRequest(
+            "nothing to search for".into(),
+        ));
+    }
+    let mut hits = index()?.search(query)?;
+    if let Some(visible) = notebook_api::visible_notebooks(principal)? {
+        hits.retain(|h| h.notebook_id.as_ref().is_none_or(|id| visible.contains(id)));
+    }
+    Ok(hits)
+}
+
+fn parse_severity(severity: Option<String>) -> Result<Option<Severity>, NotebookApiError> {
+    severity
+        .map(|s| {
+            Severity::parse(&s).ok_or_else(|| {
+                NotebookApiError::InvalidRequest(format!(
+                    "invalid severity {:?}, use sev1 to sev4",
+                    s
+                ))
+            })
+        })
+        .transpose()
+}
+
+fn parse_time(time: Option<String>) -> Result<Option<DateTime<Utc>>, NotebookApiError> {
+    time.map(|t| {
+        t.parse::<DateTime<Utc>>().map_err(|e| {
+            NotebookApiError::InvalidRequest(format!("invalid time {:?}, use RFC 3339 {}", t, e))
+        })
+    })
+    .transpose()
+}
+
+fn to_grpc_hit(hit: SearchHit) -> databook::SearchHit {
+    databook::SearchHit {
+        notebook_id: hit.notebook_id.unwrap_or_default(),
+        notebook_title: hit.notebook_title.unwrap_or_default(),
+        source: hit.source.as_str().to_string(),
+        cell_id: hit.cell_id.unwrap_or_default(),
+        event_id: hit.event_id.unwrap_or_default(),
+        plugin: hit.plugin.unwrap_or_default(),
+        at: hit.at.timestamp_millis(),
+        snippet: hit.snippet,
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SearchGrpc {}
+
+#[tonic::async_trait]
+impl Search for SearchGrpc {
+    #[instrument]
+    async fn search(
+        &self,
+        request: Request<databook::SearchRequest>,
+    ) -> Result<Response<databook::SearchResponse>, Status> {
+        let principal = auth::actor(&request, "").principal;
+        run_blocking(move || {
+            let request = request.into_inner();
+            let query = SearchQuery {
+                text: request.text,
+                plugin: empty_as_none(request.plugin),
+                tag: empty_as_none(request.tag),
+                severity: parse_severity(empty_as_none(request.severity))?,
+                notebook_id: empty_as_none(request.notebook_id),
+                from: from_millis(request.from),
+                to: from_millis(request.to),
+                limit: request.limit as usize,
+            };
+            let hits = search(&query, &principal)?;
+            Ok::<_, NotebookApiError>(databook::SearchResponse {
+                hits: hits.into_iter().map(to_grpc_hit).collect(),
+            })
+        })
+        .await
+    }
+}
+
+// from and to are RFC 3339 times, e.g. 2026-03-04T10:00:00Z
+#[get("/search?<q>&<plugin>&<tag>&<severity>&<notebook_id>&<from>&<to>&<limit>")]
+#[allow(clippy::too_many_arguments)]
+pub fn rest_search(
+    caller: auth::Caller,
+    q: String,
+    plugin: Option<String>,
+    tag: Option<String>,
+    severity: Option<String>,
+    notebook_id: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    limit: Option<usize>,
+) -> Json<rest::SearchResponse> {
+    let result = (|| {
+        let query = SearchQuery {
+            text: q,
+            plugin,
+            tag,
+            severity: parse_severity(severity)?,
+            notebook_id,
+            from: parse_time(from)?,
+            to: parse_time(to)?,
+            limit: limit.unwrap_or_default(),
+        };
+        search(&query, &caller.actor(None).principal)
+    })();
+    match result {
+        Ok(hits) => Json(rest::SearchResponse { hits, error: None }),
+        Err(e) => Json(rest::SearchResponse {
+            hits: vec![],
+            error: Some(format!("{:?}", e)),
+        }),
+    }
+}
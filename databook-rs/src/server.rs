@@ -1,11 +1,26 @@
 #![feature(proc_macro_hygiene, decl_macro)]
 use clap::Parser;
+use databook::approvals_server::ApprovalsServer;
+use databook::databook_admin_server::DatabookAdminServer;
 use databook::databook_server::{Databook, DatabookServer};
-use databook::{GetRequest, GetResponse};
+use databook::exports_server::ExportsServer;
+use databook::imports_server::ImportsServer;
+use databook::incidents_server::IncidentsServer;
+use databook::live_server::LiveServer;
+use databook::notebooks_server::NotebooksServer;
+use databook::revisions_server::RevisionsServer;
+use databook::runbooks_server::RunbooksServer;
+use databook::runs_server::RunsServer;
+use databook::schedules_server::SchedulesServer;
+use databook::search_server::SearchServer;
+use databook::timeline_server::TimelineServer;
+use databook::{
+    GetRequest, GetResponse, InvokeCellRequest, InvokeCellResponse, ParseCellRequest, ParsedCell,
+};
 use once_cell::sync::OnceCell;
 use std::path::PathBuf;
-use std::sync::RwLock;
-use tonic::transport::Server;
+use std::sync::{Arc, RwLock};
+use tonic::transport::{Certificate, Identity, Server, ServerTlsConfig};
 use tonic::{Code, Request, Response, Status};
 use tracing::instrument;
 
@@ -15,17 +30,75 @@ use rocket::request::Form;
 use rocket_contrib::json::Json;
 use tokio::spawn;
 
+mod admin;
+mod approval;
+mod approval_api;
+mod assertions;
+mod audit;
+mod auth;
+mod cell_parser;
+mod crdt;
+mod export;
+mod export_api;
+mod git_store;
+mod import;
+mod import_api;
+mod incident;
+mod incident_api;
+mod live;
+mod live_api;
+mod notebook;
+mod notebook_api;
+mod notebook_file;
+mod notebook_run;
+mod notebook_store;
+mod oci_registry;
 mod plugin_config;
 mod plugin_manager;
+mod plugin_package;
 mod plugin_runtime;
+mod plugin_signature;
+mod policy;
+mod redaction;
+mod references;
 mod rest;
+mod revision;
+mod revision_api;
+mod run_api;
+mod runbook;
+mod runbook_api;
+mod schedule;
+mod schedule_api;
+mod search;
+mod search_api;
+mod server_config;
+mod sqlite_store;
+mod template;
+mod timeline;
+mod timeline_api;
 mod wasm;
 
+// streaming rpcs get an associated type named after them (e.g. followStream)
+// and oneofs are not boxed by prost
+#[allow(non_camel_case_types, clippy::large_enum_variant)]
 pub mod databook {
     tonic::include_proto!("databook");
 }
 
 static PLUGINS: OnceCell<RwLock<plugin_manager::PluginManager>> = OnceCell::new();
+static CONFIG: OnceCell<server_config::ServerConfig> = OnceCell::new();
+static NOTEBOOKS: OnceCell<Box<dyn notebook_store::NotebookStore>> = OnceCell::new();
+static TIMELINE: OnceCell<Box<dyn timeline::TimelineStore>> = OnceCell::new();
+static RUNBOOKS: OnceCell<Box<dyn runbook::RunbookStore>> = OnceCell::new();
+static RUNS: OnceCell<Box<dyn notebook_run::RunStore>> = OnceCell::new();
+static REVISIONS: OnceCell<Box<dyn revision::RevisionStore>> = OnceCell::new();
+static SEARCH: OnceCell<Box<dyn search::SearchIndex>> = OnceCell::new();
+static SCHEDULES: OnceCell<Box<dyn schedule::ScheduleStore>> = OnceCell::new();
+static APPROVALS: OnceCell<Box<dyn approval::ApprovalStore>> = OnceCell::new();
+static LIVE: OnceCell<live::LiveHub> = OnceCell::new();
+static AUTH: OnceCell<auth::Authenticator> = OnceCell::new();
+static POLICY: OnceCell<Arc<policy::PolicyStore>> = OnceCell::new();
+static AUDIT: OnceCell<Arc<audit::AuditLog>> = OnceCell::new();
 
 // CLI arguments to start the server
 #[derive(Parser, Debug)]
@@ -36,6 +109,26 @@ struct Args {
     plugin_folder: String,
     #[clap(short, long, value_parser, default_value_t = String::from("[::1]:50051"))]
     address_to_listen: String,
+    // Path of the server config file (e.g. trusted keys for plugin signatures)
+    #[clap(short, long, value_parser)]
+    config: Option<String>,
+}
+
+// run_blocking moves blocking work (wasm, storage, plugin files) out of the
+// async grpc handlers
+async fn run_blocking<T, E>(
+    f: impl FnOnce() -> Result<T, E> + Send + 'static,
+) -> Result<Response<T>, Status>
+where
+    T: Send + 'static,
+    E: Send + 'static,
+    Status: From<E>,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .expect("could not join back the tokio task thread")
+        .map(Response::new)
+        .map_err(Status::from)
 }
 
 #[derive(Debug)]
@@ -52,31 +145,73 @@ impl Databook for DatabookGrpc {
     #[instrument]
     async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
         tracing::info!("received get request");
-        let response = tokio::task::spawn_blocking(|| {
+        let actor = auth::actor(&request, "");
+        let context = timeline::ExecutionContext {
+            user: actor.name,
+            principal: actor.principal,
+            source: auth::source(&request),
+            ..Default::default()
+        };
+        let event = run_blocking(|| {
             let request = request.into_inner();
-            match PLUGINS.get() {
-                Some(p) => p
-                    .read()
-                    .map_err(|e| {
-                        tracing::error!("Could not get lock for plugins object {:?}", e);
-                        Status::new(Code::Internal, "Internal Error")
-                    })?
-                    .invoke(&request.name, request.options)
-                    .map_err(|e| {
-                        tracing::error!("error while calling wasm plugin {:?}", e);
-                        Status::new(Code::Internal, "Internal Error")
-                    }),
-                None => Err(Status::new(Code::Internal, "No plugins setup")),
-            }
+            timeline_api::execute(
+                context,
+                &request.name,
+                request.options.into_iter().collect(),
+            )
         })
-        .await
-        .expect("could not join back the tokio task thread");
+        .await?
+        .into_inner();
 
-        match response {
-            Ok(response) => Ok(Response::new(GetResponse { output: response })),
-            Err(e) => Err(e),
+        match event.output {
+            Some(output) => Ok(Response::new(GetResponse { output })),
+            None => {
+                tracing::error!("error while calling wasm plugin {:?}", event.error);
+                Err(Status::new(Code::Internal, "Internal Error"))
+            }
         }
     }
+
+    #[instrument]
+    async fn invoke_cell(
+        &self,
+        request: Request<InvokeCellRequest>,
+    ) -> Result<Response<InvokeCellResponse>, Status> {
+        tracing::info!("received invoke cell request");
+        let actor = auth::actor(&request, &request.get_ref().user);
+        let source = auth::source(&request);
+        let event =
+            run_blocking(|| timeline_api::invoke_cell(&request.into_inner().text, actor, source))
+                .await?
+                .into_inner();
+
+        match event.output {
+            Some(output) => Ok(Response::new(InvokeCellResponse {
+                output,
+                plugin: event.plugin,
+                options: event.options.into_iter().collect(),
+            })),
+            None => {
+                tracing::error!("error while calling wasm plugin {:?}", event.error);
+                Err(Status::new(Code::Internal, "Internal Error"))
+            }
+        }
+    }
+
+    #[instrument]
+    async fn parse_cell(
+        &self,
+        request: Request<ParseCellRequest>,
+    ) -> Result<Response<ParsedCell>, Status> {
+        run_blocking(|| {
+            let (plugin, options) = timeline_api::parse_invocation(&request.into_inner().text)?;
+            Ok::<_, notebook_api::NotebookApiError>(ParsedCell {
+                plugin,
+                options: options.into_iter().collect(),
+            })
+        })
+        .await
+    }
 }
 
 impl Default for DatabookGrpc {
@@ -87,32 +222,101 @@ impl Default for DatabookGrpc {
 
 #[instrument]
 #[post("/invoke", data = "<request>")]
-fn rest_invoke(request: Json<rest::InvokePluginRequest>) -> Json<rest::InvokePluginResponse> {
+fn rest_invoke(
+    caller: auth::Caller,
+    request: Json<rest::InvokePluginRequest>,
+) -> Json<rest::InvokePluginResponse> {
     tracing::info!("received get request");
-    let response = {
-        let request = request.into_inner();
-        match PLUGINS.get() {
-            Some(p) => p
-                .read()
-                .unwrap() //TODO
-                .invoke(&request.name, request.options)
-                .map_err(|e| {
-                    tracing::error!("error while calling wasm plugin {:?}", e);
-                    format!("error while invoking plugin {:?}", e)
-                }),
-            None => Err("No plugins setup".to_string()),
-        }
+    let request = request.into_inner();
+    let actor = caller.actor(None);
+    let context = timeline::ExecutionContext {
+        user: actor.name,
+        principal: actor.principal,
+        source: caller.source,
+        ..Default::default()
     };
+    let response = timeline_api::execute(
+        context,
+        &request.name,
+        request.options.into_iter().collect(),
+    );
 
     match response {
-        Ok(response) => Json(rest::InvokePluginResponse {
-            output: Some(response),
-            error: None,
+        Ok(event) => Json(rest::InvokePluginResponse {
+            output: event.output,
+            error: event
+                .error
+                .map(|e| format!("error while invoking plugin {}", e)),
         }),
         Err(e) => Json(rest::InvokePluginResponse {
             output: None,
-            error: Some(e.to_string()),
+            error: Some(format!("{:?}", e)),
+        }),
+    }
+}
+
+// rest_cell_error keeps the position of parse errors so editors can point at it
+fn rest_cell_error(e: notebook_api::NotebookApiError) -> (String, Option<cell_parser::Position>) {
+    match e {
+        notebook_api::NotebookApiError::InvalidCell(e) => (e.to_string(), Some(e.position)),
+        e => (format!("{:?}", e), None),
+    }
+}
+
+#[instrument]
+#[post("/invoke_cell", data = "<request>")]
+fn rest_invoke_cell(
+    caller: auth::Caller,
+    request: Json<rest::InvokeCellRequest>,
+) -> Json<rest::InvokeCellResponse> {
+    tracing::info!("received invoke cell request");
+    let request = request.into_inner();
+    match timeline_api::invoke_cell(&request.text, caller.actor(request.user), caller.source) {
+        Ok(event) => Json(rest::InvokeCellResponse {
+            plugin: Some(event.plugin),
+            options: event.options,
+            output: event.output,
+            error: event
+                .error
+                .map(|e| format!("error while invoking plugin {}", e)),
+            position: None,
+        }),
+        Err(e) => {
+            let (error, position) = rest_cell_error(e);
+            Json(rest::InvokeCellResponse {
+                plugin: None,
+                options: Default::default(),
+                output: None,
+                error: Some(error),
+                position,
+            })
+        }
+    }
+}
+
+#[post("/cells/parse", data = "<request>")]
+fn rest_parse_cell(
+    _caller: auth::Caller,
+    request: Json<rest::ParseCellRequest>,
+) -> Json<rest::InvokeCellResponse> {
+    match timeline_api::parse_invocation(&request.into_inner().text) {
+        Ok((plugin, options)) => Json(rest::InvokeCellResponse {
+            plugin: Some(plugin),
+            options,
+            output: None,
+            error: None,
+            position: None,
         }),
+        Err(e) => {
+            let (error, position) = rest_cell_error(e);
+            Json(rest::InvokeCellResponse {
+                plugin: None,
+                options: Default::default(),
+                output: None,
+                error: Some(error),
+                position,
+            })
+        }
     }
 }
 
@@ -122,27 +326,287 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let subscriber = tracing_subscriber::FmtSubscriber::new();
     tracing::subscriber::set_global_default(subscriber)?;
 
-    let mut plugin_manager = plugin_manager::PluginManager::new(PathBuf::from(args.plugin_folder));
+    let config = match args.config {
+        Some(ref path) => server_config::ServerConfig::new_from_file(PathBuf::from(path))
+            .expect("could not load server config"),
+        None => server_config::ServerConfig::default(),
+    };
+
+    let trusted_keys = config.trusted_keys.as_ref().map(|keys| {
+        plugin_signature::TrustedKeys::new_from_hex(keys).expect("invalid trusted key")
+    });
+
+    if config.admin_tokens.is_none() {
+        tracing::warn!("no admin tokens configured, the admin API is disabled");
+    }
+    match &config.auth {
+        Some(auth_config) => {
+            let authenticator = auth::Authenticator::new(auth_config.clone())
+                .expect("could not setup authentication");
+            if AUTH.set(authenticator).is_err() {
+                panic!("should always add authenticator to once_cell");
+            }
+        }
+        None => tracing::warn!("no [auth] configured, the API accepts anonymous requests"),
+    }
+    let tls = config
+        .auth
+        .as_ref()
+        .and_then(|a| a.mtls.as_ref())
+        .map(|mtls| {
+            let read = |path: &str| std::fs::read(path).expect("could not read mtls certificate");
+            ServerTlsConfig::new()
+                .identity(Identity::from_pem(read(&mtls.certificate), read(&mtls.key)))
+                .client_ca_root(Certificate::from_pem(read(&mtls.client_ca)))
+        });
+
+    let mut plugin_manager =
+        plugin_manager::PluginManager::new(PathBuf::from(&args.plugin_folder), trusted_keys);
     plugin_manager
         .registry()
         .expect("could not register plugins");
 
+    if let Some(ref path) = config.plugins_manifest {
+        let manifest = oci_registry::PluginsManifest::new_from_file(PathBuf::from(path))
+            .expect("could not load plugins manifest");
+        let client = oci_registry::RegistryClient::new(
+            PathBuf::from(
+                config
+                    .plugin_cache
+                    .clone()
+                    .unwrap_or_else(|| String::from("./plugin_cache")),
+            ),
+            config.insecure_registries.clone().unwrap_or_default(),
+            config.registry_tokens.clone().unwrap_or_default(),
+        );
+        plugin_manager.sync_registry(&manifest, &client);
+    }
+
+    if let Some(ref path) = config.policy {
+        let store = Arc::new(
+            policy::PolicyStore::open(PathBuf::from(path)).expect("could not load policy"),
+        );
+        plugin_manager.set_policy(store.clone());
+        if POLICY.set(store).is_err() {
+            panic!("should always add policy to once_cell");
+        }
+    }
+    let redaction = redaction::RedactionRules::new(config.redaction.as_ref())
+        .expect("could not load redaction rules");
+    plugin_manager.set_redaction(Arc::new(redaction));
+    if let Some(ref audit_config) = config.audit {
+        let log = Arc::new(audit::AuditLog::open(audit_config).expect("could not open audit log"));
+        plugin_manager.set_audit(log.clone());
+        if AUDIT.set(log).is_err() {
+            panic!("should always add audit log to once_cell");
+        }
+    }
+
+    CONFIG
+        .set(config)
+        .expect("should always add server config to once_cell");
+
     PLUGINS
         .set(RwLock::new(plugin_manager))
         .expect("should always add plugin manager to once_cell");
 
+    let notebook_database = CONFIG
+        .get()
+        .and_then(|c| c.notebook_database.clone())
+        .unwrap_or_else(|| String::from("./databook.db"));
+    let config = CONFIG.get().expect("server config is set");
+    let store: Box<dyn notebook_store::NotebookStore> = match &config.notebook_git_repository {
+        Some(repository) => Box::new(
+            git_store::GitStore::open(
+                repository,
+                config.notebook_git_branch.as_deref().unwrap_or("main"),
+                config.notebook_git_branch_per_incident.unwrap_or(false),
+            )
+            .expect("could not open notebook git repository"),
+        ),
+        None => Box::new(
+            sqlite_store::SqliteStore::open(&notebook_database)
+                .expect("could not open notebook database"),
+        ),
+    };
+    if NOTEBOOKS.set(store).is_err() {
+        panic!("should always add notebook store to once_cell");
+    }
+    // the timeline lives next to the notebooks, on its own connection
+    let timeline = sqlite_store::SqliteStore::open(&notebook_database)
+        .expect("could not open timeline database");
+    if TIMELINE.set(Box::new(timeline)).is_err() {
+        panic!("should always add timeline store to once_cell");
+    }
+    let runbooks = sqlite_store::SqliteStore::open(&notebook_database)
+        .expect("could not open runbook database");
+    if RUNBOOKS.set(Box::new(runbooks)).is_err() {
+        panic!("should always add runbook store to once_cell");
+    }
+    let runs =
+        sqlite_store::SqliteStore::open(&notebook_database).expect("could not open run database");
+    if RUNS.set(Box::new(runs)).is_err() {
+        panic!("should always add run store to once_cell");
+    }
+    let revisions = sqlite_store::SqliteStore::open(&notebook_database)
+        .expect("could not open revision database");
+    if REVISIONS.set(Box::new(revisions)).is_err() {
+        panic!("should always add revision store to once_cell");
+    }
+    let search_index = sqlite_store::SqliteStore::open(&notebook_database)
+        .expect("could not open search database");
+    if SEARCH.set(Box::new(search_index)).is_err() {
+        panic!("should always add search index to once_cell");
+    }
+    if let Err(e) = search_api::backfill() {
+        tracing::error!("could not index the stored notebooks {:?}", e);
+    }
+    if LIVE.set(live::LiveHub::new()).is_err() {
+        panic!("should always add live hub to once_cell");
+    }
+    let schedules = sqlite_store::SqliteStore::open(&notebook_database)
+        .expect("could not open schedule database");
+    if SCHEDULES.set(Box::new(schedules)).is_err() {
+        panic!("should always add schedule store to once_cell");
+    }
+    schedule_api::start();
+    let approvals = sqlite_store::SqliteStore::open(&notebook_database)
+        .expect("could not open approval database");
+    if APPROVALS.set(Box::new(approvals)).is_err() {
+        panic!("should always add approval store to once_cell");
+    }
+    auth::start();
+    policy::start();
+
     let rt = tokio::runtime::Runtime::new().unwrap();
 
     let rest_join = rt.spawn(async {
-        rocket::ignite().mount("/", routes![rest_invoke]).launch();
+        rocket::ignite()
+            .mount(
+                "/",
+                routes![
+                    rest_invoke,
+                    rest_invoke_cell,
+                    rest_parse_cell,
+                    admin::rest_list,
+                    admin::rest_install,
+                    admin::rest_upgrade,
+                    admin::rest_disable,
+                    admin::rest_enable,
+                    admin::rest_uninstall,
+                    admin::rest_audit,
+                    notebook_api::rest_list,
+                    notebook_api::rest_create,
+                    notebook_api::rest_get,
+                    notebook_api::rest_update,
+                    notebook_api::rest_delete,
+                    notebook_api::rest_edit,
+                    notebook_api::rest_operations,
+                    live_api::rest_ticket,
+                    timeline_api::rest_run_cell,
+                    timeline_api::rest_timeline,
+                    timeline_api::rest_cell_states,
+                    runbook_api::rest_list,
+                    runbook_api::rest_create,
+                    runbook_api::rest_get,
+                    runbook_api::rest_update,
+                    runbook_api::rest_versions,
+                    runbook_api::rest_instantiate,
+                    run_api::rest_run_notebook,
+                    run_api::rest_run_runbook,
+                    run_api::rest_runs,
+                    export_api::rest_export,
+                    import_api::rest_import,
+                    revision_api::rest_list,
+                    revision_api::rest_get,
+                    revision_api::rest_diff,
+                    revision_api::rest_restore,
+                    revision_api::rest_fork,
+                    incident_api::rest_declare,
+                    incident_api::rest_transition,
+                    incident_api::rest_update,
+                    incident_api::rest_get,
+                    search_api::rest_search,
+                    schedule_api::rest_create,
+                    schedule_api::rest_list,
+                    schedule_api::rest_delete,
+                    approval_api::rest_list,
+                    approval_api::rest_approve,
+                    approval_api::rest_reject
+                ],
+            )
+            .launch();
     });
 
+    let live_address = CONFIG
+        .get()
+        .and_then(|c| c.live_address.clone())
+        .unwrap_or_else(|| String::from("[::1]:8001"));
+    rt.spawn(live_api::serve_websocket(live_address));
+
     let grpc_join = rt.spawn(async move {
         // Setups GRPC server
         let addr = args.address_to_listen.parse().unwrap();
         let grpc = DatabookGrpc::new();
-        Server::builder()
-            .add_service(DatabookServer::new(grpc))
+        let admin = admin::DatabookAdminGrpc::default();
+        let mut builder = Server::builder();
+        if let Some(tls) = tls {
+            builder = builder.tls_config(tls).expect("invalid mtls config");
+        }
+        builder
+            .add_service(DatabookServer::with_interceptor(grpc, auth::check))
+            .add_service(DatabookAdminServer::with_interceptor(
+                admin,
+                admin::check_admin_token,
+            ))
+            .add_service(NotebooksServer::with_interceptor(
+                notebook_api::NotebooksGrpc::default(),
+                auth::check,
+            ))
+            .add_service(TimelineServer::with_interceptor(
+                timeline_api::TimelineGrpc::default(),
+                auth::check,
+            ))
+            .add_service(LiveServer::with_interceptor(
+                live_api::LiveGrpc::default(),
+                auth::check,
+            ))
+            .add_service(RunbooksServer::with_interceptor(
+                runbook_api::RunbooksGrpc::default(),
+                auth::check,
+            ))
+            .add_service(RunsServer::with_interceptor(
+                run_api::RunsGrpc::default(),
+                auth::check,
+            ))
+            .add_service(ExportsServer::with_interceptor(
+                export_api::ExportsGrpc::default(),
+                auth::check,
+            ))
+            .add_service(ImportsServer::with_interceptor(
+                import_api::ImportsGrpc::default(),
+                auth::check,
+            ))
+            .add_service(RevisionsServer::with_interceptor(
+                revision_api::RevisionsGrpc::default(),
+                auth::check,
+            ))
+            .add_service(IncidentsServer::with_interceptor(
+                incident_api::IncidentsGrpc::default(),
+                auth::check,
+            ))
+            .add_service(SearchServer::with_interceptor(
+                search_api::SearchGrpc::default(),
+                auth::check,
+            ))
+            .add_service(SchedulesServer::with_interceptor(
+                schedule_api::SchedulesGrpc::default(),
+                auth::check,
+            ))
+            .add_service(ApprovalsServer::with_interceptor(
+                approval_api::ApprovalsGrpc::default(),
+                auth::check,
+            ))
             .serve(addr)
             .await
             .unwrap();
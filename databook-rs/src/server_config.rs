@@ -0,0 +1,151 @@
+use crate::audit::AuditConfig;
+use crate::auth::AuthConfig;
+use crate::oci_registry::RegistryToken;
+use crate::redaction::RedactionConfig;
+
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+
+// ServerConfig holds the settings of databook-rs itself (as opposed to
+// PluginConfig, which is per plugin). It is read from a toml file passed
+// with --config.
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone, Default)]
+pub struct ServerConfig {
+    // hex encoded ed25519 public keys. When set, only plugins signed by one
+    // of those keys are loaded.
+    pub trusted_keys: Option<Vec<String>>,
+    // bearer tokens accepted by the admin API. Without it the admin API
+    // refuses every request.
+    pub admin_tokens: Option<Vec<String>>,
+    // toml file listing the OCI references of plugins to pull at startup
+    pub plugins_manifest: Option<String>,
+    // where pulled plugins are cached, defaults to ./plugin_cache
+    pub plugin_cache: Option<String>,
+    // registries reached over http instead of https (e.g. localhost:5000)
+    pub insecure_registries: Option<Vec<String>>,
+    // bearer tokens sent to the registries plugins are pulled from
+    pub registry_tokens: Option<Vec<RegistryToken>>,
+    // SQLite database holding the notebooks, defaults to ./databook.db
+    pub notebook_database: Option<String>,
+    // git repository holding the notebooks instead of the database, created
+    // (bare) when missing. The timeline, runs and the rest stay in the
+    // database.
+    pub notebook_git_repository: Option<String>,
+    // branch the notebooks are committed to, defaults to main
+    pub notebook_git_branch: Option<String>,
+    // commit notebooks with an incident to their own incident/<id> branch
+    pub notebook_git_branch_per_incident: Option<bool>,
+    // where viewers connect to follow notebooks over websocket,
+    // defaults to [::1]:8001
+    pub live_address: Option<String>,
+    // directory of templates for notebook exports, picked by name as
+    // <name>.md or <name>.html
+    pub export_templates: Option<String>,
+    // language -> plugin used when importing code blocks and Jupyter
+    // cells, replaces the default table (promql and logql)
+    pub import_languages: Option<BTreeMap<String, String>>,
+    // hosts (`host` or `host:port`) the webhooks of schedules may post to,
+    // schedules with a webhook are refused when missing
+    pub webhook_hosts: Option<Vec<String>>,
+    // how many revisions are kept per notebook, all when missing
+    pub revisions_keep: Option<usize>,
+    // revisions older than that are removed, the latest one is always kept
+    pub revisions_max_age_days: Option<u32>,
+    // path of the policy file saying who may invoke plugins and view, edit
+    // or execute notebooks, everything is allowed when missing
+    pub policy: Option<String>,
+    // how callers of the gRPC and REST APIs authenticate, anonymous when
+    // missing
+    pub auth: Option<AuthConfig>,
+    // where plugin invocations and admin changes are recorded, not
+    // recorded when missing
+    pub audit: Option<AuditConfig>,
+    // extra rules redacting credentials from plugin outputs and logs, the
+    // built-in ones apply when missing
+    pub redaction: Option<RedactionConfig>,
+}
+
+impl ServerConfig {
+    pub fn new_from_file(path: std::path::PathBuf) -> Option<Self> {
+        match fs::read_to_string(&path) {
+            Ok(config) => Self::new_from_str(&config),
+            Err(e) => {
+                tracing::warn!("unable to read server config file {:?}", e);
+                None
+            }
+        }
+    }
+    pub fn new_from_str(config: &str) -> Option<Self> {
+        match toml::from_str::<ServerConfig>(config) {
+            Ok(config) => Some(config),
+            Err(e) => {
+                tracing::warn!("unable to parse server config file {:?}", e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_create_server_config_from_str() {
+        let config = ServerConfig::new_from_str("trusted_keys=['abcd']");
+        assert_eq!(
+            Some(ServerConfig {
+                trusted_keys: Some(vec!["abcd".to_string()]),
+                ..Default::default()
+            }),
+            config
+        );
+    }
+
+    #[test]
+    fn test_auth_config() {
+        let config = ServerConfig::new_from_str(
+            r#"
+            [auth]
+            allow_anonymous = false
+            [[auth.tokens]]
+            token = "s3cret"
+            user = "ci"
+            [auth.jwt]
+            jwks = "https://sso.example.com/.well-known/jwks.json"
+            audience = "databook"
+            "#,
+        )
+        .unwrap();
+        let auth = config.auth.unwrap();
+        assert_eq!(Some(false), auth.allow_anonymous);
+        assert_eq!("ci", auth.tokens.unwrap()[0].user);
+        assert_eq!(Some("databook".to_string()), auth.jwt.unwrap().audience);
+        assert_eq!(None, auth.mtls);
+    }
+
+    #[test]
+    fn test_redaction_config() {
+        let config = ServerConfig::new_from_str(
+            r#"
+            [redaction]
+            builtin_rules = false
+            [[redaction.rules]]
+            name = "slack-token"
+            pattern = "xox[baprs]-[0-9A-Za-z-]+"
+            "#,
+        )
+        .unwrap();
+        let redaction = config.redaction.unwrap();
+        assert_eq!(Some(false), redaction.builtin_rules);
+        assert_eq!("slack-token", redaction.rules[0].name);
+    }
+
+    #[test]
+    fn test_empty_server_config() {
+        assert_eq!(
+            Some(ServerConfig::default()),
+            ServerConfig::new_from_str("")
+        );
+    }
+}
@@ -0,0 +1,1460 @@
+use crate::approval::{ApprovalRequest, ApprovalStore};
+use crate::crdt::{Operation, SequencedOperation};
+use crate::notebook::{Cell, Notebook, NotebookMetadata};
+use crate::notebook_run::{NotebookRun, RunStore};
+use crate::notebook_store::{NotebookStore, StoreError};
+use crate::revision::{Retention, Revision, RevisionInfo, RevisionStore};
+use crate::runbook::{Runbook, RunbookStore};
+use crate::schedule::{Schedule, ScheduleStore};
+use crate::search::{self, Document, SearchHit, SearchIndex, SearchQuery, Source};
+use crate::timeline::{ExecutionEvent, TimelineFilter, TimelineStore};
+
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS notebooks (
+    id TEXT PRIMARY KEY,
+    title TEXT NOT NULL,
+    owner TEXT NOT NULL,
+    tags TEXT NOT NULL,
+    cells TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL,
+    variables TEXT NOT NULL DEFAULT '{}',
+    -- the runbook version the notebook was created from
+    runbook TEXT,
+    incident TEXT
+);
+
+CREATE TABLE IF NOT EXISTS notebook_operations (
+    notebook_id TEXT NOT NULL,
+    seq INTEGER NOT NULL,
+    operation TEXT NOT NULL,
+    PRIMARY KEY (notebook_id, seq)
+);
+
+CREATE TABLE IF NOT EXISTS execution_events (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    id TEXT NOT NULL UNIQUE,
+    notebook_id TEXT,
+    cell_id TEXT,
+    event TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS notebook_runs (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    id TEXT NOT NULL UNIQUE,
+    notebook_id TEXT NOT NULL,
+    run TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS notebook_runs_notebook ON notebook_runs (notebook_id);
+
+CREATE TABLE IF NOT EXISTS schedules (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    id TEXT NOT NULL UNIQUE,
+    notebook_id TEXT NOT NULL,
+    schedule TEXT NOT NULL
+);
+
+-- invocations waiting for approval, and the ones decided
+CREATE TABLE IF NOT EXISTS approvals (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    id TEXT NOT NULL UNIQUE,
+    notebook_id TEXT,
+    approval TEXT NOT NULL
+);
+
+-- every version of a runbook, versions never change once stored
+CREATE TABLE IF NOT EXISTS runbooks (
+    id TEXT NOT NULL,
+    version INTEGER NOT NULL,
+    runbook TEXT NOT NULL,
+    PRIMARY KEY (id, version)
+);
+
+CREATE TRIGGER IF NOT EXISTS runbooks_no_update BEFORE UPDATE ON runbooks
+BEGIN
+    SELECT RAISE(ABORT, 'runbook versions cannot be changed');
+END;
+
+-- snapshots of the notebooks, one per save
+CREATE TABLE IF NOT EXISTS notebook_revisions (
+    notebook_id TEXT NOT NULL,
+    number INTEGER NOT NULL,
+    created_at TEXT NOT NULL,
+    revision TEXT NOT NULL,
+    PRIMARY KEY (notebook_id, number)
+);
+
+-- the full-text index, see search.rs: the documents with what they are
+-- filtered on, their text indexed under the same rowid
+CREATE TABLE IF NOT EXISTS search_documents (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    notebook_id TEXT,
+    source TEXT NOT NULL,
+    cell_id TEXT,
+    event_id TEXT,
+    plugin TEXT,
+    at TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS search_documents_notebook ON search_documents (notebook_id, source);
+
+-- what hits are filtered on and shown with, apart from the notebooks as
+-- they may be stored elsewhere (see git_store.rs)
+CREATE TABLE IF NOT EXISTS search_notebooks (
+    notebook_id TEXT PRIMARY KEY,
+    title TEXT NOT NULL,
+    tags TEXT NOT NULL,
+    severity TEXT
+);
+
+CREATE VIRTUAL TABLE IF NOT EXISTS search_text USING fts5(text, tokenize = 'porter unicode61');
+
+CREATE INDEX IF NOT EXISTS execution_events_notebook ON execution_events (notebook_id, cell_id);
+
+-- the timeline is append only, even for someone with access to the database file
+CREATE TRIGGER IF NOT EXISTS execution_events_no_update BEFORE UPDATE ON execution_events
+BEGIN
+    SELECT RAISE(ABORT, 'execution events are append only');
+END;
+
+CREATE TRIGGER IF NOT EXISTS execution_events_no_delete BEFORE DELETE ON execution_events
+BEGIN
+    SELECT RAISE(ABORT, 'execution events are append only');
+END;
+";
+
+// columns added after the table was first created, databases from older
+// versions get them when opened
+const ADDED_COLUMNS: [(&str, &str, &str); 3] = [
+    (
+        "notebooks",
+        "variables",
+        "ALTER TABLE notebooks ADD COLUMN variables TEXT NOT NULL DEFAULT '{}'",
+    ),
+    (
+        "notebooks",
+        "runbook",
+        "ALTER TABLE notebooks ADD COLUMN runbook TEXT",
+    ),
+    (
+        "notebooks",
+        "incident",
+        "ALTER TABLE notebooks ADD COLUMN incident TEXT",
+    ),
+];
+
+// SqliteStore keeps notebooks and the execution timeline on a SQLite
+// database, tags, cells, events, runs, runbooks, revisions and schedules
+// are stored as json. The search index is a full-text (fts5) table of the same
+// database.
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(e: rusqlite::Error) -> Self {
+        StoreError::Backend(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for StoreError {
+    fn from(e: serde_json::Error) -> Self {
+        StoreError::Backend(e.to_string())
+    }
+}
+
+impl SqliteStore {
+    pub fn open(path: &str) -> Result<Self, StoreError> {
+        Self::new(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> Result<Self, StoreError> {
+        Self::new(Connection::open_in_memory()?)
+    }
+
+    fn new(conn: Connection) -> Result<Self, StoreError> {
+        // the notebook and timeline stores use their own connections
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.execute_batch(SCHEMA)?;
+        for (table, column, statement) in ADDED_COLUMNS {
+            let exists: bool = conn.query_row(
+                "SELECT COUNT(*) > 0 FROM pragma_table_info(?1) WHERE name = ?2",
+                [table, column],
+                |row| row.get(0),
+            )?;
+            if !exists {
+                conn.execute_batch(statement)?;
+            }
+        }
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn conn(&self) -> Result<std::sync::MutexGuard<'_, Connection>, StoreError> {
+        self.conn
+            .lock()
+            .map_err(|e| StoreError::Backend(format!("could not lock sqlite connection {:?}", e)))
+    }
+}
+
+// NotebookRow holds the json columns of a notebook, they are parsed
+// outside of rusqlite as its errors cannot carry serde ones
+struct NotebookRow {
+    notebook: Notebook,
+    tags: String,
+    cells: String,
+    variables: String,
+    runbook: Option<String>,
+    incident: Option<String>,
+}
+
+fn notebook_from_row(row: &rusqlite::Row) -> rusqlite::Result<NotebookRow> {
+    Ok(NotebookRow {
+        notebook: Notebook {
+            id: row.get(0)?,
+            metadata: NotebookMetadata {
+                title: row.get(1)?,
+                owner: row.get(2)?,
+                tags: vec![],
+                variables: BTreeMap::new(),
+                runbook: None,
+                incident: None,
+                created_at: row.get(5)?,
+                updated_at: row.get(6)?,
+            },
+            cells: vec![],
+        },
+        tags: row.get(3)?,
+        cells: row.get(4)?,
+        variables: row.get(7)?,
+        runbook: row.get(8)?,
+        incident: row.get(9)?,
+    })
+}
+
+fn parse_json(row: NotebookRow) -> Result<Notebook, StoreError> {
+    let mut notebook = row.notebook;
+    notebook.metadata.tags = serde_json::from_str::<Vec<String>>(&row.tags)?;
+    notebook.metadata.variables = serde_json::from_str(&row.variables)?;
+    notebook.metadata.runbook = row.runbook.map(|r| serde_json::from_str(&r)).transpose()?;
+    notebook.metadata.incident = row.incident.map(|i| serde_json::from_str(&i)).transpose()?;
+    notebook.cells = serde_json::from_str::<Vec<Cell>>(&row.cells)?;
+    Ok(notebook)
+}
+
+const SELECT: &str =
+    "SELECT id, title, owner, tags, cells, created_at, updated_at, variables, runbook,
+                      incident FROM notebooks";
+
+impl NotebookStore for SqliteStore {
+    fn create(&self, notebook: &Notebook) -> Result<(), StoreError> {
+        let inserted = self.conn()?.execute(
+            "INSERT OR IGNORE INTO notebooks
+             (id, title, owner, tags, cells, created_at, updated_at, variables, runbook, incident)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                notebook.id,
+                notebook.metadata.title,
+                notebook.metadata.owner,
+                serde_json::to_string(&notebook.metadata.tags)?,
+                serde_json::to_string(&notebook.cells)?,
+                notebook.metadata.created_at,
+                notebook.metadata.updated_at,
+                serde_json::to_string(&notebook.metadata.variables)?,
+                notebook
+                    .metadata
+                    .runbook
+                    .as_ref()
+                    .map(serde_json::to_string)
+                    .transpose()?,
+                notebook
+                    .metadata
+                    .incident
+                    .as_ref()
+                    .map(serde_json::to_string)
+                    .transpose()?,
+            ],
+        )?;
+        if inserted == 0 {
+            return Err(StoreError::AlreadyExists(notebook.id.clone()));
+        }
+        Ok(())
+    }
+
+    fn get(&self, id: &str) -> Result<Notebook, StoreError> {
+        let row = self
+            .conn()?
+            .query_row(
+                &format!("{} WHERE id = ?1", SELECT),
+                [id],
+                notebook_from_row,
+            )
+            .optional()?
+            .ok_or_else(|| StoreError::NotFound(id.to_string()))?;
+        parse_json(row)
+    }
+
+    fn list(&self) -> Result<Vec<Notebook>, StoreError> {
+        let conn = self.conn()?;
+        let mut statement = conn.prepare(&format!("{} ORDER BY updated_at DESC", SELECT))?;
+        let rows = statement
+            .query_map([], notebook_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        rows.into_iter().map(parse_json).collect()
+    }
+
+    fn update(
+        &self,
+        notebook: &Notebook,
+        previous: DateTime<Utc>,
+        operations: &[Operation],
+        _author: Option<&str>,
+    ) -> Result<u64, StoreError> {
+        let mut conn = self.conn()?;
+        let transaction = conn.transaction()?;
+        let updated = transaction.execute(
+            "UPDATE notebooks SET title = ?2, tags = ?3, cells = ?4, updated_at = ?5, variables = ?6,
+             incident = ?7 WHERE id = ?1 AND updated_at = ?8",
+            params![
+                notebook.id,
+                notebook.metadata.title,
+                serde_json::to_string(&notebook.metadata.tags)?,
+                serde_json::to_string(&notebook.cells)?,
+                notebook.metadata.updated_at,
+                serde_json::to_string(&notebook.metadata.variables)?,
+                notebook
+                    .metadata
+                    .incident
+                    .as_ref()
+                    .map(serde_json::to_string)
+                    .transpose()?,
+                previous,
+            ],
+        )?;
+        if updated == 0 {
+            let exists = transaction
+                .query_row(
+                    "SELECT 1 FROM notebooks WHERE id = ?1",
+                    [&notebook.id],
+                    |_| Ok(()),
+                )
+                .optional()?
+                .is_some();
+            return Err(if exists {
+                StoreError::Conflict(notebook.id.clone())
+            } else {
+                StoreError::NotFound(notebook.id.clone())
+            });
+        }
+        let seq = insert_operations(&transaction, &notebook.id, operations)?;
+        transaction.commit()?;
+        Ok(seq)
+    }
+
+    fn delete(&self, id: &str) -> Result<(), StoreError> {
+        let mut conn = self.conn()?;
+        let transaction = conn.transaction()?;
+        let deleted = transaction.execute("DELETE FROM notebooks WHERE id = ?1", [id])?;
+        if deleted == 0 {
+            return Err(StoreError::NotFound(id.to_string()));
+        }
+        transaction.execute(
+            "DELETE FROM notebook_operations WHERE notebook_id = ?1",
+            [id],
+        )?;
+        transaction.execute(
+            "DELETE FROM notebook_revisions WHERE notebook_id = ?1",
+            [id],
+        )?;
+        remove_documents(&transaction, id, true)?;
+        transaction.execute("DELETE FROM search_notebooks WHERE notebook_id = ?1", [id])?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn append_operations(&self, id: &str, operations: &[Operation]) -> Result<u64, StoreError> {
+        let mut conn = self.conn()?;
+        let transaction = conn.transaction()?;
+        let seq = insert_operations(&transaction, id, operations)?;
+        transaction.commit()?;
+        Ok(seq)
+    }
+
+    fn operations(&self, id: &str, since: u64) -> Result<Vec<SequencedOperation>, StoreError> {
+        let conn = self.conn()?;
+        let mut statement = conn.prepare(
+            "SELECT seq, operation FROM notebook_operations
+             WHERE notebook_id = ?1 AND seq > ?2 ORDER BY seq",
+        )?;
+        let rows = statement
+            .query_map(params![id, since], |row| {
+                Ok((row.get::<_, u64>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        rows.into_iter()
+            .map(|(seq, operation)| {
+                Ok(SequencedOperation {
+                    seq,
+                    operation: serde_json::from_str(&operation)?,
+                })
+            })
+            .collect()
+    }
+}
+
+impl TimelineStore for SqliteStore {
+    fn append(&self, event: &ExecutionEvent) -> Result<(), StoreError> {
+        self.conn()?.execute(
+            "INSERT INTO execution_events (id, notebook_id, cell_id, event) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                event.id,
+                event.notebook_id,
+                event.cell_id,
+                serde_json::to_string(event)?,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn events(&self, filter: &TimelineFilter) -> Result<Vec<ExecutionEvent>, StoreError> {
+        let conn = self.conn()?;
+        let mut statement = conn.prepare(
+            "SELECT event FROM execution_events
+             WHERE (?1 IS NULL OR notebook_id = ?1) AND (?2 IS NULL OR cell_id = ?2)
+             ORDER BY seq",
+        )?;
+        let events = statement
+            .query_map(params![filter.notebook_id, filter.cell_id], |row| {
+                row.get::<_, String>(0)
+            })?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        events
+            .iter()
+            .map(|e| serde_json::from_str(e).map_err(StoreError::from))
+            .collect()
+    }
+}
+
+impl RunStore for SqliteStore {
+    fn append_run(&self, run: &NotebookRun) -> Result<(), StoreError> {
+        self.conn()?.execute(
+            "INSERT INTO notebook_runs (id, notebook_id, run) VALUES (?1, ?2, ?3)",
+            params![run.id, run.notebook_id, serde_json::to_string(run)?],
+        )?;
+        Ok(())
+    }
+
+    fn runs(&self, notebook_id: &str) -> Result<Vec<NotebookRun>, StoreError> {
+        let conn = self.conn()?;
+        let mut statement =
+            conn.prepare("SELECT run FROM notebook_runs WHERE notebook_id = ?1 ORDER BY seq DESC")?;
+        let runs = statement
+            .query_map([notebook_id], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        runs.iter()
+            .map(|r| serde_json::from_str(r).map_err(StoreError::from))
+            .collect()
+    }
+}
+
+impl ScheduleStore for SqliteStore {
+    fn save_schedule(&self, schedule: &Schedule) -> Result<(), StoreError> {
+        self.conn()?.execute(
+            "INSERT INTO schedules (id, notebook_id, schedule) VALUES (?1, ?2, ?3)
+             ON CONFLICT (id) DO UPDATE SET schedule = excluded.schedule",
+            params![
+                schedule.id,
+                schedule.notebook_id,
+                serde_json::to_string(schedule)?
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn schedule(&self, id: &str) -> Result<Schedule, StoreError> {
+        let schedule: String = self
+            .conn()?
+            .query_row(
+                "SELECT schedule FROM schedules WHERE id = ?1",
+                [id],
+                |row| row.get(0),
+            )
+            .optional()?
+            .ok_or_else(|| StoreError::NotFound(id.to_string()))?;
+        Ok(serde_json::from_str(&schedule)?)
+    }
+
+    fn schedules(&self, notebook_id: Option<&str>) -> Result<Vec<Schedule>, StoreError> {
+        let conn = self.conn()?;
+        let mut statement = conn.prepare(
+            "SELECT schedule FROM schedules WHERE ?1 IS NULL OR notebook_id = ?1 ORDER BY seq",
+        )?;
+        let schedules = statement
+            .query_map([notebook_id], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        schedules
+            .iter()
+            .map(|s| serde_json::from_str(s).map_err(StoreError::from))
+            .collect()
+    }
+
+    fn delete_schedule(&self, id: &str) -> Result<(), StoreError> {
+        let deleted = self
+            .conn()?
+            .execute("DELETE FROM schedules WHERE id = ?1", [id])?;
+        if deleted == 0 {
+            return Err(StoreError::NotFound(id.to_string()));
+        }
+        Ok(())
+    }
+}
+
+impl ApprovalStore for SqliteStore {
+    fn save_approval(&self, request: &ApprovalRequest) -> Result<(), StoreError> {
+        self.conn()?.execute(
+            "INSERT INTO approvals (id, notebook_id, approval) VALUES (?1, ?2, ?3)
+             ON CONFLICT (id) DO UPDATE SET approval = excluded.approval",
+            params![
+                request.id,
+                request.notebook_id,
+                serde_json::to_string(request)?
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn approval(&self, id: &str) -> Result<ApprovalRequest, StoreError> {
+        let approval: String = self
+            .conn()?
+            .query_row(
+                "SELECT approval FROM approvals WHERE id = ?1",
+                [id],
+                |row| row.get(0),
+            )
+            .optional()?
+            .ok_or_else(|| StoreError::NotFound(id.to_string()))?;
+        Ok(serde_json::from_str(&approval)?)
+    }
+
+    fn approvals(&self, notebook_id: Option<&str>) -> Result<Vec<ApprovalRequest>, StoreError> {
+        let conn = self.conn()?;
+        let mut statement = conn.prepare(
+            "SELECT approval FROM approvals WHERE ?1 IS NULL OR notebook_id = ?1 ORDER BY seq",
+        )?;
+        let approvals = statement
+            .query_map([notebook_id], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        approvals
+            .iter()
+            .map(|a| serde_json::from_str(a).map_err(StoreError::from))
+            .collect()
+    }
+}
+
+fn parse_runbooks(rows: Vec<String>) -> Result<Vec<Runbook>, StoreError> {
+    rows.iter()
+        .map(|r| serde_json::from_str(r).map_err(StoreError::from))
+        .collect()
+}
+
+impl RunbookStore for SqliteStore {
+    fn add_runbook_version(&self, runbook: &Runbook) -> Result<(), StoreError> {
+        let inserted = self.conn()?.execute(
+            "INSERT OR IGNORE INTO runbooks (id, version, runbook) VALUES (?1, ?2, ?3)",
+            params![runbook.id, runbook.version, serde_json::to_string(runbook)?],
+        )?;
+        if inserted == 0 {
+            return Err(StoreError::AlreadyExists(format!(
+                "{} version {}",
+                runbook.id, runbook.version
+            )));
+        }
+        Ok(())
+    }
+
+    fn runbook(&self, id: &str, version: Option<u32>) -> Result<Runbook, StoreError> {
+        let row: String = self
+            .conn()?
+            .query_row(
+                "SELECT runbook FROM runbooks WHERE id = ?1 AND (?2 IS NULL OR version = ?2)
+                 ORDER BY version DESC LIMIT 1",
+                params![id, version],
+                |row| row.get(0),
+            )
+            .optional()?
+            .ok_or_else(|| match version {
+                Some(version) => StoreError::NotFound(format!("{} version {}", id, version)),
+                None => StoreError::NotFound(id.to_string()),
+            })?;
+        Ok(serde_json::from_str(&row)?)
+    }
+
+    fn runbooks(&self) -> Result<Vec<Runbook>, StoreError> {
+        let conn = self.conn()?;
+        let mut statement = conn.prepare(
+            "SELECT runbook FROM runbooks r
+             WHERE version = (SELECT MAX(version) FROM runbooks WHERE id = r.id)
+             ORDER BY id",
+        )?;
+        let rows = statement
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        parse_runbooks(rows)
+    }
+
+    fn runbook_versions(&self, id: &str) -> Result<Vec<Runbook>, StoreError> {
+        let conn = self.conn()?;
+        let mut statement =
+            conn.prepare("SELECT runbook FROM runbooks WHERE id = ?1 ORDER BY version")?;
+        let rows = statement
+            .query_map([id], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        parse_runbooks(rows)
+    }
+}
+
+impl RevisionStore for SqliteStore {
+    fn append_revision(
+        &self,
+        notebook: &Notebook,
+        author: Option<&str>,
+    ) -> Result<Revision, StoreError> {
+        let mut conn = self.conn()?;
+        let transaction = conn.transaction()?;
+        let last: u64 = transaction.query_row(
+            "SELECT COALESCE(MAX(number), 0) FROM notebook_revisions WHERE notebook_id = ?1",
+            [&notebook.id],
+            |row| row.get(0),
+        )?;
+        let revision = Revision {
+            notebook_id: notebook.id.clone(),
+            number: last + 1,
+            author: author.map(str::to_string),
+            created_at: Utc::now(),
+            notebook: notebook.clone(),
+        };
+        transaction.execute(
+            "INSERT INTO notebook_revisions (notebook_id, number, created_at, revision)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![
+                revision.notebook_id,
+                revision.number,
+                revision.created_at,
+                serde_json::to_string(&revision)?
+            ],
+        )?;
+        transaction.commit()?;
+        Ok(revision)
+    }
+
+    fn revision(&self, notebook_id: &str, number: Option<u64>) -> Result<Revision, StoreError> {
+        let row: String = self
+            .conn()?
+            .query_row(
+                "SELECT revision FROM notebook_revisions WHERE notebook_id = ?1 AND (?2 IS NULL OR number = ?2)
+                 ORDER BY number DESC LIMIT 1",
+                params![notebook_id, number],
+                |row| row.get(0),
+            )
+            .optional()?
+            .ok_or_else(|| match number {
+                Some(number) => StoreError::NotFound(format!("{} revision {}", notebook_id, number)),
+                None => StoreError::NotFound(notebook_id.to_string()),
+            })?;
+        Ok(serde_json::from_str(&row)?)
+    }
+
+    fn revisions(&self, notebook_id: &str) -> Result<Vec<RevisionInfo>, StoreError> {
+        let conn = self.conn()?;
+        let mut statement = conn.prepare(
+            "SELECT revision FROM notebook_revisions WHERE notebook_id = ?1 ORDER BY number DESC",
+        )?;
+        let rows = statement
+            .query_map([notebook_id], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        rows.iter()
+            .map(|r| Ok(serde_json::from_str::<Revision>(r)?.info()))
+            .collect()
+    }
+
+    fn prune_revisions(
+        &self,
+        notebook_id: &str,
+        retention: Retention,
+    ) -> Result<usize, StoreError> {
+        let conn = self.conn()?;
+        let latest: u64 = conn.query_row(
+            "SELECT COALESCE(MAX(number), 0) FROM notebook_revisions WHERE notebook_id = ?1",
+            [notebook_id],
+            |row| row.get(0),
+        )?;
+        let mut removed = 0;
+        if let Some(keep) = retention.keep {
+            let keep = keep.max(1) as u64;
+            removed += conn.execute(
+                "DELETE FROM notebook_revisions WHERE notebook_id = ?1 AND number <= ?2",
+                params![notebook_id, latest.saturating_sub(keep)],
+            )?;
+        }
+        if let Some(days) = retention.max_age_days {
+            let before = Utc::now() - chrono::Duration::days(days.into());
+            removed += conn.execute(
+                "DELETE FROM notebook_revisions WHERE notebook_id = ?1 AND number < ?2 AND created_at < ?3",
+                params![notebook_id, latest, before],
+            )?;
+        }
+        Ok(removed)
+    }
+}
+
+// remove_documents drops the documents of the notebook, the ones of its
+// executions too when `outputs`
+// insert_operations appends to the edit history of the notebook and
+// returns the seq of the last operation
+fn insert_operations(
+    transaction: &Transaction,
+    id: &str,
+    operations: &[Operation],
+) -> Result<u64, StoreError> {
+    let mut seq: u64 = transaction.query_row(
+        "SELECT COALESCE(MAX(seq), 0) FROM notebook_operations WHERE notebook_id = ?1",
+        [id],
+        |row| row.get(0),
+    )?;
+    for operation in operations {
+        seq += 1;
+        transaction.execute(
+            "INSERT INTO notebook_operations (notebook_id, seq, operation) VALUES (?1, ?2, ?3)",
+            params![id, seq, serde_json::to_string(operation)?],
+        )?;
+    }
+    Ok(seq)
+}
+
+fn remove_documents(
+    transaction: &Transaction,
+    notebook_id: &str,
+    outputs: bool,
+) -> Result<(), StoreError> {
+    let filter = "notebook_id = ?1 AND (?2 OR source != 'output')";
+    transaction.execute(
+        &format!(
+            "DELETE FROM search_text WHERE rowid IN (SELECT id FROM search_documents WHERE {})",
+            filter
+        ),
+        params![notebook_id, outputs],
+    )?;
+    transaction.execute(
+        &format!("DELETE FROM search_documents WHERE {}", filter),
+        params![notebook_id, outputs],
+    )?;
+    Ok(())
+}
+
+fn insert_document(transaction: &Transaction, document: &Document) -> Result<(), StoreError> {
+    transaction.execute(
+        "INSERT INTO search_documents (notebook_id, source, cell_id, event_id, plugin, at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            document.notebook_id,
+            document.source.as_str(),
+            document.cell_id,
+            document.event_id,
+            document.plugin,
+            document.at,
+        ],
+    )?;
+    transaction.execute(
+        "INSERT INTO search_text (rowid, text) VALUES (?1, ?2)",
+        params![transaction.last_insert_rowid(), document.text],
+    )?;
+    Ok(())
+}
+
+impl SearchIndex for SqliteStore {
+    fn index_notebook(&self, notebook: &Notebook) -> Result<(), StoreError> {
+        let mut conn = self.conn()?;
+        let transaction = conn.transaction()?;
+        remove_documents(&transaction, &notebook.id, false)?;
+        for document in search::notebook_documents(notebook) {
+            insert_document(&transaction, &document)?;
+        }
+        transaction.execute(
+            "INSERT OR REPLACE INTO search_notebooks (notebook_id, title, tags, severity) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                notebook.id,
+                notebook.metadata.title,
+                serde_json::to_string(&notebook.metadata.tags)?,
+                notebook.metadata.incident.as_ref().map(|i| i.severity.to_string()),
+            ],
+        )?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn index_event(&self, event: &ExecutionEvent) -> Result<(), StoreError> {
+        if let Some(document) = search::event_document(event) {
+            let mut conn = self.conn()?;
+            let transaction = conn.transaction()?;
+            // indexing an event again replaces it
+            transaction.execute(
+                "DELETE FROM search_text WHERE rowid IN (SELECT id FROM search_documents WHERE event_id = ?1)",
+                [&event.id],
+            )?;
+            transaction.execute(
+                "DELETE FROM search_documents WHERE event_id = ?1",
+                [&event.id],
+            )?;
+            insert_document(&transaction, &document)?;
+            transaction.commit()?;
+        }
+        Ok(())
+    }
+
+    fn remove_notebook(&self, notebook_id: &str) -> Result<(), StoreError> {
+        let mut conn = self.conn()?;
+        let transaction = conn.transaction()?;
+        remove_documents(&transaction, notebook_id, true)?;
+        transaction.execute(
+            "DELETE FROM search_notebooks WHERE notebook_id = ?1",
+            [notebook_id],
+        )?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn is_empty(&self) -> Result<bool, StoreError> {
+        Ok(self.conn()?.query_row(
+            "SELECT NOT EXISTS (SELECT 1 FROM search_notebooks)",
+            [],
+            |row| row.get(0),
+        )?)
+    }
+
+    fn search(&self, query: &SearchQuery) -> Result<Vec<SearchHit>, StoreError> {
+        let expression = match search::match_expression(&query.text) {
+            Some(expression) => expression,
+            None => return Ok(vec![]),
+        };
+        let conn = self.conn()?;
+        let mut statement = conn.prepare(
+            "SELECT d.notebook_id, n.title, d.source, d.cell_id, d.event_id, d.plugin, d.at,
+                    snippet(search_text, 0, ?2, ?3, '…', 24)
+             FROM search_text
+             JOIN search_documents d ON d.id = search_text.rowid
+             LEFT JOIN search_notebooks n ON n.notebook_id = d.notebook_id
+             WHERE search_text MATCH ?1
+               AND (?4 IS NULL OR d.plugin = ?4)
+               AND (?5 IS NULL OR d.notebook_id = ?5)
+               AND (?6 IS NULL OR d.at >= ?6)
+               AND (?7 IS NULL OR d.at < ?7)
+               AND (?8 IS NULL OR EXISTS (SELECT 1 FROM json_each(n.tags) WHERE value = ?8))
+               AND (?9 IS NULL OR n.severity = ?9)
+             ORDER BY search_text.rank
+             LIMIT ?10",
+        )?;
+        let hits = statement
+            .query_map(
+                params![
+                    expression,
+                    search::HIGHLIGHT_START,
+                    search::HIGHLIGHT_END,
+                    query.plugin,
+                    query.notebook_id,
+                    query.from,
+                    query.to,
+                    query.tag,
+                    query.severity.map(|s| s.to_string()),
+                    query.limit() as i64,
+                ],
+                |row| {
+                    Ok(SearchHit {
+                        notebook_id: row.get(0)?,
+                        notebook_title: row.get(1)?,
+                        // sources are only written by insert_document
+                        source: Source::parse(&row.get::<_, String>(2)?).unwrap_or(Source::Output),
+                        cell_id: row.get(3)?,
+                        event_id: row.get(4)?,
+                        plugin: row.get(5)?,
+                        at: row.get::<_, DateTime<Utc>>(6)?,
+                        snippet: row.get(7)?,
+                    })
+                },
+            )?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(hits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crdt::NotebookDoc;
+    use crate::incident::{Incident, Severity, Status};
+    use crate::notebook::RunbookSource;
+    use crate::plugin_manager::Execution;
+    use crate::plugin_runtime::InvocationTrace;
+    use crate::policy::Actor;
+    use crate::schedule::Trigger;
+    use crate::timeline::ExecutionContext;
+    use chrono::Utc;
+
+    fn notebook() -> Notebook {
+        Notebook::new(
+            "api latency".into(),
+            "elias".into(),
+            vec!["sev2".into()],
+            vec![
+                Cell::markdown("p99 is up since 10:00"),
+                Cell::plugin(
+                    "prometheus",
+                    BTreeMap::from([("query".to_string(), "latency[5m]".to_string())]),
+                ),
+            ],
+        )
+    }
+
+    #[test]
+    fn test_create_and_get() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let notebook = notebook();
+        store.create(&notebook).unwrap();
+
+        assert_eq!(notebook, store.get(&notebook.id).unwrap());
+        assert_eq!(
+            Err(StoreError::AlreadyExists(notebook.id.clone())),
+            store.create(&notebook)
+        );
+    }
+
+    #[test]
+    fn test_update() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let mut notebook = notebook();
+        store.create(&notebook).unwrap();
+
+        notebook.metadata.title = "api latency (resolved)".into();
+        notebook.cells.push(Cell::markdown("rolled back"));
+        notebook
+            .metadata
+            .variables
+            .insert("cluster".into(), "prod-eu".into());
+        store
+            .update(&notebook, notebook.metadata.updated_at, &[], None)
+            .unwrap();
+        assert_eq!(notebook, store.get(&notebook.id).unwrap());
+
+        // a save made on the version before is refused
+        let previous = notebook.metadata.updated_at;
+        notebook.metadata.updated_at = Utc::now();
+        store.update(&notebook, previous, &[], None).unwrap();
+        assert_eq!(
+            Err(StoreError::Conflict(notebook.id.clone())),
+            store.update(&notebook, previous, &[], None)
+        );
+        let mut missing = notebook.clone();
+        missing.id = "missing".into();
+        assert_eq!(
+            Err(StoreError::NotFound("missing".into())),
+            store.update(&missing, previous, &[], None)
+        );
+    }
+
+    #[test]
+    fn test_list_and_delete() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let first = notebook();
+        let second = notebook();
+        store.create(&first).unwrap();
+        store.create(&second).unwrap();
+        assert_eq!(2, store.list().unwrap().len());
+
+        store.delete(&first.id).unwrap();
+        assert_eq!(vec![second], store.list().unwrap());
+        assert_eq!(
+            Err(StoreError::NotFound(first.id.clone())),
+            store.get(&first.id)
+        );
+        assert_eq!(
+            Err(StoreError::NotFound(first.id.clone())),
+            store.delete(&first.id)
+        );
+    }
+
+    #[test]
+    fn test_operations() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let notebook = notebook();
+        store.create(&notebook).unwrap();
+        let operations = NotebookDoc::default()
+            .diff(&notebook.cells, "server")
+            .unwrap();
+
+        assert_eq!(
+            operations.len() as u64,
+            store.append_operations(&notebook.id, &operations).unwrap()
+        );
+        let stored = store.operations(&notebook.id, 1).unwrap();
+        assert_eq!(operations.len() - 1, stored.len());
+        assert_eq!(2, stored[0].seq);
+        assert_eq!(operations[1], stored[0].operation);
+
+        // the operations of a refused update are not kept
+        let stale = notebook.metadata.updated_at - chrono::Duration::seconds(1);
+        assert_eq!(
+            Err(StoreError::Conflict(notebook.id.clone())),
+            store.update(&notebook, stale, &operations, None)
+        );
+        assert_eq!(
+            operations.len(),
+            store.operations(&notebook.id, 0).unwrap().len()
+        );
+        assert_eq!(
+            2 * operations.len() as u64,
+            store
+                .update(&notebook, notebook.metadata.updated_at, &operations, None)
+                .unwrap()
+        );
+
+        store.delete(&notebook.id).unwrap();
+        assert!(store.operations(&notebook.id, 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_adds_columns_to_older_databases() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE notebooks (
+                id TEXT PRIMARY KEY,
+                title TEXT NOT NULL,
+                owner TEXT NOT NULL,
+                tags TEXT NOT NULL,
+                cells TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                updated_at TEXT NOT NULL
+            );
+            INSERT INTO notebooks VALUES ('n1', 'old', 'elias', '[]', '[]',
+                '2022-10-01T10:00:00Z', '2022-10-01T10:00:00Z');",
+        )
+        .unwrap();
+        let store = SqliteStore::new(conn).unwrap();
+        assert!(store.get("n1").unwrap().metadata.variables.is_empty());
+
+        let notebook = notebook();
+        store.create(&notebook).unwrap();
+        assert_eq!(notebook, store.get(&notebook.id).unwrap());
+    }
+
+    fn event(notebook_id: &str, cell_id: &str, output: &str) -> ExecutionEvent {
+        ExecutionEvent::new(
+            ExecutionContext {
+                notebook_id: Some(notebook_id.into()),
+                cell_id: Some(cell_id.into()),
+                user: Some("elias".into()),
+                ..Default::default()
+            },
+            "prometheus",
+            BTreeMap::new(),
+            Execution {
+                plugin_version: None,
+                output: Ok(output.into()),
+                trace: InvocationTrace::default(),
+            },
+            Utc::now(),
+            Utc::now(),
+        )
+    }
+
+    #[test]
+    fn test_timeline_keeps_every_run() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let first = event("n1", "c1", "10 errors");
+        let second = event("n1", "c1", "0 errors");
+        let other = event("n2", "c1", "other notebook");
+        store.append(&first).unwrap();
+        store.append(&other).unwrap();
+        store.append(&second).unwrap();
+
+        let filter = TimelineFilter {
+            notebook_id: Some("n1".into()),
+            cell_id: Some("c1".into()),
+        };
+        assert_eq!(vec![first, second], store.events(&filter).unwrap());
+        assert_eq!(3, store.events(&TimelineFilter::default()).unwrap().len());
+    }
+
+    #[test]
+    fn test_timeline_is_append_only() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        store.append(&event("n1", "c1", "10 errors")).unwrap();
+
+        let conn = store.conn().unwrap();
+        assert!(conn
+            .execute("UPDATE execution_events SET event = '{}'", [])
+            .is_err());
+        assert!(conn.execute("DELETE FROM execution_events", []).is_err());
+    }
+
+    fn runbook(version: u32) -> Runbook {
+        Runbook {
+            id: "api-latency".into(),
+            version,
+            title: "api latency".into(),
+            description: String::new(),
+            tags: vec![],
+            parameters: vec![],
+            cells: vec![Cell::markdown("check the dashboards")],
+            author: "elias".into(),
+            created_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_runbook_versions() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        store.add_runbook_version(&runbook(1)).unwrap();
+        store.add_runbook_version(&runbook(2)).unwrap();
+        assert!(matches!(
+            store.add_runbook_version(&runbook(2)),
+            Err(StoreError::AlreadyExists(_))
+        ));
+
+        assert_eq!(2, store.runbook("api-latency", None).unwrap().version);
+        assert_eq!(1, store.runbook("api-latency", Some(1)).unwrap().version);
+        assert!(matches!(
+            store.runbook("api-latency", Some(3)),
+            Err(StoreError::NotFound(_))
+        ));
+        let latest = store.runbooks().unwrap();
+        assert_eq!(1, latest.len());
+        assert_eq!(2, latest[0].version);
+        assert_eq!(2, store.runbook_versions("api-latency").unwrap().len());
+
+        let conn = store.conn().unwrap();
+        assert!(conn.execute("UPDATE runbooks SET version = 3", []).is_err());
+    }
+
+    #[test]
+    fn test_notebook_keeps_runbook_source() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let mut notebook = notebook();
+        notebook.metadata.runbook = Some(RunbookSource {
+            id: "api-latency".into(),
+            version: 2,
+            parameters: BTreeMap::from([("service".to_string(), "api".to_string())]),
+        });
+        store.create(&notebook).unwrap();
+        assert_eq!(notebook, store.get(&notebook.id).unwrap());
+    }
+
+    #[test]
+    fn test_notebook_keeps_incident() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let mut notebook = notebook();
+        store.create(&notebook).unwrap();
+        let mut incident = Incident::declare(
+            Severity::Sev1,
+            None,
+            vec!["ana".into()],
+            Some("elias".into()),
+            Utc::now(),
+        );
+        incident
+            .transition(
+                Status::Mitigated,
+                Some("ana".into()),
+                Some("rolled back".into()),
+                Utc::now(),
+            )
+            .unwrap();
+        notebook.metadata.incident = Some(incident);
+        store
+            .update(&notebook, notebook.metadata.updated_at, &[], None)
+            .unwrap();
+        assert_eq!(notebook, store.get(&notebook.id).unwrap());
+    }
+
+    #[test]
+    fn test_search() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let mut api = notebook();
+        api.metadata.incident = Some(Incident::declare(
+            Severity::Sev2,
+            None,
+            vec![],
+            None,
+            Utc::now(),
+        ));
+        let mut db = notebook();
+        db.metadata.title = "db failover".into();
+        db.metadata.tags = vec!["db".into()];
+        for notebook in [&api, &db] {
+            store.create(notebook).unwrap();
+            store.index_notebook(notebook).unwrap();
+        }
+        assert!(!store.is_empty().unwrap());
+        let mut refused = event(
+            &api.id,
+            &api.cells[1].id,
+            "dial tcp 10.0.0.1:5432: connection refused",
+        );
+        refused.plugin = "k8s".into();
+        store.index_event(&refused).unwrap();
+        store
+            .index_event(&event(
+                &db.id,
+                &db.cells[1].id,
+                "connections were refused by the primary",
+            ))
+            .unwrap();
+
+        let search = |query: SearchQuery| store.search(&query).unwrap();
+        let query = |text: &str| SearchQuery {
+            text: text.into(),
+            ..Default::default()
+        };
+        let hits = search(query("connection refused"));
+        assert_eq!(2, hits.len());
+        let hit = hits
+            .iter()
+            .find(|h| h.plugin.as_deref() == Some("k8s"))
+            .unwrap();
+        assert_eq!(
+            (
+                Some("api latency"),
+                Source::Output,
+                Some(refused.id.as_str())
+            ),
+            (
+                hit.notebook_title.as_deref(),
+                hit.source,
+                hit.event_id.as_deref()
+            )
+        );
+        assert_eq!(
+            "dial tcp 10.0.0.1:5432: **connection** **refused**",
+            hit.snippet
+        );
+
+        assert_eq!(
+            1,
+            search(SearchQuery {
+                plugin: Some("k8s".into()),
+                ..query("refused")
+            })
+            .len()
+        );
+        assert_eq!(
+            1,
+            search(SearchQuery {
+                tag: Some("db".into()),
+                ..query("refused")
+            })
+            .len()
+        );
+        assert_eq!(
+            1,
+            search(SearchQuery {
+                severity: Some(Severity::Sev2),
+                ..query("refused")
+            })
+            .len()
+        );
+        assert!(search(SearchQuery {
+            to: Some(Utc::now() - chrono::Duration::hours(1)),
+            ..query("refused")
+        })
+        .is_empty());
+        let inputs = search(SearchQuery {
+            plugin: Some("prometheus".into()),
+            ..query("latency*")
+        });
+        assert_eq!(
+            vec![Source::Input; 2],
+            inputs.iter().map(|h| h.source).collect::<Vec<_>>()
+        );
+        assert!(search(query("  ")).is_empty());
+
+        // saving the notebook again replaces its documents, not its outputs
+        db.cells = vec![Cell::markdown("promoted the replica")];
+        store.index_notebook(&db).unwrap();
+        assert!(search(query("latency*"))
+            .iter()
+            .all(|h| h.notebook_id != Some(db.id.clone())));
+        assert_eq!(1, search(query("replica")).len());
+        assert_eq!(2, search(query("refused")).len());
+
+        // indexing again, as a backfill does, adds nothing
+        store.index_event(&refused).unwrap();
+        assert_eq!(2, search(query("refused")).len());
+
+        store.delete(&db.id).unwrap();
+        assert_eq!(1, search(query("refused")).len());
+        store.remove_notebook(&api.id).unwrap();
+        assert!(search(query("refused")).is_empty());
+        assert!(store.is_empty().unwrap());
+    }
+
+    #[test]
+    fn test_schedules() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let every = Trigger::Every { seconds: 60 };
+        let mut first = Schedule::new(
+            "n1",
+            Some("errors".into()),
+            every.clone(),
+            None,
+            None,
+            Actor::default(),
+            Utc::now(),
+        )
+        .unwrap();
+        let second =
+            Schedule::new("n2", None, every, None, None, Actor::default(), Utc::now()).unwrap();
+        store.save_schedule(&first).unwrap();
+        store.save_schedule(&second).unwrap();
+
+        first.record(Utc::now(), Some("e1".into()), None);
+        store.save_schedule(&first).unwrap();
+        assert_eq!(first, store.schedule(&first.id).unwrap());
+        assert_eq!(
+            vec![first.clone(), second.clone()],
+            store.schedules(None).unwrap()
+        );
+        assert_eq!(vec![second.clone()], store.schedules(Some("n2")).unwrap());
+
+        store.delete_schedule(&first.id).unwrap();
+        assert_eq!(
+            Err(StoreError::NotFound(first.id.clone())),
+            store.schedule(&first.id)
+        );
+        assert_eq!(
+            Err(StoreError::NotFound(first.id.clone())),
+            store.delete_schedule(&first.id)
+        );
+        assert_eq!(vec![second], store.schedules(None).unwrap());
+    }
+
+    #[test]
+    fn test_approvals() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let config = crate::plugin_config::ApprovalConfig {
+            group: "sre-leads".into(),
+            min_approvers: None,
+            window_minutes: None,
+        };
+        let context = ExecutionContext {
+            notebook_id: Some("n1".into()),
+            user: Some("alice".into()),
+            ..Default::default()
+        };
+        let mut first = ApprovalRequest::new(
+            context,
+            "restart-service",
+            BTreeMap::new(),
+            &config,
+            Utc::now(),
+        );
+        let second = ApprovalRequest::new(
+            ExecutionContext::default(),
+            "kubectl",
+            BTreeMap::new(),
+            &config,
+            Utc::now(),
+        );
+        store.save_approval(&first).unwrap();
+        store.save_approval(&second).unwrap();
+
+        assert!(first.approve("bob", Utc::now()).unwrap());
+        store.save_approval(&first).unwrap();
+        assert_eq!(first, store.approval(&first.id).unwrap());
+        assert_eq!(vec![first.clone(), second], store.approvals(None).unwrap());
+        assert_eq!(vec![first], store.approvals(Some("n1")).unwrap());
+        assert_eq!(Err(StoreError::NotFound("x".into())), store.approval("x"));
+    }
+
+    #[test]
+    fn test_runs_most_recent_first() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let first = NotebookRun::new("n1", None, Utc::now(), vec![]);
+        let second = NotebookRun::new("n1", Some("nightly".into()), Utc::now(), vec![]);
+        store.append_run(&first).unwrap();
+        store.append_run(&second).unwrap();
+        store
+            .append_run(&NotebookRun::new("n2", None, Utc::now(), vec![]))
+            .unwrap();
+
+        assert_eq!(vec![second, first], store.runs("n1").unwrap());
+    }
+
+    #[test]
+    fn test_revisions() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let mut notebook = notebook();
+        store.create(&notebook).unwrap();
+        let first = store.append_revision(&notebook, None).unwrap();
+        notebook.metadata.title = "api down".into();
+        let second = store.append_revision(&notebook, Some("ana")).unwrap();
+        assert_eq!((1, 2), (first.number, second.number));
+
+        assert_eq!(first, store.revision(&notebook.id, Some(1)).unwrap());
+        assert_eq!(second, store.revision(&notebook.id, None).unwrap());
+        assert_eq!(
+            vec![
+                (2, Some("ana".to_string()), "api down".to_string()),
+                (1, None, "api latency".to_string())
+            ],
+            store
+                .revisions(&notebook.id)
+                .unwrap()
+                .into_iter()
+                .map(|r| (r.number, r.author, r.title))
+                .collect::<Vec<_>>()
+        );
+        assert!(matches!(
+            store.revision(&notebook.id, Some(7)),
+            Err(StoreError::NotFound(_))
+        ));
+
+        store.delete(&notebook.id).unwrap();
+        assert!(store.revisions(&notebook.id).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_prune_revisions() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let notebook = notebook();
+        for _ in 0..5 {
+            store.append_revision(&notebook, None).unwrap();
+        }
+        let retention = Retention {
+            keep: Some(3),
+            max_age_days: None,
+        };
+        assert_eq!(2, store.prune_revisions(&notebook.id, retention).unwrap());
+        let numbers: Vec<u64> = store
+            .revisions(&notebook.id)
+            .unwrap()
+            .iter()
+            .map(|r| r.number)
+            .collect();
+        assert_eq!(vec![5, 4, 3], numbers);
+
+        // the latest revision is kept whatever its age
+        let retention = Retention {
+            keep: None,
+            max_age_days: Some(0),
+        };
+        assert_eq!(2, store.prune_revisions(&notebook.id, retention).unwrap());
+        assert_eq!(5, store.revision(&notebook.id, None).unwrap().number);
+        assert_eq!(6, store.append_revision(&notebook, None).unwrap().number);
+    }
+}
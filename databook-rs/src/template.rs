@@ -0,0 +1,302 @@
+use serde_json::Value;
+use std::fmt;
+
+// Templates are a small subset of mustache, enough for people to change
+// the layout of exports without rebuilding the server:
+//
+//   {{path}}              the value, escaped for html exports
+//   {{{path}}}            the value as is (e.g. pre-rendered outputs)
+//   {{#path}}...{{/path}} repeated for every item of a list, or shown once
+//                         if the value is set (not false, null or empty)
+//   {{^path}}...{{/path}} shown if the value is not set or an empty list
+//
+// Paths are dot separated and looked up from the innermost section out,
+// `.` is the current item and numbers index lists (`{{#tags.0}}` shows a
+// section only if there are tags).
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TemplateError {
+    Unclosed(String),
+    UnexpectedClose(String),
+    EmptyTag,
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateError::Unclosed(tag) => write!(f, "{{{{{}}}}} is never closed", tag),
+            TemplateError::UnexpectedClose(tag) => {
+                write!(f, "{{{{/{}}}}} does not close the last opened section", tag)
+            }
+            TemplateError::EmptyTag => write!(f, "empty {{{{}}}} tag"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Escape {
+    None,
+    Html,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Node {
+    Text(String),
+    Value {
+        path: String,
+        escape: bool,
+    },
+    Section {
+        path: String,
+        inverted: bool,
+        children: Vec<Node>,
+    },
+}
+
+#[derive(Debug)]
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+impl Template {
+    pub fn parse(text: &str) -> Result<Self, TemplateError> {
+        // the stack holds the open sections: their tag and nodes so far
+        let mut stack: Vec<(String, bool, Vec<Node>)> = vec![(String::new(), false, vec![])];
+        let mut rest = text;
+        while let Some(start) = rest.find("{{") {
+            let current = &mut stack.last_mut().expect("the root is never popped").2;
+            if start > 0 {
+                current.push(Node::Text(rest[..start].to_string()));
+            }
+            let (tag, raw, after) = if let Some(inner) = rest[start..].strip_prefix("{{{") {
+                let end = inner
+                    .find("}}}")
+                    .ok_or_else(|| TemplateError::Unclosed(inner.chars().take(20).collect()))?;
+                (&inner[..end], true, &inner[end + 3..])
+            } else {
+                let inner = &rest[start + 2..];
+                let end = inner
+                    .find("}}")
+                    .ok_or_else(|| TemplateError::Unclosed(inner.chars().take(20).collect()))?;
+                (&inner[..end], false, &inner[end + 2..])
+            };
+            let tag = tag.trim();
+            rest = after;
+
+            if raw {
+                if tag.is_empty() {
+                    return Err(TemplateError::EmptyTag);
+                }
+                current.push(Node::Value {
+                    path: tag.to_string(),
+                    escape: false,
+                });
+            } else if let Some(path) = tag.strip_prefix('#') {
+                stack.push((path.trim().to_string(), false, vec![]));
+            } else if let Some(path) = tag.strip_prefix('^') {
+                stack.push((path.trim().to_string(), true, vec![]));
+            } else if let Some(path) = tag.strip_prefix('/') {
+                let path = path.trim();
+                if stack.len() == 1 || stack.last().map(|s| s.0.as_str()) != Some(path) {
+                    return Err(TemplateError::UnexpectedClose(path.to_string()));
+                }
+                let (path, inverted, children) = stack.pop().expect("checked above");
+                stack
+                    .last_mut()
+                    .expect("the root is never popped")
+                    .2
+                    .push(Node::Section {
+                        path,
+                        inverted,
+                        children,
+                    });
+            } else if tag.is_empty() {
+                return Err(TemplateError::EmptyTag);
+            } else {
+                current.push(Node::Value {
+                    path: tag.to_string(),
+                    escape: true,
+                });
+            }
+        }
+        if stack.len() > 1 {
+            return Err(TemplateError::Unclosed(
+                stack.pop().expect("checked above").0,
+            ));
+        }
+        let mut nodes = stack.pop().expect("the root is never popped").2;
+        if !rest.is_empty() {
+            nodes.push(Node::Text(rest.to_string()));
+        }
+        Ok(Self { nodes })
+    }
+
+    pub fn render(&self, data: &Value, escape: Escape) -> String {
+        let mut output = String::new();
+        render_nodes(&self.nodes, &mut vec![data], escape, &mut output);
+        output
+    }
+}
+
+fn lookup<'a>(scopes: &[&'a Value], path: &str) -> Option<&'a Value> {
+    let current = *scopes.last()?;
+    if path == "." {
+        return Some(current);
+    }
+    let mut parts = path.split('.');
+    let first = parts.next()?;
+    // the first part decides the scope, like mustache
+    let mut value = scopes.iter().rev().find_map(|s| (*s).get(first))?;
+    for part in parts {
+        value = match (value, part.parse::<usize>()) {
+            (Value::Array(items), Ok(index)) => items.get(index)?,
+            _ => value.get(part)?,
+        };
+    }
+    Some(value)
+}
+
+fn is_set(value: Option<&Value>) -> bool {
+    match value {
+        None | Some(Value::Null) | Some(Value::Bool(false)) => false,
+        Some(Value::String(s)) => !s.is_empty(),
+        Some(Value::Array(a)) => !a.is_empty(),
+        Some(_) => true,
+    }
+}
+
+fn to_text(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
+
+fn render_nodes<'a>(
+    nodes: &'a [Node],
+    scopes: &mut Vec<&'a Value>,
+    escape: Escape,
+    output: &mut String,
+) {
+    for node in nodes {
+        match node {
+            Node::Text(text) => output.push_str(text),
+            Node::Value {
+                path,
+                escape: escaped,
+            } => {
+                let text = lookup(scopes, path).map(to_text).unwrap_or_default();
+                if *escaped && escape == Escape::Html {
+                    output.push_str(&escape_html(&text));
+                } else {
+                    output.push_str(&text);
+                }
+            }
+            Node::Section {
+                path,
+                inverted,
+                children,
+            } => {
+                let value = lookup(scopes, path);
+                if *inverted {
+                    if !is_set(value) {
+                        render_nodes(children, scopes, escape, output);
+                    }
+                    continue;
+                }
+                match value {
+                    Some(Value::Array(items)) => {
+                        for item in items {
+                            scopes.push(item);
+                            render_nodes(children, scopes, escape, output);
+                            scopes.pop();
+                        }
+                    }
+                    Some(value) if is_set(Some(value)) => {
+                        scopes.push(value);
+                        render_nodes(children, scopes, escape, output);
+                        scopes.pop();
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn render(template: &str, data: Value, escape: Escape) -> String {
+        Template::parse(template).unwrap().render(&data, escape)
+    }
+
+    #[test]
+    fn test_values_and_escaping() {
+        let data = json!({"title": "<p99> & co", "count": 3, "owner": {"name": "elias"}});
+        assert_eq!(
+            "&lt;p99&gt; &amp; co, 3 by elias <p99> & co",
+            render(
+                "{{title}}, {{ count }} by {{owner.name}} {{{title}}}",
+                data.clone(),
+                Escape::Html
+            )
+        );
+        assert_eq!("<p99> & co", render("{{title}}", data, Escape::None));
+    }
+
+    #[test]
+    fn test_sections() {
+        let data = json!({
+            "title": "incident",
+            "cells": [{"id": "a", "error": "timeout"}, {"id": "b"}],
+            "tags": [],
+        });
+        assert_eq!(
+            "a: timeout (incident)\nb: ok (incident)\nno tags",
+            render(
+                "{{#cells}}{{id}}: {{#error}}{{.}}{{/error}}{{^error}}ok{{/error}} ({{title}})\n{{/cells}}{{#tags}}{{.}}{{/tags}}{{^tags}}no tags{{/tags}}",
+                data,
+                Escape::None
+            )
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            TemplateError::Unclosed("cells".into()),
+            Template::parse("{{#cells}}{{id}}").unwrap_err()
+        );
+        assert_eq!(
+            TemplateError::UnexpectedClose("runs".into()),
+            Template::parse("{{#cells}}{{/runs}}").unwrap_err()
+        );
+        assert_eq!(
+            TemplateError::EmptyTag,
+            Template::parse("{{ }}").unwrap_err()
+        );
+        assert!(matches!(
+            Template::parse("{{title").unwrap_err(),
+            TemplateError::Unclosed(_)
+        ));
+    }
+}
@@ -0,0 +1,142 @@
+use crate::approval::Approval;
+use crate::notebook::new_id;
+use crate::notebook_store::StoreError;
+use crate::plugin_manager::Execution;
+use crate::plugin_runtime::{HostCall, LogEntry};
+use crate::policy::Principal;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+// ExecutionEvent is the record of one plugin execution. Events are append
+// only: re-running a cell adds a new event, the previous output stays.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExecutionEvent {
+    pub id: String,
+    // notebook and cell are empty for direct invocations (e.g. grpc `get`)
+    pub notebook_id: Option<String>,
+    pub cell_id: Option<String>,
+    pub user: Option<String>,
+    pub plugin: String,
+    pub plugin_version: Option<String>,
+    pub options: BTreeMap<String, String>,
+    pub output: Option<String>,
+    pub logs: Vec<LogEntry>,
+    pub error: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub host_calls: Vec<HostCall>,
+    // inputs are the references resolved for the run, `vars.<name>` to the
+    // value used and `cells.<id>` to the execution whose output was used
+    #[serde(default)]
+    pub inputs: BTreeMap<String, String>,
+    #[serde(default)]
+    pub kind: EventKind,
+    // who approved the execution, for plugins requiring approval
+    #[serde(default)]
+    pub approvals: Vec<Approval>,
+}
+
+// EventKind tells plugin executions from the other events written to the
+// timeline of a notebook
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    #[default]
+    Execution,
+    // a change to the incident of the notebook, see incident_api
+    Incident,
+}
+
+// ExecutionContext is where an execution comes from
+#[derive(Debug, Clone, Default)]
+pub struct ExecutionContext {
+    pub notebook_id: Option<String>,
+    pub cell_id: Option<String>,
+    pub user: Option<String>,
+    // who the plugin policy is checked for, `user` only names them
+    pub principal: Principal,
+    pub inputs: BTreeMap<String, String>,
+    // address of the caller, only recorded in the audit log
+    pub source: Option<String>,
+    pub approvals: Vec<Approval>,
+}
+
+impl ExecutionEvent {
+    pub fn new(
+        context: ExecutionContext,
+        plugin: &str,
+        options: BTreeMap<String, String>,
+        execution: Execution,
+        started_at: DateTime<Utc>,
+        finished_at: DateTime<Utc>,
+    ) -> Self {
+        let (output, error) = match execution.output {
+            Ok(output) => (Some(output), None),
+            Err(e) => (None, Some(format!("{:?}", e))),
+        };
+        Self {
+            id: new_id(),
+            notebook_id: context.notebook_id,
+            cell_id: context.cell_id,
+            user: context.user,
+            plugin: plugin.to_string(),
+            plugin_version: execution.plugin_version,
+            options,
+            output,
+            logs: execution.trace.logs,
+            error,
+            started_at,
+            finished_at,
+            host_calls: execution.trace.host_calls,
+            inputs: context.inputs,
+            kind: EventKind::Execution,
+            approvals: context.approvals,
+        }
+    }
+
+    // incident records a change to the incident of the notebook, `summary`
+    // is the output and `details` the options of the event
+    pub fn incident(
+        notebook_id: &str,
+        user: Option<String>,
+        summary: String,
+        details: BTreeMap<String, String>,
+        at: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            id: new_id(),
+            notebook_id: Some(notebook_id.to_string()),
+            cell_id: None,
+            user,
+            plugin: "incident".to_string(),
+            plugin_version: None,
+            options: details,
+            output: Some(summary),
+            logs: vec![],
+            error: None,
+            started_at: at,
+            finished_at: at,
+            host_calls: vec![],
+            inputs: BTreeMap::new(),
+            kind: EventKind::Incident,
+            approvals: vec![],
+        }
+    }
+}
+
+// TimelineFilter narrows the events listed, empty fields match everything
+#[derive(Debug, Clone, Default)]
+pub struct TimelineFilter {
+    pub notebook_id: Option<String>,
+    pub cell_id: Option<String>,
+}
+
+// TimelineStore keeps the execution events, there is no way to change or
+// remove an event once appended
+pub trait TimelineStore: Send + Sync {
+    fn append(&self, event: &ExecutionEvent) -> Result<(), StoreError>;
+    // events returns the events in the order they were appended
+    fn events(&self, filter: &TimelineFilter) -> Result<Vec<ExecutionEvent>, StoreError>;
+}
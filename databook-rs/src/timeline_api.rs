@@ -0,0 +1,468 @@
+use crate::approval::Approval;
+use crate::approval_api;
+use crate::assertions::EXPECT_OPTION;
+use crate::auth;
+use crate::cell_parser::parse_cell;
+use crate::databook;
+use crate::databook::timeline_server::Timeline;
+use crate::live::LiveEvent;
+use crate::live_api;
+use crate::notebook::CellContent;
+use crate::notebook_api::{self, NotebookApiError};
+use crate::notebook_store::StoreError;
+use crate::plugin_manager::InvocationError;
+use crate::plugin_runtime::{HostCall, LogEntry};
+use crate::policy::{Actor, Permission};
+use crate::references::{self, CellState, ReferenceContext};
+use crate::search_api;
+use crate::timeline::{EventKind, ExecutionContext, ExecutionEvent, TimelineFilter, TimelineStore};
+use crate::{rest, run_blocking, PLUGINS, TIMELINE};
+
+use chrono::{DateTime, TimeZone, Utc};
+use rocket_contrib::json::Json;
+use std::collections::BTreeMap;
+use tonic::{Request, Response, Status};
+use tracing::instrument;
+
+pub fn timeline() -> Result<&'static dyn TimelineStore, NotebookApiError> {
+    TIMELINE
+        .get()
+        .map(|s| s.as_ref())
+        .ok_or_else(|| NotebookApiError::Internal("No timeline store setup".into()))
+}
+
+// append adds the event to the timeline and to the search index
+pub fn append(event: &ExecutionEvent) -> Result<(), NotebookApiError> {
+    timeline()?.append(event)?;
+    search_api::index_event(event);
+    Ok(())
+}
+
+// execute invokes the plugin and appends the execution to the timeline.
+// The event is returned even if the plugin failed, the error is part of it.
+// Plugins requiring approval do not run without it, an approval request is
+// made instead and the invocation fails with ApprovalPending.
+pub fn execute(
+    context: ExecutionContext,
+    plugin: &str,
+    options: BTreeMap<String, String>,
+) -> Result<ExecutionEvent, NotebookApiError> {
+    // fails before running the plugin when there is no timeline
+    timeline()?;
+    let plugins = PLUGINS
+        .get()
+        .ok_or_else(|| NotebookApiError::Internal("No plugins setup".into()))?
+        .read()
+        .map_err(|e| NotebookApiError::Internal(format!("could not lock plugins {:?}", e)))?;
+    let approval = if context.approvals.is_empty() {
+        plugins.approval_config(plugin)
+    } else {
+        None
+    };
+
+    if let (None, Some(notebook_id), Some(cell_id)) =
+        (&approval, &context.notebook_id, &context.cell_id)
+    {
+        live_api::publish(
+            notebook_id,
+            LiveEvent::ExecutionStarted {
+                cell_id: cell_id.clone(),
+                user: context.user.clone(),
+                plugin: plugin.to_string(),
+            },
+        );
+    }
+
+    let started_at = Utc::now();
+    let execution = plugins.execute(
+        plugin,
+        options.clone().into_iter().collect(),
+        &context.principal,
+        context.source.as_deref(),
+        &context.approvals,
+    );
+    let finished_at = Utc::now();
+
+    if let (Some(config), Err(InvocationError::ApprovalRequired(_))) =
+        (&approval, &execution.output)
+    {
+        drop(plugins);
+        let request = approval_api::request_approval(context, plugin, options, config)?;
+        return Err(NotebookApiError::ApprovalPending(format!(
+            "{} needs {} approval(s) from {}, approval request {} is pending",
+            plugin, request.min_approvers, request.group, request.id
+        )));
+    }
+
+    let event = ExecutionEvent::new(context, plugin, options, execution, started_at, finished_at);
+    append(&event)?;
+    if let Some(notebook_id) = &event.notebook_id {
+        live_api::publish(
+            notebook_id,
+            LiveEvent::ExecutionFinished {
+                execution: Box::new(event.clone()),
+            },
+        );
+    }
+    Ok(event)
+}
+
+// parse_invocation reads a raw `@plugin=` cell into the plugin and the
+// options it is invoked with
+pub fn parse_invocation(
+    text: &str,
+) -> Result<(String, BTreeMap<String, String>), NotebookApiError> {
+    let cell = parse_cell(text).map_err(NotebookApiError::InvalidCell)?;
+    let body_option = PLUGINS
+        .get()
+        .ok_or_else(|| NotebookApiError::Internal("No plugins setup".into()))?
+        .read()
+        .map_err(|e| NotebookApiError::Internal(format!("could not lock plugins {:?}", e)))?
+        .body_option(&cell.plugin);
+    let plugin = cell.plugin.clone();
+    let options = cell
+        .into_options(&body_option)
+        .map_err(NotebookApiError::InvalidCell)?;
+    Ok((plugin, options))
+}
+
+// invoke_cell parses and executes a raw cell sent by a front-end
+pub fn invoke_cell(
+    text: &str,
+    actor: Actor,
+    source: Option<String>,
+) -> Result<ExecutionEvent, NotebookApiError> {
+    let (plugin, mut options) = parse_invocation(text)?;
+    // assertions are only checked on headless runs
+    options.remove(EXPECT_OPTION);
+    let context = ExecutionContext {
+        user: actor.name,
+        principal: actor.principal,
+        source,
+        ..Default::default()
+    };
+    execute(context, &plugin, options)
+}
+
+// run_cell executes a plugin cell with the options saved on the notebook,
+// after resolving their references to variables and other cells. Cells
+// that referred to this one are then announced as stale.
+pub fn run_cell(
+    notebook_id: &str,
+    cell_id: &str,
+    actor: Actor,
+    source: Option<String>,
+) -> Result<ExecutionEvent, NotebookApiError> {
+    let notebook =
+        notebook_api::get_authorized(notebook_id, &actor.principal, Permission::Execute)?;
+    let cell = notebook.cell(cell_id).ok_or_else(|| {
+        NotebookApiError::InvalidRequest(format!(
+            "cell {} does not exist on notebook {}",
+            cell_id, notebook_id
+        ))
+    })?;
+    let (plugin, options) = match &cell.content {
+        CellContent::Plugin { plugin, options } => {
+            let mut options = options.clone();
+            options.remove(EXPECT_OPTION);
+            (plugin.clone(), options)
+        }
+        CellContent::Markdown { .. } => {
+            return Err(NotebookApiError::InvalidRequest(format!(
+                "cell {} is not a plugin cell",
+                cell_id
+            )))
+        }
+    };
+    let mut events = notebook_events(notebook_id)?;
+    let (options, inputs) = ReferenceContext::new(&notebook.metadata.variables, &events)
+        .resolve(&options)
+        .map_err(NotebookApiError::InvalidReference)?;
+    let context = ExecutionContext {
+        notebook_id: Some(notebook_id.to_string()),
+        cell_id: Some(cell_id.to_string()),
+        user: actor.name,
+        principal: actor.principal,
+        inputs,
+        source,
+        approvals: vec![],
+    };
+    let event = execute(context, &plugin, options)?;
+
+    events.push(event.clone());
+    let stale: Vec<String> = references::cell_states(&notebook, &events)
+        .into_iter()
+        .filter(|c| c.stale)
+        .map(|c| c.cell_id)
+        .collect();
+    if !stale.is_empty() {
+        live_api::publish(notebook_id, LiveEvent::CellsStale { cell_ids: stale });
+    }
+    Ok(event)
+}
+
+pub fn notebook_events(notebook_id: &str) -> Result<Vec<ExecutionEvent>, NotebookApiError> {
+    list_events(&TimelineFilter {
+        notebook_id: Some(notebook_id.to_string()),
+        cell_id: None,
+    })
+}
+
+// cell_states lists the dependencies of the plugin cells and whether their
+// last result is stale
+pub fn cell_states(notebook_id: &str) -> Result<Vec<CellState>, NotebookApiError> {
+    let notebook = notebook_api::store()?.get(notebook_id)?;
+    Ok(references::cell_states(
+        &notebook,
+        &notebook_events(notebook_id)?,
+    ))
+}
+
+pub fn list_events(filter: &TimelineFilter) -> Result<Vec<ExecutionEvent>, NotebookApiError> {
+    Ok(timeline()?.events(filter)?)
+}
+
+// empty_as_none reads a string of a grpc request, "" when it is not set
+pub fn empty_as_none(value: String) -> Option<String> {
+    if value.is_empty() {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+// millis is a time on a grpc message, milliseconds since the epoch
+pub fn millis(time: DateTime<Utc>) -> i64 {
+    time.timestamp_millis()
+}
+
+// from_millis reads a time of a grpc request, 0 when it is not set
+pub fn from_millis(millis: i64) -> Option<DateTime<Utc>> {
+    if millis == 0 {
+        None
+    } else {
+        Utc.timestamp_millis_opt(millis).single()
+    }
+}
+
+// not_found reports a missing `what` (e.g. schedule) by its id, instead of
+// the missing notebook of a plain StoreError
+pub fn not_found(what: &str, id: &str, e: StoreError) -> NotebookApiError {
+    match e {
+        StoreError::NotFound(_) => {
+            NotebookApiError::NotFound(format!("{} {} does not exist", what, id))
+        }
+        e => e.into(),
+    }
+}
+
+pub fn to_grpc_event(event: ExecutionEvent) -> databook::ExecutionEvent {
+    databook::ExecutionEvent {
+        id: event.id,
+        notebook_id: event.notebook_id.unwrap_or_default(),
+        cell_id: event.cell_id.unwrap_or_default(),
+        user: event.user.unwrap_or_default(),
+        plugin: event.plugin,
+        plugin_version: event.plugin_version.unwrap_or_default(),
+        options: event.options.into_iter().collect(),
+        output: event.output.unwrap_or_default(),
+        logs: event.logs.into_iter().map(to_grpc_log).collect(),
+        error: event.error.unwrap_or_default(),
+        started_at: millis(event.started_at),
+        finished_at: millis(event.finished_at),
+        host_calls: event
+            .host_calls
+            .into_iter()
+            .map(to_grpc_host_call)
+            .collect(),
+        inputs: event.inputs.into_iter().collect(),
+        kind: match event.kind {
+            EventKind::Execution => "execution",
+            EventKind::Incident => "incident",
+        }
+        .to_string(),
+        approvals: event.approvals.into_iter().map(to_grpc_approval).collect(),
+    }
+}
+
+pub fn to_grpc_approval(approval: Approval) -> databook::Approval {
+    databook::Approval {
+        user: approval.user,
+        at: millis(approval.at),
+    }
+}
+
+fn to_grpc_cell_state(state: CellState) -> databook::CellState {
+    databook::CellState {
+        cell_id: state.cell_id,
+        dependencies: state.dependencies,
+        last_event_id: state.last_event_id.unwrap_or_default(),
+        stale: state.stale,
+    }
+}
+
+fn to_grpc_log(log: LogEntry) -> databook::LogEntry {
+    databook::LogEntry {
+        level: log.level,
+        message: log.message,
+    }
+}
+
+fn to_grpc_host_call(call: HostCall) -> databook::HostCall {
+    databook::HostCall {
+        function: call.function,
+        target: call.target,
+        outcome: call.outcome,
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct TimelineGrpc {}
+
+#[tonic::async_trait]
+impl Timeline for TimelineGrpc {
+    #[instrument]
+    async fn run_cell(
+        &self,
+        request: Request<databook::RunCellRequest>,
+    ) -> Result<Response<databook::ExecutionEvent>, Status> {
+        tracing::info!("received run cell request");
+        let actor = auth::actor(&request, &request.get_ref().user);
+        let source = auth::source(&request);
+        run_blocking(|| {
+            let request = request.into_inner();
+            run_cell(&request.notebook_id, &request.cell_id, actor, source).map(to_grpc_event)
+        })
+        .await
+    }
+
+    #[instrument]
+    async fn list_events(
+        &self,
+        request: Request<databook::ListEventsRequest>,
+    ) -> Result<Response<databook::ListEventsResponse>, Status> {
+        let principal = auth::actor(&request, "").principal;
+        run_blocking(move || {
+            let request = request.into_inner();
+            if !request.notebook_id.is_empty() {
+                notebook_api::get_authorized(&request.notebook_id, &principal, Permission::View)?;
+            }
+            let filter = TimelineFilter {
+                notebook_id: empty_as_none(request.notebook_id),
+                cell_id: empty_as_none(request.cell_id),
+            };
+            let mut events = list_events(&filter)?;
+            if filter.notebook_id.is_none() {
+                if let Some(visible) = notebook_api::visible_notebooks(&principal)? {
+                    events.retain(|e| e.notebook_id.as_ref().is_none_or(|id| visible.contains(id)));
+                }
+            }
+            Ok::<_, NotebookApiError>(databook::ListEventsResponse {
+                events: events.into_iter().map(to_grpc_event).collect(),
+            })
+        })
+        .await
+    }
+
+    #[instrument]
+    async fn cell_states(
+        &self,
+        request: Request<databook::CellStatesRequest>,
+    ) -> Result<Response<databook::CellStatesResponse>, Status> {
+        let principal = auth::actor(&request, "").principal;
+        run_blocking(move || {
+            let notebook_id = request.into_inner().notebook_id;
+            notebook_api::get_authorized(&notebook_id, &principal, Permission::View)?;
+            let states = cell_states(&notebook_id)?;
+            Ok::<_, NotebookApiError>(databook::CellStatesResponse {
+                cells: states.into_iter().map(to_grpc_cell_state).collect(),
+            })
+        })
+        .await
+    }
+}
+
+#[post("/notebooks/<id>/cells/<cell_id>/run", data = "<request>")]
+pub fn rest_run_cell(
+    caller: auth::Caller,
+    id: String,
+    cell_id: String,
+    request: Json<rest::RunCellRequest>,
+) -> Json<rest::ExecutionEventResponse> {
+    tracing::info!("received run cell request");
+    match run_cell(
+        &id,
+        &cell_id,
+        caller.actor(request.into_inner().user),
+        caller.source,
+    ) {
+        Ok(event) => Json(rest::ExecutionEventResponse {
+            event: Some(event),
+            error: None,
+        }),
+        Err(e) => {
+            tracing::error!("run cell request failed {:?}", e);
+            Json(rest::ExecutionEventResponse {
+                event: None,
+                error: Some(format!("{:?}", e)),
+            })
+        }
+    }
+}
+
+#[get("/notebooks/<id>/timeline")]
+pub fn rest_timeline(caller: auth::Caller, id: String) -> Json<rest::TimelineResponse> {
+    let events = notebook_api::get_authorized(&id, &caller.actor(None).principal, Permission::View)
+        .and_then(|_| notebook_events(&id));
+    match events {
+        Ok(events) => Json(rest::TimelineResponse {
+            events,
+            error: None,
+        }),
+        Err(e) => Json(rest::TimelineResponse {
+            events: vec![],
+            error: Some(format!("{:?}", e)),
+        }),
+    }
+}
+
+#[get("/notebooks/<id>/cells/states")]
+pub fn rest_cell_states(caller: auth::Caller, id: String) -> Json<rest::CellStatesResponse> {
+    let states = notebook_api::get_authorized(&id, &caller.actor(None).principal, Permission::View)
+        .and_then(|_| cell_states(&id));
+    match states {
+        Ok(cells) => Json(rest::CellStatesResponse { cells, error: None }),
+        Err(e) => Json(rest::CellStatesResponse {
+            cells: vec![],
+            error: Some(format!("{:?}", e)),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugin_manager::Execution;
+    use crate::plugin_runtime::InvocationTrace;
+
+    #[test]
+    fn test_grpc_event_keeps_failures() {
+        let event = ExecutionEvent::new(
+            ExecutionContext::default(),
+            "prometheus",
+            BTreeMap::from([("query".to_string(), "up".to_string())]),
+            Execution {
+                plugin_version: Some("1.0.0".into()),
+                output: Err(crate::plugin_manager::InvocationError::GenericError),
+                trace: InvocationTrace::default(),
+            },
+            Utc::now(),
+            Utc::now(),
+        );
+        let grpc = to_grpc_event(event);
+        assert_eq!("", grpc.output);
+        assert_eq!("GenericError", grpc.error);
+        assert_eq!("", grpc.notebook_id);
+        assert_eq!("1.0.0", grpc.plugin_version);
+    }
+}
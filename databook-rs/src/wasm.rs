@@ -1,11 +1,16 @@
-use crate::plugin_config::PluginConfig;
+use crate::plugin_config::{Capability, PluginConfig};
 use crate::plugin_runtime::runtime::add_to_linker;
-use crate::plugin_runtime::PluginRuntime;
+use crate::plugin_runtime::{InvocationTrace, LogEntry, PluginRuntime};
+use crate::redaction::{RedactionRules, Redactor};
 use std::collections::HashMap;
+use std::env;
 use std::fmt;
+use std::io::Cursor;
 use std::str;
+use std::sync::{Arc, PoisonError, RwLock};
 
 use tracing::instrument;
+use wasi_common::pipe::WritePipe;
 
 use wit_bindgen_host_wasmtime_rust::wasmtime::{Engine, Linker, Module, Store}; // 0.1.25
 
@@ -21,8 +26,12 @@ struct Context {
 #[derive(Debug)]
 pub enum WasmError {
     GenericError(String),
+    CapabilityNotGranted(String),
 }
 
+const RUNTIME_MODULE: &str = "runtime";
+const WASI_MODULES: [&str; 2] = ["wasi_snapshot_preview1", "wasi_unstable"];
+
 pub struct WasmModule {
     module: Module,
     linker: Linker<Context>,
@@ -35,29 +44,141 @@ impl fmt::Debug for WasmModule {
     }
 }
 
-fn default_wasi() -> wasmtime_wasi::WasiCtx {
-    wasmtime_wasi::sync::WasiCtxBuilder::new()
-        .inherit_stdio()
-        .build()
+// Stdio is what the plugin prints, captured so it is redacted and kept
+// with its logs rather than written to the stdout of the server. The
+// buffers are shared with the pipes of the WASI context, so they are read
+// even while something still holds the pipes.
+#[derive(Default)]
+struct Stdio {
+    stdout: Arc<RwLock<Cursor<Vec<u8>>>>,
+    stderr: Arc<RwLock<Cursor<Vec<u8>>>>,
+}
+
+impl Stdio {
+    fn new() -> Self {
+        Self::default()
+    }
+
+    // into_logs adds what was printed to the logs. A write that panicked
+    // poisons its buffer, what the buffer holds is kept anyway.
+    fn into_logs(self, redactor: &Redactor, logs: &mut Vec<LogEntry>) {
+        for (level, buffer) in [("stdout", self.stdout), ("stderr", self.stderr)] {
+            let printed = buffer.read().unwrap_or_else(PoisonError::into_inner);
+            for line in String::from_utf8_lossy(printed.get_ref()).lines() {
+                let message = redactor.redact(line);
+                tracing::info!("plugin {} {}", level, message);
+                logs.push(LogEntry {
+                    level: level.to_string(),
+                    message,
+                });
+            }
+        }
+    }
+}
+
+// the WASI context only exposes what the plugin capabilities allow:
+// stdio for `log` and the allowed env vars for `env`, whose values are
+// then redacted
+fn wasi_for_config(
+    config: &PluginConfig,
+    stdio: &Stdio,
+    redactor: &mut Redactor,
+) -> wasmtime_wasi::WasiCtx {
+    let mut builder = wasmtime_wasi::sync::WasiCtxBuilder::new();
+    if config.has_capability(Capability::Log) {
+        builder = builder
+            .stdout(Box::new(WritePipe::from_shared(stdio.stdout.clone())))
+            .stderr(Box::new(WritePipe::from_shared(stdio.stderr.clone())));
+    }
+    if config.has_capability(Capability::Env) {
+        for key in config.allowed_env_vars.iter().flatten() {
+            if let Ok(value) = env::var(key) {
+                builder = builder
+                    .env(key, &value)
+                    .expect("env vars always fit the WASI limits");
+                redactor.track(&value);
+            }
+        }
+    }
+    builder.build()
+}
+
+// required_capability maps an import of the module to the capability that
+// grants it, None means the import is always available
+fn required_capability(module: &str, name: &str) -> Result<Option<Capability>, WasmError> {
+    if module == RUNTIME_MODULE {
+        return Ok(match name {
+            "http" => Some(Capability::Http),
+            "env" => Some(Capability::Env),
+            "log" => Some(Capability::Log),
+            _ => None,
+        });
+    }
+
+    if WASI_MODULES.contains(&module) {
+        return Ok(match name {
+            "proc_exit" | "proc_raise" | "args_get" | "args_sizes_get" | "sched_yield" => None,
+            "environ_get" | "environ_sizes_get" => Some(Capability::Env),
+            "clock_time_get" | "clock_res_get" | "poll_oneoff" => Some(Capability::Clock),
+            "random_get" => Some(Capability::Random),
+            "fd_write" => Some(Capability::Log),
+            _ => Some(Capability::Fs),
+        });
+    }
+
+    Err(WasmError::GenericError(format!(
+        "unknown import module {:?}",
+        module
+    )))
+}
+
+// check_capabilities refuses modules importing host functions of a
+// capability that was not granted in config.toml
+fn check_capabilities(module: &Module, config: &PluginConfig) -> Result<(), WasmError> {
+    if config.capabilities.is_none() {
+        tracing::warn!(
+            "plugin {:?} does not declare capabilities, granting all of them",
+            config.name
+        );
+    }
+    for import in module.imports() {
+        if let Some(capability) = required_capability(import.module(), import.name())? {
+            if !config.has_capability(capability) {
+                return Err(WasmError::CapabilityNotGranted(format!(
+                    "plugin {:?} imports {}::{} which needs the {:?} capability, add it to capabilities in config.toml",
+                    config.name,
+                    import.module(),
+                    import.name(),
+                    capability
+                )));
+            }
+        }
+    }
+    Ok(())
 }
 
 impl WasmModule {
-    pub fn new(path: &str) -> Result<Self, WasmError> {
+    pub fn new(binary: &[u8], config: &PluginConfig) -> Result<Self, WasmError> {
         // An engine stores and configures global compilation settings like
         // optimization level, enabled wasm features, etc.
         let engine = Engine::default();
 
         // We start off by creating a `Module` which represents a compiled form
         // of our input wasm module. In this case it'll be JIT-compiled after
-        // we parse the text format.
-        //could use from_binary as well
-        let module =
-            Module::from_file(&engine, path).map_err(|e| WasmError::GenericError(e.to_string()))?;
+        // we parse the binary format.
+        let module = Module::from_binary(&engine, binary)
+            .map_err(|e| WasmError::GenericError(e.to_string()))?;
+
+        check_capabilities(&module, config)?;
 
         let mut linker = Linker::new(&engine);
 
-        wasmtime_wasi::add_to_linker(&mut linker, |cx: &mut Context| &mut cx.wasi)
-            .map_err(|e| WasmError::GenericError(e.to_string()))?;
+        // WASI is only linked for modules importing it, the imports were
+        // already checked against the granted capabilities
+        if module.imports().any(|i| WASI_MODULES.contains(&i.module())) {
+            wasmtime_wasi::add_to_linker(&mut linker, |cx: &mut Context| &mut cx.wasi)
+                .map_err(|e| WasmError::GenericError(e.to_string()))?;
+        }
 
         add_to_linker(&mut linker, |cx| &mut cx.runtime)
             .map_err(|e| WasmError::GenericError(e.to_string()))?;
@@ -69,36 +190,128 @@ impl WasmModule {
         })
     }
 
-    fn new_store(&self, config: PluginConfig, input: HashMap<String, String>) -> Store<Context> {
+    fn new_store(
+        &self,
+        config: PluginConfig,
+        input: HashMap<String, String>,
+        rules: Arc<RedactionRules>,
+        stdio: &Stdio,
+    ) -> Store<Context> {
+        let mut redactor = Redactor::new(rules);
+        let wasi = wasi_for_config(&config, stdio, &mut redactor);
         Store::new(
             &self.engine,
             Context {
-                wasi: default_wasi(),
+                wasi,
                 exports: PluginData::default(),
-                runtime: PluginRuntime { config, input },
+                runtime: PluginRuntime {
+                    config,
+                    input,
+                    trace: InvocationTrace::default(),
+                    redactor,
+                },
             },
         )
     }
 
-    // invokes the plugin and gets the output from it
-    #[instrument]
-    pub fn invoke<'a>(
+    // invokes the plugin and gets the output from it, together with the
+    // logs and host calls made, even if the invocation failed. Env values
+    // handed to the plugin and credentials matching the rules are redacted
+    // from all of them.
+    #[instrument(skip(rules))]
+    pub fn invoke(
         &self,
         input: HashMap<String, String>,
         config: PluginConfig,
-    ) -> Result<String, WasmError> {
-        let mut store = self.new_store(config, input);
-        let (plugin, _instance) =
+        rules: Arc<RedactionRules>,
+    ) -> (Result<String, WasmError>, InvocationTrace) {
+        let stdio = Stdio::new();
+        let mut store = self.new_store(config, input, rules, &stdio);
+        let output =
             Plugin::instantiate(&mut store, &self.module, &mut self.linker.clone(), |cx| {
                 &mut cx.exports
             })
             .map_err(|e| {
                 tracing::error!("error while instantiating plugin {:?}", e);
                 WasmError::GenericError(e.to_string())
-            })?;
+            })
+            .and_then(|(plugin, _instance)| {
+                plugin
+                    .invoke(&mut store)
+                    .map_err(|e| WasmError::GenericError(e.to_string()))
+            });
+
+        let runtime = &mut store.data_mut().runtime;
+        let output = output.map(|o| runtime.redactor.redact(&o));
+        let mut trace = runtime.take_trace();
+        let redactor = std::mem::take(&mut runtime.redactor);
+        drop(store);
+        stdio.into_logs(&redactor, &mut trace.logs);
+        (output, trace)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(capabilities: Option<Vec<Capability>>) -> PluginConfig {
+        PluginConfig {
+            name: "TestPlugin".to_string(),
+            allowed_env_vars: None,
+            allowed_domains: None,
+            capabilities,
+            body_option: None,
+            requires_approval: None,
+        }
+    }
+
+    fn module(imports: &str) -> Module {
+        Module::new(&Engine::default(), format!("(module {})", imports)).unwrap()
+    }
+
+    #[test]
+    fn test_granted_capabilities() {
+        let module = module(
+            r#"(import "runtime" "http" (func (param i32)))
+               (import "runtime" "get" (func (param i32)))"#,
+        );
+        assert!(check_capabilities(&module, &config(Some(vec![Capability::Http]))).is_ok());
+        assert!(check_capabilities(&module, &config(None)).is_ok());
+    }
+
+    #[test]
+    fn test_capability_not_granted() {
+        let module = module(r#"(import "wasi_snapshot_preview1" "path_open" (func (param i32)))"#);
+        assert!(matches!(
+            check_capabilities(&module, &config(Some(vec![Capability::Log]))),
+            Err(WasmError::CapabilityNotGranted(_))
+        ));
+    }
+
+    #[test]
+    fn test_stdio_is_read_while_pipes_are_held() {
+        use std::io::Write;
+
+        let stdio = Stdio::new();
+        let _pipe = WritePipe::from_shared(stdio.stdout.clone());
+        stdio
+            .stdout
+            .write()
+            .unwrap()
+            .write_all(b"first\nsecond\n")
+            .unwrap();
+        let mut logs = vec![];
+        stdio.into_logs(&Redactor::default(), &mut logs);
+        assert_eq!(
+            vec!["first", "second"],
+            logs.iter().map(|l| l.message.as_str()).collect::<Vec<_>>()
+        );
+    }
 
-        plugin
-            .invoke(&mut store)
-            .map_err(|e| WasmError::GenericError(e.to_string()))
+    #[test]
+    fn test_unknown_import_module() {
+        let module = module(r#"(import "host" "exec" (func (param i32)))"#);
+        assert!(check_capabilities(&module, &config(None)).is_err());
     }
 }